@@ -0,0 +1,200 @@
+//! Audit rules
+//!
+//! Panels define audit rules on outbound destinations. Connections matching any of them are rejected,
+//! and recorded as violations for reporting back to the panel.
+
+use std::{fmt, io, mem, sync::Mutex};
+
+use bytes::Bytes;
+use shadowsocks::{config::ServerUser, relay::socks5::Address};
+
+use super::{AccessControl, ParsingRules, PortRange, Rules};
+
+/// Maximum violations kept between two reports, extra violations are dropped
+const MAX_PENDING_VIOLATIONS: usize = 4096;
+
+/// Audit rules, identified by IDs from panels
+#[derive(Clone, Default)]
+pub struct AuditRules {
+    rules: Vec<(i32, Rules)>,
+    port_rules: Vec<(i32, Vec<PortRange>)>,
+}
+
+impl fmt::Debug for AuditRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditRules")
+            .field("rules", &self.rules.iter().map(|(id, _)| id).collect::<Vec<_>>())
+            .field("port_rules", &self.port_rules)
+            .finish()
+    }
+}
+
+impl AuditRules {
+    /// Create an empty rule set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule matching destinations by `patterns`
+    ///
+    /// Patterns are ACL rules (IP addresses, CIDR networks, `|domain`, `||domain` and regular expressions),
+    /// or rules with v2ray style prefixes `regexp:`, `domain:`, `full:` and `keyword:`.
+    pub fn add_rule<S: AsRef<str>>(&mut self, id: i32, patterns: &[S]) -> io::Result<()> {
+        let mut rules = ParsingRules::new("audit rule");
+
+        for pattern in patterns {
+            let pattern = pattern.as_ref().trim();
+            if pattern.is_empty() {
+                continue;
+            }
+
+            if let Some(rule) = pattern.strip_prefix("regexp:") {
+                rules.add_regex_rule(rule.to_owned());
+            } else if let Some(rule) = pattern.strip_prefix("domain:") {
+                rules.add_tree_rule(rule)?;
+            } else if let Some(rule) = pattern.strip_prefix("full:") {
+                rules.add_set_rule(rule)?;
+            } else if let Some(rule) = pattern.strip_prefix("keyword:") {
+                rules.add_substr_rule(rule);
+            } else {
+                rules.add_rule(pattern)?;
+            }
+        }
+
+        self.rules.push((id, rules.into_rules()?));
+        Ok(())
+    }
+
+    /// Add a rule matching destination ports
+    pub fn add_port_rule(&mut self, id: i32, ports: Vec<PortRange>) {
+        self.port_rules.push((id, ports));
+    }
+
+    /// Check if there is no rule
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.port_rules.is_empty()
+    }
+
+    /// Number of rules added, of both destinations and ports
+    pub fn len(&self) -> usize {
+        self.rules.len() + self.port_rules.len()
+    }
+
+    /// Find the first rule matching `addr`
+    ///
+    /// Domain names are matched as they are, without resolving.
+    pub fn check_matched(&self, addr: &Address) -> Option<i32> {
+        let port = addr.port();
+        if let Some((id, _)) = self
+            .port_rules
+            .iter()
+            .find(|(_, ports)| ports.iter().any(|r| r.contains(port)))
+        {
+            return Some(*id);
+        }
+
+        match *addr {
+            Address::SocketAddress(ref sa) => {
+                let ip = sa.ip();
+                self.rules.iter().find(|(_, r)| r.check_ip_matched(&ip)).map(|(id, _)| *id)
+            }
+            Address::DomainNameAddress(ref host, ..) => {
+                let host = AccessControl::convert_to_ascii(host);
+                self.rules
+                    .iter()
+                    .find(|(_, r)| r.check_host_matched(&host))
+                    .map(|(id, _)| *id)
+            }
+        }
+    }
+}
+
+/// A connection blocked by an audit rule
+#[derive(Debug, Clone)]
+pub struct AuditViolation {
+    /// Identity hash of the user, `None` for single-user servers
+    pub user: Option<Bytes>,
+    /// ID of the matched rule
+    pub rule_id: i32,
+    /// Destination of the connection
+    pub target: String,
+}
+
+/// Violations waiting for reporting
+#[derive(Default)]
+pub struct AuditLog {
+    violations: Mutex<Vec<AuditViolation>>,
+}
+
+impl AuditLog {
+    /// Create an empty log
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a violation
+    pub fn record(&self, user: Option<&ServerUser>, rule_id: i32, target: &Address) {
+        let mut violations = self.violations.lock().expect("audit log poisoned");
+        if violations.len() >= MAX_PENDING_VIOLATIONS {
+            return;
+        }
+        violations.push(AuditViolation {
+            user: user.map(|u| Bytes::copy_from_slice(u.identity_hash())),
+            rule_id,
+            target: target.to_string(),
+        });
+    }
+
+    /// Take violations recorded since last call
+    pub fn take(&self) -> Vec<AuditViolation> {
+        let mut violations = self.violations.lock().expect("audit log poisoned");
+        mem::take(&mut *violations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_matched() {
+        let mut rules = AuditRules::new();
+        rules
+            .add_rule(1, &["(.*\\.|)(speedtest)\\.(net)", "domain:example.com"])
+            .unwrap();
+        rules.add_rule(2, &["10.8.0.0/16", "full:exact.example.org"]).unwrap();
+        rules.add_port_rule(3, vec![PortRange::from(25)]);
+
+        let check = |addr: &str| {
+            let addr = match addr.parse() {
+                Ok(sa) => Address::SocketAddress(sa),
+                Err(..) => {
+                    let (host, port) = addr.rsplit_once(':').unwrap();
+                    Address::DomainNameAddress(host.to_owned(), port.parse().unwrap())
+                }
+            };
+            rules.check_matched(&addr)
+        };
+
+        assert_eq!(check("www.speedtest.net:443"), Some(1));
+        assert_eq!(check("a.example.com:443"), Some(1));
+        assert_eq!(check("10.8.1.1:443"), Some(2));
+        assert_eq!(check("exact.example.org:80"), Some(2));
+        assert_eq!(check("sub.exact.example.org:80"), None);
+        assert_eq!(check("smtp.example.net:25"), Some(3));
+        assert_eq!(check("1.1.1.1:443"), None);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let log = AuditLog::new();
+        let target = Address::DomainNameAddress("example.com".to_owned(), 443);
+        log.record(None, 1, &target);
+
+        let violations = log.take();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].rule_id, 1);
+        assert_eq!(violations[0].target, "example.com:443");
+        assert!(log.take().is_empty());
+    }
+}
@@ -0,0 +1,580 @@
+//! GeoIP and GeoSite databases for ACL rules
+//!
+//! ACL rules `geoip:CODE` and `geosite:NAME[@ATTR]` are expanded into IP ranges and domain rules
+//! while loading the ACL file, lookups of connections won't touch the databases.
+//!
+//! Supported databases are
+//!
+//! - v2ray `geoip.dat` and `geosite.dat`
+//! - MaxMind GeoIP2 / GeoLite2 Country databases (`.mmdb`), requires feature `acl-maxminddb`
+
+use std::{
+    fs,
+    io::{self, Error},
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+use log::trace;
+
+use super::ParsingRules;
+
+/// Paths of databases referenced by `geoip:` and `geosite:` rules
+#[derive(Debug, Clone, Default)]
+pub struct GeoDataConfig {
+    /// GeoIP database, `.mmdb` files are loaded as MaxMind databases, others as v2ray `geoip.dat`
+    pub geoip: Option<PathBuf>,
+    /// v2ray `geosite.dat`
+    pub geosite: Option<PathBuf>,
+}
+
+enum GeoIpSource {
+    Dat(Vec<u8>),
+    #[cfg(feature = "acl-maxminddb")]
+    MaxMind(maxminddb::Reader<Vec<u8>>),
+}
+
+/// Databases opened while loading one ACL file
+pub(super) struct GeoDataLoader<'a> {
+    config: &'a GeoDataConfig,
+    geoip: Option<GeoIpSource>,
+    geosite: Option<Vec<u8>>,
+}
+
+impl<'a> GeoDataLoader<'a> {
+    pub(super) fn new(config: &'a GeoDataConfig) -> Self {
+        Self {
+            config,
+            geoip: None,
+            geosite: None,
+        }
+    }
+
+    /// Add networks of country `code` into `rules`
+    pub(super) fn add_geoip_rules(&mut self, code: &str, rules: &mut ParsingRules) -> io::Result<()> {
+        trace!("GEOIP-RULE {}", code);
+
+        let (ipv4, ipv6) = match *self.geoip_source()? {
+            GeoIpSource::Dat(ref buf) => load_dat_geoip(buf, code)?,
+            #[cfg(feature = "acl-maxminddb")]
+            GeoIpSource::MaxMind(ref reader) => load_mmdb_geoip(reader, code)?,
+        };
+
+        if ipv4.is_empty() && ipv6.is_empty() {
+            return Err(Error::other(format!("geoip:{code} not found in GeoIP database")));
+        }
+
+        for net in ipv4.iter() {
+            rules.ipv4.add(net);
+        }
+        for net in ipv6.iter() {
+            rules.ipv6.add(net);
+        }
+
+        Ok(())
+    }
+
+    /// Add domains of site `name`, optionally filtered by `@attr`, into `rules`
+    pub(super) fn add_geosite_rules(&mut self, name: &str, rules: &mut ParsingRules) -> io::Result<()> {
+        trace!("GEOSITE-RULE {}", name);
+
+        let (name, attr) = match name.split_once('@') {
+            Some((name, attr)) => (name, Some(attr)),
+            None => (name, None),
+        };
+
+        let buf = self.geosite_source()?;
+        let domains = load_dat_geosite(buf, name, attr)?;
+        if domains.is_empty() {
+            return Err(Error::other(format!("geosite:{name} not found in GeoSite database")));
+        }
+
+        for domain in domains {
+            match domain.ty {
+                DOMAIN_TYPE_PLAIN => rules.add_substr_rule(&domain.value),
+                DOMAIN_TYPE_REGEX => rules.add_regex_rule(domain.value),
+                DOMAIN_TYPE_ROOT_DOMAIN => rules.add_tree_rule_inner(&domain.value)?,
+                DOMAIN_TYPE_FULL => rules.add_set_rule_inner(&domain.value)?,
+                ty => trace!("GEOSITE-RULE {} unknown domain type {}, skipped", name, ty),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn geoip_source(&mut self) -> io::Result<&GeoIpSource> {
+        if self.geoip.is_none() {
+            let path = self
+                .config
+                .geoip
+                .as_deref()
+                .ok_or_else(|| Error::other("geoip: rules require a GeoIP database"))?;
+            self.geoip = Some(open_geoip(path)?);
+        }
+        Ok(self.geoip.as_ref().expect("geoip source"))
+    }
+
+    fn geosite_source(&mut self) -> io::Result<&[u8]> {
+        if self.geosite.is_none() {
+            let path = self
+                .config
+                .geosite
+                .as_deref()
+                .ok_or_else(|| Error::other("geosite: rules require a GeoSite database"))?;
+            self.geosite = Some(fs::read(path)?);
+        }
+        Ok(self.geosite.as_deref().expect("geosite source"))
+    }
+}
+
+fn open_geoip(path: &Path) -> io::Result<GeoIpSource> {
+    let is_mmdb = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("mmdb"));
+    if !is_mmdb {
+        return Ok(GeoIpSource::Dat(fs::read(path)?));
+    }
+
+    #[cfg(feature = "acl-maxminddb")]
+    {
+        maxminddb::Reader::open_readfile(path)
+            .map(GeoIpSource::MaxMind)
+            .map_err(|err| Error::other(format!("failed to open GeoIP database {}: {err}", path.display())))
+    }
+
+    #[cfg(not(feature = "acl-maxminddb"))]
+    Err(Error::other(format!(
+        "GeoIP database {} requires feature \"acl-maxminddb\"",
+        path.display()
+    )))
+}
+
+#[cfg(feature = "acl-maxminddb")]
+fn load_mmdb_geoip(
+    reader: &maxminddb::Reader<Vec<u8>>,
+    code: &str,
+) -> io::Result<(IpRange<Ipv4Net>, IpRange<Ipv6Net>)> {
+    use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct CountryCode<'a> {
+        #[serde(borrow)]
+        iso_code: Option<&'a str>,
+    }
+
+    #[derive(Deserialize)]
+    struct CountryRecord<'a> {
+        #[serde(borrow)]
+        country: Option<CountryCode<'a>>,
+        #[serde(borrow)]
+        registered_country: Option<CountryCode<'a>>,
+    }
+
+    let mut ipv4 = IpRange::new();
+    let mut ipv6 = IpRange::new();
+
+    let roots = [
+        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).expect("ipv4 root")),
+        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::UNSPECIFIED, 0).expect("ipv6 root")),
+    ];
+
+    for root in roots {
+        // IPv4 only databases
+        if root.is_ipv6() && reader.metadata.ip_version != 6 {
+            continue;
+        }
+
+        let within = reader
+            .within::<CountryRecord>(root)
+            .map_err(|err| Error::other(format!("GeoIP database error: {err}")))?;
+
+        for item in within {
+            let item = item.map_err(|err| Error::other(format!("GeoIP database error: {err}")))?;
+            let matched = item
+                .info
+                .country
+                .or(item.info.registered_country)
+                .and_then(|c| c.iso_code)
+                .is_some_and(|c| c.eq_ignore_ascii_case(code));
+            if !matched {
+                continue;
+            }
+
+            match item.ip_net {
+                IpNetwork::V4(net) => {
+                    if let Ok(net) = Ipv4Net::new(net.network(), net.prefix()) {
+                        ipv4.add(net);
+                    }
+                }
+                IpNetwork::V6(net) => {
+                    // IPv4 subtree of IPv6 databases, already iterated as IPv4
+                    if net.network().segments()[..6] == [0; 6] {
+                        continue;
+                    }
+                    if let Ok(net) = Ipv6Net::new(net.network(), net.prefix()) {
+                        ipv6.add(net);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok((ipv4, ipv6))
+}
+
+// v2ray-core/app/router/routercommon/common.proto
+//
+// message Domain { Type type = 1; string value = 2; repeated Attribute attribute = 3; }
+// message Attribute { string key = 1; oneof typed_value { bool bool_value = 2; int64 int_value = 3; } }
+// message CIDR { bytes ip = 1; uint32 prefix = 2; }
+// message GeoIP { string country_code = 1; repeated CIDR cidr = 2; bool reverse_match = 3; }
+// message GeoIPList { repeated GeoIP entry = 1; }
+// message GeoSite { string country_code = 1; repeated Domain domain = 2; }
+// message GeoSiteList { repeated GeoSite entry = 1; }
+
+const DOMAIN_TYPE_PLAIN: u64 = 0;
+const DOMAIN_TYPE_REGEX: u64 = 1;
+const DOMAIN_TYPE_ROOT_DOMAIN: u64 = 2;
+const DOMAIN_TYPE_FULL: u64 = 3;
+
+struct GeoSiteDomain {
+    ty: u64,
+    value: String,
+}
+
+fn load_dat_geoip(buf: &[u8], code: &str) -> io::Result<(IpRange<Ipv4Net>, IpRange<Ipv6Net>)> {
+    let mut ipv4 = IpRange::new();
+    let mut ipv6 = IpRange::new();
+
+    let mut list = ProtoReader::new(buf);
+    while let Some((field, value)) = list.next_field()? {
+        let entry = match (field, value) {
+            (1, ProtoValue::Bytes(entry)) => entry,
+            _ => continue,
+        };
+
+        if !entry_code_matches(entry, code)? {
+            continue;
+        }
+
+        // Ranges of this entry, inverted on their own if it's a reverse match
+        let mut entry_ipv4 = IpRange::new();
+        let mut entry_ipv6 = IpRange::new();
+        let mut reverse_match = false;
+        let mut reader = ProtoReader::new(entry);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (2, ProtoValue::Bytes(cidr)) => {
+                    let mut ip = &[][..];
+                    let mut prefix = 0;
+                    let mut cidr_reader = ProtoReader::new(cidr);
+                    while let Some((field, value)) = cidr_reader.next_field()? {
+                        match (field, value) {
+                            (1, ProtoValue::Bytes(b)) => ip = b,
+                            (2, ProtoValue::Varint(p)) => prefix = p,
+                            _ => {}
+                        }
+                    }
+
+                    let invalid_prefix = || Error::other(format!("geoip:{code} has invalid CIDR prefix {prefix}"));
+                    let prefix_len = u8::try_from(prefix).map_err(|_| invalid_prefix())?;
+                    if let Ok(octets) = <[u8; 4]>::try_from(ip) {
+                        let net = Ipv4Net::new(Ipv4Addr::from(octets), prefix_len).map_err(|_| invalid_prefix())?;
+                        entry_ipv4.add(net);
+                    } else if let Ok(octets) = <[u8; 16]>::try_from(ip) {
+                        let net = Ipv6Net::new(Ipv6Addr::from(octets), prefix_len).map_err(|_| invalid_prefix())?;
+                        entry_ipv6.add(net);
+                    }
+                }
+                (3, ProtoValue::Varint(v)) => reverse_match = v != 0,
+                _ => {}
+            }
+        }
+
+        if reverse_match {
+            let mut all_ipv4 = IpRange::new();
+            all_ipv4.add(Ipv4Net::default());
+            let mut all_ipv6 = IpRange::new();
+            all_ipv6.add(Ipv6Net::default());
+            entry_ipv4 = all_ipv4.exclude(&entry_ipv4);
+            entry_ipv6 = all_ipv6.exclude(&entry_ipv6);
+        }
+        ipv4 = ipv4.merge(&entry_ipv4);
+        ipv6 = ipv6.merge(&entry_ipv6);
+    }
+
+    Ok((ipv4, ipv6))
+}
+
+fn load_dat_geosite(buf: &[u8], name: &str, attr: Option<&str>) -> io::Result<Vec<GeoSiteDomain>> {
+    let mut domains = Vec::new();
+
+    let mut list = ProtoReader::new(buf);
+    while let Some((field, value)) = list.next_field()? {
+        let entry = match (field, value) {
+            (1, ProtoValue::Bytes(entry)) => entry,
+            _ => continue,
+        };
+
+        if !entry_code_matches(entry, name)? {
+            continue;
+        }
+
+        let mut reader = ProtoReader::new(entry);
+        while let Some((field, value)) = reader.next_field()? {
+            let domain = match (field, value) {
+                (2, ProtoValue::Bytes(domain)) => domain,
+                _ => continue,
+            };
+
+            let mut ty = DOMAIN_TYPE_PLAIN;
+            let mut value = "";
+            let mut has_attr = attr.is_none();
+
+            let mut domain_reader = ProtoReader::new(domain);
+            while let Some((field, v)) = domain_reader.next_field()? {
+                match (field, v) {
+                    (1, ProtoValue::Varint(t)) => ty = t,
+                    (2, ProtoValue::Bytes(b)) => value = proto_str(b)?,
+                    (3, ProtoValue::Bytes(attribute)) => {
+                        if let Some(attr) = attr {
+                            let mut attr_reader = ProtoReader::new(attribute);
+                            while let Some((field, v)) = attr_reader.next_field()? {
+                                if let (1, ProtoValue::Bytes(key)) = (field, v)
+                                    && proto_str(key)?.eq_ignore_ascii_case(attr)
+                                {
+                                    has_attr = true;
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if has_attr && value.is_ascii() {
+                domains.push(GeoSiteDomain {
+                    ty,
+                    value: value.to_owned(),
+                });
+            }
+        }
+    }
+
+    Ok(domains)
+}
+
+/// Check if `country_code` (field 1) of a GeoIP or GeoSite entry is `code`
+fn entry_code_matches(entry: &[u8], code: &str) -> io::Result<bool> {
+    let mut reader = ProtoReader::new(entry);
+    while let Some((field, value)) = reader.next_field()? {
+        if let (1, ProtoValue::Bytes(b)) = (field, value) {
+            return Ok(proto_str(b)?.eq_ignore_ascii_case(code));
+        }
+    }
+    Ok(false)
+}
+
+fn proto_str(b: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(b).map_err(|_| Error::other("geodata contains invalid UTF-8 string"))
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Minimal reader of Protocol Buffers wire format
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for (i, b) in self.buf.iter().enumerate().take(10) {
+            value |= ((b & 0x7f) as u64) << (i * 7);
+            if b & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(Error::other("geodata contains invalid varint"))
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(Error::other("geodata is truncated"));
+        }
+        let (b, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(b)
+    }
+
+    fn next_field(&mut self) -> io::Result<Option<(u64, ProtoValue<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.read_varint()?;
+        let value = match key & 0x07 {
+            0 => ProtoValue::Varint(self.read_varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = self.read_varint()? as usize;
+                ProtoValue::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                ProtoValue::Fixed
+            }
+            ty => return Err(Error::other(format!("geodata contains unsupported wire type {ty}"))),
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn bytes_field(field: u64, b: &[u8], out: &mut Vec<u8>) {
+        varint((field << 3) | 2, out);
+        varint(b.len() as u64, out);
+        out.extend_from_slice(b);
+    }
+
+    fn varint_field(field: u64, v: u64, out: &mut Vec<u8>) {
+        varint(field << 3, out);
+        varint(v, out);
+    }
+
+    fn geoip_entry(code: &str, cidrs: &[(&[u8], u64)]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        bytes_field(1, code.as_bytes(), &mut entry);
+        for (ip, prefix) in cidrs {
+            let mut cidr = Vec::new();
+            bytes_field(1, ip, &mut cidr);
+            varint_field(2, *prefix, &mut cidr);
+            bytes_field(2, &cidr, &mut entry);
+        }
+        entry
+    }
+
+    fn geosite_entry(code: &str, domains: &[(u64, &str, Option<&str>)]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        bytes_field(1, code.as_bytes(), &mut entry);
+        for (ty, value, attr) in domains {
+            let mut domain = Vec::new();
+            varint_field(1, *ty, &mut domain);
+            bytes_field(2, value.as_bytes(), &mut domain);
+            if let Some(attr) = attr {
+                let mut attribute = Vec::new();
+                bytes_field(1, attr.as_bytes(), &mut attribute);
+                varint_field(2, 1, &mut attribute);
+                bytes_field(3, &attribute, &mut domain);
+            }
+            bytes_field(2, &domain, &mut entry);
+        }
+        entry
+    }
+
+    #[test]
+    fn test_dat_geoip() {
+        let mut list = Vec::new();
+        bytes_field(1, &geoip_entry("CN", &[(&[1, 0, 1, 0], 24), (&[0x24, 0x0e, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], 20)]), &mut list);
+        bytes_field(1, &geoip_entry("US", &[(&[8, 8, 8, 0], 24)]), &mut list);
+
+        let (ipv4, ipv6) = load_dat_geoip(&list, "cn").unwrap();
+        assert!(ipv4.contains(&"1.0.1.1".parse::<Ipv4Addr>().unwrap()));
+        assert!(!ipv4.contains(&"8.8.8.8".parse::<Ipv4Addr>().unwrap()));
+        assert!(ipv6.contains(&"240e::1".parse::<Ipv6Addr>().unwrap()));
+
+        let (ipv4, ipv6) = load_dat_geoip(&list, "jp").unwrap();
+        assert!(ipv4.is_empty() && ipv6.is_empty());
+
+        // A reverse match inverts only the CIDRs of its own entry
+        let mut reversed = geoip_entry("CN", &[(&[8, 8, 8, 0], 24)]);
+        varint_field(3, 1, &mut reversed);
+        let mut list = Vec::new();
+        bytes_field(1, &geoip_entry("CN", &[(&[1, 0, 1, 0], 24)]), &mut list);
+        bytes_field(1, &reversed, &mut list);
+        let (ipv4, _) = load_dat_geoip(&list, "cn").unwrap();
+        assert!(ipv4.contains(&"1.0.1.1".parse::<Ipv4Addr>().unwrap()));
+        assert!(ipv4.contains(&"9.9.9.9".parse::<Ipv4Addr>().unwrap()));
+        assert!(!ipv4.contains(&"8.8.8.8".parse::<Ipv4Addr>().unwrap()));
+
+        // Prefixes out of range are rejected, instead of truncated
+        for prefix in [33, 256 + 8] {
+            let mut list = Vec::new();
+            bytes_field(1, &geoip_entry("CN", &[(&[1, 0, 1, 0], prefix)]), &mut list);
+            assert!(load_dat_geoip(&list, "cn").is_err());
+        }
+    }
+
+    #[test]
+    fn test_dat_geosite() {
+        let mut list = Vec::new();
+        bytes_field(
+            1,
+            &geosite_entry(
+                "CATEGORY-ADS",
+                &[
+                    (DOMAIN_TYPE_ROOT_DOMAIN, "ads.example.com", None),
+                    (DOMAIN_TYPE_FULL, "tracker.example.org", Some("cn")),
+                    (DOMAIN_TYPE_PLAIN, "doubleclick", None),
+                    (DOMAIN_TYPE_PLAIN, "a.b*c", None),
+                ],
+            ),
+            &mut list,
+        );
+
+        let mut rules = ParsingRules::new("[outbound_block_list]");
+        let geodata = GeoDataConfig::default();
+        let mut loader = GeoDataLoader::new(&geodata);
+        loader.geosite = Some(list.clone());
+        loader.add_geosite_rules("category-ads", &mut rules).unwrap();
+        assert!(loader.add_geosite_rules("not-exist", &mut rules).is_err());
+
+        let rules = rules.into_rules().unwrap();
+        assert!(rules.check_host_matched("ads.example.com"));
+        assert!(rules.check_host_matched("x.ads.example.com"));
+        assert!(rules.check_host_matched("tracker.example.org"));
+        assert!(!rules.check_host_matched("a.tracker.example.org"));
+        assert!(rules.check_host_matched("ad.doubleclick.net"));
+        assert!(rules.check_host_matched("xa.b*cx.com"));
+        assert!(!rules.check_host_matched("axbbc.com"));
+        assert!(!rules.check_host_matched("example.com"));
+
+        let domains = load_dat_geosite(&list, "category-ads", Some("cn")).unwrap();
+        assert_eq!(domains.len(), 1);
+        assert_eq!(domains[0].value, "tracker.example.org");
+    }
+
+    #[test]
+    fn test_missing_database() {
+        let geodata = GeoDataConfig::default();
+        let mut loader = GeoDataLoader::new(&geodata);
+        let mut rules = ParsingRules::new("[outbound_block_list]");
+        assert!(loader.add_geoip_rules("cn", &mut rules).is_err());
+        assert!(loader.add_geosite_rules("google", &mut rules).is_err());
+    }
+}
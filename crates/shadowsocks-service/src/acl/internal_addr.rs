@@ -0,0 +1,233 @@
+//! Built-in filter of internal destinations
+//!
+//! Loopback, private, link-local and other special-purpose addresses are not routable on the Internet.
+//! Proxy clients reaching them could access services of the server's host and its local network,
+//! for example the cloud metadata service at `169.254.169.254`.
+//!
+//! Public addresses of the host itself are blocked as well, services bound to them, like SSH or
+//! a panel on the same host, are not meant to be reached through the proxy.
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use ipnet::{AddrParseError, IpNet, Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+
+/// Filter blocking outbound connections to internal addresses
+#[derive(Debug, Clone, Default)]
+pub struct InternalAddrFilter {
+    allow_ipv4: IpRange<Ipv4Net>,
+    allow_ipv6: IpRange<Ipv6Net>,
+    local_addrs: HashSet<IpAddr>,
+}
+
+impl InternalAddrFilter {
+    /// Create a filter blocking all internal addresses
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow an internal address or network, for example `10.0.0.53` or `192.168.1.0/24`
+    pub fn add_allowed(&mut self, rule: &str) -> Result<(), AddrParseError> {
+        let net = match rule.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(err) => match rule.parse::<IpAddr>() {
+                Ok(ip) => IpNet::from(ip),
+                Err(..) => return Err(err),
+            },
+        };
+
+        match net {
+            IpNet::V4(v4) => {
+                self.allow_ipv4.add(v4);
+                self.allow_ipv4.simplify();
+            }
+            IpNet::V6(v6) => {
+                self.allow_ipv6.add(v6);
+                self.allow_ipv6.simplify();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block an address of the host itself, like addresses of its interfaces or its public address behind NAT
+    pub fn add_local(&mut self, ip: IpAddr) {
+        self.local_addrs.insert(to_embedded_ipv4(&ip));
+    }
+
+    /// Check if outbound connections to `ip` should be blocked
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        // IPv4-mapped-IPv6 and translated addresses are connecting to the IPv4 destinations
+        let ip = to_embedded_ipv4(ip);
+
+        if !is_internal_ip(&ip) && !self.local_addrs.contains(&ip) {
+            return false;
+        }
+
+        match ip {
+            IpAddr::V4(v4) => !self.allow_ipv4.contains(&v4),
+            IpAddr::V6(v6) => !self.allow_ipv6.contains(&v6),
+        }
+    }
+}
+
+/// Check if `ip` is an internal address
+pub fn is_internal_ip(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref v4) => is_internal_ipv4(v4),
+        IpAddr::V6(ref v6) => match embedded_ipv4(v6) {
+            Some(v4) => is_internal_ipv4(&v4),
+            None => is_internal_ipv6(v6),
+        },
+    }
+}
+
+/// IPv4 address reached through `ip`, or `ip` itself
+fn to_embedded_ipv4(ip: &IpAddr) -> IpAddr {
+    match *ip {
+        IpAddr::V4(..) => *ip,
+        IpAddr::V6(ref v6) => match embedded_ipv4(v6) {
+            Some(v4) => IpAddr::V4(v4),
+            None => *ip,
+        },
+    }
+}
+
+/// IPv4 address embedded in an IPv6 address that is translated or tunneled to it
+fn embedded_ipv4(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let low = Ipv4Addr::from(((segments[6] as u32) << 16) | segments[7] as u32);
+
+    match segments {
+        // ::ffff:0:0/96, IPv4-mapped
+        [0, 0, 0, 0, 0, 0xffff, ..] => Some(low),
+        // ::/96, deprecated IPv4-compatible, except the unspecified and loopback addresses
+        [0, 0, 0, 0, 0, 0, ..] if !ip.is_unspecified() && !ip.is_loopback() => Some(low),
+        // 64:ff9b::/96, NAT64 Well-Known Prefix
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(low),
+        // 2002::/16, 6to4
+        [0x2002, v4_high, v4_low, ..] => Some(Ipv4Addr::from(((v4_high as u32) << 16) | v4_low as u32)),
+        _ => None,
+    }
+}
+
+fn is_internal_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+
+    // 0.0.0.0/8, "this network", 0.0.0.0 is connecting to the local host on most systems
+    octets[0] == 0
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        // 100.64.0.0/10, Shared Address Space (Carrier-Grade NAT)
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // 192.0.0.0/24, IETF Protocol Assignments
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // 198.18.0.0/15, Benchmarking
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        || ip.is_multicast()
+        // 240.0.0.0/4, Reserved, including the limited broadcast address
+        || octets[0] >= 240
+}
+
+fn is_internal_ipv6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // fec0::/10, deprecated Site-Local
+        || (segments[0] & 0xffc0) == 0xfec0
+        // 64:ff9b:1::/48, Local-Use NAT64 prefix
+        || (segments[0] == 0x64 && segments[1] == 0xff9b && segments[2] == 1)
+        || ip.is_multicast()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_internal_ip() {
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::192.168.1.1",
+            "64:ff9b:1::1",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(is_internal_ip(&ip.parse().unwrap()), "{ip} should be internal");
+        }
+
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "172.32.0.1",
+            "100.128.0.1",
+            "2001:4860:4860::8888",
+            "::ffff:1.1.1.1",
+            "64:ff9b::1.1.1.1",
+            "2002:808:808::1",
+        ] {
+            assert!(!is_internal_ip(&ip.parse().unwrap()), "{ip} should not be internal");
+        }
+    }
+
+    #[test]
+    fn test_allowed() {
+        let mut filter = InternalAddrFilter::new();
+        filter.add_allowed("10.0.0.53").unwrap();
+        filter.add_allowed("192.168.1.0/24").unwrap();
+        filter.add_allowed("fd00::/64").unwrap();
+        assert!(filter.add_allowed("not-an-address").is_err());
+
+        assert!(!filter.is_blocked(&"10.0.0.53".parse().unwrap()));
+        assert!(!filter.is_blocked(&"::ffff:10.0.0.53".parse().unwrap()));
+        assert!(filter.is_blocked(&"10.0.0.54".parse().unwrap()));
+        assert!(!filter.is_blocked(&"192.168.1.100".parse().unwrap()));
+        assert!(filter.is_blocked(&"192.168.2.1".parse().unwrap()));
+        assert!(!filter.is_blocked(&"fd00::1".parse().unwrap()));
+        assert!(filter.is_blocked(&"fd01::1".parse().unwrap()));
+        assert!(!filter.is_blocked(&"1.1.1.1".parse().unwrap()));
+        assert!(!filter.is_blocked(&"64:ff9b::10.0.0.53".parse().unwrap()));
+        assert!(filter.is_blocked(&"64:ff9b::10.0.0.54".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_local() {
+        let mut filter = InternalAddrFilter::new();
+        filter.add_local("203.0.113.10".parse().unwrap());
+        filter.add_local("2001:db8::10".parse().unwrap());
+
+        assert!(filter.is_blocked(&"203.0.113.10".parse().unwrap()));
+        assert!(filter.is_blocked(&"::ffff:203.0.113.10".parse().unwrap()));
+        assert!(filter.is_blocked(&"64:ff9b::203.0.113.10".parse().unwrap()));
+        assert!(filter.is_blocked(&"2002:cb00:710a::1".parse().unwrap()));
+        assert!(filter.is_blocked(&"2001:db8::10".parse().unwrap()));
+        assert!(!filter.is_blocked(&"203.0.113.11".parse().unwrap()));
+        assert!(!filter.is_blocked(&"2001:db8::11".parse().unwrap()));
+
+        filter.add_allowed("203.0.113.10").unwrap();
+        assert!(!filter.is_blocked(&"203.0.113.10".parse().unwrap()));
+    }
+}
@@ -0,0 +1,376 @@
+//! Temporary bans of clients failing handshakes
+//!
+//! Active probers and scanners keep connecting with garbage, replayed or outdated requests.
+//! Handshake failures are counted per source network, and networks failing repeatedly are banned
+//! for a while, longer for each ban again. Connections from banned networks are closed right after accepted.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ipnet::IpNet;
+use log::warn;
+use serde::{Serialize, Serializer};
+use shadowsocks::security::replay::ReplayedNonce;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Reason of a failed handshake
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakeFailure {
+    /// Request couldn't be decrypted, or with an unknown user
+    BadAuth,
+    /// Salt of the request was seen before
+    Replay,
+    /// Timestamp of the request is too far from now
+    TimestampSkew,
+    /// Request wasn't completed in time
+    Timeout,
+}
+
+impl HandshakeFailure {
+    /// Classify a handshake error, `None` for errors which are not caused by the client's request
+    pub fn classify(err: &io::Error) -> Option<Self> {
+        match err.kind() {
+            io::ErrorKind::TimedOut => return Some(Self::Timeout),
+            io::ErrorKind::Other => {}
+            _ => return None,
+        }
+
+        #[cfg(feature = "aead-cipher-2022")]
+        {
+            use shadowsocks::relay::tcprelay::Aead2022ProtocolError;
+
+            if let Some(Aead2022ProtocolError::InvalidTimestamp(..)) =
+                err.get_ref().and_then(|e| e.downcast_ref::<Aead2022ProtocolError>())
+            {
+                return Some(Self::TimestampSkew);
+            }
+        }
+
+        if ReplayedNonce::is_replayed(err) {
+            return Some(Self::Replay);
+        }
+
+        Some(Self::BadAuth)
+    }
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Self::BadAuth => "bad auth",
+            Self::Replay => "replay",
+            Self::TimestampSkew => "timestamp skew",
+            Self::Timeout => "timeout",
+        })
+    }
+}
+
+/// Settings of banning clients failing handshakes
+#[derive(Debug, Clone, Copy)]
+pub struct IpBanConfig {
+    /// Handshake failures in `window` to trigger the ban
+    pub threshold: u32,
+    /// Period of counting failures
+    pub window: Duration,
+    /// Duration of the first ban, doubled for each ban again
+    pub duration: Duration,
+    /// Upper limit of ban durations, bans are forgotten after clean for this long
+    pub max_duration: Duration,
+    /// Prefix length of IPv4 networks counted together
+    pub ipv4_prefix: u8,
+    /// Prefix length of IPv6 networks counted together
+    pub ipv6_prefix: u8,
+}
+
+/// A banned network
+#[derive(Debug, Clone, Serialize)]
+pub struct IpBan {
+    /// Banned network
+    #[serde(serialize_with = "serialize_display")]
+    pub network: IpNet,
+    /// Failure triggering the ban
+    pub failure: HandshakeFailure,
+    /// Times banned, including this one
+    pub bans: u32,
+    /// Duration of this ban
+    #[serde(rename = "duration_secs", serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    /// When the ban expires, in Unix timestamp seconds
+    #[serde(serialize_with = "serialize_time")]
+    pub expires: SystemTime,
+}
+
+fn serialize_display<S: Serializer>(network: &IpNet, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(network)
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_secs())
+}
+
+fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+}
+
+struct Offender {
+    window_start: Instant,
+    window_failures: u32,
+    bans: u32,
+    banned_until: Option<Instant>,
+    ban: Option<IpBan>,
+}
+
+impl Offender {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            window_failures: 0,
+            bans: 0,
+            banned_until: None,
+            ban: None,
+        }
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|t| t > now)
+    }
+}
+
+struct Offenders {
+    networks: HashMap<IpNet, Offender>,
+    last_purge: Instant,
+}
+
+/// Bans of networks failing handshakes
+pub struct IpBanList {
+    config: IpBanConfig,
+    offenders: Mutex<Offenders>,
+    notifier: Option<UnboundedSender<IpBan>>,
+}
+
+impl IpBanList {
+    /// Create an empty ban list
+    pub fn new(config: IpBanConfig) -> Self {
+        Self {
+            config,
+            offenders: Mutex::new(Offenders {
+                networks: HashMap::new(),
+                last_purge: Instant::now(),
+            }),
+            notifier: None,
+        }
+    }
+
+    /// Send new bans to `notifier`, for applying them to firewalls
+    pub fn set_notifier(&mut self, notifier: UnboundedSender<IpBan>) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Network of `ip` counted together
+    pub fn network_of(&self, ip: IpAddr) -> IpNet {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let prefix = match ip {
+            IpAddr::V4(..) => self.config.ipv4_prefix.min(32),
+            IpAddr::V6(..) => self.config.ipv6_prefix.min(128),
+        };
+        IpNet::new(ip, prefix).expect("valid prefix length").trunc()
+    }
+
+    /// Check if connections from `ip` should be rejected
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let network = self.network_of(ip);
+        let offenders = self.offenders.lock().expect("ip ban list poisoned");
+        offenders
+            .networks
+            .get(&network)
+            .is_some_and(|o| o.is_banned(Instant::now()))
+    }
+
+    /// Count a handshake failure from `ip`, returns the ban if it was just triggered
+    pub fn record_failure(&self, ip: IpAddr, failure: HandshakeFailure) -> Option<IpBan> {
+        let now = Instant::now();
+        let network = self.network_of(ip);
+
+        let mut offenders = self.offenders.lock().expect("ip ban list poisoned");
+        if now.duration_since(offenders.last_purge) >= self.config.window {
+            self.purge(&mut offenders.networks, now);
+            offenders.last_purge = now;
+        }
+
+        let offender = offenders.networks.entry(network).or_insert_with(|| Offender::new(now));
+        if offender.is_banned(now) {
+            return None;
+        }
+
+        if now.duration_since(offender.window_start) >= self.config.window {
+            offender.window_start = now;
+            offender.window_failures = 0;
+        }
+        offender.window_failures += 1;
+        if offender.window_failures < self.config.threshold {
+            return None;
+        }
+
+        let duration = self
+            .config
+            .duration
+            .saturating_mul(1u32.checked_shl(offender.bans).unwrap_or(u32::MAX))
+            .min(self.config.max_duration);
+        offender.bans += 1;
+        offender.window_failures = 0;
+        offender.banned_until = Some(now + duration);
+
+        let ban = IpBan {
+            network,
+            failure,
+            bans: offender.bans,
+            duration,
+            expires: SystemTime::now() + duration,
+        };
+        offender.ban = Some(ban.clone());
+        drop(offenders);
+
+        warn!(
+            "{} banned for {:?} after {} handshake failures, last failure: {}",
+            network, duration, self.config.threshold, failure
+        );
+
+        if let Some(ref notifier) = self.notifier {
+            let _ = notifier.send(ban.clone());
+        }
+
+        Some(ban)
+    }
+
+    /// Current bans, sorted by expiration
+    pub fn bans(&self) -> Vec<IpBan> {
+        let now = Instant::now();
+        let offenders = self.offenders.lock().expect("ip ban list poisoned");
+        let mut bans: Vec<IpBan> = offenders
+            .networks
+            .values()
+            .filter(|o| o.is_banned(now))
+            .filter_map(|o| o.ban.clone())
+            .collect();
+        bans.sort_by_key(|b| b.expires);
+        bans
+    }
+
+    fn purge(&self, networks: &mut HashMap<IpNet, Offender>, now: Instant) {
+        networks.retain(|_, o| {
+            // Keep networks which are still banned, counting, or remembered for escalation
+            o.banned_until
+                .is_some_and(|t| now < t + self.config.max_duration)
+                || now.duration_since(o.window_start) < self.config.window
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> IpBanConfig {
+        IpBanConfig {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+            max_duration: Duration::from_secs(150),
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        }
+    }
+
+    #[test]
+    fn test_classify_failure() {
+        assert_eq!(
+            HandshakeFailure::classify(&io::ErrorKind::TimedOut.into()),
+            Some(HandshakeFailure::Timeout)
+        );
+        assert_eq!(HandshakeFailure::classify(&io::ErrorKind::ConnectionReset.into()), None);
+        assert_eq!(
+            HandshakeFailure::classify(&ReplayedNonce.into()),
+            Some(HandshakeFailure::Replay)
+        );
+        assert_eq!(
+            HandshakeFailure::classify(&io::Error::other("decrypt header chunk failed")),
+            Some(HandshakeFailure::BadAuth)
+        );
+
+        #[cfg(feature = "aead-cipher-2022")]
+        {
+            use shadowsocks::relay::tcprelay::Aead2022ProtocolError;
+
+            let err: io::Error = Aead2022ProtocolError::InvalidTimestamp(0, 100).into();
+            assert_eq!(HandshakeFailure::classify(&err), Some(HandshakeFailure::TimestampSkew));
+
+            // Replays found by the context, passed through errors of the protocol
+            use shadowsocks::{config::ServerType, context::Context, crypto::CipherKind};
+
+            let context = Context::new(ServerType::Server);
+            let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+            context.check_nonce_replay(method, &[1u8; 16]).unwrap();
+            let err = context.check_nonce_replay(method, &[1u8; 16]).unwrap_err();
+            let err: io::Error = Aead2022ProtocolError::from(err).into();
+            assert_eq!(HandshakeFailure::classify(&err), Some(HandshakeFailure::Replay));
+        }
+    }
+
+    #[test]
+    fn test_ban_network() {
+        let list = IpBanList::new(config());
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let neighbor: IpAddr = "198.51.100.200".parse().unwrap();
+
+        assert!(list.record_failure(ip, HandshakeFailure::BadAuth).is_none());
+        assert!(list.record_failure(neighbor, HandshakeFailure::Timeout).is_none());
+        assert!(!list.is_banned(ip));
+
+        let ban = list.record_failure(ip, HandshakeFailure::Replay).expect("should be banned");
+        assert_eq!(ban.network.to_string(), "198.51.100.0/24");
+        assert_eq!(ban.duration, Duration::from_secs(60));
+        assert!(list.is_banned(neighbor));
+        assert!(!list.is_banned("198.51.101.1".parse().unwrap()));
+        // IPv4-mapped IPv6 addresses are the same clients
+        assert!(list.is_banned("::ffff:198.51.100.1".parse().unwrap()));
+
+        let bans = list.bans();
+        assert_eq!(bans.len(), 1);
+        let json = serde_json::to_value(&bans[0]).unwrap();
+        assert_eq!(json["network"], "198.51.100.0/24");
+        assert_eq!(json["failure"], "replay");
+        assert_eq!(json["duration_secs"], 60);
+    }
+
+    #[test]
+    fn test_escalate_duration() {
+        let list = IpBanList::new(config());
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        let mut durations = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..3 {
+                if let Some(ban) = list.record_failure(ip, HandshakeFailure::BadAuth) {
+                    durations.push(ban.duration);
+                }
+            }
+            // Lift the ban, keeping the count of bans
+            let mut offenders = list.offenders.lock().unwrap();
+            offenders.networks.get_mut(&list.network_of(ip)).unwrap().banned_until = Some(Instant::now());
+        }
+
+        assert_eq!(
+            durations,
+            vec![Duration::from_secs(60), Duration::from_secs(120), Duration::from_secs(150)]
+        );
+    }
+}
@@ -14,16 +14,46 @@ use std::{
     sync::LazyLock,
 };
 
+use aho_corasick::AhoCorasick;
+use bytes::Bytes;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use log::{trace, warn};
 use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
-use shadowsocks::{context::Context, relay::socks5::Address};
+use shadowsocks::{config::ServerUser, context::Context, relay::socks5::Address};
 
-use self::sub_domains_tree::SubDomainsTree;
+use self::{geodata::GeoDataLoader, sub_domains_tree::SubDomainsTree};
 
+pub use self::{
+    audit::{AuditLog, AuditRules, AuditViolation},
+    geodata::GeoDataConfig,
+    internal_addr::InternalAddrFilter,
+    ip_ban::{HandshakeFailure, IpBan, IpBanConfig, IpBanList},
+    p2p::{P2pAction, P2pPolicy},
+    port_policy::{PortAutoBlock, PortPolicy, PortProtocol, PortRange, PortRules},
+    user_limit::{UserLimitExceeded, UserLimitGuard, UserLimiter, UserLimits},
+};
+
+pub mod audit;
+pub mod geodata;
+pub mod internal_addr;
+pub mod ip_ban;
+pub mod p2p;
+pub mod port_policy;
 mod sub_domains_tree;
+pub mod user_limit;
+
+/// Identify a client by the authenticated `user`, or `peer_addr` for single-user servers
+fn client_key(user: Option<&ServerUser>, peer_addr: &SocketAddr) -> Bytes {
+    match user {
+        Some(user) => Bytes::copy_from_slice(user.identity_hash()),
+        None => match peer_addr.ip() {
+            IpAddr::V4(v4) => Bytes::copy_from_slice(&v4.octets()),
+            IpAddr::V6(v6) => Bytes::copy_from_slice(&v6.octets()),
+        },
+    }
+}
 
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -39,6 +69,7 @@ struct Rules {
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
     rule_regex: RegexSet,
+    rule_substr: AhoCorasick,
     rule_set: HashSet<String>,
     rule_tree: SubDomainsTree,
 }
@@ -79,7 +110,12 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
-        write!(f, "], rule_tree: {:?} }}", self.rule_tree)
+        write!(
+            f,
+            "], rule_substr: {} patterns, rule_tree: {:?} }}",
+            self.rule_substr.patterns_len(),
+            self.rule_tree
+        )
     }
 }
 
@@ -89,6 +125,7 @@ impl Rules {
         mut ipv4: IpRange<Ipv4Net>,
         mut ipv6: IpRange<Ipv6Net>,
         rule_regex: RegexSet,
+        rule_substr: AhoCorasick,
         rule_set: HashSet<String>,
         rule_tree: SubDomainsTree,
     ) -> Self {
@@ -100,6 +137,7 @@ impl Rules {
             ipv4,
             ipv6,
             rule_regex,
+            rule_substr,
             rule_set,
             rule_tree,
         }
@@ -142,7 +180,10 @@ impl Rules {
     /// Check if the specified ASCII host matches any rules
     fn check_host_matched(&self, host: &str) -> bool {
         let host = host.trim_end_matches('.'); // FQDN, removes the last `.`
-        self.rule_set.contains(host) || self.rule_tree.contains(host) || self.rule_regex.is_match(host.as_bytes())
+        self.rule_set.contains(host)
+            || self.rule_tree.contains(host)
+            || self.rule_substr.is_match(host)
+            || self.rule_regex.is_match(host.as_bytes())
     }
 
     /// Check if there are no rules for IP addresses
@@ -152,7 +193,10 @@ impl Rules {
 
     /// Check if there are no rules for domain names
     fn is_host_empty(&self) -> bool {
-        self.rule_set.is_empty() && self.rule_tree.is_empty() && self.rule_regex.is_empty()
+        self.rule_set.is_empty()
+            && self.rule_tree.is_empty()
+            && self.rule_substr.patterns_len() == 0
+            && self.rule_regex.is_empty()
     }
 }
 
@@ -161,6 +205,7 @@ struct ParsingRules {
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
     rules_regex: Vec<String>,
+    rules_substr: Vec<String>,
     rules_set: HashSet<String>,
     rules_tree: SubDomainsTree,
 }
@@ -172,11 +217,48 @@ impl ParsingRules {
             ipv4: IpRange::new(),
             ipv6: IpRange::new(),
             rules_regex: Vec::new(),
+            rules_substr: Vec::new(),
             rules_set: HashSet::new(),
             rules_tree: SubDomainsTree::new(),
         }
     }
 
+    /// Add a rule line, which is an IP address, a CIDR network, a domain with preceding `|` or `||`, or a regex
+    fn add_rule(&mut self, line: &str) -> io::Result<()> {
+        if let Some(rule) = line.strip_prefix("||") {
+            return self.add_tree_rule(rule);
+        }
+
+        if let Some(rule) = line.strip_prefix('|') {
+            return self.add_set_rule(rule);
+        }
+
+        match line.parse::<IpNet>() {
+            Ok(IpNet::V4(v4)) => {
+                self.add_ipv4_rule(v4);
+            }
+            Ok(IpNet::V6(v6)) => {
+                self.add_ipv6_rule(v6);
+            }
+            Err(..) => {
+                // Maybe it is a pure IpAddr
+                match line.parse::<IpAddr>() {
+                    Ok(IpAddr::V4(v4)) => {
+                        self.add_ipv4_rule(v4);
+                    }
+                    Ok(IpAddr::V6(v6)) => {
+                        self.add_ipv6_rule(v6);
+                    }
+                    Err(..) => {
+                        self.add_regex_rule(line.to_owned());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn add_ipv4_rule(&mut self, rule: impl Into<Ipv4Net>) {
         let rule = rule.into();
         trace!("IPV4-RULE {}", rule);
@@ -228,6 +310,12 @@ impl ParsingRules {
         self.rules_regex.push(rule);
     }
 
+    /// Add a rule matching domains containing `rule`, like `keyword:` rules of v2ray
+    fn add_substr_rule(&mut self, rule: &str) {
+        trace!("SUBSTR-RULE {}", rule);
+        self.rules_substr.push(rule.to_ascii_lowercase());
+    }
+
     #[inline]
     fn add_set_rule(&mut self, rule: &str) -> io::Result<()> {
         trace!("SET-RULE {}", rule);
@@ -273,10 +361,14 @@ impl ParsingRules {
     }
 
     fn into_rules(self) -> io::Result<Rules> {
+        let rule_substr = AhoCorasick::new(self.rules_substr)
+            .map_err(|err| Error::other(format!("{} substring rules error: {err}", self.name)))?;
+
         Ok(Rules::new(
             self.ipv4,
             self.ipv6,
             Self::compile_regex(self.name, self.rules_regex)?,
+            rule_substr,
             self.rules_set,
             self.rules_tree,
         ))
@@ -335,6 +427,11 @@ impl ParsingRules {
 /// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
 /// - Domain with preceding `|` for exact matching, like `|google.com`
 /// - Domain with preceding `||` for matching with subdomains, like `||google.com`
+/// - Country networks in GeoIP database with preceding `geoip:`, like `geoip:cn`
+/// - Domains in GeoSite database with preceding `geosite:`, like `geosite:category-ads`
+///   or `geosite:google@cn` for domains with attribute `cn`
+///
+/// GeoIP and GeoSite rules require databases in `GeoDataConfig`, see `load_from_file_with_geodata`.
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
@@ -349,6 +446,11 @@ pub struct AccessControl {
 impl AccessControl {
     /// Load ACL rules from a file
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<Self> {
+        Self::load_from_file_with_geodata(p, &GeoDataConfig::default())
+    }
+
+    /// Load ACL rules from a file, `geoip:` and `geosite:` rules are loaded from databases in `geodata`
+    pub fn load_from_file_with_geodata<P: AsRef<Path>>(p: P, geodata: &GeoDataConfig) -> io::Result<Self> {
         trace!("ACL loading from {:?}", p.as_ref());
 
         let file_path_ref = p.as_ref();
@@ -357,6 +459,22 @@ impl AccessControl {
         let fp = File::open(file_path_ref)?;
         let r = BufReader::new(fp);
 
+        Self::load_from_reader_inner(r, file_path, geodata)
+    }
+
+    /// Load ACL rules of the file at `p` from `contents` already read, like when they were hashed too
+    pub fn load_from_file_contents<P: AsRef<Path>>(contents: &[u8], p: P, geodata: &GeoDataConfig) -> io::Result<Self> {
+        Self::load_from_reader_inner(contents, p.as_ref().to_path_buf(), geodata)
+    }
+
+    /// Load ACL rules from a reader, for rules not stored in local files, like downloaded from URLs
+    ///
+    /// `file_path` of the loaded ACL will be empty.
+    pub fn load_from_reader<R: BufRead>(r: R, geodata: &GeoDataConfig) -> io::Result<Self> {
+        Self::load_from_reader_inner(r, PathBuf::new(), geodata)
+    }
+
+    fn load_from_reader_inner<R: BufRead>(r: R, file_path: PathBuf, geodata: &GeoDataConfig) -> io::Result<Self> {
         let mut mode = Mode::BlackList;
         let mut outbound_mode = Mode::BlackList;
 
@@ -365,6 +483,7 @@ impl AccessControl {
         let mut bypass = ParsingRules::new("[black_list] or [bypass_list]");
         let mut proxy = ParsingRules::new("[white_list] or [proxy_list]");
         let mut curr = &mut bypass;
+        let mut geodata = GeoDataLoader::new(geodata);
 
         trace!("ACL parsing start from mode {:?} and black_list / bypass_list", mode);
 
@@ -386,13 +505,13 @@ impl AccessControl {
                 continue;
             }
 
-            if let Some(rule) = line.strip_prefix("||") {
-                curr.add_tree_rule(rule)?;
+            if let Some(code) = line.strip_prefix("geoip:") {
+                geodata.add_geoip_rules(code, curr)?;
                 continue;
             }
 
-            if let Some(rule) = line.strip_prefix('|') {
-                curr.add_set_rule(rule)?;
+            if let Some(name) = line.strip_prefix("geosite:") {
+                geodata.add_geosite_rules(name, curr)?;
                 continue;
             }
 
@@ -429,30 +548,7 @@ impl AccessControl {
                     curr = &mut proxy;
                     trace!("loading white_list / proxy_list");
                 }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr.add_ipv4_rule(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr.add_ipv6_rule(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr.add_ipv4_rule(v4);
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr.add_ipv6_rule(v6);
-                                }
-                                Err(..) => {
-                                    curr.add_regex_rule(line.to_owned());
-                                }
-                            }
-                        }
-                    }
-                }
+                _ => curr.add_rule(line)?,
             }
         }
 
@@ -600,6 +696,20 @@ impl AccessControl {
         }
     }
 
+    /// Check if outbound host matches domain rules (for server)
+    ///
+    /// Returns `Some(true)` if blocked, `Some(false)` if allowed, `None` if no domain rule matched.
+    pub fn check_outbound_host_matched(&self, host: &str) -> Option<bool> {
+        let ascii_host = Self::convert_to_ascii(host);
+        if self.outbound_block.check_host_matched(&ascii_host) {
+            return Some(true);
+        }
+        if self.outbound_allow.check_host_matched(&ascii_host) {
+            return Some(false);
+        }
+        None
+    }
+
     /// Check if outbound address is blocked (for server)
     ///
     /// NOTE: `Address::DomainName` is only validated by regex rules,
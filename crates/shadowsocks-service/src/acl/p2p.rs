@@ -0,0 +1,114 @@
+//! P2P traffic policy
+//!
+//! BitTorrent traffic relayed by servers brings copyright complaints to their providers.
+//! Detected P2P connections could be blocked, throttled or just logged, and are counted per client.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use shadowsocks::config::ServerUser;
+
+use crate::net::throttle::RateLimiter;
+
+use super::client_key;
+
+/// Action on detected P2P traffic
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum P2pAction {
+    /// Reject connections and drop packets
+    Block,
+    /// Limit bandwidth of the client's P2P traffic
+    Throttle,
+    /// Only count and log
+    Log,
+}
+
+/// P2P traffic policy
+pub struct P2pPolicy {
+    action: P2pAction,
+    throttle_rate: u64,
+    hits: Mutex<HashMap<Bytes, u64>>,
+    limiters: Mutex<HashMap<Bytes, Weak<RateLimiter>>>,
+}
+
+impl P2pPolicy {
+    /// Create a policy, `throttle_rate` is bytes per second shared by P2P traffic of a client
+    pub fn new(action: P2pAction, throttle_rate: u64) -> Self {
+        Self {
+            action,
+            throttle_rate,
+            hits: Mutex::new(HashMap::new()),
+            limiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Action on detected P2P traffic
+    pub fn action(&self) -> P2pAction {
+        self.action
+    }
+
+    /// Count detected P2P traffic of a client
+    ///
+    /// Returns the client's rate limiter if the action is `Throttle`.
+    pub fn record(&self, user: Option<&ServerUser>, peer_addr: &SocketAddr) -> Option<Arc<RateLimiter>> {
+        let key = client_key(user, peer_addr);
+        *self
+            .hits
+            .lock()
+            .expect("p2p policy hits poisoned")
+            .entry(key.clone())
+            .or_default() += 1;
+
+        if self.action != P2pAction::Throttle {
+            return None;
+        }
+
+        let mut limiters = self.limiters.lock().expect("p2p policy limiters poisoned");
+        if let Some(limiter) = limiters.get(&key).and_then(Weak::upgrade) {
+            return Some(limiter);
+        }
+        let limiter = Arc::new(RateLimiter::new(self.throttle_rate));
+        limiters.insert(key, Arc::downgrade(&limiter));
+        Some(limiter)
+    }
+
+    /// Take P2P detections of clients since last call
+    ///
+    /// Keys are users' identity hashes, or IP addresses' octets of clients without users.
+    pub fn take_hits(&self) -> HashMap<Bytes, u64> {
+        self.limiters
+            .lock()
+            .expect("p2p policy limiters poisoned")
+            .retain(|_, limiter| limiter.strong_count() > 0);
+
+        std::mem::take(&mut *self.hits.lock().expect("p2p policy hits poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let peer: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+
+        let policy = P2pPolicy::new(P2pAction::Throttle, 1024);
+        let limiter = policy.record(None, &peer).expect("limiter should be returned");
+        assert!(Arc::ptr_eq(&limiter, &policy.record(None, &peer).unwrap()));
+        assert_eq!(limiter.rate(), 1024);
+
+        let hits = policy.take_hits();
+        assert_eq!(hits.get(&[198u8, 51, 100, 1][..]), Some(&2));
+        assert!(policy.take_hits().is_empty());
+
+        let policy = P2pPolicy::new(P2pAction::Block, 1024);
+        assert!(policy.record(None, &peer).is_none());
+    }
+}
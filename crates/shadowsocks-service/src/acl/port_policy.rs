@@ -0,0 +1,425 @@
+//! Outbound port policy
+//!
+//! Some destination ports are abused by clients, for example sending spam through SMTP (TCP 25),
+//! which gets the server's addresses blacklisted. Connections to these ports could be rejected,
+//! and clients hitting them repeatedly could be blocked for a while.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasher, RandomState},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use shadowsocks::config::ServerUser;
+
+use super::client_key;
+
+/// Shards of counted clients, connections of different clients rarely wait for each other
+const CLIENT_SHARDS: usize = 16;
+
+/// Transport protocol of an outbound connection
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
+}
+
+/// An inclusive range of ports, `25` or `6881-6889`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    /// Create a range of ports from `start` to `end`, both inclusive
+    pub fn new(start: u16, end: u16) -> Self {
+        Self { start, end }
+    }
+
+    /// Check if `port` is in this range
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    /// Iterate ports in this range
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        Self::new(port, port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// Error while parsing `PortRange`
+#[derive(Debug, Clone)]
+pub struct PortRangeError;
+
+impl fmt::Display for PortRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid port range")
+    }
+}
+
+impl std::error::Error for PortRangeError {}
+
+impl FromStr for PortRange {
+    type Err = PortRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim(), end.trim()),
+            None => (s.trim(), s.trim()),
+        };
+
+        let start = start.parse::<u16>().map_err(|_| PortRangeError)?;
+        let end = end.parse::<u16>().map_err(|_| PortRangeError)?;
+        if start > end {
+            return Err(PortRangeError);
+        }
+
+        Ok(Self::new(start, end))
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.collect_str(self)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct PortRangeVisitor;
+
+        impl de::Visitor<'_> for PortRangeVisitor {
+            type Value = PortRange;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a port number or a port range like \"6881-6889\"")
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                u16::try_from(v)
+                    .map(PortRange::from)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                u16::try_from(v)
+                    .map(PortRange::from)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                v.parse().map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(PortRangeVisitor)
+    }
+}
+
+/// Port rules of one protocol
+///
+/// Ports in `deny` are always blocked. If `allow` is not empty, ports not in `allow` are blocked too.
+#[derive(Debug, Clone, Default)]
+pub struct PortRules {
+    deny: Vec<PortRange>,
+    allow: Vec<PortRange>,
+}
+
+impl PortRules {
+    /// Create port rules from deny and allow lists
+    pub fn new(deny: Vec<PortRange>, allow: Vec<PortRange>) -> Self {
+        Self { deny, allow }
+    }
+
+    /// Check if there is no rule
+    pub fn is_empty(&self) -> bool {
+        self.deny.is_empty() && self.allow.is_empty()
+    }
+
+    /// Check if outbound connections to `port` should be blocked
+    pub fn is_blocked(&self, port: u16) -> bool {
+        if self.deny.iter().any(|r| r.contains(port)) {
+            return true;
+        }
+        !self.allow.is_empty() && !self.allow.iter().any(|r| r.contains(port))
+    }
+}
+
+/// Temporary block of clients hitting blocked ports repeatedly
+#[derive(Debug, Clone, Copy)]
+pub struct PortAutoBlock {
+    /// Blocked port hits in `window` to trigger the block
+    pub threshold: u32,
+    /// Period of counting hits
+    pub window: Duration,
+    /// How long the client will be blocked
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+struct ClientHits {
+    hits: u64,
+    window_start: Option<Instant>,
+    window_hits: u32,
+    blocked_until: Option<Instant>,
+}
+
+/// Outbound port policy
+pub struct PortPolicy {
+    tcp: PortRules,
+    udp: PortRules,
+    auto_block: Option<PortAutoBlock>,
+    clients: Box<[Mutex<HashMap<Bytes, ClientHits>>]>,
+    hasher: RandomState,
+}
+
+impl PortPolicy {
+    /// Create a policy with port rules of TCP and UDP
+    pub fn new(tcp: PortRules, udp: PortRules) -> Self {
+        Self {
+            tcp,
+            udp,
+            auto_block: None,
+            clients: (0..CLIENT_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn clients_shard(&self, key: &Bytes) -> &Mutex<HashMap<Bytes, ClientHits>> {
+        &self.clients[self.hasher.hash_one(key) as usize % self.clients.len()]
+    }
+
+    /// Block clients temporarily after repeated hits on blocked ports
+    pub fn set_auto_block(&mut self, auto_block: PortAutoBlock) {
+        self.auto_block = Some(auto_block);
+    }
+
+    /// Check if `port` is blocked by rules of `protocol`, hits are not counted
+    pub fn is_port_blocked(&self, protocol: PortProtocol, port: u16) -> bool {
+        match protocol {
+            PortProtocol::Tcp => self.tcp.is_blocked(port),
+            PortProtocol::Udp => self.udp.is_blocked(port),
+        }
+    }
+
+    /// Check if the outbound connection to `port` of a client should be blocked
+    ///
+    /// Clients are identified by the authenticated `user`, or `peer_addr` for single-user servers.
+    /// Blocked attempts are counted for the client.
+    pub fn check_blocked(
+        &self,
+        protocol: PortProtocol,
+        port: u16,
+        user: Option<&ServerUser>,
+        peer_addr: &SocketAddr,
+    ) -> bool {
+        let rules = match protocol {
+            PortProtocol::Tcp => &self.tcp,
+            PortProtocol::Udp => &self.udp,
+        };
+
+        let port_blocked = rules.is_blocked(port);
+        if !port_blocked && self.auto_block.is_none() {
+            return false;
+        }
+
+        let key = client_key(user, peer_addr);
+
+        let now = Instant::now();
+        let mut clients = self.clients_shard(&key).lock().expect("port policy clients poisoned");
+
+        if !port_blocked {
+            // Only clients under temporary block will be rejected
+            return match clients.get(&key) {
+                Some(client) => client.blocked_until.is_some_and(|t| t > now),
+                None => false,
+            };
+        }
+
+        let client = clients.entry(key).or_default();
+        client.hits += 1;
+
+        if let Some(ref auto_block) = self.auto_block {
+            if client.blocked_until.is_some_and(|t| t > now) {
+                return true;
+            }
+
+            match client.window_start {
+                Some(start) if now.duration_since(start) < auto_block.window => client.window_hits += 1,
+                _ => {
+                    client.window_start = Some(now);
+                    client.window_hits = 1;
+                }
+            }
+
+            if client.window_hits >= auto_block.threshold {
+                client.blocked_until = Some(now + auto_block.duration);
+                client.window_start = None;
+                client.window_hits = 0;
+
+                match user {
+                    Some(user) => warn!(
+                        "user {} temporarily blocked for {:?} after hitting blocked ports {} times",
+                        user.name(),
+                        auto_block.duration,
+                        auto_block.threshold
+                    ),
+                    None => warn!(
+                        "client {} temporarily blocked for {:?} after hitting blocked ports {} times",
+                        peer_addr.ip(),
+                        auto_block.duration,
+                        auto_block.threshold
+                    ),
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Take blocked port hits of clients since last call
+    ///
+    /// Keys are users' identity hashes, or IP addresses' octets of clients without users.
+    pub fn take_hits(&self) -> HashMap<Bytes, u64> {
+        let now = Instant::now();
+        let window = self.auto_block.map(|b| b.window).unwrap_or_default();
+
+        let mut hits = HashMap::new();
+        for shard in self.clients.iter() {
+            let mut clients = shard.lock().expect("port policy clients poisoned");
+            clients.retain(|key, client| {
+                if client.hits > 0 {
+                    hits.insert(key.clone(), client.hits);
+                    client.hits = 0;
+                }
+
+                // Keep clients which are still blocked or counting
+                client.blocked_until.is_some_and(|t| t > now)
+                    || client.window_start.is_some_and(|t| now.duration_since(t) < window)
+            });
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "198.51.100.1:40000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!("25".parse::<PortRange>().unwrap(), PortRange::new(25, 25));
+        assert_eq!("6881-6889".parse::<PortRange>().unwrap(), PortRange::new(6881, 6889));
+        assert!("6889-6881".parse::<PortRange>().is_err());
+        assert!("70000".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_port_rules() {
+        let rules = PortRules::new(vec![PortRange::from(25)], Vec::new());
+        assert!(rules.is_blocked(25));
+        assert!(!rules.is_blocked(443));
+
+        let rules = PortRules::new(vec![PortRange::from(8443)], vec![PortRange::new(80, 80), PortRange::new(443, 9000)]);
+        assert!(rules.is_blocked(22));
+        assert!(rules.is_blocked(8443));
+        assert!(!rules.is_blocked(80));
+        assert!(!rules.is_blocked(8080));
+    }
+
+    #[test]
+    fn test_check_blocked_counts_hits() {
+        let policy = PortPolicy::new(PortRules::new(vec![PortRange::from(25)], Vec::new()), PortRules::default());
+        assert!(policy.check_blocked(PortProtocol::Tcp, 25, None, &peer()));
+        assert!(policy.check_blocked(PortProtocol::Tcp, 25, None, &peer()));
+        assert!(!policy.check_blocked(PortProtocol::Udp, 25, None, &peer()));
+        assert!(!policy.check_blocked(PortProtocol::Tcp, 443, None, &peer()));
+
+        let hits = policy.take_hits();
+        assert_eq!(hits.get(&[198u8, 51, 100, 1][..]), Some(&2));
+        assert!(policy.take_hits().is_empty());
+    }
+
+    #[test]
+    fn test_hits_of_all_shards_taken() {
+        let policy = PortPolicy::new(PortRules::new(vec![PortRange::from(25)], Vec::new()), PortRules::default());
+        for i in 0..100u8 {
+            let peer = SocketAddr::from(([198, 51, 100, i], 40000));
+            assert!(policy.check_blocked(PortProtocol::Tcp, 25, None, &peer));
+        }
+
+        let hits = policy.take_hits();
+        assert_eq!(hits.len(), 100);
+        assert!(hits.values().all(|hits| *hits == 1));
+    }
+
+    #[test]
+    fn test_auto_block() {
+        let mut policy = PortPolicy::new(PortRules::new(vec![PortRange::from(25)], Vec::new()), PortRules::default());
+        policy.set_auto_block(PortAutoBlock {
+            threshold: 2,
+            window: Duration::from_secs(60),
+            duration: Duration::from_secs(60),
+        });
+
+        assert!(!policy.check_blocked(PortProtocol::Tcp, 443, None, &peer()));
+        assert!(policy.check_blocked(PortProtocol::Tcp, 25, None, &peer()));
+        assert!(!policy.check_blocked(PortProtocol::Tcp, 443, None, &peer()));
+        assert!(policy.check_blocked(PortProtocol::Tcp, 25, None, &peer()));
+
+        // Blocked for all ports now
+        assert!(policy.check_blocked(PortProtocol::Tcp, 443, None, &peer()));
+        assert!(policy.check_blocked(PortProtocol::Udp, 53, None, &peer()));
+
+        let other: SocketAddr = "198.51.100.2:40000".parse().unwrap();
+        assert!(!policy.check_blocked(PortProtocol::Tcp, 443, None, &other));
+    }
+}
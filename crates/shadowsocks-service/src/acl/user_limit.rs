@@ -0,0 +1,301 @@
+//! Per-user connection limits
+//!
+//! A single user opening lots of connections could exhaust file descriptors of the server.
+//! Concurrent TCP connections, concurrent UDP associations and new connections or associations per second
+//! of each user could be limited, by node-wide defaults or per-user overrides.
+//! UDP associations tunneled over TCP, by UoT or multiplexed streams, are limited as UDP associations.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use arc_swap::ArcSwap;
+use shadowsocks::config::ServerUser;
+
+/// Limits of a user, `None` for unlimited
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct UserLimits {
+    /// Concurrent TCP connections
+    pub max_tcp_connections: Option<u32>,
+    /// Concurrent UDP associations
+    pub max_udp_associations: Option<u32>,
+    /// New TCP connections and UDP associations per second
+    pub max_new_connections: Option<u32>,
+}
+
+impl UserLimits {
+    /// Check if there is no limit
+    pub fn is_unlimited(&self) -> bool {
+        self.max_tcp_connections.is_none() && self.max_udp_associations.is_none() && self.max_new_connections.is_none()
+    }
+
+    /// Limits set in `overrides`, or limits of `self` for unset ones
+    pub fn overridden_by(&self, overrides: &UserLimits) -> UserLimits {
+        UserLimits {
+            max_tcp_connections: overrides.max_tcp_connections.or(self.max_tcp_connections),
+            max_udp_associations: overrides.max_udp_associations.or(self.max_udp_associations),
+            max_new_connections: overrides.max_new_connections.or(self.max_new_connections),
+        }
+    }
+}
+
+/// Limit exceeded by a user
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UserLimitExceeded {
+    TcpConnections(u32),
+    UdpAssociations(u32),
+    NewConnections(u32),
+}
+
+impl fmt::Display for UserLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::TcpConnections(n) => write!(f, "exceeded {n} concurrent tcp connections"),
+            Self::UdpAssociations(n) => write!(f, "exceeded {n} concurrent udp associations"),
+            Self::NewConnections(n) => write!(f, "exceeded {n} new connections per second"),
+        }
+    }
+}
+
+impl std::error::Error for UserLimitExceeded {}
+
+#[derive(Default)]
+struct UserState {
+    tcp_connections: u32,
+    udp_associations: u32,
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl UserState {
+    fn is_idle(&self) -> bool {
+        self.tcp_connections == 0 && self.udp_associations == 0
+    }
+
+    /// Take a token of `max_new_connections`, the bucket allows bursts of one second
+    fn take_new_connection(&mut self, limits: &UserLimits) -> Result<(), UserLimitExceeded> {
+        let Some(rate) = limits.max_new_connections else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let rate = rate as f64;
+        self.tokens = match self.last_refill {
+            Some(last) => (self.tokens + now.duration_since(last).as_secs_f64() * rate).min(rate),
+            None => rate,
+        };
+        self.last_refill = Some(now);
+        if self.tokens < 1.0 {
+            return Err(UserLimitExceeded::NewConnections(rate as u32));
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum GuardKind {
+    Tcp,
+    Udp,
+}
+
+/// Per-user connection limiter
+pub struct UserLimiter {
+    defaults: UserLimits,
+    overrides: ArcSwap<HashMap<String, UserLimits>>,
+    users: Mutex<HashMap<String, UserState>>,
+}
+
+impl UserLimiter {
+    /// Create a limiter with node-wide default limits
+    pub fn new(defaults: UserLimits) -> Self {
+        Self {
+            defaults,
+            overrides: ArcSwap::from_pointee(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replace per-user overrides, keyed by user names
+    pub fn set_overrides(&self, overrides: HashMap<String, UserLimits>) {
+        self.overrides.store(Arc::new(overrides));
+    }
+
+    /// Effective limits of a user
+    pub fn limits_of(&self, name: &str) -> UserLimits {
+        match self.overrides.load().get(name) {
+            Some(overrides) => self.defaults.overridden_by(overrides),
+            None => self.defaults,
+        }
+    }
+
+    /// Acquire a new TCP connection of `user`, which is released when the guard is dropped
+    ///
+    /// Returns `None` if the user is unlimited.
+    pub fn acquire_tcp(self: &Arc<Self>, user: &ServerUser) -> Result<Option<UserLimitGuard>, UserLimitExceeded> {
+        let limits = self.limits_of(user.name());
+        if limits.is_unlimited() {
+            return Ok(None);
+        }
+
+        let mut users = self.users.lock().expect("user limiter poisoned");
+        let state = users.entry(user.name().to_owned()).or_default();
+
+        if let Some(max) = limits.max_tcp_connections
+            && state.tcp_connections >= max
+        {
+            return Err(UserLimitExceeded::TcpConnections(max));
+        }
+
+        state.take_new_connection(&limits)?;
+
+        state.tcp_connections += 1;
+        Ok(Some(UserLimitGuard::new(self.clone(), user.name(), GuardKind::Tcp)))
+    }
+
+    /// Acquire a new UDP association of `user`, which is released when the guard is dropped
+    ///
+    /// Returns `None` if the user is unlimited.
+    pub fn acquire_udp(self: &Arc<Self>, user: &ServerUser) -> Result<Option<UserLimitGuard>, UserLimitExceeded> {
+        let limits = self.limits_of(user.name());
+        if limits.is_unlimited() {
+            return Ok(None);
+        }
+
+        let mut users = self.users.lock().expect("user limiter poisoned");
+        let state = users.entry(user.name().to_owned()).or_default();
+
+        if let Some(max) = limits.max_udp_associations
+            && state.udp_associations >= max
+        {
+            return Err(UserLimitExceeded::UdpAssociations(max));
+        }
+
+        state.take_new_connection(&limits)?;
+
+        state.udp_associations += 1;
+        Ok(Some(UserLimitGuard::new(self.clone(), user.name(), GuardKind::Udp)))
+    }
+
+    fn release(&self, name: &str, kind: GuardKind) {
+        let mut users = self.users.lock().expect("user limiter poisoned");
+        if let Some(state) = users.get_mut(name) {
+            match kind {
+                GuardKind::Tcp => state.tcp_connections = state.tcp_connections.saturating_sub(1),
+                GuardKind::Udp => state.udp_associations = state.udp_associations.saturating_sub(1),
+            }
+
+            // Rate tokens of idle users are refilled in one second anyway
+            if state.is_idle()
+                && state
+                    .last_refill
+                    .is_none_or(|last| last.elapsed().as_secs() >= 1)
+            {
+                users.remove(name);
+            }
+        }
+    }
+}
+
+/// A connection or association counted by `UserLimiter`
+pub struct UserLimitGuard {
+    limiter: Arc<UserLimiter>,
+    name: String,
+    kind: GuardKind,
+}
+
+impl UserLimitGuard {
+    fn new(limiter: Arc<UserLimiter>, name: &str, kind: GuardKind) -> Self {
+        Self {
+            limiter,
+            name: name.to_owned(),
+            kind,
+        }
+    }
+}
+
+impl Drop for UserLimitGuard {
+    fn drop(&mut self) {
+        self.limiter.release(&self.name, self.kind);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(name: &str) -> ServerUser {
+        ServerUser::new(name, vec![0u8; 16])
+    }
+
+    #[test]
+    fn test_concurrent_limits() {
+        let limiter = Arc::new(UserLimiter::new(UserLimits {
+            max_tcp_connections: Some(2),
+            ..Default::default()
+        }));
+
+        let alice = user("1");
+        let first = limiter.acquire_tcp(&alice).unwrap();
+        let _second = limiter.acquire_tcp(&alice).unwrap();
+        assert_eq!(
+            limiter.acquire_tcp(&alice).err(),
+            Some(UserLimitExceeded::TcpConnections(2))
+        );
+        // UDP associations are unlimited
+        assert!(limiter.acquire_udp(&alice).unwrap().is_some());
+
+        drop(first);
+        assert!(limiter.acquire_tcp(&alice).is_ok());
+
+        // Overrides of other users
+        let mut overrides = HashMap::new();
+        overrides.insert("2".to_owned(), UserLimits {
+            max_tcp_connections: Some(1),
+            max_udp_associations: Some(0),
+            ..Default::default()
+        });
+        limiter.set_overrides(overrides);
+
+        let bob = user("2");
+        let _guard = limiter.acquire_tcp(&bob).unwrap();
+        assert!(limiter.acquire_tcp(&bob).is_err());
+        assert_eq!(
+            limiter.acquire_udp(&bob).err(),
+            Some(UserLimitExceeded::UdpAssociations(0))
+        );
+    }
+
+    #[test]
+    fn test_new_connection_rate() {
+        let limiter = Arc::new(UserLimiter::new(UserLimits {
+            max_new_connections: Some(3),
+            ..Default::default()
+        }));
+
+        let alice = user("1");
+        for _ in 0..3 {
+            drop(limiter.acquire_tcp(&alice).unwrap());
+        }
+        assert_eq!(
+            limiter.acquire_tcp(&alice).err(),
+            Some(UserLimitExceeded::NewConnections(3))
+        );
+
+        // UDP associations share the rate of TCP connections
+        let bob = user("2");
+        drop(limiter.acquire_tcp(&bob).unwrap());
+        drop(limiter.acquire_udp(&bob).unwrap());
+        drop(limiter.acquire_udp(&bob).unwrap());
+        assert_eq!(
+            limiter.acquire_udp(&bob).err(),
+            Some(UserLimitExceeded::NewConnections(3))
+        );
+
+        let unlimited = Arc::new(UserLimiter::new(UserLimits::default()));
+        assert!(unlimited.acquire_tcp(&alice).unwrap().is_none());
+    }
+}
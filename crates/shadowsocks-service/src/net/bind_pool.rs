@@ -0,0 +1,140 @@
+//! Outbound source address pool
+//!
+//! Servers with multiple public addresses could spread clients across them,
+//! so that every outbound connection of one client leaves from the same (or a random) address.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use shadowsocks::net::AddrFamily;
+
+/// Strategy of choosing an address from `BindAddrPool`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BindAddrStrategy {
+    /// Hash the client's identity, the same client always binds to the same address
+    #[default]
+    Stable,
+    /// Choose a random address for every outbound connection
+    Random,
+    /// Choose addresses one by one for every outbound connection
+    RoundRobin,
+}
+
+/// A pool of local addresses for binding outbound sockets
+#[derive(Debug)]
+pub struct BindAddrPool {
+    ipv4: Vec<IpAddr>,
+    ipv6: Vec<IpAddr>,
+    strategy: BindAddrStrategy,
+    next: AtomicUsize,
+}
+
+impl BindAddrPool {
+    /// Create a pool from addresses
+    pub fn new<I>(addrs: I, strategy: BindAddrStrategy) -> Self
+    where
+        I: IntoIterator<Item = IpAddr>,
+    {
+        let (ipv4, ipv6) = addrs.into_iter().partition(IpAddr::is_ipv4);
+        Self {
+            ipv4,
+            ipv6,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Check if there is no address in the pool
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// Strategy of this pool
+    pub fn strategy(&self) -> BindAddrStrategy {
+        self.strategy
+    }
+
+    /// Choose an address of `family` for the client identified by `key`
+    ///
+    /// Returns `None` if there is no address of `family` in the pool,
+    /// outbound sockets should be left unbound in that case.
+    pub fn select(&self, family: AddrFamily, key: &[u8]) -> Option<IpAddr> {
+        let addrs = match family {
+            AddrFamily::Ipv4 => &self.ipv4,
+            AddrFamily::Ipv6 => &self.ipv6,
+        };
+
+        if addrs.is_empty() {
+            return None;
+        }
+
+        let idx = match self.strategy {
+            BindAddrStrategy::Stable => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % addrs.len() as u64) as usize
+            }
+            BindAddrStrategy::Random => rand::rng().random_range(0..addrs.len()),
+            BindAddrStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % addrs.len(),
+        };
+
+        Some(addrs[idx])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_pool(strategy: BindAddrStrategy) -> BindAddrPool {
+        BindAddrPool::new(
+            [
+                "192.0.2.1".parse().unwrap(),
+                "192.0.2.2".parse().unwrap(),
+                "192.0.2.3".parse().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+            ],
+            strategy,
+        )
+    }
+
+    #[test]
+    fn test_stable_select() {
+        let pool = make_pool(BindAddrStrategy::Stable);
+        let first = pool.select(AddrFamily::Ipv4, b"user-1").unwrap();
+        for _ in 0..16 {
+            assert_eq!(pool.select(AddrFamily::Ipv4, b"user-1"), Some(first));
+        }
+        assert!(first.is_ipv4());
+        assert_eq!(pool.select(AddrFamily::Ipv6, b"user-1"), "2001:db8::1".parse().ok());
+    }
+
+    #[test]
+    fn test_round_robin_select() {
+        let pool = make_pool(BindAddrStrategy::RoundRobin);
+        let selected = (0..3)
+            .map(|_| pool.select(AddrFamily::Ipv4, b"user-1").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            selected,
+            vec![
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "192.0.2.2".parse().unwrap(),
+                "192.0.2.3".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_family() {
+        let pool = BindAddrPool::new(["192.0.2.1".parse().unwrap()], BindAddrStrategy::Random);
+        assert!(pool.select(AddrFamily::Ipv4, b"").is_some());
+        assert!(pool.select(AddrFamily::Ipv6, b"").is_none());
+    }
+}
@@ -1,13 +1,33 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    bind_pool::{BindAddrPool, BindAddrStrategy},
+    flow::FlowStat,
+    mon_socket::MonProxySocket,
+    mon_stream::MonProxyStream,
+    proxy_protocol::ProxyProtocolTrust,
+    record_stream::RecordStream,
+    sniff::SniffConfig,
+    throttle::{RateLimiter, ThrottledStream},
+};
 
+pub mod bind_pool;
 pub mod flow;
 #[cfg(target_os = "macos")]
 pub mod launch_activate_socket;
 pub mod mon_socket;
 pub mod mon_stream;
+#[cfg(feature = "server-mux")]
+pub mod mux;
 pub mod packet_window;
+pub mod proxy_protocol;
+pub mod record_stream;
+pub mod sniff;
+#[cfg(unix)]
+pub mod systemd_activate_socket;
+pub mod throttle;
+#[cfg(feature = "server-transport")]
+pub mod transport;
 pub mod utils;
 
 /// Packet size for all UDP associations' send queue
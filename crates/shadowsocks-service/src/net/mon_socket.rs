@@ -16,12 +16,33 @@ use super::flow::FlowStat;
 pub struct MonProxySocket<S> {
     socket: ProxySocket<S>,
     flow_stat: Arc<FlowStat>,
+    shared_node_flow_stats: Vec<Arc<FlowStat>>,
 }
 
 impl<S> MonProxySocket<S> {
     /// Create a new socket with flow monitor
     pub fn from_socket(socket: ProxySocket<S>, flow_stat: Arc<FlowStat>) -> Self {
-        Self { socket, flow_stat }
+        Self {
+            socket,
+            flow_stat,
+            shared_node_flow_stats: Vec::new(),
+        }
+    }
+
+    /// Count packets of users from nodes sharing the server port in their own flow statistics
+    ///
+    /// Nodes are numbered from 1 in the order of `flow_stats`.
+    pub fn set_shared_node_flow_stats(&mut self, flow_stats: Vec<Arc<FlowStat>>) {
+        self.shared_node_flow_stats = flow_stats;
+    }
+
+    /// Get the flow statistic of the node `control` belongs to
+    fn control_flow_stat(&self, control: Option<&UdpSocketControlData>) -> &FlowStat {
+        let node = control.map_or(0, |c| c.node);
+        match node.checked_sub(1).and_then(|idx| self.shared_node_flow_stats.get(idx)) {
+            Some(flow_stat) => flow_stat,
+            None => &self.flow_stat,
+        }
     }
 
     /// Get the underlying `ProxySocket<S>` immutable reference
@@ -59,7 +80,8 @@ where
         payload: &[u8],
     ) -> io::Result<()> {
         let n = self.socket.send_with_ctrl(addr, control, payload).await?;
-        self.flow_stat.incr_tx(n as u64, control.user.as_deref());
+        self.control_flow_stat(Some(control))
+            .incr_tx(n as u64, control.user.as_deref());
 
         Ok(())
     }
@@ -83,7 +105,8 @@ where
         payload: &[u8],
     ) -> io::Result<()> {
         let n = self.socket.send_to_with_ctrl(target, addr, control, payload).await?;
-        self.flow_stat.incr_tx(n as u64, control.user.as_deref());
+        self.control_flow_stat(Some(control))
+            .incr_tx(n as u64, control.user.as_deref());
 
         Ok(())
     }
@@ -117,7 +140,8 @@ where
         recv_buf: &mut [u8],
     ) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
         let (n, addr, recv_n, control) = self.socket.recv_with_ctrl(recv_buf).await?;
-        self.flow_stat.incr_rx(recv_n as u64, control.as_ref().and_then(|o| o.user.as_deref()));
+        self.control_flow_stat(control.as_ref())
+            .incr_rx(recv_n as u64, control.as_ref().and_then(|o| o.user.as_deref()));
 
         Ok((n, addr, control))
     }
@@ -146,7 +170,8 @@ where
         recv_buf: &mut [u8],
     ) -> io::Result<(usize, SocketAddr, Address, Option<UdpSocketControlData>)> {
         let (n, peer_addr, addr, recv_n, control) = self.socket.recv_from_with_ctrl(recv_buf).await?;
-        self.flow_stat.incr_rx(recv_n as u64, control.as_ref().and_then(|o| o.user.as_deref()));
+        self.control_flow_stat(control.as_ref())
+            .incr_rx(recv_n as u64, control.as_ref().and_then(|o| o.user.as_deref()));
 
         Ok((n, peer_addr, addr, control))
     }
@@ -33,6 +33,12 @@ where
         Self { stream, flow_stat }
     }
 
+    /// Count following traffic in `flow_stat`, like of nodes sharing the server port
+    #[inline]
+    pub fn set_flow_stat(&mut self, flow_stat: Arc<FlowStat>) {
+        self.flow_stat = flow_stat;
+    }
+
     #[inline]
     pub fn get_ref(&self) -> &ProxyServerStream<S> {
         &self.stream
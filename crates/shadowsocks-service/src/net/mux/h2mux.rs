@@ -0,0 +1,206 @@
+//! h2mux sessions, streams are HTTP/2 requests
+//!
+//! Request bodies carry data of clients, bodies of `200` responses carry data of the server.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, Bytes};
+use h2::{Reason, RecvStream, SendStream, server::Connection};
+use http::Response;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Receive windows of streams, larger than HTTP/2's default for throughput
+const STREAM_WINDOW_SIZE: u32 = 1024 * 1024;
+const CONNECTION_WINDOW_SIZE: u32 = 4 * 1024 * 1024;
+
+fn h2_error(err: h2::Error) -> io::Error {
+    if err.is_io() {
+        err.into_io().expect("io error")
+    } else {
+        io::Error::other(err)
+    }
+}
+
+/// Server side of a h2mux session
+pub struct H2MuxSession<S> {
+    connection: Connection<S, Bytes>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> H2MuxSession<S> {
+    /// Start a session with the HTTP/2 handshake
+    pub async fn handshake(stream: S) -> io::Result<Self> {
+        let connection = h2::server::Builder::new()
+            .initial_window_size(STREAM_WINDOW_SIZE)
+            .initial_connection_window_size(CONNECTION_WINDOW_SIZE)
+            .handshake(stream)
+            .await
+            .map_err(h2_error)?;
+        Ok(Self { connection })
+    }
+
+    /// Accept a request of the client as a stream, `None` if the session is closed
+    ///
+    /// Streams are only driven while waiting for the next one.
+    pub async fn next_stream(&mut self) -> Option<io::Result<H2Stream>> {
+        let (request, mut respond) = match self.connection.accept().await? {
+            Ok(r) => r,
+            Err(err) => return Some(Err(h2_error(err))),
+        };
+        let send = match respond.send_response(Response::new(()), false) {
+            Ok(send) => send,
+            Err(err) => return Some(Err(h2_error(err))),
+        };
+        Some(Ok(H2Stream {
+            recv: request.into_body(),
+            send,
+            buffer: Bytes::new(),
+        }))
+    }
+}
+
+/// Stream of a h2mux session
+pub struct H2Stream {
+    recv: RecvStream,
+    send: SendStream<Bytes>,
+    buffer: Bytes,
+}
+
+impl H2Stream {
+    /// Reset the stream refused by the server with `REFUSED_STREAM`
+    pub fn reset(mut self) {
+        self.send.send_reset(Reason::REFUSED_STREAM);
+    }
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while !self.buffer.has_remaining() {
+            match ready!(self.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = self.recv.flow_control().release_capacity(data.len());
+                    self.buffer = data;
+                }
+                Some(Err(err)) if err.is_reset() => return Poll::Ready(Ok(())),
+                Some(Err(err)) => return Poll::Ready(Err(h2_error(err))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..n]);
+        self.buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        self.send.reserve_capacity(buf.len());
+        match ready!(self.send.poll_capacity(cx)) {
+            Some(Ok(n)) => {
+                let n = n.min(buf.len());
+                self.send
+                    .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                    .map_err(h2_error)?;
+                Poll::Ready(Ok(n))
+            }
+            Some(Err(err)) => Poll::Ready(Err(h2_error(err))),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.send.send_data(Bytes::new(), true) {
+            Ok(()) => Poll::Ready(Ok(())),
+            // Already ended or reset
+            Err(err) if !err.is_io() => Poll::Ready(Ok(())),
+            Err(err) => Poll::Ready(Err(h2_error(err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::{Method, Request};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_h2mux_stream() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client = tokio::spawn(async move {
+            let (mut client, connection) = h2::client::handshake(client).await.unwrap();
+            tokio::spawn(connection);
+
+            let request = Request::builder()
+                .method(Method::CONNECT)
+                .uri("localhost:443")
+                .body(())
+                .unwrap();
+            let (response, mut send) = client.send_request(request, false).unwrap();
+            send.send_data(Bytes::from_static(b"hello"), true).unwrap();
+
+            let mut body = response.await.unwrap().into_body();
+            let mut received = Vec::new();
+            while let Some(data) = body.data().await {
+                let data = data.unwrap();
+                let _ = body.flow_control().release_capacity(data.len());
+                received.extend_from_slice(&data);
+            }
+            received
+        });
+
+        let mut session = H2MuxSession::handshake(server).await.unwrap();
+        let mut stream = session.next_stream().await.unwrap().unwrap();
+        tokio::spawn(async move { while session.next_stream().await.is_some() {} });
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+
+        stream.write_all(b"world").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(client.await.unwrap(), b"world");
+    }
+
+    #[tokio::test]
+    async fn test_h2mux_stream_reset() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client = tokio::spawn(async move {
+            let (mut client, connection) = h2::client::handshake(client).await.unwrap();
+            tokio::spawn(connection);
+
+            let request = Request::builder()
+                .method(Method::CONNECT)
+                .uri("localhost:443")
+                .body(())
+                .unwrap();
+            let (response, _send) = client.send_request(request, false).unwrap();
+            // The reset may arrive before or after the response
+            let err = match response.await {
+                Ok(response) => response.into_body().data().await.unwrap().unwrap_err(),
+                Err(err) => err,
+            };
+            err.reason()
+        });
+
+        let mut session = H2MuxSession::handshake(server).await.unwrap();
+        session.next_stream().await.unwrap().unwrap().reset();
+        tokio::spawn(async move { while session.next_stream().await.is_some() {} });
+
+        assert_eq!(client.await.unwrap(), Some(Reason::REFUSED_STREAM));
+    }
+}
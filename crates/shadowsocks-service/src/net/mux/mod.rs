@@ -0,0 +1,256 @@
+//! Server side of sing-mux, multiplexing streams in a shadowsocks connection
+//!
+//! Clients connect to a magic destination and start a session with a request choosing the protocol,
+//! then every stream of the session starts with a request of its destination.
+
+use std::{
+    future, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use shadowsocks::relay::socks5::Address;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+pub use self::{
+    h2mux::{H2MuxSession, H2Stream},
+    padding::PaddingStream,
+    smux::{SmuxSession, SmuxStream},
+};
+
+mod h2mux;
+mod padding;
+mod smux;
+
+/// Destination of sessions
+const MUX_DESTINATION: &str = "sp.mux.sing-box.arpa";
+
+const VERSION_0: u8 = 0;
+/// Version 1 adds padding
+const VERSION_1: u8 = 1;
+
+/// Flags of stream requests
+const FLAG_UDP: u16 = 1;
+const FLAG_ADDR: u16 = 2;
+
+const STATUS_SUCCESS: u8 = 0;
+
+/// Check if the destination of a TCP stream starts a session
+pub fn is_mux_destination(addr: &Address) -> bool {
+    matches!(addr, Address::DomainNameAddress(name, _) if name == MUX_DESTINATION)
+}
+
+/// Protocol multiplexing streams of a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxProtocol {
+    H2Mux,
+    Smux,
+    Yamux,
+}
+
+impl MuxProtocol {
+    fn from_u8(protocol: u8) -> io::Result<Self> {
+        match protocol {
+            0 => Ok(Self::H2Mux),
+            1 => Ok(Self::Smux),
+            2 => Ok(Self::Yamux),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported mux protocol {protocol}"),
+            )),
+        }
+    }
+}
+
+/// Session of multiplexed streams
+pub enum MuxSession<S> {
+    H2Mux(Box<H2MuxSession<PaddingStream<S>>>),
+    Smux(SmuxSession<PaddingStream<S>>),
+    Yamux(Box<yamux::Connection<Compat<PaddingStream<S>>>>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> MuxSession<S> {
+    /// Read the session request from the stream and start the session
+    pub async fn accept(mut stream: S) -> io::Result<Self> {
+        let version = stream.read_u8().await?;
+        if version != VERSION_0 && version != VERSION_1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported mux version {version}"),
+            ));
+        }
+        let protocol = MuxProtocol::from_u8(stream.read_u8().await?)?;
+
+        let mut padding = false;
+        if version == VERSION_1 {
+            padding = stream.read_u8().await? != 0;
+            if padding {
+                let padding_len = stream.read_u16().await?;
+                let mut discard = (&mut stream).take(padding_len as u64);
+                tokio::io::copy(&mut discard, &mut tokio::io::sink()).await?;
+                if discard.limit() > 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+
+        let stream = PaddingStream::new(stream, padding);
+        let session = match protocol {
+            MuxProtocol::H2Mux => Self::H2Mux(Box::new(H2MuxSession::handshake(stream).await?)),
+            MuxProtocol::Smux => Self::Smux(SmuxSession::new(stream)),
+            MuxProtocol::Yamux => Self::Yamux(Box::new(yamux::Connection::new(
+                stream.compat(),
+                yamux::Config::default(),
+                yamux::Mode::Server,
+            ))),
+        };
+        Ok(session)
+    }
+
+    /// Protocol of the session
+    pub fn protocol(&self) -> MuxProtocol {
+        match *self {
+            Self::H2Mux(..) => MuxProtocol::H2Mux,
+            Self::Smux(..) => MuxProtocol::Smux,
+            Self::Yamux(..) => MuxProtocol::Yamux,
+        }
+    }
+
+    /// Accept the next stream opened by the client, `None` if the session is closed
+    ///
+    /// Sessions should keep accepting, streams of h2mux and yamux are driven while waiting.
+    pub async fn next_stream(&mut self) -> Option<io::Result<MuxStream>> {
+        match self {
+            Self::H2Mux(session) => session.next_stream().await.map(|r| r.map(MuxStream::H2Mux)),
+            Self::Smux(session) => session.next_stream().await.map(|r| r.map(MuxStream::Smux)),
+            Self::Yamux(connection) => future::poll_fn(|cx| connection.poll_next_inbound(cx))
+                .await
+                .map(|r| r.map(|s| MuxStream::Yamux(s.compat())).map_err(io::Error::other)),
+        }
+    }
+}
+
+/// Network of a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxNetwork {
+    Tcp,
+    /// UDP packets, prefixed by their addresses if `packet_addr`, otherwise sent to the destination of the stream
+    Udp { packet_addr: bool },
+}
+
+/// Request starting a stream
+#[derive(Debug, Clone)]
+pub struct StreamRequest {
+    pub network: MuxNetwork,
+    pub destination: Address,
+}
+
+/// Stream of a session
+pub enum MuxStream {
+    H2Mux(H2Stream),
+    Smux(SmuxStream),
+    Yamux(Compat<yamux::Stream>),
+}
+
+impl MuxStream {
+    /// Close the stream refused by the server without relaying it
+    ///
+    /// yamux streams are reset when dropped before being closed.
+    pub fn reset(self) {
+        match self {
+            Self::H2Mux(stream) => stream.reset(),
+            Self::Smux(stream) => stream.reset(),
+            Self::Yamux(stream) => drop(stream),
+        }
+    }
+
+    /// Read the request starting the stream, then respond to it
+    ///
+    /// Failures to connect are seen by clients as streams closed after the response.
+    pub async fn accept_request(&mut self) -> io::Result<StreamRequest> {
+        let flags = self.read_u16().await?;
+        let destination = Address::read_from(self).await?;
+        let network = if flags & FLAG_UDP == 0 {
+            MuxNetwork::Tcp
+        } else {
+            MuxNetwork::Udp {
+                packet_addr: flags & FLAG_ADDR != 0,
+            }
+        };
+
+        self.write_all(&[STATUS_SUCCESS]).await?;
+        Ok(StreamRequest { network, destination })
+    }
+}
+
+macro_rules! dispatch {
+    ($self:expr, $s:ident => $e:expr) => {
+        match $self.get_mut() {
+            MuxStream::H2Mux($s) => $e,
+            MuxStream::Smux($s) => $e,
+            MuxStream::Yamux($s) => $e,
+        }
+    };
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        dispatch!(self, s => Pin::new(s).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_shutdown(cx))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_smux_session_with_padding() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let accept = tokio::spawn(async move {
+            let mut session = MuxSession::accept(server).await.unwrap();
+            assert_eq!(session.protocol(), MuxProtocol::Smux);
+            let mut stream = session.next_stream().await.unwrap().unwrap();
+            tokio::spawn(async move { while session.next_stream().await.is_some() {} });
+            let request = stream.accept_request().await.unwrap();
+            (stream, request)
+        });
+
+        // Version 1, smux, padding of 3 bytes
+        client.write_all(&[VERSION_1, 1, 1, 0, 3, 0, 0, 0]).await.unwrap();
+        let mut client = PaddingStream::new(client, true);
+
+        // SYN and PSH of stream 1, carrying a request to connect to example.com:443 over UDP with packet addresses
+        let mut request = (FLAG_UDP | FLAG_ADDR).to_be_bytes().to_vec();
+        Address::DomainNameAddress("example.com".to_owned(), 443).write_to_buf(&mut request);
+        let mut frames = vec![1, 0, 0, 0, 1, 0, 0, 0, 1, 2];
+        frames.extend((request.len() as u16).to_le_bytes());
+        frames.extend(1u32.to_le_bytes());
+        frames.extend(&request);
+        client.write_all(&frames).await.unwrap();
+        client.flush().await.unwrap();
+
+        let (_stream, request) = accept.await.unwrap();
+        assert_eq!(request.network, MuxNetwork::Udp { packet_addr: true });
+        assert_eq!(request.destination, Address::DomainNameAddress("example.com".to_owned(), 443));
+
+        // Status of the stream in a padded PSH frame
+        let mut response = [0u8; 9];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [1, 2, 1, 0, 1, 0, 0, 0, STATUS_SUCCESS]);
+    }
+}
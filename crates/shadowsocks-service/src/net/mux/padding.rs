@@ -0,0 +1,179 @@
+//! Padding of sing-mux sessions
+//!
+//! The first 16 reads and writes of a session are framed with padding of random lengths,
+//! hiding lengths of requests and responses at the start.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Frames with padding in each direction
+const PADDED_FRAMES: usize = 16;
+/// Header of padded frames, carrying lengths of data and padding
+const PADDING_HEADER_SIZE: usize = 4;
+
+/// Stream of a session, with the first frames padded if enabled
+pub struct PaddingStream<S> {
+    stream: S,
+    read_frames: usize,
+    read_header: [u8; PADDING_HEADER_SIZE],
+    read_header_len: usize,
+    read_remaining: usize,
+    padding_remaining: usize,
+    write_frames: usize,
+    write_buffer: BytesMut,
+}
+
+impl<S> PaddingStream<S> {
+    /// Create a stream, padding nothing if `enabled` is false
+    pub fn new(stream: S, enabled: bool) -> Self {
+        let frames = if enabled { 0 } else { PADDED_FRAMES };
+        Self {
+            stream,
+            read_frames: frames,
+            read_header: [0u8; PADDING_HEADER_SIZE],
+            read_header_len: 0,
+            read_remaining: 0,
+            padding_remaining: 0,
+            write_frames: frames,
+            write_buffer: BytesMut::new(),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> PaddingStream<S> {
+    /// Write the pending padded frame out
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_buffer.has_remaining() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buffer))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PaddingStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.read_remaining > 0 {
+                let n = this.read_remaining.min(buf.remaining());
+                let mut data = ReadBuf::new(buf.initialize_unfilled_to(n));
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut data))?;
+                let n = data.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                buf.advance(n);
+                this.read_remaining -= n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.padding_remaining > 0 {
+                let mut discard = [0u8; 256];
+                let n = this.padding_remaining.min(discard.len());
+                let mut discard = ReadBuf::new(&mut discard[..n]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut discard))?;
+                let n = discard.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.padding_remaining -= n;
+                continue;
+            }
+
+            if this.read_frames >= PADDED_FRAMES {
+                return Pin::new(&mut this.stream).poll_read(cx, buf);
+            }
+
+            while this.read_header_len < PADDING_HEADER_SIZE {
+                let mut header = ReadBuf::new(&mut this.read_header[this.read_header_len..]);
+                ready!(Pin::new(&mut this.stream).poll_read(cx, &mut header))?;
+                let n = header.filled().len();
+                if n == 0 {
+                    // EOF between frames
+                    if this.read_header_len == 0 {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.read_header_len += n;
+            }
+
+            this.read_remaining = u16::from_be_bytes([this.read_header[0], this.read_header[1]]) as usize;
+            this.padding_remaining = u16::from_be_bytes([this.read_header[2], this.read_header[3]]) as usize;
+            this.read_header_len = 0;
+            this.read_frames += 1;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PaddingStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_write_buffer(cx))?;
+
+        if this.write_frames >= PADDED_FRAMES {
+            return Pin::new(&mut this.stream).poll_write(cx, buf);
+        }
+
+        // Buffered, then written out by following writes and flushes
+        let n = buf.len().min(u16::MAX as usize);
+        let padding = rand::rng().random_range(256..768);
+        this.write_buffer.reserve(PADDING_HEADER_SIZE + n + padding);
+        this.write_buffer.put_u16(n as u16);
+        this.write_buffer.put_u16(padding as u16);
+        this.write_buffer.put_slice(&buf[..n]);
+        this.write_buffer.put_bytes(0, padding);
+        this.write_frames += 1;
+
+        if let Poll::Ready(Err(err)) = this.poll_write_buffer(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buffer(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buffer(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_padding_round_trip() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = PaddingStream::new(client, true);
+        let mut server = PaddingStream::new(server, true);
+
+        let messages: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 100 + i as usize]).collect();
+        for message in &messages {
+            client.write_all(message).await.unwrap();
+        }
+        client.flush().await.unwrap();
+        drop(client);
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, messages.concat());
+    }
+}
@@ -0,0 +1,476 @@
+//! smux (version 1) sessions
+//!
+//! Frames start with an 8 bytes header: version, command, length (little endian) and stream ID (little endian).
+//! Version 1 has no flow control, so a stream slow to read is closed once its buffered bytes exceed its budget or
+//! the budget of the session, instead of holding back other streams of the session.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::trace;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    sync::mpsc,
+};
+use tokio_util::sync::PollSender;
+
+const SMUX_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8;
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+
+/// Maximum data of frames sent, the default of smux
+const MAX_FRAME_SIZE: usize = 32 * 1024;
+/// Frames queued for writing to the session
+const SEND_CHANNEL_SIZE: usize = 64;
+/// Bytes buffered for each stream, the stream is reset if it reads slower
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+/// Bytes buffered for all streams of a session, the stream receiving more is reset
+const SESSION_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+struct Frame {
+    cmd: u8,
+    sid: u32,
+    data: Bytes,
+}
+
+/// Bytes received but not yet read by a stream, counted in the stream and in its session
+struct Buffered {
+    stream: AtomicUsize,
+    session: Arc<AtomicUsize>,
+}
+
+impl Buffered {
+    fn release(&self, n: usize) {
+        self.stream.fetch_sub(n, Ordering::AcqRel);
+        self.session.fetch_sub(n, Ordering::AcqRel);
+    }
+}
+
+/// Receiving end of a stream in the session
+struct StreamEntry {
+    data_tx: mpsc::UnboundedSender<Bytes>,
+    buffered: Arc<Buffered>,
+    reset: Arc<AtomicBool>,
+}
+
+/// Server side of a smux session
+pub struct SmuxSession<S> {
+    reader: ReadHalf<S>,
+    frame_tx: mpsc::Sender<Frame>,
+    streams: HashMap<u32, StreamEntry>,
+    buffered: Arc<AtomicUsize>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + 'static> SmuxSession<S> {
+    /// Start a session, writing frames of its streams in a task
+    pub fn new(stream: S) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        let (frame_tx, frame_rx) = mpsc::channel(SEND_CHANNEL_SIZE);
+        tokio::spawn(write_frames(writer, frame_rx));
+        Self {
+            reader,
+            frame_tx,
+            streams: HashMap::new(),
+            buffered: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Read frames until a stream is opened by the client, `None` if the session is closed
+    pub async fn next_stream(&mut self) -> Option<io::Result<SmuxStream>> {
+        loop {
+            let mut header = [0u8; HEADER_SIZE];
+            match self.reader.read_exact(&mut header).await {
+                Ok(..) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(err) => return Some(Err(err)),
+            }
+
+            if header[0] != SMUX_VERSION {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported smux version {}", header[0]),
+                )));
+            }
+            let cmd = header[1];
+            let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let sid = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+            let mut data = BytesMut::zeroed(length);
+            if let Err(err) = self.reader.read_exact(&mut data).await {
+                return Some(Err(err));
+            }
+
+            match cmd {
+                CMD_SYN => {
+                    // Streams dropped by the server are evicted before adding new ones
+                    self.streams.retain(|_, entry| !entry.data_tx.is_closed());
+                    if self.streams.contains_key(&sid) {
+                        continue;
+                    }
+                    let (data_tx, data_rx) = mpsc::unbounded_channel();
+                    let buffered = Arc::new(Buffered {
+                        stream: AtomicUsize::new(0),
+                        session: self.buffered.clone(),
+                    });
+                    let reset = Arc::new(AtomicBool::new(false));
+                    self.streams.insert(
+                        sid,
+                        StreamEntry {
+                            data_tx,
+                            buffered: buffered.clone(),
+                            reset: reset.clone(),
+                        },
+                    );
+                    return Some(Ok(SmuxStream::new(
+                        sid,
+                        data_rx,
+                        buffered,
+                        reset,
+                        self.frame_tx.clone(),
+                    )));
+                }
+                CMD_PSH => {
+                    let Some(entry) = self.streams.get(&sid) else {
+                        continue;
+                    };
+                    if data.is_empty() {
+                        continue;
+                    }
+
+                    // Only the session adds to the counters, streams reading concurrently only lower them
+                    if entry.buffered.stream.load(Ordering::Acquire) + length > STREAM_BUFFER_SIZE
+                        || self.buffered.load(Ordering::Acquire) + length > SESSION_BUFFER_SIZE
+                    {
+                        trace!("smux stream {} reset, reading too slowly", sid);
+                        entry.reset.store(true, Ordering::Release);
+                        self.streams.remove(&sid);
+                        let _ = self.frame_tx.try_send(Frame {
+                            cmd: CMD_FIN,
+                            sid,
+                            data: Bytes::new(),
+                        });
+                        continue;
+                    }
+
+                    entry.buffered.stream.fetch_add(length, Ordering::AcqRel);
+                    self.buffered.fetch_add(length, Ordering::AcqRel);
+                    if let Err(err) = entry.data_tx.send(data.freeze()) {
+                        // Stream dropped by the server
+                        entry.buffered.release(err.0.len());
+                        self.streams.remove(&sid);
+                    }
+                }
+                // Dropping the sender ends reading of the stream
+                CMD_FIN => {
+                    self.streams.remove(&sid);
+                }
+                CMD_NOP => {}
+                cmd => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid smux command {cmd}"),
+                    )));
+                }
+            }
+        }
+    }
+}
+
+async fn write_frames<S: AsyncWrite>(mut writer: WriteHalf<S>, mut frame_rx: mpsc::Receiver<Frame>) {
+    let mut buf = BytesMut::new();
+    while let Some(frame) = frame_rx.recv().await {
+        buf.clear();
+        buf.put_u8(SMUX_VERSION);
+        buf.put_u8(frame.cmd);
+        buf.put_u16_le(frame.data.len() as u16);
+        buf.put_u32_le(frame.sid);
+        buf.put_slice(&frame.data);
+
+        let result = match writer.write_all(&buf).await {
+            Ok(()) => writer.flush().await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            trace!("smux session closed, {}", err);
+            break;
+        }
+    }
+}
+
+/// Stream of a smux session
+///
+/// Reads and writes fail with `ConnectionReset` after the session reset the stream.
+pub struct SmuxStream {
+    sid: u32,
+    data_rx: mpsc::UnboundedReceiver<Bytes>,
+    buffered: Arc<Buffered>,
+    reset: Arc<AtomicBool>,
+    buffer: Bytes,
+    frame_tx: PollSender<Frame>,
+    fin_sent: bool,
+}
+
+impl SmuxStream {
+    fn new(
+        sid: u32,
+        data_rx: mpsc::UnboundedReceiver<Bytes>,
+        buffered: Arc<Buffered>,
+        reset: Arc<AtomicBool>,
+        frame_tx: mpsc::Sender<Frame>,
+    ) -> Self {
+        Self {
+            sid,
+            data_rx,
+            buffered,
+            reset,
+            buffer: Bytes::new(),
+            frame_tx: PollSender::new(frame_tx),
+            fin_sent: false,
+        }
+    }
+
+    fn is_reset(&self) -> bool {
+        self.reset.load(Ordering::Acquire)
+    }
+
+    /// Close the stream refused by the server, smux has no reset so the client sees it finished
+    pub fn reset(mut self) {
+        self.send_fin();
+    }
+
+    fn send_fin(&mut self) {
+        if !self.fin_sent
+            && !self.is_reset()
+            && let Some(frame_tx) = self.frame_tx.get_ref()
+        {
+            let _ = frame_tx.try_send(Frame {
+                cmd: CMD_FIN,
+                sid: self.sid,
+                data: Bytes::new(),
+            });
+        }
+        self.fin_sent = true;
+    }
+
+    fn poll_send_frame(&mut self, cx: &mut Context<'_>, cmd: u8, data: Bytes) -> Poll<io::Result<()>> {
+        ready!(self.frame_tx.poll_reserve(cx)).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.frame_tx
+            .send_item(Frame { cmd, sid: self.sid, data })
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SmuxStream {
+    fn drop(&mut self) {
+        self.send_fin();
+
+        // Data never read no longer counts against the session
+        self.data_rx.close();
+        while let Ok(data) = self.data_rx.try_recv() {
+            self.buffered.release(data.len());
+        }
+    }
+}
+
+impl AsyncRead for SmuxStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while !self.buffer.has_remaining() {
+            match ready!(self.data_rx.poll_recv(cx)) {
+                Some(data) => {
+                    self.buffered.release(data.len());
+                    self.buffer = data;
+                }
+                None if self.is_reset() => return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let n = self.buffer.len().min(buf.remaining());
+        buf.put_slice(&self.buffer[..n]);
+        self.buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SmuxStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if self.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(MAX_FRAME_SIZE);
+        ready!(self.poll_send_frame(cx, CMD_PSH, Bytes::copy_from_slice(&buf[..n])))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.fin_sent && !self.is_reset() {
+            ready!(self.poll_send_frame(cx, CMD_FIN, Bytes::new()))?;
+            self.fin_sent = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(cmd: u8, sid: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![SMUX_VERSION, cmd];
+        frame.extend((data.len() as u16).to_le_bytes());
+        frame.extend(sid.to_le_bytes());
+        frame.extend(data);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_smux_streams() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut session = SmuxSession::new(server);
+
+        client.write_all(&frame(CMD_SYN, 1, b"")).await.unwrap();
+        client.write_all(&frame(CMD_SYN, 3, b"")).await.unwrap();
+        client.write_all(&frame(CMD_PSH, 1, b"hello")).await.unwrap();
+        client.write_all(&frame(CMD_FIN, 1, b"")).await.unwrap();
+
+        let mut first = session.next_stream().await.unwrap().unwrap();
+        let mut second = session.next_stream().await.unwrap().unwrap();
+        tokio::spawn(async move { while session.next_stream().await.is_some() {} });
+
+        let mut received = Vec::new();
+        first.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+
+        second.write_all(b"world").await.unwrap();
+        second.shutdown().await.unwrap();
+        let mut response = vec![0u8; 2 * HEADER_SIZE + 5];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..HEADER_SIZE + 5], &frame(CMD_PSH, 3, b"world")[..]);
+        assert_eq!(&response[HEADER_SIZE + 5..], &frame(CMD_FIN, 3, b"")[..]);
+    }
+
+    #[tokio::test]
+    async fn test_smux_slow_stream_reset() {
+        let (mut client, server) = tokio::io::duplex(2 * STREAM_BUFFER_SIZE);
+        let mut session = SmuxSession::new(server);
+
+        client.write_all(&frame(CMD_SYN, 1, b"")).await.unwrap();
+        client.write_all(&frame(CMD_SYN, 3, b"")).await.unwrap();
+        let data = vec![0u8; MAX_FRAME_SIZE];
+        for _ in 0..=STREAM_BUFFER_SIZE / MAX_FRAME_SIZE {
+            client.write_all(&frame(CMD_PSH, 1, &data)).await.unwrap();
+        }
+        client.write_all(&frame(CMD_PSH, 3, b"hello")).await.unwrap();
+        client.write_all(&frame(CMD_FIN, 3, b"")).await.unwrap();
+
+        let mut slow = session.next_stream().await.unwrap().unwrap();
+        let mut other = session.next_stream().await.unwrap().unwrap();
+        tokio::spawn(async move { while session.next_stream().await.is_some() {} });
+
+        // Not held back by the stream that isn't read
+        let mut received = Vec::new();
+        other.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+
+        let mut fin = vec![0u8; HEADER_SIZE];
+        client.read_exact(&mut fin).await.unwrap();
+        assert_eq!(fin, frame(CMD_FIN, 1, b""));
+
+        let mut received = Vec::new();
+        let err = slow.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(received.len(), STREAM_BUFFER_SIZE);
+        assert_eq!(slow.write(b"data").await.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_smux_small_frames_buffered() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut session = SmuxSession::new(server);
+
+        // Small frames are limited by their bytes, not by their count
+        client.write_all(&frame(CMD_SYN, 1, b"")).await.unwrap();
+        for _ in 0..1024 {
+            client.write_all(&frame(CMD_PSH, 1, b"data")).await.unwrap();
+        }
+        client.write_all(&frame(CMD_FIN, 1, b"")).await.unwrap();
+        drop(client);
+
+        let mut stream = session.next_stream().await.unwrap().unwrap();
+        assert!(session.next_stream().await.is_none());
+        assert_eq!(session.buffered.load(Ordering::Acquire), 1024 * 4);
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 1024 * 4);
+        assert_eq!(session.buffered.load(Ordering::Acquire), 0);
+    }
+
+    #[tokio::test]
+    async fn test_smux_session_buffer_bounded() {
+        let (mut client, server) = tokio::io::duplex(2 * SESSION_BUFFER_SIZE);
+        let mut session = SmuxSession::new(server);
+
+        // Streams filling their budgets use up the budget of the session
+        let streams = (SESSION_BUFFER_SIZE / STREAM_BUFFER_SIZE) as u32;
+        let data = vec![0u8; MAX_FRAME_SIZE];
+        for sid in 0..=streams {
+            client.write_all(&frame(CMD_SYN, sid, b"")).await.unwrap();
+        }
+        for sid in 0..=streams {
+            for _ in 0..STREAM_BUFFER_SIZE / MAX_FRAME_SIZE {
+                client.write_all(&frame(CMD_PSH, sid, &data)).await.unwrap();
+            }
+        }
+
+        let mut accepted = Vec::new();
+        for _ in 0..=streams {
+            accepted.push(session.next_stream().await.unwrap().unwrap());
+        }
+        tokio::spawn(async move { while session.next_stream().await.is_some() {} });
+
+        let mut fin = vec![0u8; HEADER_SIZE];
+        client.read_exact(&mut fin).await.unwrap();
+        assert_eq!(fin, frame(CMD_FIN, streams, b""));
+
+        let mut last = accepted.pop().unwrap();
+        let mut received = Vec::new();
+        let err = last.read_to_end(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_smux_dropped_streams_evicted() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut session = SmuxSession::new(server);
+
+        client.write_all(&frame(CMD_SYN, 1, b"")).await.unwrap();
+        client.write_all(&frame(CMD_SYN, 3, b"")).await.unwrap();
+
+        drop(session.next_stream().await.unwrap().unwrap());
+        let _stream = session.next_stream().await.unwrap().unwrap();
+        assert_eq!(session.streams.keys().collect::<Vec<_>>(), [&3]);
+    }
+}
@@ -0,0 +1,400 @@
+//! HAProxy PROXY protocol on inbound connections from trusted load balancers
+//!
+//! Balancers prefix connections with a header carrying addresses of the original client,
+//! version 1 in text and version 2 in binary. Datagrams are prefixed by version 2 headers of UDP,
+//! responses are sent back to the balancer without headers.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use ipnet::{AddrParseError, IpNet, Ipv4Net, Ipv6Net};
+use iprange::IpRange;
+use log::debug;
+use lru_time_cache::LruCache;
+use shadowsocks::relay::udprelay::{DatagramReceive, DatagramSend, DatagramSocket};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+/// Signature of version 2 headers
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Signature, version and command, family and protocol, length of addresses
+const V2_HEADER_SIZE: usize = 16;
+/// Version 1 headers are at most 107 bytes, including CRLF
+const V1_MAX_HEADER_SIZE: usize = 107;
+
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+
+const V2_FAMILY_INET: u8 = 0x10;
+const V2_FAMILY_INET6: u8 = 0x20;
+const V2_PROTO_STREAM: u8 = 0x01;
+const V2_PROTO_DGRAM: u8 = 0x02;
+
+/// Sources trusted to send PROXY protocol headers
+#[derive(Debug, Clone, Default)]
+pub struct ProxyProtocolTrust {
+    ipv4: IpRange<Ipv4Net>,
+    ipv6: IpRange<Ipv6Net>,
+}
+
+impl ProxyProtocolTrust {
+    /// Create a list trusting nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust an address or network, for example `10.0.0.2` or `10.0.0.0/24`
+    pub fn add_trusted(&mut self, rule: &str) -> Result<(), AddrParseError> {
+        let net = match rule.parse::<IpNet>() {
+            Ok(net) => net,
+            Err(err) => match rule.parse::<IpAddr>() {
+                Ok(ip) => IpNet::from(ip),
+                Err(..) => return Err(err),
+            },
+        };
+
+        match net {
+            IpNet::V4(v4) => {
+                self.ipv4.add(v4);
+                self.ipv4.simplify();
+            }
+            IpNet::V6(v6) => {
+                self.ipv6.add(v6);
+                self.ipv6.simplify();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if nothing is trusted
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// Check if connections from `addr` must start with a header
+    pub fn is_trusted(&self, addr: &SocketAddr) -> bool {
+        // Dual-stack listeners see IPv4 peers as IPv4-mapped-IPv6
+        match addr.ip().to_canonical() {
+            IpAddr::V4(v4) => self.ipv4.contains(&v4),
+            IpAddr::V6(v6) => self.ipv6.contains(&v6),
+        }
+    }
+}
+
+fn invalid_header(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid proxy protocol header, {msg}"))
+}
+
+/// Read the header of a connection, returning the original client
+///
+/// Returns `None` for connections of the balancer itself, like health checks.
+/// Nothing after the header is read.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // Shorter than both the signature of version 2 and the shortest header of version 1
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await?;
+
+    if header[..] == V2_SIGNATURE {
+        header.resize(V2_HEADER_SIZE, 0);
+        stream.read_exact(&mut header[V2_SIGNATURE.len()..]).await?;
+        let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+        header.resize(V2_HEADER_SIZE + length, 0);
+        stream.read_exact(&mut header[V2_HEADER_SIZE..]).await?;
+        return parse_v2(&header, V2_PROTO_STREAM);
+    }
+
+    if !header.starts_with(b"PROXY ") {
+        return Err(invalid_header("missing signature"));
+    }
+    // Byte by byte, for not reading after the line
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_HEADER_SIZE {
+            return Err(invalid_header("line too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    parse_v1(&header)
+}
+
+/// Parse the header of a datagram, returning its length and the original client
+///
+/// Only version 2 is supported for datagrams.
+pub fn parse_datagram_header(data: &[u8]) -> io::Result<(usize, Option<SocketAddr>)> {
+    if data.len() < V2_HEADER_SIZE || data[..V2_SIGNATURE.len()] != V2_SIGNATURE {
+        return Err(invalid_header("missing signature"));
+    }
+    let length = V2_HEADER_SIZE + u16::from_be_bytes([data[14], data[15]]) as usize;
+    if data.len() < length {
+        return Err(invalid_header("truncated"));
+    }
+    Ok((length, parse_v2(&data[..length], V2_PROTO_DGRAM)?))
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid_header("not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[..] {
+        [_, "UNKNOWN", ..] => Ok(None),
+        [_, protocol @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid_header("invalid source address"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid_header("invalid source port"))?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid_header("address mismatches protocol"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid_header("unknown protocol")),
+    }
+}
+
+fn parse_v2(header: &[u8], expected_proto: u8) -> io::Result<Option<SocketAddr>> {
+    let version_command = header[12];
+    if version_command & 0xf0 != V2_VERSION {
+        return Err(invalid_header("unsupported version"));
+    }
+    match version_command & 0x0f {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        _ => return Err(invalid_header("unknown command")),
+    }
+
+    let family_proto = header[13];
+    let addrs = &header[V2_HEADER_SIZE..];
+    let family = family_proto & 0xf0;
+    if family != V2_FAMILY_INET && family != V2_FAMILY_INET6 {
+        // Unspecified and UNIX sockets, addresses are ignored like LOCAL
+        return Ok(None);
+    }
+    if family_proto & 0x0f != expected_proto {
+        return Err(invalid_header("transport protocol mismatches"));
+    }
+
+    // Source and destination addresses, then source and destination ports, followed by TLVs ignored
+    let source = if family == V2_FAMILY_INET {
+        if addrs.len() < 12 {
+            return Err(invalid_header("truncated addresses"));
+        }
+        let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[..4]).unwrap());
+        SocketAddr::new(ip.into(), u16::from_be_bytes([addrs[8], addrs[9]]))
+    } else {
+        if addrs.len() < 36 {
+            return Err(invalid_header("truncated addresses"));
+        }
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).unwrap());
+        SocketAddr::new(ip.into(), u16::from_be_bytes([addrs[32], addrs[33]]))
+    };
+    Ok(Some(source))
+}
+
+/// UDP socket taking sources of datagrams from headers of trusted balancers
+///
+/// Datagrams are received as sent by the original clients, responses to them are sent to their balancers.
+pub struct ProxyProtocolSocket<S> {
+    socket: S,
+    trust: ProxyProtocolTrust,
+    // Balancers relaying clients, `None` if nothing is trusted
+    balancers: Option<Mutex<LruCache<SocketAddr, SocketAddr>>>,
+}
+
+impl<S> ProxyProtocolSocket<S> {
+    /// Wrap a socket, routes to clients are forgotten after `time_to_live` without datagrams
+    pub fn new(socket: S, trust: ProxyProtocolTrust, time_to_live: Duration) -> Self {
+        let balancers = if trust.is_empty() {
+            None
+        } else {
+            Some(Mutex::new(LruCache::with_expiry_duration(time_to_live)))
+        };
+        Self {
+            socket,
+            trust,
+            balancers,
+        }
+    }
+
+    fn balancer_of(&self, target: SocketAddr) -> SocketAddr {
+        match self.balancers {
+            None => target,
+            Some(ref balancers) => balancers.lock().unwrap().get(&target).copied().unwrap_or(target),
+        }
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for ProxyProtocolSocket<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl<S: DatagramReceive> DatagramReceive for ProxyProtocolSocket<S> {
+    fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.socket.poll_recv(cx, buf)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        loop {
+            let peer_addr = ready!(self.socket.poll_recv_from(cx, buf))?;
+            let Some(ref balancers) = self.balancers else {
+                return Poll::Ready(Ok(peer_addr));
+            };
+            if !self.trust.is_trusted(&peer_addr) {
+                return Poll::Ready(Ok(peer_addr));
+            }
+
+            let (header_len, source) = match parse_datagram_header(buf.filled()) {
+                Ok(h) => h,
+                Err(err) => {
+                    debug!("udp datagram from {} dropped, {}", peer_addr, err);
+                    buf.clear();
+                    continue;
+                }
+            };
+
+            let n = buf.filled().len();
+            buf.filled_mut().copy_within(header_len.., 0);
+            buf.set_filled(n - header_len);
+
+            return match source {
+                None => Poll::Ready(Ok(peer_addr)),
+                Some(source) => {
+                    balancers.lock().unwrap().insert(source, peer_addr);
+                    Poll::Ready(Ok(source))
+                }
+            };
+        }
+    }
+
+    fn poll_recv_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.socket.poll_recv_ready(cx)
+    }
+}
+
+impl<S: DatagramSend> DatagramSend for ProxyProtocolSocket<S> {
+    fn poll_send(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.socket.poll_send(cx, buf)
+    }
+
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, self.balancer_of(target))
+    }
+
+    fn poll_send_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.socket.poll_send_ready(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_header_v1() {
+        let data = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello";
+        let mut reader = &data[..];
+        let source = read_header(&mut reader).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(reader, b"hello");
+
+        let mut reader = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut reader).await.unwrap(), None);
+
+        let mut reader = &b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n"[..];
+        assert!(read_header(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_header_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_VERSION | V2_CMD_PROXY, V2_FAMILY_INET6 | V2_PROTO_STREAM]);
+        // Addresses followed by a NOOP TLV of 3 bytes
+        data.extend((36u16 + 6).to_be_bytes());
+        data.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend(56324u16.to_be_bytes());
+        data.extend(443u16.to_be_bytes());
+        data.extend([0x04, 0, 3, 0, 0, 0]);
+        data.extend(b"hello");
+
+        let mut reader = &data[..];
+        let source = read_header(&mut reader).await.unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(reader, b"hello");
+
+        // Datagram headers carry UDP
+        assert!(parse_datagram_header(&data).is_err());
+    }
+
+    #[test]
+    fn test_datagram_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_VERSION | V2_CMD_PROXY, V2_FAMILY_INET | V2_PROTO_DGRAM]);
+        data.extend(12u16.to_be_bytes());
+        data.extend([192, 0, 2, 1, 198, 51, 100, 1]);
+        data.extend(5353u16.to_be_bytes());
+        data.extend(8388u16.to_be_bytes());
+        data.extend(b"payload");
+
+        let (length, source) = parse_datagram_header(&data).unwrap();
+        assert_eq!(&data[length..], b"payload");
+        assert_eq!(source, Some("192.0.2.1:5353".parse().unwrap()));
+
+        data[12] = V2_VERSION | V2_CMD_LOCAL;
+        assert_eq!(parse_datagram_header(&data).unwrap(), (length, None));
+        assert!(parse_datagram_header(&data[..20]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_routes_to_balancer() {
+        use shadowsocks::{
+            net::UdpSocket,
+            relay::udprelay::{DatagramReceiveExt, DatagramSendExt},
+        };
+
+        let mut trust = ProxyProtocolTrust::new();
+        trust.add_trusted("127.0.0.1").unwrap();
+        let socket = UdpSocket::listen(&"127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let socket = ProxyProtocolSocket::new(socket, trust, Duration::from_secs(60));
+        let socket_addr = socket.local_addr().unwrap();
+        let balancer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // A datagram without a header is dropped, then one relayed for 192.0.2.1:5353
+        balancer.send_to(b"payload", socket_addr).await.unwrap();
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([V2_VERSION | V2_CMD_PROXY, V2_FAMILY_INET | V2_PROTO_DGRAM]);
+        data.extend(12u16.to_be_bytes());
+        data.extend([192, 0, 2, 1, 127, 0, 0, 1]);
+        data.extend(5353u16.to_be_bytes());
+        data.extend(socket_addr.port().to_be_bytes());
+        data.extend(b"payload");
+        balancer.send_to(&data, socket_addr).await.unwrap();
+
+        let mut buf = [0u8; 1500];
+        let (n, source) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"payload");
+        assert_eq!(source, "192.0.2.1:5353".parse().unwrap());
+
+        socket.send_to(b"response", source).await.unwrap();
+        let (n, _) = balancer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"response");
+    }
+
+    #[test]
+    fn test_trust() {
+        let mut trust = ProxyProtocolTrust::new();
+        assert!(trust.is_empty());
+        trust.add_trusted("10.0.0.0/24").unwrap();
+        trust.add_trusted("2001:db8::1").unwrap();
+        assert!(trust.add_trusted("10.0.0").is_err());
+
+        assert!(trust.is_trusted(&"10.0.0.2:1234".parse().unwrap()));
+        assert!(trust.is_trusted(&"[::ffff:10.0.0.2]:1234".parse().unwrap()));
+        assert!(trust.is_trusted(&"[2001:db8::1]:1234".parse().unwrap()));
+        assert!(!trust.is_trusted(&"10.0.1.2:1234".parse().unwrap()));
+    }
+}
@@ -0,0 +1,124 @@
+//! Stream keeping a copy of bytes read
+
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use pin_project::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Stream keeping a copy of bytes read, for replaying them to another destination
+///
+/// Recording stops after `limit` bytes, or when `stop_recording` is called.
+#[pin_project]
+pub struct RecordStream<S> {
+    #[pin]
+    stream: S,
+    record: Option<Vec<u8>>,
+    limit: usize,
+}
+
+impl<S> RecordStream<S> {
+    /// Record at most `limit` bytes read from `stream`, 0 disables recording
+    pub fn new(stream: S, limit: usize) -> Self {
+        Self {
+            stream,
+            record: (limit > 0).then(Vec::new),
+            limit,
+        }
+    }
+
+    /// Check if bytes read are still recorded
+    pub fn is_recording(&self) -> bool {
+        self.record.is_some()
+    }
+
+    /// Stop recording and free the copy
+    pub fn stop_recording(&mut self) {
+        self.record = None;
+    }
+
+    /// Get the inner stream reference
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Consumes the object and returns the inner stream with all bytes read so far
+    ///
+    /// Bytes are `None` if recording was stopped or exceeded the limit.
+    pub fn into_parts(self) -> (S, Option<Vec<u8>>) {
+        (self.stream, self.record)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for RecordStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let res = this.stream.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res
+            && let Some(record) = this.record
+        {
+            let read = &buf.filled()[filled..];
+            if record.len() + read.len() > *this.limit {
+                *this.record = None;
+            } else {
+                record.extend_from_slice(read);
+            }
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for RecordStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_record_stream() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut stream = RecordStream::new(&b"hello world"[..], 8);
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(stream.record.as_deref(), Some(&b"hello"[..]));
+
+            // Exceeding the limit
+            stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(stream.into_parts().1, None);
+
+            let stream = RecordStream::new(&b"hello"[..], 0);
+            assert_eq!(stream.into_parts().1, None);
+        });
+    }
+}
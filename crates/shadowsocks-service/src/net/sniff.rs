@@ -0,0 +1,713 @@
+//! Protocol sniffing
+//!
+//! Most clients resolve domain names locally and send IP addresses in the target address,
+//! so domain rules never match them. The domain name could usually be found in the first payload:
+//! SNI of TLS ClientHello, `Host` header of HTTP/1 requests, and SNI of QUIC Initial packets.
+
+use std::{fmt, net::IpAddr, time::Duration};
+
+/// Maximum bytes buffered for sniffing a stream, a TLS record could carry at most 16KiB
+pub const MAX_SNIFF_BUFFER_SIZE: usize = 5 + 16 * 1024;
+
+/// Default time waiting for the first payload
+pub const DEFAULT_SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Sniffing configuration
+#[derive(Debug, Clone, Copy)]
+pub struct SniffConfig {
+    /// Sniff TLS and HTTP of TCP connections
+    pub tcp: bool,
+    /// Sniff QUIC Initial packets of UDP associations
+    pub quic: bool,
+    /// Connect TCP connections to the sniffed domain name instead of the original target
+    pub override_destination: bool,
+    /// Time waiting for the first payload, protocols which servers speak first will be delayed this long
+    pub timeout: Duration,
+}
+
+impl Default for SniffConfig {
+    fn default() -> Self {
+        Self {
+            tcp: false,
+            quic: false,
+            override_destination: false,
+            timeout: DEFAULT_SNIFF_TIMEOUT,
+        }
+    }
+}
+
+/// Protocol which the domain name was sniffed from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SniffedProtocol {
+    Tls,
+    Http,
+    Quic,
+}
+
+impl fmt::Display for SniffedProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Tls => f.write_str("tls"),
+            Self::Http => f.write_str("http"),
+            Self::Quic => f.write_str("quic"),
+        }
+    }
+}
+
+/// Domain name sniffed from the payload
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SniffedDomain {
+    pub protocol: SniffedProtocol,
+    pub domain: String,
+}
+
+/// Result of sniffing a stream
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SniffResult {
+    /// Domain name is found
+    Found(SniffedDomain),
+    /// More data is required
+    Incomplete,
+    /// Not a supported protocol, or there is no domain name
+    NotFound,
+}
+
+/// Sniff the first payload of a stream
+pub fn sniff_stream(buf: &[u8]) -> SniffResult {
+    match buf.first() {
+        None => SniffResult::Incomplete,
+        Some(0x16) => sniff_tls(buf),
+        // Wait for the complete BitTorrent handshake, it has no domain name
+        Some(0x13) if buf.len() < BITTORRENT_HANDSHAKE.len() && BITTORRENT_HANDSHAKE.starts_with(buf) => {
+            SniffResult::Incomplete
+        }
+        Some(_) => sniff_http(buf),
+    }
+}
+
+const BITTORRENT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+
+/// Protocol ID of UDP tracker connect requests
+const UDP_TRACKER_PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Check if the first payload of a stream is a BitTorrent handshake, or an HTTP request to a tracker
+///
+/// Encrypted peer connections (MSE/PE) and trackers over HTTPS are not detected.
+pub fn is_bittorrent_stream(buf: &[u8]) -> bool {
+    if buf.starts_with(BITTORRENT_HANDSHAKE) {
+        return true;
+    }
+
+    // GET /announce?info_hash=...
+    let line_end = buf.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(buf.len());
+    let line = &buf[..line_end];
+    line.starts_with(b"GET /")
+        && contains(line, b"info_hash=")
+        && (contains(line, b"/announce") || contains(line, b"/scrape"))
+}
+
+/// Check if a domain name looks like a BitTorrent tracker, like `tracker.example.org`
+pub fn is_tracker_domain(domain: &str) -> bool {
+    let label = domain.split('.').next().unwrap_or_default();
+    label.starts_with("tracker") || label.starts_with("announce")
+}
+
+/// Check if a UDP packet is a DHT message, uTP connection request or UDP tracker request
+pub fn is_bittorrent_packet(data: &[u8]) -> bool {
+    is_dht_message(data) || is_utp_syn(data) || is_udp_tracker_connect(data)
+}
+
+/// Bencoded dictionary with the message type key `y`
+fn is_dht_message(data: &[u8]) -> bool {
+    data.starts_with(b"d1:") && data.ends_with(b"e") && contains(data, b"1:y1:")
+}
+
+/// uTP ST_SYN of version 1, which has no payload after extensions
+fn is_utp_syn(data: &[u8]) -> bool {
+    if data.len() < 20 || data[0] != 0x41 {
+        return false;
+    }
+
+    let mut extension = data[1];
+    let mut pos = 20;
+    while extension != 0 {
+        if pos + 2 > data.len() {
+            return false;
+        }
+        extension = data[pos];
+        pos += 2 + data[pos + 1] as usize;
+    }
+    pos == data.len()
+}
+
+fn is_udp_tracker_connect(data: &[u8]) -> bool {
+    data.len() >= 16 && data[..8] == UDP_TRACKER_PROTOCOL_ID.to_be_bytes() && data[8..12] == [0, 0, 0, 0]
+}
+
+/// Sniff SNI from a TLS ClientHello
+fn sniff_tls(buf: &[u8]) -> SniffResult {
+    // Handshake messages could be fragmented into multiple records
+    let mut handshake = Vec::new();
+    let mut records = buf;
+
+    loop {
+        if records.len() < 5 {
+            return SniffResult::Incomplete;
+        }
+        if records[0] != 0x16 || records[1] != 0x03 {
+            return SniffResult::NotFound;
+        }
+        let record_len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if records.len() < 5 + record_len {
+            if 5 + record_len > MAX_SNIFF_BUFFER_SIZE {
+                return SniffResult::NotFound;
+            }
+            return SniffResult::Incomplete;
+        }
+        handshake.extend_from_slice(&records[5..5 + record_len]);
+        records = &records[5 + record_len..];
+
+        match parse_client_hello(&handshake) {
+            SniffResult::Found(d) => {
+                return SniffResult::Found(SniffedDomain {
+                    protocol: SniffedProtocol::Tls,
+                    domain: d.domain,
+                });
+            }
+            SniffResult::NotFound => return SniffResult::NotFound,
+            SniffResult::Incomplete => continue,
+        }
+    }
+}
+
+/// Parse SNI from a ClientHello handshake message
+fn parse_client_hello(msg: &[u8]) -> SniffResult {
+    if msg.len() < 4 {
+        return SniffResult::Incomplete;
+    }
+    if msg[0] != 0x01 {
+        return SniffResult::NotFound;
+    }
+    let len = u32::from_be_bytes([0, msg[1], msg[2], msg[3]]) as usize;
+    if msg.len() < 4 + len {
+        return SniffResult::Incomplete;
+    }
+
+    match parse_client_hello_body(&msg[4..4 + len]) {
+        Some(domain) => SniffResult::Found(SniffedDomain {
+            protocol: SniffedProtocol::Tls,
+            domain,
+        }),
+        None => SniffResult::NotFound,
+    }
+}
+
+fn parse_client_hello_body(body: &[u8]) -> Option<String> {
+    let mut r = Reader::new(body);
+    // legacy_version, random
+    r.skip(2 + 32)?;
+    // legacy_session_id
+    let n = r.u8()? as usize;
+    r.skip(n)?;
+    // cipher_suites
+    let n = r.u16()? as usize;
+    r.skip(n)?;
+    // legacy_compression_methods
+    let n = r.u8()? as usize;
+    r.skip(n)?;
+
+    let n = r.u16()? as usize;
+    let mut extensions = Reader::new(r.take(n)?);
+    while !extensions.is_empty() {
+        let ext_type = extensions.u16()?;
+        let n = extensions.u16()? as usize;
+        let ext = extensions.take(n)?;
+        if ext_type != 0x0000 {
+            continue;
+        }
+
+        // server_name
+        let mut ext = Reader::new(ext);
+        let n = ext.u16()? as usize;
+        let mut names = Reader::new(ext.take(n)?);
+        while !names.is_empty() {
+            let name_type = names.u8()?;
+            let n = names.u16()? as usize;
+            let name = names.take(n)?;
+            if name_type == 0x00 {
+                return normalize_domain(name);
+            }
+        }
+    }
+
+    None
+}
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ", b"POST ", b"PUT ", b"HEAD ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE ",
+];
+
+/// Sniff `Host` header from an HTTP/1 request
+fn sniff_http(buf: &[u8]) -> SniffResult {
+    let is_http = HTTP_METHODS.iter().any(|m| {
+        let n = buf.len().min(m.len());
+        buf[..n] == m[..n]
+    });
+    if !is_http {
+        return SniffResult::NotFound;
+    }
+
+    let head_end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos,
+        None => return SniffResult::Incomplete,
+    };
+
+    let mut lines = buf[..head_end].split(|&b| b == b'\n').skip(1);
+    let host = lines.find_map(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = line.iter().position(|&b| b == b':')?;
+        let (name, value) = line.split_at(colon);
+        if !name.eq_ignore_ascii_case(b"host") {
+            return None;
+        }
+        Some(value[1..].trim_ascii())
+    });
+
+    let host = match host {
+        Some(host) => host,
+        None => return SniffResult::NotFound,
+    };
+
+    // Strip port, IPv6 literals in brackets are not domain names
+    if host.first() == Some(&b'[') {
+        return SniffResult::NotFound;
+    }
+    let host = match host.iter().rposition(|&b| b == b':') {
+        Some(pos) => &host[..pos],
+        None => host,
+    };
+
+    match normalize_domain(host) {
+        Some(domain) => SniffResult::Found(SniffedDomain {
+            protocol: SniffedProtocol::Http,
+            domain,
+        }),
+        None => SniffResult::NotFound,
+    }
+}
+
+/// Validate and lowercase a domain name, IP addresses are rejected
+fn normalize_domain(name: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(name).ok()?;
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() || name.len() > 253 {
+        return None;
+    }
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+    {
+        return None;
+    }
+    if name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some(name.to_ascii_lowercase())
+}
+
+/// Sniff SNI from a QUIC Initial packet
+///
+/// Only the first packet of a datagram is inspected, and the ClientHello must not be split
+/// into multiple packets.
+#[cfg(feature = "sniff-quic")]
+pub fn sniff_quic(packet: &[u8]) -> Option<SniffedDomain> {
+    let crypto = quic::decrypt_initial_crypto(packet)?;
+    match parse_client_hello(&crypto) {
+        SniffResult::Found(d) => Some(SniffedDomain {
+            protocol: SniffedProtocol::Quic,
+            domain: d.domain,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "sniff-quic")]
+mod quic {
+    use ring::{aead, hkdf};
+
+    use super::Reader;
+
+    const VERSION_1: u32 = 0x0000_0001;
+    const VERSION_2: u32 = 0x6b33_43cf;
+
+    const INITIAL_SALT_V1: [u8; 20] = [
+        0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad, 0xcc, 0xbb,
+        0x7f, 0x0a,
+    ];
+    const INITIAL_SALT_V2: [u8; 20] = [
+        0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26, 0x9d, 0xcb, 0xf9, 0xbd,
+        0x2e, 0xd9,
+    ];
+
+    struct OkmLen(usize);
+
+    impl hkdf::KeyType for OkmLen {
+        fn len(&self) -> usize {
+            self.0
+        }
+    }
+
+    /// HKDF-Expand-Label of TLS 1.3 with empty context
+    fn expand_label(prk: &hkdf::Prk, label: &[u8], out: &mut [u8]) -> Option<()> {
+        let out_len = (out.len() as u16).to_be_bytes();
+        let label_len = [(b"tls13 ".len() + label.len()) as u8];
+        let info: [&[u8]; 5] = [&out_len, &label_len, b"tls13 ", label, &[0]];
+        prk.expand(&info, OkmLen(out.len())).ok()?.fill(out).ok()
+    }
+
+    fn read_varint(r: &mut Reader<'_>) -> Option<u64> {
+        let first = r.u8()?;
+        let len = 1usize << (first >> 6);
+        let mut v = (first & 0x3f) as u64;
+        for _ in 1..len {
+            v = (v << 8) | r.u8()? as u64;
+        }
+        Some(v)
+    }
+
+    /// Decrypt the first Initial packet, returns CRYPTO data from offset 0
+    pub(super) fn decrypt_initial_crypto(packet: &[u8]) -> Option<Vec<u8>> {
+        let mut r = Reader::new(packet);
+        let first = r.u8()?;
+        // Long header with fixed bit
+        if first & 0xc0 != 0xc0 {
+            return None;
+        }
+        let version = r.u32()?;
+        let (salt, key_label, iv_label, hp_label, initial_type) = match version {
+            VERSION_1 => (&INITIAL_SALT_V1, &b"quic key"[..], &b"quic iv"[..], &b"quic hp"[..], 0),
+            VERSION_2 => (&INITIAL_SALT_V2, &b"quicv2 key"[..], &b"quicv2 iv"[..], &b"quicv2 hp"[..], 1),
+            _ => return None,
+        };
+        if (first >> 4) & 0x03 != initial_type {
+            return None;
+        }
+
+        let n = r.u8()? as usize;
+        let dcid = r.take(n)?;
+        let n = r.u8()? as usize;
+        r.skip(n)?;
+        let n = read_varint(&mut r)? as usize;
+        r.skip(n)?;
+        let length = read_varint(&mut r)? as usize;
+
+        let pn_offset = packet.len() - r.remaining();
+        if length < 20 || r.remaining() < length {
+            return None;
+        }
+
+        // Initial keys of the client
+        let initial_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(dcid);
+        let mut client_secret = [0u8; 32];
+        expand_label(&initial_secret, b"client in", &mut client_secret)?;
+        let client_secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &client_secret);
+        let mut key = [0u8; 16];
+        let mut iv = [0u8; 12];
+        let mut hp = [0u8; 16];
+        expand_label(&client_secret, key_label, &mut key)?;
+        expand_label(&client_secret, iv_label, &mut iv)?;
+        expand_label(&client_secret, hp_label, &mut hp)?;
+
+        // Remove header protection, sample starts 4 bytes after the packet number offset
+        let hp = aead::quic::HeaderProtectionKey::new(&aead::quic::AES_128, &hp).ok()?;
+        let mask = hp.new_mask(&packet[pn_offset + 4..pn_offset + 20]).ok()?;
+
+        let mut header = packet[..pn_offset].to_vec();
+        header[0] ^= mask[0] & 0x0f;
+        let pn_len = (header[0] & 0x03) as usize + 1;
+        let mut pn = 0u64;
+        for i in 0..pn_len {
+            let b = packet[pn_offset + i] ^ mask[1 + i];
+            header.push(b);
+            pn = (pn << 8) | b as u64;
+        }
+
+        let mut nonce = iv;
+        for (i, b) in pn.to_be_bytes().iter().enumerate() {
+            nonce[4 + i] ^= b;
+        }
+
+        let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, &key).ok()?);
+        let mut payload = packet[pn_offset + pn_len..pn_offset + length].to_vec();
+        let plaintext = key
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(&header),
+                &mut payload,
+            )
+            .ok()?;
+
+        // Reassemble CRYPTO frames, which could be out of order
+        let mut fragments = Vec::new();
+        let mut frames = Reader::new(plaintext);
+        while !frames.is_empty() {
+            match read_varint(&mut frames)? {
+                // PADDING, PING
+                0x00 | 0x01 => {}
+                // ACK
+                frame_type @ (0x02 | 0x03) => {
+                    read_varint(&mut frames)?;
+                    read_varint(&mut frames)?;
+                    let ranges = read_varint(&mut frames)?;
+                    read_varint(&mut frames)?;
+                    for _ in 0..ranges {
+                        read_varint(&mut frames)?;
+                        read_varint(&mut frames)?;
+                    }
+                    if frame_type == 0x03 {
+                        for _ in 0..3 {
+                            read_varint(&mut frames)?;
+                        }
+                    }
+                }
+                // CRYPTO
+                0x06 => {
+                    let offset = read_varint(&mut frames)? as usize;
+                    let n = read_varint(&mut frames)? as usize;
+                    fragments.push((offset, frames.take(n)?));
+                }
+                _ => break,
+            }
+        }
+
+        fragments.sort_by_key(|(offset, _)| *offset);
+        let mut crypto = Vec::new();
+        for (offset, data) in fragments {
+            if offset > crypto.len() {
+                break;
+            }
+            let skip = crypto.len() - offset;
+            if skip < data.len() {
+                crypto.extend_from_slice(&data[skip..]);
+            }
+        }
+
+        if crypto.is_empty() { None } else { Some(crypto) }
+    }
+}
+
+/// Bounds checked reader of big-endian integers
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    #[cfg(feature = "sniff-quic")]
+    fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        self.take(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    #[cfg(feature = "sniff-quic")]
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut server_name = Vec::new();
+        server_name.extend_from_slice(&((sni.len() + 3) as u16).to_be_bytes());
+        server_name.push(0);
+        server_name.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(sni.as_bytes());
+
+        let mut extensions = Vec::new();
+        // supported_versions
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut msg = vec![0x01];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(&body);
+        msg
+    }
+
+    fn tls_record(payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let record = tls_record(&client_hello("www.Example.com"));
+        let expected = SniffResult::Found(SniffedDomain {
+            protocol: SniffedProtocol::Tls,
+            domain: "www.example.com".to_owned(),
+        });
+        assert_eq!(sniff_stream(&record), expected);
+        assert_eq!(sniff_stream(&record[..record.len() - 1]), SniffResult::Incomplete);
+
+        // ClientHello fragmented into two records
+        let hello = client_hello("www.example.com");
+        let mut records = tls_record(&hello[..10]);
+        records.extend_from_slice(&tls_record(&hello[10..]));
+        assert_eq!(sniff_stream(&records), expected);
+
+        assert_eq!(sniff_stream(&tls_record(&[0x02, 0x00, 0x00, 0x00])), SniffResult::NotFound);
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        let req = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nhost: Example.com:8080\r\n\r\n";
+        assert_eq!(
+            sniff_stream(req),
+            SniffResult::Found(SniffedDomain {
+                protocol: SniffedProtocol::Http,
+                domain: "example.com".to_owned(),
+            })
+        );
+        assert_eq!(sniff_stream(&req[..20]), SniffResult::Incomplete);
+        assert_eq!(sniff_stream(b"GE"), SniffResult::Incomplete);
+        assert_eq!(sniff_stream(b"GET / HTTP/1.1\r\nHost: 1.2.3.4\r\n\r\n"), SniffResult::NotFound);
+        assert_eq!(sniff_stream(b"SSH-2.0-OpenSSH_9.6\r\n"), SniffResult::NotFound);
+    }
+
+    #[test]
+    fn test_bittorrent() {
+        let mut handshake = BITTORRENT_HANDSHAKE.to_vec();
+        handshake.extend_from_slice(&[0u8; 48]);
+        assert_eq!(sniff_stream(&handshake[..10]), SniffResult::Incomplete);
+        assert_eq!(sniff_stream(&handshake), SniffResult::NotFound);
+        assert!(is_bittorrent_stream(&handshake));
+
+        assert!(is_bittorrent_stream(
+            b"GET /announce?info_hash=%12%34&peer_id=-qB4500-&port=6881 HTTP/1.1\r\nHost: tracker.example.org\r\n\r\n"
+        ));
+        assert!(!is_bittorrent_stream(b"GET /index.html HTTP/1.1\r\nHost: example.org\r\n\r\n"));
+        assert!(is_tracker_domain("tracker.opentrackr.org"));
+        assert!(!is_tracker_domain("www.example.org"));
+
+        assert!(is_bittorrent_packet(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        ));
+
+        let mut syn = vec![0x41, 0x00];
+        syn.extend_from_slice(&[0u8; 18]);
+        assert!(is_bittorrent_packet(&syn));
+        syn.push(0);
+        assert!(!is_bittorrent_packet(&syn));
+
+        let mut connect = UDP_TRACKER_PROTOCOL_ID.to_be_bytes().to_vec();
+        connect.extend_from_slice(&[0, 0, 0, 0, 1, 2, 3, 4]);
+        assert!(is_bittorrent_packet(&connect));
+
+        // DNS query
+        assert!(!is_bittorrent_packet(b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01"));
+    }
+
+    #[cfg(feature = "sniff-quic")]
+    #[test]
+    fn test_sniff_quic() {
+        // Client Initial packet from RFC 9001 Appendix A.2
+        const PACKET: &str = "c000000001088394c8f03e5157080000449e7b9aec34d1b1c98dd7689fb8ec11\
+            d242b123dc9bd8bab936b47d92ec356c0bab7df5976d27cd449f63300099f399\
+            1c260ec4c60d17b31f8429157bb35a1282a643a8d2262cad67500cadb8e7378c\
+            8eb7539ec4d4905fed1bee1fc8aafba17c750e2c7ace01e6005f80fcb7df6212\
+            30c83711b39343fa028cea7f7fb5ff89eac2308249a02252155e2347b63d58c5\
+            457afd84d05dfffdb20392844ae812154682e9cf012f9021a6f0be17ddd0c208\
+            4dce25ff9b06cde535d0f920a2db1bf362c23e596d11a4f5a6cf3948838a3aec\
+            4e15daf8500a6ef69ec4e3feb6b1d98e610ac8b7ec3faf6ad760b7bad1db4ba3\
+            485e8a94dc250ae3fdb41ed15fb6a8e5eba0fc3dd60bc8e30c5c4287e53805db\
+            059ae0648db2f64264ed5e39be2e20d82df566da8dd5998ccabdae053060ae6c\
+            7b4378e846d29f37ed7b4ea9ec5d82e7961b7f25a9323851f681d582363aa5f8\
+            9937f5a67258bf63ad6f1a0b1d96dbd4faddfcefc5266ba6611722395c906556\
+            be52afe3f565636ad1b17d508b73d8743eeb524be22b3dcbc2c7468d54119c74\
+            68449a13d8e3b95811a198f3491de3e7fe942b330407abf82a4ed7c1b311663a\
+            c69890f4157015853d91e923037c227a33cdd5ec281ca3f79c44546b9d90ca00\
+            f064c99e3dd97911d39fe9c5d0b23a229a234cb36186c4819e8b9c5927726632\
+            291d6a418211cc2962e20fe47feb3edf330f2c603a9d48c0fcb5699dbfe58964\
+            25c5bac4aee82e57a85aaf4e2513e4f05796b07ba2ee47d80506f8d2c25e50fd\
+            14de71e6c418559302f939b0e1abd576f279c4b2e0feb85c1f28ff18f58891ff\
+            ef132eef2fa09346aee33c28eb130ff28f5b766953334113211996d20011a198\
+            e3fc433f9f2541010ae17c1bf202580f6047472fb36857fe843b19f5984009dd\
+            c324044e847a4f4a0ab34f719595de37252d6235365e9b84392b061085349d73\
+            203a4a13e96f5432ec0fd4a1ee65accdd5e3904df54c1da510b0ff20dcc0c77f\
+            cb2c0e0eb605cb0504db87632cf3d8b4dae6e705769d1de354270123cb11450e\
+            fc60ac47683d7b8d0f811365565fd98c4c8eb936bcab8d069fc33bd801b03ade\
+            a2e1fbc5aa463d08ca19896d2bf59a071b851e6c239052172f296bfb5e724047\
+            90a2181014f3b94a4e97d117b438130368cc39dbb2d198065ae3986547926cd2\
+            162f40a29f0c3c8745c0f50fba3852e566d44575c29d39a03f0cda721984b6f4\
+            40591f355e12d439ff150aab7613499dbd49adabc8676eef023b15b65bfc5ca0\
+            6948109f23f350db82123535eb8a7433bdabcb909271a6ecbcb58b936a88cd4e\
+            8f2e6ff5800175f113253d8fa9ca8885c2f552e657dc603f252e1a8e308f76f0\
+            be79e2fb8f5d5fbbe2e30ecadd220723c8c0aea8078cdfcb3868263ff8f09400\
+            54da48781893a7e49ad5aff4af300cd804a6b6279ab3ff3afb64491c85194aab\
+            760d58a606654f9f4400e8b38591356fbf6425aca26dc85244259ff2b19c41b9\
+            f96f3ca9ec1dde434da7d2d392b905ddf3d1f9af93d1af5950bd493f5aa731b4\
+            056df31bd267b6b90a079831aaf579be0a39013137aac6d404f518cfd4684064\
+            7e78bfe706ca4cf5e9c5453e9f7cfd2b8b4c8d169a44e55c88d4a9a7f9474241\
+            e221af44860018ab0856972e194cd934";
+
+        let packet = (0..PACKET.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&PACKET[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            sniff_quic(&packet),
+            Some(SniffedDomain {
+                protocol: SniffedProtocol::Quic,
+                domain: "example.com".to_owned(),
+            })
+        );
+        assert_eq!(sniff_quic(&packet[..100]), None);
+    }
+}
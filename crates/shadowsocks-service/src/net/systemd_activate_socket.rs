@@ -0,0 +1,78 @@
+//! systemd socket activation
+//!
+//! <https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html>
+
+use std::{
+    env, io,
+    net::{TcpListener, UdpSocket},
+    os::unix::io::{FromRawFd, RawFd},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{debug, warn};
+use socket2::{Socket, Type};
+
+/// Passed file descriptors start from 3, after stdin, stdout and stderr
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// File descriptors are owned by the first caller
+static SOCKETS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Sockets passed by systemd
+#[derive(Debug, Default)]
+pub struct SystemdActivatedSockets {
+    /// The first stream socket
+    pub tcp_listener: Option<TcpListener>,
+    /// The first datagram socket
+    pub udp_socket: Option<UdpSocket>,
+}
+
+impl SystemdActivatedSockets {
+    /// Check if no socket was passed
+    pub fn is_empty(&self) -> bool {
+        self.tcp_listener.is_none() && self.udp_socket.is_none()
+    }
+}
+
+/// Take sockets passed by systemd, empty if the process isn't socket activated
+///
+/// Sockets other than the first stream and datagram sockets are closed.
+pub fn take_systemd_activated_sockets() -> io::Result<SystemdActivatedSockets> {
+    let mut sockets = SystemdActivatedSockets::default();
+
+    // Passed to the main process only, not to its children
+    let Ok(pid) = env::var("LISTEN_PID") else {
+        return Ok(sockets);
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        debug!("systemd sockets are passed to process {}, ignored", pid);
+        return Ok(sockets);
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid LISTEN_FDS"))?;
+
+    if SOCKETS_TAKEN.swap(true, Ordering::SeqCst) {
+        return Err(io::Error::other("systemd sockets are already taken"));
+    }
+
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_cloexec(true)?;
+
+        match socket.r#type()? {
+            Type::STREAM if sockets.tcp_listener.is_none() => {
+                debug!("created TCP listener from systemd socket {}", fd);
+                sockets.tcp_listener = Some(socket.into());
+            }
+            Type::DGRAM if sockets.udp_socket.is_none() => {
+                debug!("created UDP socket from systemd socket {}", fd);
+                sockets.udp_socket = Some(socket.into());
+            }
+            ty => warn!("closing systemd socket {} of type {:?}, unused", fd, ty),
+        }
+    }
+
+    Ok(sockets)
+}
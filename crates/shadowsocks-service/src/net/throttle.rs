@@ -0,0 +1,194 @@
+//! Bandwidth throttling
+
+use std::{
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket rate limiter, allows bursts of up to one second of traffic
+pub struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a limiter of `rate` bytes per second
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Limit of bytes per second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        bucket.last_refill = now;
+    }
+
+    /// Take `n` bytes if available, returns false if they should be dropped
+    pub fn try_acquire(&self, n: usize) -> bool {
+        let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+        self.refill(&mut bucket);
+        if bucket.tokens < n as f64 {
+            return false;
+        }
+        bucket.tokens -= n as f64;
+        true
+    }
+
+    /// Take `n` bytes which were already transferred
+    ///
+    /// Returns how long the transfer should be paused if the limit is exceeded.
+    pub fn acquire(&self, n: usize) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().expect("rate limiter poisoned");
+        self.refill(&mut bucket);
+        bucket.tokens -= n as f64;
+        if bucket.tokens >= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(-bucket.tokens / self.rate as f64))
+    }
+}
+
+/// Stream with reading and writing throttled by `RateLimiter`s
+#[pin_project]
+pub struct ThrottledStream<S> {
+    #[pin]
+    stream: S,
+    limiters: Vec<Arc<RateLimiter>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    /// Throttle `stream` by all of `limiters`
+    pub fn new(stream: S, limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self {
+            stream,
+            limiters,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Get the inner stream reference
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+fn poll_delay(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = delay {
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        *delay = None;
+    }
+    Poll::Ready(())
+}
+
+fn acquire_all(limiters: &[Arc<RateLimiter>], n: usize) -> Option<Pin<Box<Sleep>>> {
+    let wait = limiters.iter().filter_map(|l| l.acquire(n)).max()?;
+    Some(Box::pin(time::sleep(wait)))
+}
+
+impl<S: AsyncRead> AsyncRead for ThrottledStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if poll_delay(this.read_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let filled = buf.filled().len();
+        let res = this.stream.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            *this.read_delay = acquire_all(this.limiters, buf.filled().len() - filled);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if poll_delay(this.write_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let res = this.stream.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            *this.write_delay = acquire_all(this.limiters, n);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if poll_delay(this.write_delay, cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let res = this.stream.poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = res {
+            *this.write_delay = acquire_all(this.limiters, n);
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(1000);
+        assert!(limiter.try_acquire(600));
+        assert!(!limiter.try_acquire(600));
+
+        let wait = limiter.acquire(900).expect("limit should be exceeded");
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,356 @@
+//! In-process transports of the server, replacing SIP003 plugin subprocesses
+//!
+//! Transports wrap accepted TCP streams before shadowsocks decryption, so users are still found by their keys.
+
+use std::{
+    fmt::{self, Debug},
+    fs::File,
+    io::{self, BufReader, IoSlice},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    server::TlsStream,
+};
+
+pub use self::{
+    obfs_http::ObfsHttpStream,
+    shadow_tls::{ShadowTlsConfig, ShadowTlsStream},
+    websocket::WebSocketStream,
+};
+
+mod obfs_http;
+mod shadow_tls;
+mod websocket;
+
+/// Maximum size of HTTP request headers sent by clients
+const MAX_REQUEST_HEADER_SIZE: usize = 8192;
+
+/// Transport of the server
+#[derive(Clone)]
+pub enum ServerTransport {
+    /// simple-obfs with `obfs=http`
+    ObfsHttp,
+    /// v2ray-plugin WebSocket, clients should disable multiplexing with `mux=0`
+    WebSocket(WebSocketConfig),
+    /// ShadowTLS v3, hiding behind the TLS handshake of another server
+    ShadowTls(ShadowTlsConfig),
+}
+
+/// WebSocket transport settings
+#[derive(Clone)]
+pub struct WebSocketConfig {
+    /// Path of WebSocket requests, others are rejected
+    pub path: String,
+    /// Serve WebSocket over TLS, like v2ray-plugin with `tls`
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Debug for ServerTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::ObfsHttp => f.write_str("http"),
+            Self::WebSocket(ref config) if config.tls.is_some() => write!(f, "websocket+tls (path {})", config.path),
+            Self::WebSocket(ref config) => write!(f, "websocket (path {})", config.path),
+            Self::ShadowTls(ref config) => write!(f, "shadowtls v3 (handshake {})", config.handshake_server),
+        }
+    }
+}
+
+impl ServerTransport {
+    /// Check if the transport encrypts streams with TLS, bytes read are useless for fallback backends
+    pub fn is_tls(&self) -> bool {
+        matches!(
+            *self,
+            Self::WebSocket(WebSocketConfig { tls: Some(..), .. }) | Self::ShadowTls(..)
+        )
+    }
+
+    /// Complete the transport handshake of an accepted stream in `timeout`
+    ///
+    /// Returns `None` if the client was served by the transport itself, like ShadowTLS relaying unauthenticated
+    /// clients to the handshake server. On failures, the stream is returned for replaying bytes read to a fallback,
+    /// unless TLS was established.
+    pub async fn accept<S>(
+        &self,
+        mut stream: S,
+        timeout: Option<Duration>,
+    ) -> Result<Option<TransportStream<S>>, (io::Error, Option<S>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let deadline = timeout.map(|d| time::Instant::now() + d);
+
+        match *self {
+            Self::ObfsHttp => match with_deadline(deadline, obfs_http::accept(&mut stream)).await {
+                Ok(payload) => Ok(Some(TransportStream::ObfsHttp(ObfsHttpStream::new(stream, payload)))),
+                Err(err) => Err((err, Some(stream))),
+            },
+            Self::ShadowTls(ref config) => {
+                let mut buf = BytesMut::new();
+                let client_hello = match with_deadline(deadline, shadow_tls::read_client_hello(&mut stream, &mut buf)).await
+                {
+                    Ok(r) => r,
+                    Err(err) => return Err((err, Some(stream))),
+                };
+
+                // Unauthenticated clients are served by the handshake server until idle, without deadlines
+                match shadow_tls::handshake(&mut stream, client_hello, buf, config, deadline).await {
+                    Ok(shadow_tls::Handshake::Authenticated(session)) => {
+                        Ok(Some(TransportStream::ShadowTls(Box::new(ShadowTlsStream::new(stream, *session)))))
+                    }
+                    Ok(shadow_tls::Handshake::Relayed) => Ok(None),
+                    Err(err) => Err((err, None)),
+                }
+            }
+            Self::WebSocket(ref config) => match config.tls {
+                None => match with_deadline(deadline, websocket::accept(&mut stream, &config.path)).await {
+                    Ok(payload) => Ok(Some(TransportStream::WebSocket(WebSocketStream::new(stream, payload)))),
+                    Err(err) => Err((err, Some(stream))),
+                },
+                Some(ref tls) => {
+                    let acceptor = TlsAcceptor::from(tls.clone());
+                    let mut stream = match with_deadline(deadline, async {
+                        Ok(acceptor.accept(stream).into_fallible().await)
+                    })
+                    .await
+                    {
+                        Ok(Ok(s)) => s,
+                        Ok(Err((err, stream))) => return Err((err, Some(stream))),
+                        Err(err) => return Err((err, None)),
+                    };
+
+                    match with_deadline(deadline, websocket::accept(&mut stream, &config.path)).await {
+                        Ok(payload) => Ok(Some(TransportStream::TlsWebSocket(Box::new(WebSocketStream::new(
+                            stream, payload,
+                        ))))),
+                        Err(err) => Err((err, None)),
+                    }
+                }
+            },
+        }
+    }
+}
+
+async fn with_deadline<F, R>(deadline: Option<time::Instant>, f: F) -> io::Result<R>
+where
+    F: Future<Output = io::Result<R>>,
+{
+    match deadline {
+        None => f.await,
+        Some(d) => match time::timeout_at(d, f).await {
+            Ok(o) => o,
+            Err(..) => Err(io::ErrorKind::TimedOut.into()),
+        },
+    }
+}
+
+/// Load TLS settings of transports from PEM files of the certificate chain and the private key
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    let certs = CertificateDer::pem_reader_iter(BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", cert_path.display(), err)))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", cert_path.display()),
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_reader(BufReader::new(File::open(key_path)?))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", key_path.display(), err)))?;
+
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// HTTP request of a transport handshake
+struct HttpRequest {
+    method: String,
+    path: String,
+    upgrade: Option<String>,
+    websocket_key: Option<String>,
+}
+
+impl HttpRequest {
+    /// Read an HTTP request, returning bytes following the header
+    async fn read_from<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(Self, Bytes)> {
+        let mut buf = BytesMut::with_capacity(1024);
+
+        loop {
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(&buf) {
+                Ok(httparse::Status::Complete(header_len)) => {
+                    let header_value = |name: &str| {
+                        req.headers
+                            .iter()
+                            .find(|h| h.name.eq_ignore_ascii_case(name))
+                            .map(|h| String::from_utf8_lossy(h.value).into_owned())
+                    };
+                    let request = Self {
+                        method: req.method.unwrap_or_default().to_owned(),
+                        path: req.path.unwrap_or_default().to_owned(),
+                        upgrade: header_value("Upgrade"),
+                        websocket_key: header_value("Sec-WebSocket-Key"),
+                    };
+                    let payload = buf.split_off(header_len).freeze();
+                    return Ok((request, payload));
+                }
+                Ok(httparse::Status::Partial) if buf.len() < MAX_REQUEST_HEADER_SIZE => {}
+                Ok(httparse::Status::Partial) => return Err(io::Error::other("HTTP request header too large")),
+                Err(err) => return Err(io::Error::other(format!("invalid HTTP request, {err}"))),
+            }
+        }
+    }
+
+    /// Check if the request asks for upgrading to WebSocket
+    fn is_websocket_upgrade(&self) -> bool {
+        self.upgrade.as_deref().is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// Path without the query
+    fn path_only(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+}
+
+/// `Sec-WebSocket-Accept` of the `Sec-WebSocket-Key` sent by the client
+fn websocket_accept_key(key: &str) -> String {
+    use base64::Engine;
+
+    const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key.as_bytes());
+    ctx.update(WEBSOCKET_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(ctx.finish())
+}
+
+/// Write the `101 Switching Protocols` response of an upgrade request
+async fn write_upgrade_response<S: AsyncWrite + Unpin>(stream: &mut S, request: &HttpRequest) -> io::Result<()> {
+    let accept_key = websocket_accept_key(request.websocket_key.as_deref().unwrap_or_default());
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nServer: nginx\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept_key}\r\n\r\n"
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Stream wrapped by a server transport
+pub enum TransportStream<S> {
+    /// No transport
+    Plain(S),
+    /// simple-obfs HTTP
+    ObfsHttp(ObfsHttpStream<S>),
+    /// WebSocket
+    WebSocket(WebSocketStream<S>),
+    /// WebSocket over TLS
+    TlsWebSocket(Box<WebSocketStream<TlsStream<S>>>),
+    /// ShadowTLS
+    ShadowTls(Box<ShadowTlsStream<S>>),
+}
+
+impl<S> TransportStream<S> {
+    /// Get the accepted stream reference
+    pub fn get_ref(&self) -> &S {
+        match *self {
+            Self::Plain(ref s) => s,
+            Self::ObfsHttp(ref s) => s.get_ref(),
+            Self::WebSocket(ref s) => s.get_ref(),
+            Self::TlsWebSocket(ref s) => s.get_ref().get_ref().0,
+            Self::ShadowTls(ref s) => s.get_ref(),
+        }
+    }
+
+    /// Get the accepted stream mutable reference
+    pub fn get_mut(&mut self) -> &mut S {
+        match *self {
+            Self::Plain(ref mut s) => s,
+            Self::ObfsHttp(ref mut s) => s.get_mut(),
+            Self::WebSocket(ref mut s) => s.get_mut(),
+            Self::TlsWebSocket(ref mut s) => s.get_mut().get_mut().0,
+            Self::ShadowTls(ref mut s) => s.get_mut(),
+        }
+    }
+
+    /// Consumes the object and returns the accepted stream, buffered data is dropped
+    pub fn into_inner(self) -> S {
+        match self {
+            Self::Plain(s) => s,
+            Self::ObfsHttp(s) => s.into_inner(),
+            Self::WebSocket(s) => s.into_inner(),
+            Self::TlsWebSocket(s) => s.into_inner().into_inner().0,
+            Self::ShadowTls(s) => (*s).into_inner(),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:expr, $s:ident => $e:expr) => {
+        match $self.get_mut() {
+            TransportStream::Plain($s) => $e,
+            TransportStream::ObfsHttp($s) => $e,
+            TransportStream::WebSocket($s) => $e,
+            TransportStream::ShadowTls($s) => {
+                let $s = &mut **$s;
+                $e
+            }
+            TransportStream::TlsWebSocket($s) => {
+                let $s = &mut **$s;
+                $e
+            }
+        }
+    };
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TransportStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_read(cx, buf))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TransportStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        dispatch!(self, s => Pin::new(s).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_shutdown(cx))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        dispatch!(self, s => Pin::new(s).poll_write_vectored(cx, bufs))
+    }
+}
@@ -0,0 +1,94 @@
+//! simple-obfs HTTP transport
+//!
+//! Clients send a fake WebSocket upgrade request carrying the first payload as its body,
+//! then the stream continues without framing in both directions.
+
+use std::{
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{HttpRequest, write_upgrade_response};
+
+/// Read the request of a simple-obfs client and respond, returning the first payload
+pub(super) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> io::Result<Bytes> {
+    let (request, payload) = HttpRequest::read_from(stream).await?;
+    if !request.is_websocket_upgrade() {
+        return Err(io::Error::other(format!(
+            "{} {} is not an obfs request",
+            request.method, request.path
+        )));
+    }
+    write_upgrade_response(stream, &request).await?;
+    Ok(payload)
+}
+
+/// Stream of a simple-obfs HTTP client, after the handshake
+pub struct ObfsHttpStream<S> {
+    stream: S,
+    payload: Bytes,
+}
+
+impl<S> ObfsHttpStream<S> {
+    /// Create a stream reading `payload` in the request body first
+    pub fn new(stream: S, payload: Bytes) -> Self {
+        Self { stream, payload }
+    }
+
+    /// Get the inner stream reference
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get the inner stream mutable reference
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the object and returns the inner stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ObfsHttpStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.payload.has_remaining() {
+            let n = self.payload.len().min(buf.remaining());
+            buf.put_slice(&self.payload[..n]);
+            self.payload.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ObfsHttpStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
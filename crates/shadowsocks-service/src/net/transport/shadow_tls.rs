@@ -0,0 +1,682 @@
+//! ShadowTLS v3 transport
+//!
+//! The TLS handshake is relayed to a real TLS server. Clients are authenticated by the HMAC in the session ID of
+//! ClientHello, then by HMACs prepended to application data, keyed by the password and the ServerRandom.
+//! Unauthenticated clients are relayed to the handshake server entirely.
+//!
+//! <https://github.com/ihciah/shadow-tls/blob/master/docs/protocol-v3-en.md>
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::trace;
+use ring::{digest, hmac};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+    time,
+};
+
+use crate::net::utils::copy_bidirectional_idle;
+
+use super::with_deadline;
+
+const TLS_HEADER_SIZE: usize = 5;
+const HMAC_SIZE: usize = 4;
+const TLS_HMAC_HEADER_SIZE: usize = TLS_HEADER_SIZE + HMAC_SIZE;
+const TLS_RANDOM_SIZE: usize = 32;
+const TLS_SESSION_ID_SIZE: usize = 32;
+/// Maximum payload of TLS records
+const MAX_TLS_RECORD_SIZE: usize = 16 * 1024 + 2048;
+/// Maximum payload of application data records sent by the server
+const MAX_DATA_SIZE: usize = 16 * 1024;
+
+/// Unauthenticated clients relayed to the handshake server are closed after being idle this long
+const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_TYPE_SERVER_HELLO: u8 = 0x02;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 0x002b;
+const TLS13_VERSION: u16 = 0x0304;
+
+/// Offset of the session ID in ClientHello and ServerHello records, after the type, length, version and random
+const SESSION_ID_LEN_OFFSET: usize = TLS_HEADER_SIZE + 1 + 3 + 2 + TLS_RANDOM_SIZE;
+
+/// ShadowTLS v3 transport settings
+#[derive(Clone)]
+pub struct ShadowTlsConfig {
+    /// Password shared with clients
+    pub password: String,
+    /// TLS server handshaking with clients, `host:port` of the cover domain
+    pub handshake_server: String,
+}
+
+/// HMAC-SHA1 truncated to 4 bytes, chained over records
+#[derive(Clone)]
+struct Hmac(hmac::Context);
+
+impl Hmac {
+    fn new(password: &str, init: &[&[u8]]) -> Self {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        let mut ctx = hmac::Context::with_key(&key);
+        for data in init {
+            ctx.update(data);
+        }
+        Self(ctx)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(&self) -> [u8; HMAC_SIZE] {
+        let tag = self.0.clone().sign();
+        tag.as_ref()[..HMAC_SIZE].try_into().unwrap()
+    }
+
+    /// Check the HMAC of `data`, then chain it into following records
+    fn verify_chained(&mut self, hmac: &[u8], data: &[u8]) -> bool {
+        let mut next = self.clone();
+        next.update(data);
+        let expected = next.finalize();
+        if expected != hmac {
+            return false;
+        }
+        next.update(&expected);
+        *self = next;
+        true
+    }
+
+    /// HMAC of `data`, chained into following records
+    fn sign_chained(&mut self, data: &[u8]) -> [u8; HMAC_SIZE] {
+        self.update(data);
+        let hmac = self.finalize();
+        self.update(&hmac);
+        hmac
+    }
+}
+
+/// Read a complete TLS record into `buf`, cancel safe as bytes read are kept in `buf`
+async fn read_record<S: AsyncRead + Unpin>(stream: &mut S, buf: &mut BytesMut) -> io::Result<BytesMut> {
+    loop {
+        if buf.len() >= TLS_HEADER_SIZE {
+            let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if len > MAX_TLS_RECORD_SIZE {
+                return Err(io::Error::other(format!("TLS record of {len} bytes too large")));
+            }
+            if buf.len() >= TLS_HEADER_SIZE + len {
+                return Ok(buf.split_to(TLS_HEADER_SIZE + len));
+            }
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Read ClientHello of the client, with bytes following it left in `buf`
+pub(super) async fn read_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+) -> io::Result<BytesMut> {
+    // Fail fast on other protocols, instead of waiting for a record length they never send
+    while buf.is_empty() {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    if buf[0] != CONTENT_TYPE_HANDSHAKE {
+        return Err(io::Error::other("not a TLS ClientHello"));
+    }
+
+    let record = read_record(stream, buf).await?;
+    if record.len() < SESSION_ID_LEN_OFFSET + 1
+        || record[TLS_HEADER_SIZE] != HANDSHAKE_TYPE_CLIENT_HELLO
+    {
+        return Err(io::Error::other("not a TLS ClientHello"));
+    }
+    Ok(record)
+}
+
+/// Check the HMAC in the last 4 bytes of the session ID, over ClientHello with these bytes zeroed
+fn verify_client_hello(password: &str, record: &[u8]) -> bool {
+    let session_id = SESSION_ID_LEN_OFFSET + 1;
+    if record[SESSION_ID_LEN_OFFSET] as usize != TLS_SESSION_ID_SIZE || record.len() < session_id + TLS_SESSION_ID_SIZE {
+        return false;
+    }
+
+    let hmac_start = session_id + TLS_SESSION_ID_SIZE - HMAC_SIZE;
+    let mut hmac = Hmac::new(password, &[]);
+    hmac.update(&record[TLS_HEADER_SIZE..hmac_start]);
+    hmac.update(&[0; HMAC_SIZE]);
+    hmac.update(&record[hmac_start + HMAC_SIZE..]);
+    hmac.finalize() == record[hmac_start..hmac_start + HMAC_SIZE]
+}
+
+/// ServerRandom of ServerHello, if the handshake server negotiated TLS 1.3
+fn server_hello_random(record: &[u8]) -> Option<[u8; TLS_RANDOM_SIZE]> {
+    if record[0] != CONTENT_TYPE_HANDSHAKE
+        || record.len() < SESSION_ID_LEN_OFFSET + 1
+        || record[TLS_HEADER_SIZE] != HANDSHAKE_TYPE_SERVER_HELLO
+    {
+        return None;
+    }
+    let random = record[SESSION_ID_LEN_OFFSET - TLS_RANDOM_SIZE..SESSION_ID_LEN_OFFSET]
+        .try_into()
+        .unwrap();
+
+    // Session ID, cipher suite and compression method
+    let mut pos = SESSION_ID_LEN_OFFSET + 1 + record[SESSION_ID_LEN_OFFSET] as usize + 2 + 1;
+    let extensions_len = u16::from_be_bytes(record.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+    pos += 2;
+    let extensions = record.get(pos..pos + extensions_len)?;
+
+    let mut ext = extensions;
+    while ext.len() >= 4 {
+        let ty = u16::from_be_bytes([ext[0], ext[1]]);
+        let len = u16::from_be_bytes([ext[2], ext[3]]) as usize;
+        let data = ext.get(4..4 + len)?;
+        if ty == EXTENSION_SUPPORTED_VERSIONS && data == TLS13_VERSION.to_be_bytes() {
+            return Some(random);
+        }
+        ext = &ext[4 + len..];
+    }
+    None
+}
+
+/// Result of the ShadowTLS handshake
+pub(super) enum Handshake {
+    /// The client is authenticated and sent its first data
+    Authenticated(Box<ShadowTlsSession>),
+    /// The client wasn't authenticated and was served by the handshake server
+    Relayed,
+}
+
+/// State of an authenticated client, for creating the `ShadowTlsStream`
+pub(super) struct ShadowTlsSession {
+    data: Bytes,
+    read_buf: BytesMut,
+    client_hmac: Hmac,
+    server_hmac: Hmac,
+}
+
+/// Relay the handshake of the client to the handshake server, until the client sends authenticated data
+///
+/// `deadline` limits authenticated handshakes, unauthenticated clients are relayed until closed or idle for
+/// `RELAY_IDLE_TIMEOUT`.
+pub(super) async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    client_hello: BytesMut,
+    client_buf: BytesMut,
+    config: &ShadowTlsConfig,
+    deadline: Option<time::Instant>,
+) -> io::Result<Handshake> {
+    let mut server = with_deadline(deadline, TcpStream::connect(&config.handshake_server)).await?;
+    server.write_all(&client_hello).await?;
+
+    if !verify_client_hello(&config.password, &client_hello) {
+        trace!("shadowtls client hello not authenticated, relaying to {}", config.handshake_server);
+        return relay(stream, &mut server, client_buf).await;
+    }
+
+    let mut server_buf = BytesMut::new();
+    let server_hello = with_deadline(deadline, read_record(&mut server, &mut server_buf)).await?;
+    stream.write_all(&server_hello).await?;
+
+    let Some(server_random) = server_hello_random(&server_hello) else {
+        trace!("shadowtls handshake server {} didn't negotiate TLS 1.3", config.handshake_server);
+        stream.write_all(&server_buf).await?;
+        return relay(stream, &mut server, client_buf).await;
+    };
+
+    with_deadline(
+        deadline,
+        relay_until_authenticated(stream, &mut server, server_buf, client_buf, config, &server_random),
+    )
+    .await
+}
+
+/// Relay handshake messages both ways, signing encrypted ones of the handshake server for the client
+async fn relay_until_authenticated<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    server: &mut TcpStream,
+    mut server_buf: BytesMut,
+    mut client_buf: BytesMut,
+    config: &ShadowTlsConfig,
+    server_random: &[u8],
+) -> io::Result<Handshake> {
+    let xor_key = {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(config.password.as_bytes());
+        ctx.update(server_random);
+        ctx.finish()
+    };
+    let mut handshake_hmac = Hmac::new(&config.password, &[server_random]);
+    let mut client_hmac = Hmac::new(&config.password, &[server_random, b"C"]);
+    let server_hmac = Hmac::new(&config.password, &[server_random, b"S"]);
+
+    loop {
+        tokio::select! {
+            record = read_record(server, &mut server_buf) => {
+                let record = record?;
+                if record[0] != CONTENT_TYPE_APPLICATION_DATA {
+                    stream.write_all(&record).await?;
+                    continue;
+                }
+
+                // Encrypted handshake messages are signed, clients know they come from the handshake server
+                let mut payload = record[TLS_HEADER_SIZE..].to_vec();
+                for (b, k) in payload.iter_mut().zip(xor_key.as_ref().iter().cycle()) {
+                    *b ^= k;
+                }
+                let hmac = handshake_hmac.sign_chained(&payload);
+
+                let mut signed = BytesMut::with_capacity(TLS_HMAC_HEADER_SIZE + payload.len());
+                signed.put_slice(&record[..3]);
+                signed.put_u16((HMAC_SIZE + payload.len()) as u16);
+                signed.put_slice(&hmac);
+                signed.put_slice(&payload);
+                stream.write_all(&signed).await?;
+            }
+            record = read_record(stream, &mut client_buf) => {
+                let record = record?;
+                if record[0] == CONTENT_TYPE_APPLICATION_DATA
+                    && record.len() >= TLS_HMAC_HEADER_SIZE
+                    && client_hmac.verify_chained(
+                        &record[TLS_HEADER_SIZE..TLS_HMAC_HEADER_SIZE],
+                        &record[TLS_HMAC_HEADER_SIZE..],
+                    )
+                {
+                    return Ok(Handshake::Authenticated(Box::new(ShadowTlsSession {
+                        data: Bytes::copy_from_slice(&record[TLS_HMAC_HEADER_SIZE..]),
+                        read_buf: client_buf,
+                        client_hmac,
+                        server_hmac,
+                    })));
+                }
+                server.write_all(&record).await?;
+            }
+        }
+    }
+}
+
+/// Relay the client to the handshake server until both sides close or the relay is idle
+async fn relay<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    server: &mut TcpStream,
+    client_buf: BytesMut,
+) -> io::Result<Handshake> {
+    server.write_all(&client_buf).await?;
+    if let Err(err) = copy_bidirectional_idle(stream, server, RELAY_IDLE_TIMEOUT).await {
+        trace!("shadowtls relay to handshake server closed, error: {}", err);
+    }
+    Ok(Handshake::Relayed)
+}
+
+/// Stream of an authenticated ShadowTLS client
+///
+/// Data is carried in application data records with chained HMACs, other records end reading.
+pub struct ShadowTlsStream<S> {
+    stream: S,
+    data: Bytes,
+    read_buf: BytesMut,
+    read_closed: bool,
+    client_hmac: Hmac,
+    write_buf: BytesMut,
+    server_hmac: Hmac,
+}
+
+impl<S> ShadowTlsStream<S> {
+    /// Create a stream of an authenticated client
+    pub(super) fn new(stream: S, session: ShadowTlsSession) -> Self {
+        Self {
+            stream,
+            data: session.data,
+            read_buf: session.read_buf,
+            read_closed: false,
+            client_hmac: session.client_hmac,
+            write_buf: BytesMut::new(),
+            server_hmac: session.server_hmac,
+        }
+    }
+
+    /// Get the inner stream reference
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get the inner stream mutable reference
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the object and returns the inner stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ShadowTlsStream<S> {
+    /// Take a complete record from `read_buf`
+    fn take_record(&mut self) -> io::Result<Option<BytesMut>> {
+        if self.read_buf.len() < TLS_HEADER_SIZE {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+        if len > MAX_TLS_RECORD_SIZE {
+            return Err(io::Error::other(format!("TLS record of {len} bytes too large")));
+        }
+        if self.read_buf.len() < TLS_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        Ok(Some(self.read_buf.split_to(TLS_HEADER_SIZE + len)))
+    }
+
+    /// Write out records in `write_buf`
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_buf.has_remaining() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for ShadowTlsStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.data.has_remaining() {
+                let n = this.data.len().min(buf.remaining());
+                buf.put_slice(&this.data[..n]);
+                this.data.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_closed || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(record) = this.take_record()? {
+                // Alerts, like close_notify
+                if record[0] != CONTENT_TYPE_APPLICATION_DATA {
+                    this.read_closed = true;
+                    continue;
+                }
+                if record.len() < TLS_HMAC_HEADER_SIZE
+                    || !this.client_hmac.verify_chained(
+                        &record[TLS_HEADER_SIZE..TLS_HMAC_HEADER_SIZE],
+                        &record[TLS_HMAC_HEADER_SIZE..],
+                    )
+                {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "shadowtls record HMAC mismatch",
+                    )));
+                }
+                this.data = record.freeze().slice(TLS_HMAC_HEADER_SIZE..);
+                continue;
+            }
+
+            let filled = this.read_buf.len();
+            this.read_buf.resize(filled + MAX_DATA_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut this.read_buf[filled..]);
+            let res = Pin::new(&mut this.stream).poll_read(cx, &mut read_buf);
+            let n = read_buf.filled().len();
+            this.read_buf.truncate(filled + n);
+            ready!(res)?;
+
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for ShadowTlsStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_DATA_SIZE);
+        let hmac = this.server_hmac.sign_chained(&buf[..n]);
+        this.write_buf.reserve(TLS_HMAC_HEADER_SIZE + n);
+        this.write_buf.put_slice(&[CONTENT_TYPE_APPLICATION_DATA, 0x03, 0x03]);
+        this.write_buf.put_u16((HMAC_SIZE + n) as u16);
+        this.write_buf.put_slice(&hmac);
+        this.write_buf.put_slice(&buf[..n]);
+
+        // The record is buffered, the rest is written out by following writes or flushes
+        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    const PASSWORD: &str = "shadowtls password";
+
+    fn record(content_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![content_type, 0x03, 0x03];
+        record.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        record.extend_from_slice(payload);
+        record
+    }
+
+    fn hello(handshake_type: u8, random: [u8; TLS_RANDOM_SIZE], extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&random);
+        body.push(TLS_SESSION_ID_SIZE as u8);
+        body.extend_from_slice(&[0x11; TLS_SESSION_ID_SIZE]);
+        body.extend_from_slice(&[0x13, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut payload = vec![handshake_type];
+        payload.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        payload.extend_from_slice(&body);
+        record(CONTENT_TYPE_HANDSHAKE, &payload)
+    }
+
+    /// ClientHello of a ShadowTLS client, signed with `password`
+    fn client_hello(password: &str) -> Vec<u8> {
+        let mut record = hello(HANDSHAKE_TYPE_CLIENT_HELLO, [0x22; TLS_RANDOM_SIZE], &[]);
+        let hmac_start = SESSION_ID_LEN_OFFSET + 1 + TLS_SESSION_ID_SIZE - HMAC_SIZE;
+        record[hmac_start..hmac_start + HMAC_SIZE].fill(0);
+        let mut hmac = Hmac::new(password, &[]);
+        hmac.update(&record[TLS_HEADER_SIZE..]);
+        record[hmac_start..hmac_start + HMAC_SIZE].copy_from_slice(&hmac.finalize());
+        record
+    }
+
+    fn signed_record(hmac: &mut Hmac, data: &[u8]) -> Vec<u8> {
+        let mut payload = hmac.sign_chained(data).to_vec();
+        payload.extend_from_slice(data);
+        record(CONTENT_TYPE_APPLICATION_DATA, &payload)
+    }
+
+    /// Stand-in TLS server, answering ServerHello and one encrypted handshake message
+    async fn handshake_server() -> (String, tokio::sync::mpsc::UnboundedReceiver<BytesMut>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            let client_hello = read_record(&mut stream, &mut buf).await.unwrap();
+            let authenticated = verify_client_hello(PASSWORD, &client_hello);
+            tx.send(client_hello).unwrap();
+            if !authenticated {
+                stream.write_all(b"cover").await.unwrap();
+                return;
+            }
+
+            let supported_versions = [0x00, 0x2b, 0x00, 0x02, 0x03, 0x04];
+            let mut response = hello(HANDSHAKE_TYPE_SERVER_HELLO, [0x33; TLS_RANDOM_SIZE], &supported_versions);
+            response.extend(record(CONTENT_TYPE_APPLICATION_DATA, b"encrypted extensions"));
+            stream.write_all(&response).await.unwrap();
+
+            while let Ok(record) = read_record(&mut stream, &mut buf).await {
+                let _ = tx.send(record);
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn test_shadow_tls_authenticated() {
+        let (handshake_server, mut received) = handshake_server().await;
+        let config = ShadowTlsConfig {
+            password: PASSWORD.to_owned(),
+            handshake_server,
+        };
+
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut buf = BytesMut::new();
+            let client_hello = read_client_hello(&mut server, &mut buf).await.unwrap();
+            match handshake(&mut server, client_hello, buf, &config, None).await.unwrap() {
+                Handshake::Authenticated(session) => ShadowTlsStream::new(server, *session),
+                Handshake::Relayed => panic!("client should be authenticated"),
+            }
+        });
+
+        client.write_all(&client_hello(PASSWORD)).await.unwrap();
+        assert!(verify_client_hello(PASSWORD, &received.recv().await.unwrap()));
+
+        let mut buf = BytesMut::new();
+        let server_hello = read_record(&mut client, &mut buf).await.unwrap();
+        let server_random = server_hello_random(&server_hello).unwrap();
+
+        // Encrypted handshake messages are signed by the server
+        let signed = read_record(&mut client, &mut buf).await.unwrap();
+        let mut handshake_hmac = Hmac::new(PASSWORD, &[&server_random]);
+        assert!(handshake_hmac.verify_chained(&signed[TLS_HEADER_SIZE..TLS_HMAC_HEADER_SIZE], &signed[TLS_HMAC_HEADER_SIZE..]));
+        let mut xor_key = digest::Context::new(&digest::SHA256);
+        xor_key.update(PASSWORD.as_bytes());
+        xor_key.update(&server_random);
+        let xor_key = xor_key.finish();
+        let payload = signed[TLS_HMAC_HEADER_SIZE..]
+            .iter()
+            .zip(xor_key.as_ref().iter().cycle())
+            .map(|(b, k)| b ^ k)
+            .collect::<Vec<_>>();
+        assert_eq!(payload, b"encrypted extensions");
+
+        // Finished of the client goes to the handshake server
+        let finished = record(CONTENT_TYPE_APPLICATION_DATA, b"client finished");
+        client.write_all(&finished).await.unwrap();
+        assert_eq!(&received.recv().await.unwrap()[..], &finished[..]);
+
+        let mut client_hmac = Hmac::new(PASSWORD, &[&server_random, b"C"]);
+        let mut data = signed_record(&mut client_hmac, b"hello ");
+        data.extend(signed_record(&mut client_hmac, b"world"));
+        client.write_all(&data).await.unwrap();
+
+        let mut stream = server.await.unwrap();
+        let mut read = [0u8; 11];
+        stream.read_exact(&mut read).await.unwrap();
+        assert_eq!(&read, b"hello world");
+
+        stream.write_all(b"pong").await.unwrap();
+        let response = read_record(&mut client, &mut buf).await.unwrap();
+        let mut server_hmac = Hmac::new(PASSWORD, &[&server_random, b"S"]);
+        assert!(server_hmac.verify_chained(&response[TLS_HEADER_SIZE..TLS_HMAC_HEADER_SIZE], &response[TLS_HMAC_HEADER_SIZE..]));
+        assert_eq!(&response[TLS_HMAC_HEADER_SIZE..], b"pong");
+
+        // Tampered records are rejected
+        let mut tampered = signed_record(&mut client_hmac, b"evil");
+        tampered[TLS_HEADER_SIZE] ^= 1;
+        client.write_all(&tampered).await.unwrap();
+        assert!(stream.read(&mut read).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shadow_tls_unauthenticated_relayed() {
+        let (handshake_server, mut received) = handshake_server().await;
+        let config = ShadowTlsConfig {
+            password: PASSWORD.to_owned(),
+            handshake_server,
+        };
+
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut buf = BytesMut::new();
+            let client_hello = read_client_hello(&mut server, &mut buf).await.unwrap();
+            handshake(&mut server, client_hello, buf, &config, None).await.unwrap()
+        });
+
+        let hello = client_hello("wrong password");
+        client.write_all(&hello).await.unwrap();
+        assert_eq!(&received.recv().await.unwrap()[..], &hello[..]);
+
+        let mut cover = [0u8; 5];
+        client.read_exact(&mut cover).await.unwrap();
+        assert_eq!(&cover, b"cover");
+        drop(client);
+        assert!(matches!(server.await.unwrap(), Handshake::Relayed));
+    }
+
+    #[tokio::test]
+    async fn test_shadow_tls_relay_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut server = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut handshake_server, _) = listener.accept().await.unwrap();
+        let (mut client, mut stream) = tokio::io::duplex(1024);
+
+        let relay =
+            tokio::spawn(async move { copy_bidirectional_idle(&mut stream, &mut server, Duration::from_millis(200)).await });
+
+        client.write_all(b"probe").await.unwrap();
+        let mut buf = [0u8; 5];
+        handshake_server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"probe");
+
+        // Both sides stay open but silent
+        let err = relay.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_shadow_tls_rejects_other_protocols() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut buf = BytesMut::new();
+        assert!(read_client_hello(&mut server, &mut buf).await.is_err());
+        assert_eq!(&buf[..], b"GET / HTTP/1.1\r\n");
+    }
+}
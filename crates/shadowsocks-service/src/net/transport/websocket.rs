@@ -0,0 +1,434 @@
+//! WebSocket transport, compatible with v2ray-plugin
+//!
+//! Payloads are carried in binary messages, masked from clients and unmasked from the server.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{HttpRequest, write_upgrade_response};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Maximum payload of frames sent by the server
+const MAX_FRAME_PAYLOAD_SIZE: usize = 16 * 1024;
+
+/// Bytes read from the inner stream at once
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Read the upgrade request to `path` and respond, returning bytes following the request
+pub(super) async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, path: &str) -> io::Result<Bytes> {
+    let (request, payload) = HttpRequest::read_from(stream).await?;
+    if request.method != "GET" || !request.is_websocket_upgrade() || request.websocket_key.is_none() {
+        return Err(io::Error::other(format!(
+            "{} {} is not a WebSocket request",
+            request.method, request.path
+        )));
+    }
+    if request.path_only() != path {
+        return Err(io::Error::other(format!("WebSocket request to unknown path {}", request.path)));
+    }
+    write_upgrade_response(stream, &request).await?;
+    Ok(payload)
+}
+
+/// Frame being read from the client
+struct IncomingFrame {
+    opcode: u8,
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    mask_offset: usize,
+    is_control: bool,
+}
+
+impl IncomingFrame {
+    fn unmask(&mut self, data: &mut [u8]) {
+        if let Some(mask) = self.mask {
+            for b in data.iter_mut() {
+                *b ^= mask[self.mask_offset % 4];
+                self.mask_offset += 1;
+            }
+        }
+    }
+}
+
+/// Parse a frame header, returning the header length and the frame
+fn parse_frame_header(buf: &[u8]) -> io::Result<Option<(usize, IncomingFrame)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (payload_len, mut header_len) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        n => (n as u64, 2),
+    };
+
+    let mask = if masked {
+        if buf.len() < header_len + 4 {
+            return Ok(None);
+        }
+        let mask = buf[header_len..header_len + 4].try_into().unwrap();
+        header_len += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let is_control = match opcode {
+        OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => false,
+        OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG if payload_len <= 125 => true,
+        _ => return Err(io::Error::other(format!("invalid WebSocket frame, opcode {opcode:#x}"))),
+    };
+
+    Ok(Some((
+        header_len,
+        IncomingFrame {
+            opcode,
+            remaining: payload_len,
+            mask,
+            mask_offset: 0,
+            is_control,
+        },
+    )))
+}
+
+/// Append an unmasked frame of the server
+fn put_frame(buf: &mut BytesMut, opcode: u8, payload: &[u8]) {
+    buf.reserve(10 + payload.len());
+    buf.put_u8(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => buf.put_u8(n as u8),
+        n if n <= u16::MAX as usize => {
+            buf.put_u8(126);
+            buf.put_u16(n as u16);
+        }
+        n => {
+            buf.put_u8(127);
+            buf.put_u64(n as u64);
+        }
+    }
+    buf.put_slice(payload);
+}
+
+/// Stream of a WebSocket client, after the handshake
+///
+/// Pings are answered with pongs, a close frame ends reading.
+pub struct WebSocketStream<S> {
+    stream: S,
+    read_buf: BytesMut,
+    frame: Option<IncomingFrame>,
+    control_payload: BytesMut,
+    read_closed: bool,
+    write_buf: BytesMut,
+    pong_pending: bool,
+    close_sent: bool,
+}
+
+impl<S> WebSocketStream<S> {
+    /// Create a stream parsing frames from `payload` first, bytes read after the handshake
+    pub fn new(stream: S, payload: Bytes) -> Self {
+        Self {
+            stream,
+            read_buf: BytesMut::from(payload),
+            frame: None,
+            control_payload: BytesMut::new(),
+            read_closed: false,
+            write_buf: BytesMut::new(),
+            pong_pending: false,
+            close_sent: false,
+        }
+    }
+
+    /// Get the inner stream reference
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Get the inner stream mutable reference
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Consumes the object and returns the inner stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    /// Read more bytes into `read_buf`, returning the number of bytes read
+    fn poll_fill_read_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let filled = self.read_buf.len();
+        self.read_buf.resize(filled + READ_BUFFER_SIZE, 0);
+        let mut read_buf = ReadBuf::new(&mut self.read_buf[filled..]);
+        let res = Pin::new(&mut self.stream).poll_read(cx, &mut read_buf);
+        let n = read_buf.filled().len();
+        self.read_buf.truncate(filled + n);
+        ready!(res)?;
+        Poll::Ready(Ok(n))
+    }
+
+    /// Write out frames in `write_buf`
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_buf.has_remaining() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queue the pong of a ping
+    ///
+    /// Frames already buffered are sent by the writer, so the pong follows them. Otherwise the writer may be idle
+    /// and reads send the pong, without taking over wakeups of a pending writer.
+    fn send_pong(&mut self, cx: &mut Context<'_>, payload: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        let writer_idle = self.write_buf.is_empty();
+        put_frame(&mut self.write_buf, OPCODE_PONG, payload);
+        if writer_idle {
+            self.pong_pending = true;
+            self.poll_pending_pong(cx)?;
+        }
+        Ok(())
+    }
+
+    /// Send the pong queued by reads, keeping it pending until written out
+    fn poll_pending_pong(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if !self.pong_pending {
+            return Ok(());
+        }
+        if let Poll::Ready(res) = self.poll_write_buf(cx) {
+            res?;
+            if let Poll::Ready(res) = Pin::new(&mut self.stream).poll_flush(cx) {
+                res?;
+                self.pong_pending = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.read_closed || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            this.poll_pending_pong(cx)?;
+
+            if let Some(ref mut frame) = this.frame {
+                if frame.remaining == 0 {
+                    if frame.opcode == OPCODE_PING {
+                        let payload = this.control_payload.split();
+                        this.send_pong(cx, &payload)?;
+                    }
+                    this.control_payload.clear();
+                    this.frame = None;
+                    continue;
+                }
+
+                if this.read_buf.has_remaining() {
+                    let mut n = this.read_buf.len().min(frame.remaining.try_into().unwrap_or(usize::MAX));
+                    if !frame.is_control {
+                        n = n.min(buf.remaining());
+                    }
+                    let mut data = this.read_buf.split_to(n);
+                    frame.unmask(&mut data);
+                    frame.remaining -= n as u64;
+
+                    if !frame.is_control {
+                        buf.put_slice(&data);
+                        return Poll::Ready(Ok(()));
+                    }
+                    this.control_payload.extend_from_slice(&data);
+                    continue;
+                }
+            } else if let Some((header_len, frame)) = parse_frame_header(&this.read_buf)? {
+                this.read_buf.advance(header_len);
+                if frame.opcode == OPCODE_CLOSE {
+                    this.read_closed = true;
+                } else {
+                    this.frame = Some(frame);
+                }
+                continue;
+            }
+
+            if ready!(this.poll_fill_read_buf(cx))? == 0 {
+                if this.frame.is_none() && this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.pong_pending = false;
+        ready!(this.poll_write_buf(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = buf.len().min(MAX_FRAME_PAYLOAD_SIZE);
+        put_frame(&mut this.write_buf, OPCODE_BINARY, &buf[..n]);
+
+        // The frame is buffered, the rest is written out by following writes or flushes
+        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.pong_pending = false;
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.pong_pending = false;
+        if !this.close_sent {
+            ready!(this.poll_write_buf(cx))?;
+            put_frame(&mut this.write_buf, OPCODE_CLOSE, &[]);
+            this.close_sent = true;
+        }
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Frame of a client, masked
+    fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut buf = BytesMut::new();
+        put_frame(&mut buf, opcode, payload);
+        let header_len = buf.len() - payload.len();
+        buf[1] |= 0x80;
+
+        let mut frame = buf[..header_len].to_vec();
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream_read() {
+        let large = vec![0xabu8; 70000];
+        let mut input = client_frame(OPCODE_BINARY, b"hello ");
+        input.extend(client_frame(OPCODE_PING, b"ping"));
+        input.extend(client_frame(OPCODE_BINARY, b"world"));
+        input.extend(client_frame(OPCODE_BINARY, &large));
+        input.extend(client_frame(OPCODE_CLOSE, &[]));
+        input.extend(client_frame(OPCODE_BINARY, b"ignored"));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        // Bytes following the handshake are parsed first
+        let mut stream = WebSocketStream::new(server, Bytes::copy_from_slice(&input[..3]));
+        tokio::spawn(async move { client.write_all(&input[3..]).await });
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+
+        let mut expected = b"hello world".to_vec();
+        expected.extend_from_slice(&large);
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream_pong() {
+        let mut input = client_frame(OPCODE_PING, b"ping");
+        input.extend(client_frame(OPCODE_BINARY, b"hello"));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut stream = WebSocketStream::new(server, Bytes::new());
+        client.write_all(&input).await.unwrap();
+
+        // Answered while only reading
+        let mut received = [0u8; 5];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        let mut expected = BytesMut::new();
+        put_frame(&mut expected, OPCODE_PONG, b"ping");
+        let mut output = vec![0u8; expected.len()];
+        client.read_exact(&mut output).await.unwrap();
+        assert_eq!(output, expected);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream_write() {
+        let (mut client, server) = tokio::io::duplex(1024 * 1024);
+        let mut stream = WebSocketStream::new(server, Bytes::new());
+
+        let large = vec![0xcdu8; 20000];
+        stream.write_all(b"hello").await.unwrap();
+        stream.write_all(&large).await.unwrap();
+        stream.shutdown().await.unwrap();
+        drop(stream);
+
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+
+        let mut expected = BytesMut::new();
+        put_frame(&mut expected, OPCODE_BINARY, b"hello");
+        put_frame(&mut expected, OPCODE_BINARY, &large[..MAX_FRAME_PAYLOAD_SIZE]);
+        put_frame(&mut expected, OPCODE_BINARY, &large[MAX_FRAME_PAYLOAD_SIZE..]);
+        put_frame(&mut expected, OPCODE_CLOSE, &[]);
+        assert_eq!(output, expected);
+        assert_eq!(&output[..7], b"\x82\x05hello");
+    }
+
+    #[tokio::test]
+    async fn test_websocket_accept() {
+        let request = b"GET /ws?ed=2048 HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n\x82";
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+
+        let payload = accept(&mut server, "/ws").await.unwrap();
+        assert_eq!(&payload[..], b"\x82");
+
+        let mut response = vec![0u8; 256];
+        let n = client.read(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response[..n]);
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        // Example of RFC 6455
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(request).await.unwrap();
+        assert!(accept(&mut server, "/other").await.is_err());
+    }
+}
@@ -3,9 +3,16 @@
 use std::{
     io,
     net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
 };
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+/// Bytes read at once by `copy_bidirectional_idle` in each direction
+const COPY_BUFFER_SIZE: usize = 16 * 1024;
 
 /// Consumes all data from `reader` and throws away until EOF
 pub async fn ignore_until_end<R>(reader: &mut R) -> io::Result<()>
@@ -24,6 +31,48 @@ where
     Ok(())
 }
 
+/// Copy both ways until both sides close, failing with `TimedOut` if nothing is transferred for `idle_timeout`
+pub async fn copy_bidirectional_idle<A, B>(a: &mut A, b: &mut B, idle_timeout: Duration) -> io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut a_buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut b_buf = vec![0u8; COPY_BUFFER_SIZE];
+    let mut a_open = true;
+    let mut b_open = true;
+
+    while a_open || b_open {
+        let transfer = async {
+            tokio::select! {
+                n = a.read(&mut a_buf), if a_open => {
+                    match n? {
+                        0 => {
+                            a_open = false;
+                            b.shutdown().await
+                        }
+                        n => b.write_all(&a_buf[..n]).await,
+                    }
+                }
+                n = b.read(&mut b_buf), if b_open => {
+                    match n? {
+                        0 => {
+                            b_open = false;
+                            a.shutdown().await
+                        }
+                        n => a.write_all(&b_buf[..n]).await,
+                    }
+                }
+            }
+        };
+        match time::timeout(idle_timeout, transfer).await {
+            Ok(res) => res?,
+            Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+    Ok(())
+}
+
 /// Helper function for converting IPv4 mapped IPv6 address
 ///
 /// This is the same as `Ipv6Addr::to_ipv4_mapped`, but it is still unstable in the current libstd
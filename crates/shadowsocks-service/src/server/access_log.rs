@@ -0,0 +1,447 @@
+//! Access log
+//!
+//! Every relayed TCP connection, and every destination of UDP associations, is written as a JSON line.
+//! Destinations of long-lived UDP associations are written periodically, each line covering the bytes since the last.
+//! Entries are written by a background thread, relay tasks never wait for disk I/O.
+//! The thread is started by the first entry, so it inherits per-thread restrictions applied after opening the log.
+//! If the writer falls behind, new entries are dropped and counted.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, IoSlice, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, Once,
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    },
+    task::{Context, Poll},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::error;
+use pin_project::pin_project;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Entries waiting for writing, new entries are dropped if the queue is full
+const ACCESS_LOG_QUEUE_SIZE: usize = 8192;
+
+/// Time to wait for queued entries being written when the log is closed
+const ACCESS_LOG_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Access log file and rotation settings
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    /// Path of the current log file, rotated files are suffixed with `.1`, `.2`, ...
+    pub path: PathBuf,
+    /// Rotate when the file would grow over this size in bytes, 0 disables size-based rotation
+    pub max_size: u64,
+    /// Rotate when the file was opened this long ago
+    pub rotate_interval: Option<Duration>,
+    /// Rotated files to keep
+    pub max_files: usize,
+}
+
+/// Protocol of an access
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessProtocol {
+    Tcp,
+    Udp,
+}
+
+/// An access to a destination
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    /// When the access started
+    pub start: SystemTime,
+    /// Name of the authenticated user
+    pub user: Option<String>,
+    /// Client address
+    pub client: SocketAddr,
+    pub protocol: AccessProtocol,
+    /// Destination requested by the client
+    pub destination: String,
+    /// Domain name sniffed from the payload
+    pub sniffed: Option<String>,
+    /// Relay server, or local address of the outbound socket if an address pool is used
+    pub outbound: Option<String>,
+    /// Bytes sent from the client to the destination
+    pub bytes_up: u64,
+    /// Bytes sent from the destination to the client
+    pub bytes_down: u64,
+    pub duration: Duration,
+}
+
+#[derive(Serialize)]
+struct AccessLogRecord<'a> {
+    ts: String,
+    user: Option<&'a str>,
+    client_ip: String,
+    client_port: u16,
+    protocol: AccessProtocol,
+    destination: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sniffed: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound: Option<&'a str>,
+    bytes_up: u64,
+    bytes_down: u64,
+    duration_ms: u64,
+}
+
+impl AccessLogEntry {
+    fn to_json_line(&self) -> Vec<u8> {
+        let record = AccessLogRecord {
+            ts: format_rfc3339(self.start),
+            user: self.user.as_deref(),
+            client_ip: self.client.ip().to_canonical().to_string(),
+            client_port: self.client.port(),
+            protocol: self.protocol,
+            destination: &self.destination,
+            sniffed: self.sniffed.as_deref(),
+            outbound: self.outbound.as_deref(),
+            bytes_up: self.bytes_up,
+            bytes_down: self.bytes_down,
+            duration_ms: self.duration.as_millis() as u64,
+        };
+        let mut line = serde_json::to_vec(&record).expect("access log record serialization");
+        line.push(b'\n');
+        line
+    }
+}
+
+/// Format as RFC 3339 in UTC with milliseconds, like `2024-01-02T03:04:05.678Z`
+fn format_rfc3339(time: SystemTime) -> String {
+    let ts = jiff::Timestamp::try_from(time).unwrap_or(jiff::Timestamp::UNIX_EPOCH);
+    format!("{ts:.3}")
+}
+
+/// Writer waiting for the first entry, with the sender disconnecting when it exits
+type PendingWriter = (AccessLogWriter, Receiver<AccessLogEntry>, SyncSender<()>);
+
+/// Access log writing JSON lines in background
+pub struct AccessLog {
+    tx: Option<SyncSender<AccessLogEntry>>,
+    writer: Mutex<Option<PendingWriter>>,
+    writer_started: Once,
+    // Disconnected when the writer exits
+    writer_done: Mutex<Option<Receiver<()>>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// Open the log file, writing starts in background with the first entry
+    pub fn open(config: AccessLogConfig) -> io::Result<Self> {
+        let writer = AccessLogWriter::open(config)?;
+        let (tx, rx) = mpsc::sync_channel(ACCESS_LOG_QUEUE_SIZE);
+        let (done_tx, done_rx) = mpsc::sync_channel::<()>(0);
+
+        Ok(Self {
+            tx: Some(tx),
+            writer: Mutex::new(Some((writer, rx, done_tx))),
+            writer_started: Once::new(),
+            writer_done: Mutex::new(Some(done_rx)),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    fn start_writer(&self) {
+        let pending = self.writer.lock().unwrap_or_else(|err| err.into_inner()).take();
+        let Some((writer, rx, done_tx)) = pending else {
+            return;
+        };
+        let result = thread::Builder::new().name("access-log".to_owned()).spawn(move || {
+            writer.run(rx);
+            drop(done_tx);
+        });
+        if let Err(err) = result {
+            error!("failed to start access log writer, entries are dropped, error: {}", err);
+        }
+    }
+
+    /// Queue an entry for writing without blocking
+    pub fn log(&self, entry: AccessLogEntry) {
+        self.writer_started.call_once(|| self.start_writer());
+        if let Some(ref tx) = self.tx
+            && let Err(TrySendError::Full(..)) = tx.try_send(entry)
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take the number of entries dropped since last call
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // Writer exits after writing queued entries, a stuck disk doesn't hold up shutdown longer than the timeout
+        self.tx.take();
+        // Never started without entries
+        self.writer.get_mut().unwrap_or_else(|err| err.into_inner()).take();
+        let writer_done = self.writer_done.get_mut().unwrap_or_else(|err| err.into_inner());
+        if let Some(done) = writer_done.take()
+            && let Err(RecvTimeoutError::Timeout) = done.recv_timeout(ACCESS_LOG_CLOSE_TIMEOUT)
+        {
+            error!("access log writer didn't finish in {:?}, queued entries may be lost", ACCESS_LOG_CLOSE_TIMEOUT);
+        }
+    }
+}
+
+struct AccessLogWriter {
+    config: AccessLogConfig,
+    file: BufWriter<File>,
+    size: u64,
+    opened_at: SystemTime,
+}
+
+impl AccessLogWriter {
+    fn open(config: AccessLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            config,
+            file: BufWriter::new(file),
+            size,
+            opened_at: SystemTime::now(),
+        })
+    }
+
+    fn run(mut self, rx: Receiver<AccessLogEntry>) {
+        while let Ok(entry) = rx.recv() {
+            self.write_entry(&entry);
+            // Flush after writing all queued entries
+            while let Ok(entry) = rx.try_recv() {
+                self.write_entry(&entry);
+            }
+            if let Err(err) = self.file.flush() {
+                error!("failed to write access log {}, error: {}", self.config.path.display(), err);
+            }
+        }
+    }
+
+    fn write_entry(&mut self, entry: &AccessLogEntry) {
+        let line = entry.to_json_line();
+        if self.should_rotate(line.len() as u64)
+            && let Err(err) = self.rotate()
+        {
+            error!("failed to rotate access log {}, error: {}", self.config.path.display(), err);
+        }
+
+        match self.file.write_all(&line) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(err) => error!("failed to write access log {}, error: {}", self.config.path.display(), err),
+        }
+    }
+
+    fn should_rotate(&self, incoming: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if self.config.max_size > 0 && self.size + incoming > self.config.max_size {
+            return true;
+        }
+        match self.config.rotate_interval {
+            Some(interval) => self.opened_at.elapsed().unwrap_or_default() >= interval,
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let path = &self.config.path;
+        let max_files = self.config.max_files;
+        if max_files > 0 {
+            let _ = fs::remove_file(rotated_path(path, max_files));
+            for i in (1..max_files).rev() {
+                let _ = fs::rename(rotated_path(path, i), rotated_path(path, i + 1));
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        self.opened_at = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{index}"));
+    PathBuf::from(path)
+}
+
+/// Stream counting bytes read and written
+#[pin_project]
+pub(crate) struct CountedStream<S> {
+    #[pin]
+    stream: S,
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+impl<S> CountedStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            read_bytes: 0,
+            written_bytes: 0,
+        }
+    }
+
+    pub fn read_bytes(&self) -> u64 {
+        self.read_bytes
+    }
+
+    pub fn written_bytes(&self) -> u64 {
+        self.written_bytes
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for CountedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let res = this.stream.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            *this.read_bytes += (buf.filled().len() - filled) as u64;
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for CountedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let res = this.stream.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            *this.written_bytes += n as u64;
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let res = this.stream.poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(n)) = res {
+            *this.written_bytes += n as u64;
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry(destination: &str) -> AccessLogEntry {
+        AccessLogEntry {
+            start: UNIX_EPOCH + Duration::from_millis(1_704_164_645_678),
+            user: Some("7".to_owned()),
+            client: "[::ffff:198.51.100.1]:40000".parse().unwrap(),
+            protocol: AccessProtocol::Tcp,
+            destination: destination.to_owned(),
+            sniffed: Some("example.com".to_owned()),
+            outbound: None,
+            bytes_up: 100,
+            bytes_down: 200,
+            duration: Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn test_json_line() {
+        let line = String::from_utf8(entry("93.184.216.34:443").to_json_line()).unwrap();
+        assert_eq!(
+            line,
+            "{\"ts\":\"2024-01-02T03:04:05.678Z\",\"user\":\"7\",\"client_ip\":\"198.51.100.1\",\"client_port\":40000,\
+             \"protocol\":\"tcp\",\"destination\":\"93.184.216.34:443\",\"sniffed\":\"example.com\",\
+             \"bytes_up\":100,\"bytes_down\":200,\"duration_ms\":1500}\n"
+        );
+    }
+
+    #[test]
+    fn test_writer_started_by_first_entry() {
+        let dir = std::env::temp_dir().join(format!("ss-access-log-start-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let config = AccessLogConfig {
+            path: path.clone(),
+            max_size: 0,
+            rotate_interval: None,
+            max_files: 0,
+        };
+
+        // Closed without a writer thread
+        drop(AccessLog::open(config.clone()).unwrap());
+
+        let log = AccessLog::open(config).unwrap();
+        assert!(log.writer.lock().unwrap().is_some());
+        log.log(entry("93.184.216.34:443"));
+        assert!(log.writer.lock().unwrap().is_none());
+        drop(log);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("ss-access-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let line_len = entry("93.184.216.34:443").to_json_line().len() as u64;
+        let log = AccessLog::open(AccessLogConfig {
+            path: path.clone(),
+            max_size: line_len * 2,
+            rotate_interval: None,
+            max_files: 2,
+        })
+        .unwrap();
+        for _ in 0..7 {
+            log.log(entry("93.184.216.34:443"));
+        }
+        drop(log);
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&rotated_path(&path, 1)), 2);
+        assert_eq!(lines(&rotated_path(&path, 2)), 2);
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
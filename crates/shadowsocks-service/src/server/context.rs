@@ -1,16 +1,35 @@
 //! Shadowsocks Local Server Context
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use arc_swap::ArcSwapOption;
 use shadowsocks::{
-    config::ServerType,
+    config::{ServerType, ServerUser},
     context::{Context, SharedContext},
     dns_resolver::DnsResolver,
-    net::ConnectOpts,
+    net::{AddrFamily, ConnectOpts},
     relay::Address,
+    security::replay::ReplayProtectorConfig,
+};
+
+use crate::{
+    acl::{
+        AccessControl, AuditLog, AuditRules, HandshakeFailure, InternalAddrFilter, IpBanList, P2pPolicy, PortPolicy,
+        PortProtocol, UserLimiter,
+    },
+    config::SecurityConfig,
+    net::{BindAddrPool, FlowStat, ProxyProtocolTrust, SniffConfig, sniff::SniffedDomain},
 };
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+use super::access_log::AccessLog;
 
 /// Server Service Context
 #[derive(Clone)]
@@ -18,11 +37,58 @@ pub struct ServiceContext {
     context: SharedContext,
     connect_opts: ConnectOpts,
 
-    // Access Control
-    acl: Option<Arc<AccessControl>>,
+    // Access Control, shared by all clones for replacing at runtime
+    acl: Arc<ArcSwapOption<AccessControl>>,
+
+    // Built-in filter of internal destinations
+    internal_addr_filter: Option<Arc<InternalAddrFilter>>,
+
+    // Outbound port policy
+    port_policy: Option<Arc<PortPolicy>>,
+
+    // P2P traffic policy
+    p2p_policy: Option<Arc<P2pPolicy>>,
+
+    // Per-user connection limits
+    user_limiter: Option<Arc<UserLimiter>>,
+
+    // Bans of clients failing handshakes
+    ip_ban_list: Option<Arc<IpBanList>>,
+
+    // Backend serving clients failing handshakes
+    fallback_addr: Option<Address>,
+
+    // Load balancers sending PROXY protocol headers
+    proxy_protocol_trust: ProxyProtocolTrust,
+
+    // Audit rules from panels, shared by all clones for replacing at runtime
+    audit_rules: Arc<ArcSwapOption<AuditRules>>,
+    audit_log: Arc<AuditLog>,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
+
+    // Flow statistics of nodes sharing the server port, numbered from 1
+    shared_node_flow_stats: Vec<Arc<FlowStat>>,
+
+    // Outbound source address pool
+    bind_addr_pool: Option<Arc<BindAddrPool>>,
+
+    // Sniffing domain names from payloads
+    sniff_config: SniffConfig,
+
+    // Access log of destinations
+    access_log: Option<Arc<AccessLog>>,
+
+    // Relaying UDP-over-TCP tunnels to magic destinations
+    udp_over_tcp: bool,
+
+    // Demultiplexing sing-mux sessions
+    #[cfg(feature = "server-mux")]
+    mux: bool,
+
+    // Last time accept loops were polled, shared by all clones for watchdogs
+    accept_heartbeat: Arc<Mutex<Instant>>,
 }
 
 impl Default for ServiceContext {
@@ -30,8 +96,25 @@ impl Default for ServiceContext {
         Self {
             context: Context::new_shared(ServerType::Server),
             connect_opts: ConnectOpts::default(),
-            acl: None,
+            acl: Arc::new(ArcSwapOption::empty()),
+            internal_addr_filter: None,
+            port_policy: None,
+            p2p_policy: None,
+            user_limiter: None,
+            ip_ban_list: None,
+            fallback_addr: None,
+            proxy_protocol_trust: ProxyProtocolTrust::new(),
+            audit_rules: Arc::new(ArcSwapOption::empty()),
+            audit_log: Arc::new(AuditLog::new()),
             flow_stat: Arc::new(FlowStat::new()),
+            shared_node_flow_stats: Vec::new(),
+            bind_addr_pool: None,
+            sniff_config: SniffConfig::default(),
+            access_log: None,
+            udp_over_tcp: false,
+            #[cfg(feature = "server-mux")]
+            mux: false,
+            accept_heartbeat: Arc::new(Mutex::new(Instant::now())),
         }
     }
 }
@@ -62,14 +145,217 @@ impl ServiceContext {
         &self.connect_opts
     }
 
+    /// Set outbound source address pool
+    pub fn set_bind_addr_pool(&mut self, pool: Arc<BindAddrPool>) {
+        self.bind_addr_pool = Some(pool);
+    }
+
+    /// Get outbound source address pool reference
+    pub fn bind_addr_pool(&self) -> Option<&BindAddrPool> {
+        self.bind_addr_pool.as_deref()
+    }
+
+    /// Get `ConnectOpts` for an outbound socket of `family` created on behalf of a client
+    ///
+    /// If an outbound source address pool is set, the socket will bind to an address chosen by
+    /// the client's identity, which is the authenticated `user`, or `peer_addr` for single-user servers.
+    pub fn connect_opts_for(
+        &self,
+        family: AddrFamily,
+        user: Option<&ServerUser>,
+        peer_addr: &SocketAddr,
+    ) -> Cow<'_, ConnectOpts> {
+        let pool = match self.bind_addr_pool {
+            Some(ref pool) => pool,
+            None => return Cow::Borrowed(&self.connect_opts),
+        };
+
+        let bind_ip = match user {
+            Some(user) => pool.select(family, user.identity_hash()),
+            None => match peer_addr.ip() {
+                IpAddr::V4(v4) => pool.select(family, &v4.octets()),
+                IpAddr::V6(v6) => pool.select(family, &v6.octets()),
+            },
+        };
+
+        match bind_ip {
+            Some(ip) => {
+                let mut opts = self.connect_opts.clone();
+                opts.bind_local_addr = Some(SocketAddr::new(ip, 0));
+                Cow::Owned(opts)
+            }
+            None => Cow::Borrowed(&self.connect_opts),
+        }
+    }
+
+    /// Set sniffing configuration
+    pub fn set_sniff_config(&mut self, sniff_config: SniffConfig) {
+        self.sniff_config = sniff_config;
+    }
+
+    /// Get sniffing configuration reference
+    pub fn sniff_config(&self) -> &SniffConfig {
+        &self.sniff_config
+    }
+
+    /// Sniff domain name from the payload of a UDP packet, if QUIC sniffing is enabled
+    pub fn sniff_packet(&self, data: &[u8]) -> Option<SniffedDomain> {
+        if !self.sniff_config.quic {
+            return None;
+        }
+
+        #[cfg(feature = "sniff-quic")]
+        return crate::net::sniff::sniff_quic(data);
+
+        #[cfg(not(feature = "sniff-quic"))]
+        {
+            let _ = data;
+            None
+        }
+    }
+
     /// Set Access Control List
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
-        self.acl = Some(acl);
+        self.acl.store(Some(acl));
     }
 
-    /// Get Access Control List reference
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.acl.as_deref()
+    /// Replace Access Control List at runtime
+    ///
+    /// Servers built from clones of this context will check new connections with the new rules.
+    pub fn replace_acl(&self, acl: Arc<AccessControl>) {
+        self.acl.store(Some(acl));
+    }
+
+    /// Get current Access Control List
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.load_full()
+    }
+
+    /// Set filter of internal destinations
+    pub fn set_internal_addr_filter(&mut self, filter: Arc<InternalAddrFilter>) {
+        self.internal_addr_filter = Some(filter);
+    }
+
+    /// Get filter of internal destinations reference
+    pub fn internal_addr_filter(&self) -> Option<&InternalAddrFilter> {
+        self.internal_addr_filter.as_deref()
+    }
+
+    /// Set outbound port policy
+    pub fn set_port_policy(&mut self, policy: Arc<PortPolicy>) {
+        self.port_policy = Some(policy);
+    }
+
+    /// Get outbound port policy reference
+    pub fn port_policy(&self) -> Option<&PortPolicy> {
+        self.port_policy.as_deref()
+    }
+
+    /// Set P2P traffic policy
+    pub fn set_p2p_policy(&mut self, policy: Arc<P2pPolicy>) {
+        self.p2p_policy = Some(policy);
+    }
+
+    /// Get P2P traffic policy reference
+    pub fn p2p_policy(&self) -> Option<&P2pPolicy> {
+        self.p2p_policy.as_deref()
+    }
+
+    /// Set per-user connection limiter
+    pub fn set_user_limiter(&mut self, limiter: Arc<UserLimiter>) {
+        self.user_limiter = Some(limiter);
+    }
+
+    /// Get per-user connection limiter reference
+    pub fn user_limiter(&self) -> Option<&Arc<UserLimiter>> {
+        self.user_limiter.as_ref()
+    }
+
+    /// Set bans of clients failing handshakes
+    pub fn set_ip_ban_list(&mut self, ip_ban_list: Arc<IpBanList>) {
+        self.ip_ban_list = Some(ip_ban_list);
+    }
+
+    /// Get bans of clients failing handshakes
+    pub fn ip_ban_list(&self) -> Option<&IpBanList> {
+        self.ip_ban_list.as_deref()
+    }
+
+    /// Set if UDP-over-TCP tunnels to magic destinations are relayed as UDP
+    pub fn set_udp_over_tcp(&mut self, enabled: bool) {
+        self.udp_over_tcp = enabled;
+    }
+
+    /// Check if UDP-over-TCP tunnels to magic destinations are relayed as UDP
+    pub fn udp_over_tcp(&self) -> bool {
+        self.udp_over_tcp
+    }
+
+    /// Set if sing-mux sessions to the magic destination are demultiplexed
+    #[cfg(feature = "server-mux")]
+    pub fn set_mux(&mut self, enabled: bool) {
+        self.mux = enabled;
+    }
+
+    /// Check if sing-mux sessions to the magic destination are demultiplexed
+    #[cfg(feature = "server-mux")]
+    pub fn mux(&self) -> bool {
+        self.mux
+    }
+
+    /// Record that an accept loop is still polled
+    pub fn mark_accept_alive(&self) {
+        *self.accept_heartbeat.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since an accept loop was last polled
+    pub fn accept_idle_duration(&self) -> Duration {
+        self.accept_heartbeat.lock().unwrap().elapsed()
+    }
+
+    /// Set backend address, which connections failing handshakes are forwarded to
+    pub fn set_fallback_addr(&mut self, addr: Address) {
+        self.fallback_addr = Some(addr);
+    }
+
+    /// Get backend address of connections failing handshakes
+    pub fn fallback_addr(&self) -> Option<&Address> {
+        self.fallback_addr.as_ref()
+    }
+
+    /// Set load balancers, whose connections and datagrams start with PROXY protocol headers
+    pub fn set_proxy_protocol_trust(&mut self, trust: ProxyProtocolTrust) {
+        self.proxy_protocol_trust = trust;
+    }
+
+    /// Get load balancers sending PROXY protocol headers
+    pub fn proxy_protocol_trust(&self) -> &ProxyProtocolTrust {
+        &self.proxy_protocol_trust
+    }
+
+    /// Replace audit rules at runtime, `None` disables auditing
+    pub fn replace_audit_rules(&self, rules: Option<Arc<AuditRules>>) {
+        self.audit_rules.store(rules);
+    }
+
+    /// Get current audit rules
+    pub fn audit_rules(&self) -> Option<Arc<AuditRules>> {
+        self.audit_rules.load_full()
+    }
+
+    /// Get violations of audit rules reference
+    pub fn audit_log(&self) -> &AuditLog {
+        self.audit_log.as_ref()
+    }
+
+    /// Set access log
+    pub fn set_access_log(&mut self, access_log: Arc<AccessLog>) {
+        self.access_log = Some(access_log);
+    }
+
+    /// Get access log reference
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_deref()
     }
 
     /// Get cloned flow statistic
@@ -82,6 +368,24 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Set flow statistics of nodes sharing the server port, in the order of their numbers
+    pub fn set_shared_node_flow_stats(&mut self, flow_stats: Vec<Arc<FlowStat>>) {
+        self.shared_node_flow_stats = flow_stats;
+    }
+
+    /// Get flow statistics of nodes sharing the server port
+    pub fn shared_node_flow_stats(&self) -> &[Arc<FlowStat>] {
+        &self.shared_node_flow_stats
+    }
+
+    /// Get cloned flow statistic of the node, 0 is the server's own node
+    pub fn node_flow_stat(&self, node: usize) -> Arc<FlowStat> {
+        match node.checked_sub(1).and_then(|idx| self.shared_node_flow_stats.get(idx)) {
+            Some(flow_stat) => flow_stat.clone(),
+            None => self.flow_stat.clone(),
+        }
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -94,16 +398,108 @@ impl ServiceContext {
     }
 
     /// Check if target should be bypassed
+    ///
+    /// Domain names are checked against the internal destinations filter only after they are resolved,
+    /// see `check_resolved_outbound_blocked`.
     pub async fn check_outbound_blocked(&self, addr: &Address) -> bool {
-        match self.acl {
+        if let Address::SocketAddress(ref sa) = *addr
+            && self.check_resolved_outbound_blocked(sa)
+        {
+            return true;
+        }
+
+        match self.acl.load_full() {
             None => false,
-            Some(ref acl) => acl.check_outbound_blocked(&self.context, addr).await,
+            Some(acl) => acl.check_outbound_blocked(&self.context, addr).await,
+        }
+    }
+
+    /// Check if target should be bypassed, with the domain name sniffed from its payload
+    ///
+    /// The sniffed domain is chosen by the client, it could only block the target. A target blocked by
+    /// its own rules stays blocked, even if the sniffed domain matches an allow rule.
+    pub async fn check_sniffed_outbound_blocked(&self, addr: &Address, sniffed: Option<&SniffedDomain>) -> bool {
+        if self.check_outbound_blocked(addr).await {
+            return true;
+        }
+
+        let sniffed = match sniffed {
+            Some(sniffed) => sniffed,
+            None => return false,
+        };
+
+        match self.acl.load_full() {
+            None => false,
+            Some(acl) => acl.check_outbound_host_matched(&sniffed.domain) == Some(true),
+        }
+    }
+
+    /// Check if a resolved target is blocked by the internal destinations filter
+    pub fn check_resolved_outbound_blocked(&self, addr: &SocketAddr) -> bool {
+        match self.internal_addr_filter {
+            None => false,
+            Some(ref filter) => filter.is_blocked(&addr.ip()),
+        }
+    }
+
+    /// Check if outbound connection to `port` should be blocked by the port policy
+    pub fn check_outbound_port_blocked(
+        &self,
+        protocol: PortProtocol,
+        port: u16,
+        user: Option<&ServerUser>,
+        peer_addr: &SocketAddr,
+    ) -> bool {
+        match self.port_policy {
+            None => false,
+            Some(ref policy) => policy.check_blocked(protocol, port, user, peer_addr),
+        }
+    }
+
+    /// Check if target matches any audit rule, violations are recorded in `audit_log`
+    ///
+    /// Returns ID of the matched rule.
+    pub fn check_outbound_audit(&self, addr: &Address, user: Option<&ServerUser>) -> Option<i32> {
+        let rules = self.audit_rules.load();
+        let rule_id = rules.as_ref()?.check_matched(addr)?;
+        self.audit_log.record(user, rule_id, addr);
+        Some(rule_id)
+    }
+
+    /// Check if target, or the domain name sniffed from its payload, matches any audit rule
+    ///
+    /// Returns ID of the matched rule.
+    pub fn check_sniffed_outbound_audit(
+        &self,
+        addr: &Address,
+        sniffed: Option<&SniffedDomain>,
+        user: Option<&ServerUser>,
+    ) -> Option<i32> {
+        if let Some(rule_id) = self.check_outbound_audit(addr, user) {
+            return Some(rule_id);
+        }
+        let sniffed = sniffed?;
+        self.check_outbound_audit(&Address::DomainNameAddress(sniffed.domain.clone(), addr.port()), user)
+    }
+
+    /// Check if client is banned for failing handshakes
+    pub fn check_client_banned(&self, addr: &SocketAddr) -> bool {
+        match self.ip_ban_list {
+            None => false,
+            Some(ref list) => list.is_banned(addr.ip()),
+        }
+    }
+
+    /// Count a failed handshake of client
+    pub fn record_handshake_failure(&self, addr: &SocketAddr, err: &io::Error) {
+        if let (Some(list), Some(failure)) = (self.ip_ban_list.as_ref(), HandshakeFailure::classify(err)) {
+            list.record_failure(addr.ip(), failure);
         }
     }
 
     /// Check if client should be blocked
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
-        match self.acl {
+        match *self.acl.load() {
             None => false,
             Some(ref acl) => acl.check_client_blocked(addr),
         }
@@ -121,6 +517,31 @@ impl ServiceContext {
         context.set_replay_attack_policy(security.replay_attack.policy);
     }
 
+    /// Set settings of the protector against replay attack
+    pub fn set_replay_protector_config(&mut self, config: ReplayProtectorConfig) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set replay protector on a shared context");
+        context.set_replay_protector_config(config);
+    }
+
+    /// Save salts remembered by the protector against replay attack to `path`
+    ///
+    /// The file is synced to disk and replaced at once, so a crash while saving keeps the previous one.
+    pub fn save_replay_salts(&self, path: &Path) -> io::Result<usize> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let saved = self.context.replay_protector().save_salts(&mut writer)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(saved)
+    }
+
+    /// Load salts saved by `save_replay_salts` into the protector against replay attack
+    pub fn load_replay_salts(&self, path: &Path) -> io::Result<usize> {
+        let file = File::open(path)?;
+        self.context.replay_protector().load_salts(BufReader::new(file))
+    }
+
     /// Set AEAD 2022 timestamp limit
     pub fn set_timestamp_limit(&mut self, timestamp_limit: u64) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set timestamp limit on a shared context");
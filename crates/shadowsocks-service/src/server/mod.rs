@@ -18,10 +18,12 @@ pub use self::{
     udprelay::UdpServer,
 };
 
+pub mod access_log;
 pub mod context;
 #[allow(clippy::module_inception)]
 pub mod server;
 mod tcprelay;
+mod udp_over_tcp;
 mod udprelay;
 
 /// Default TCP Keep Alive timeout
@@ -29,6 +31,9 @@ mod udprelay;
 /// This is borrowed from Go's `net` library's default setting
 pub(crate) const SERVER_DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
 
+/// Period of marking accept loops alive in the context, for watchdogs
+pub(crate) const ACCEPT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Starts a shadowsocks server
 pub async fn run(config: Config) -> io::Result<()> {
     assert_eq!(config.config_type, ConfigType::Server);
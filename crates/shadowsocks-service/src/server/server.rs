@@ -1,7 +1,13 @@
 //! Shadowsocks Server instance
 
-use std::{collections::HashMap, io, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    sync::Arc,
+    time::Duration,
+};
 
+use bytes::Bytes;
 use futures::future;
 use log::{error, trace};
 use shadowsocks::{
@@ -13,6 +19,8 @@ use shadowsocks::{
 };
 use tokio::time;
 
+#[cfg(feature = "server-transport")]
+use crate::net::transport::ServerTransport;
 use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat, utils::ServerHandle};
 
 use super::{context::ServiceContext, tcprelay::TcpServer, udprelay::UdpServer};
@@ -26,6 +34,11 @@ pub struct ServerBuilder {
     manager_addr: Option<ManagerAddr>,
     accept_opts: AcceptOpts,
     relay_cfg: Option<ServerConfig>,
+    shared_nodes: Vec<(ServerConfig, Arc<FlowStat>)>,
+    #[cfg(feature = "server-transport")]
+    transport: Option<ServerTransport>,
+    tcp_listener: Option<std::net::TcpListener>,
+    udp_socket: Option<std::net::UdpSocket>,
 }
 
 impl ServerBuilder {
@@ -44,6 +57,11 @@ impl ServerBuilder {
             manager_addr: None,
             accept_opts: AcceptOpts::default(),
             relay_cfg: None,
+            shared_nodes: Vec::new(),
+            #[cfg(feature = "server-transport")]
+            transport: None,
+            tcp_listener: None,
+            udp_socket: None,
         }
     }
 
@@ -122,16 +140,89 @@ impl ServerBuilder {
         self.relay_cfg = Some(ServerConfig::from_url(encoded).expect("invalid shadowsocks url"))
     }
 
+    /// Share the server port with another node, distinguished by its server key (iPSK)
+    ///
+    /// `svr_cfg` carries the key and users of the node, with the same method as this server.
+    /// Traffic of its users is counted in `flow_stat`.
+    pub fn add_shared_node(&mut self, svr_cfg: ServerConfig, flow_stat: Arc<FlowStat>) {
+        self.shared_nodes.push((svr_cfg, flow_stat));
+    }
+
+    /// Wrap TCP connections in an in-process transport, instead of a SIP003 plugin
+    ///
+    /// UDP is not affected, like with simple-obfs and v2ray-plugin.
+    #[cfg(feature = "server-transport")]
+    pub fn set_transport(&mut self, transport: ServerTransport) {
+        self.transport = Some(transport);
+    }
+
+    /// Serve TCP on a bound listener, like one activated by systemd, instead of binding the server address
+    pub fn set_tcp_listener(&mut self, listener: std::net::TcpListener) {
+        self.tcp_listener = Some(listener);
+    }
+
+    /// Serve UDP on a bound socket, like one activated by systemd, instead of binding the server address
+    pub fn set_udp_socket(&mut self, socket: std::net::UdpSocket) {
+        self.udp_socket = Some(socket);
+    }
+
+    /// Pass nodes sharing the server port to the user manager, which tries their keys on identity headers
+    fn apply_shared_nodes(&mut self) -> io::Result<()> {
+        let Some(user_manager) = self.svr_cfg.user_manager() else {
+            if self.shared_nodes.is_empty() {
+                return Ok(());
+            }
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "sharing a port requires users, nodes are distinguished by keys of their users",
+            ));
+        };
+
+        let mut nodes = Vec::with_capacity(self.shared_nodes.len());
+        let mut flow_stats = Vec::with_capacity(self.shared_nodes.len());
+        for (node_cfg, flow_stat) in self.shared_nodes.drain(..) {
+            if node_cfg.method() != self.svr_cfg.method() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "node sharing the port uses method {}, expecting {}",
+                        node_cfg.method(),
+                        self.svr_cfg.method()
+                    ),
+                ));
+            }
+            let node_user_manager = node_cfg.clone_user_manager().ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidInput, "node sharing the port has no users")
+            })?;
+            nodes.push((Bytes::copy_from_slice(node_cfg.key()), node_user_manager));
+            flow_stats.push(flow_stat);
+        }
+
+        user_manager.set_shared_nodes(nodes);
+        self.context.set_shared_node_flow_stats(flow_stats);
+        Ok(())
+    }
+
     /// Start the server
     ///
     /// 1. Starts plugin (subprocess)
     /// 2. Starts TCP server (listener)
     /// 3. Starts UDP server (listener)
     pub async fn build(mut self) -> io::Result<Server> {
+        self.apply_shared_nodes()?;
+
         let context = Arc::new(self.context);
 
         let mut plugin = None;
 
+        #[cfg(feature = "server-transport")]
+        if self.transport.is_some() && self.svr_cfg.plugin().is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "transport and plugin are exclusive, both wrap the same connections",
+            ));
+        }
+
         if let Some(plugin_cfg) = self.svr_cfg.plugin() {
             let plugin_process = Plugin::start(plugin_cfg, self.svr_cfg.addr(), PluginMode::Server)?;
             self.svr_cfg.set_plugin_addr(plugin_process.local_addr().into());
@@ -140,7 +231,19 @@ impl ServerBuilder {
 
         let mut tcp_server = None;
         if self.svr_cfg.mode().enable_tcp() {
-            let server = TcpServer::new(context.clone(), self.svr_cfg.clone(), self.accept_opts.clone(), self.relay_cfg.clone()).await?;
+            #[cfg_attr(not(feature = "server-transport"), allow(unused_mut))]
+            let mut server = TcpServer::new(
+                context.clone(),
+                self.svr_cfg.clone(),
+                self.accept_opts.clone(),
+                self.relay_cfg.clone(),
+                self.tcp_listener.take(),
+            )
+            .await?;
+            #[cfg(feature = "server-transport")]
+            if let Some(transport) = self.transport.take() {
+                server.set_transport(transport);
+            }
             tcp_server = Some(server);
         }
 
@@ -153,6 +256,7 @@ impl ServerBuilder {
                 self.udp_capacity,
                 self.accept_opts.clone(),
                 self.relay_cfg.clone(),
+                self.udp_socket.take(),
             )
             .await?;
             udp_server = Some(server);
@@ -2,40 +2,90 @@
 
 use std::{
     future::Future,
-    io::{self, ErrorKind},
+    io::{self, Error, ErrorKind},
     net::SocketAddr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
-use futures::FutureExt;
 use log::{debug, error, info, trace, warn};
 use shadowsocks::{
     ProxyClientStream, ProxyListener, ServerConfig,
+    config::ServerUser,
     crypto::CipherKind,
-    net::{AcceptOpts, TcpStream as OutboundTcpStream},
-    relay::tcprelay::utils::copy_encrypted_bidirectional,
+    lookup_then_connect,
+    net::{AcceptOpts, AddrFamily, ConnectOpts, TcpListener as ShadowTcpListener, TcpStream as OutboundTcpStream},
+    relay::{
+        Address,
+        tcprelay::{GetUser, utils::copy_encrypted_bidirectional},
+    },
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream as TokioTcpStream,
+    net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream},
     time,
 };
 
-use crate::net::{MonProxyStream, utils::ignore_until_end};
+use crate::{
+    acl::{P2pAction, PortProtocol},
+    net::{
+        MonProxyStream,
+        RecordStream,
+        proxy_protocol,
+        ThrottledStream,
+        sniff::{MAX_SNIFF_BUFFER_SIZE, SniffResult, SniffedDomain, is_bittorrent_stream, is_tracker_domain, sniff_stream},
+        utils::{copy_bidirectional_idle, ignore_until_end},
+    },
+};
+#[cfg(feature = "server-mux")]
+use tokio::sync::Semaphore;
+
+#[cfg(feature = "server-mux")]
+use crate::net::mux::{MuxNetwork, MuxSession, is_mux_destination};
+#[cfg(feature = "server-transport")]
+use crate::net::transport::{ServerTransport, TransportStream};
+
+use super::{
+    access_log::{AccessLogEntry, AccessProtocol, CountedStream},
+    ACCEPT_HEARTBEAT_INTERVAL,
+    context::ServiceContext,
+    udp_over_tcp::{self, PacketFraming, UdpOverTcpVersion},
+};
+
+/// Bytes recorded for replaying to the fallback backend, more than this is never sent by probers
+const FALLBACK_RECORD_LIMIT: usize = 16 * 1024;
 
-use super::context::ServiceContext;
+/// Relays to the fallback backend are closed after being idle this long
+const FALLBACK_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time waiting for the first payload only to detect P2P traffic, when sniffing is disabled
+///
+/// BitTorrent clients speak first, their handshakes come right after the request header.
+/// Connections of protocols which servers speak first are delayed only this long.
+const P2P_SNIFF_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Streams relayed at once in a sing-mux session, more are reset right after being opened
+#[cfg(feature = "server-mux")]
+const MAX_MUX_STREAMS: usize = 128;
 
 pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
 
 impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
 
+/// Accepted stream under the shadowsocks layer
+#[cfg(feature = "server-transport")]
+type ClientStream = TransportStream<RecordStream<TokioTcpStream>>;
+#[cfg(not(feature = "server-transport"))]
+type ClientStream = RecordStream<TokioTcpStream>;
+
 /// TCP server instance
 pub struct TcpServer {
     context: Arc<ServiceContext>,
     svr_cfg: ServerConfig,
-    listener: ProxyListener,
+    listener: Arc<ProxyListener>,
     relay_cfg: Option<ServerConfig>,
+    #[cfg(feature = "server-transport")]
+    transport: Option<ServerTransport>,
 }
 
 impl TcpServer {
@@ -44,16 +94,33 @@ impl TcpServer {
         svr_cfg: ServerConfig,
         accept_opts: AcceptOpts,
         relay_cfg: Option<ServerConfig>,
+        std_listener: Option<std::net::TcpListener>,
     ) -> io::Result<Self> {
-        let listener = ProxyListener::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?;
+        let listener = match std_listener {
+            Some(std_listener) => {
+                std_listener.set_nonblocking(true)?;
+                let tokio_listener = TokioTcpListener::from_std(std_listener)?;
+                let listener = ShadowTcpListener::from_listener(tokio_listener, accept_opts)?;
+                ProxyListener::from_listener(context.context(), listener, &svr_cfg)
+            }
+            None => ProxyListener::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?,
+        };
         Ok(Self {
             context,
             svr_cfg,
-            listener,
+            listener: Arc::new(listener),
             relay_cfg,
+            #[cfg(feature = "server-transport")]
+            transport: None,
         })
     }
 
+    /// Wrap accepted streams in `transport` before the shadowsocks layer
+    #[cfg(feature = "server-transport")]
+    pub(crate) fn set_transport(&mut self, transport: ServerTransport) {
+        self.transport = Some(transport);
+    }
+
     /// Server's configuration
     pub fn server_config(&self) -> &ServerConfig {
         &self.svr_cfg
@@ -72,14 +139,30 @@ impl TcpServer {
             self.svr_cfg.addr()
         );
 
+        // Bytes read before handshakes are kept for the fallback backend
+        let record_limit = if self.context.fallback_addr().is_some() {
+            FALLBACK_RECORD_LIMIT
+        } else {
+            0
+        };
+
+        #[cfg(feature = "server-transport")]
+        if let Some(ref transport) = self.transport {
+            info!("shadowsocks tcp server transport {:?}", transport);
+        }
+
+        let mut heartbeat = time::interval(ACCEPT_HEARTBEAT_INTERVAL);
         loop {
             let flow_stat = self.context.flow_stat();
 
-            let (local_stream, peer_addr) = match self
-                .listener
-                .accept_map(|s| MonProxyStream::from_stream(s, flow_stat))
-                .await
-            {
+            let accepted = tokio::select! {
+                r = self.listener.get_ref().accept() => r,
+                _ = heartbeat.tick() => {
+                    self.context.mark_accept_alive();
+                    continue;
+                }
+            };
+            let (local_stream, peer_addr) = match accepted {
                 Ok(s) => s,
                 Err(err) => {
                     error!("tcp server accept failed with error: {}", err);
@@ -88,21 +171,67 @@ impl TcpServer {
                 }
             };
 
-            if self.context.check_client_blocked(&peer_addr) {
-                warn!("access denied from {} by ACL rules", peer_addr);
+            // Clients behind load balancers are checked after reading their headers
+            let proxied = self.context.proxy_protocol_trust().is_trusted(&peer_addr);
+            if !proxied && !check_client_allowed(&self.context, &peer_addr) {
                 continue;
             }
 
-            let client = TcpServerClient {
-                context: self.context.clone(),
-                method: self.svr_cfg.method(),
-                peer_addr,
-                stream: local_stream,
-                timeout: self.svr_cfg.timeout(),
-                relay_cfg: self.relay_cfg.clone(),
-            };
+            let context = self.context.clone();
+            let listener = self.listener.clone();
+            let method = self.svr_cfg.method();
+            let timeout = self.svr_cfg.timeout();
+            let relay_cfg = self.relay_cfg.clone();
+            #[cfg(feature = "server-transport")]
+            let transport = self.transport.clone();
 
             tokio::spawn(async move {
+                let mut local_stream = local_stream;
+                let peer_addr = if proxied {
+                    match timeout_fut(timeout, proxy_protocol::read_header(&mut local_stream)).await {
+                        Ok(source) => {
+                            let source = source.unwrap_or(peer_addr);
+                            trace!("tcp client {} relayed by load balancer {}", source, peer_addr);
+                            if !check_client_allowed(&context, &source) {
+                                return;
+                            }
+                            source
+                        }
+                        Err(err) => {
+                            warn!("tcp proxy protocol header from {} failed, {}", peer_addr, err);
+                            return;
+                        }
+                    }
+                } else {
+                    peer_addr
+                };
+
+                let local_stream = RecordStream::new(local_stream, record_limit);
+
+                #[cfg(feature = "server-transport")]
+                let local_stream = match transport {
+                    None => TransportStream::Plain(local_stream),
+                    Some(ref transport) => {
+                        match accept_transport(&context, transport, peer_addr, local_stream, timeout).await {
+                            Ok(Some(s)) => s,
+                            Ok(None) => return,
+                            Err(err) => {
+                                debug!("tcp server stream aborted with error: {}", err);
+                                return;
+                            }
+                        }
+                    }
+                };
+
+                let client = TcpServerClient {
+                    context,
+                    method,
+                    peer_addr,
+                    stream: MonProxyStream::from_stream(listener.wrap_stream(local_stream, peer_addr), flow_stat),
+                    timeout,
+                    relay_cfg,
+                };
+
                 if let Err(err) = client.serve().await {
                     debug!("tcp server stream aborted with error: {}", err);
                 }
@@ -111,6 +240,64 @@ impl TcpServer {
     }
 }
 
+/// Check if the client is allowed by ACL rules and not banned
+fn check_client_allowed(context: &ServiceContext, peer_addr: &SocketAddr) -> bool {
+    if context.check_client_blocked(peer_addr) {
+        warn!("access denied from {} by ACL rules", peer_addr);
+        return false;
+    }
+
+    if context.check_client_banned(peer_addr) {
+        debug!("access denied from {}, banned for failing handshakes", peer_addr);
+        return false;
+    }
+
+    true
+}
+
+/// Complete the transport handshake, returns `None` if the client failed or was served by the transport
+#[cfg(feature = "server-transport")]
+async fn accept_transport(
+    context: &ServiceContext,
+    transport: &ServerTransport,
+    peer_addr: SocketAddr,
+    stream: RecordStream<TokioTcpStream>,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ClientStream>> {
+    match transport.accept(stream, timeout).await {
+        Ok(Some(mut stream)) => {
+            // Bytes read are encrypted by TLS, replaying them is useless
+            if transport.is_tls() {
+                stream.get_mut().stop_recording();
+            }
+            Ok(Some(stream))
+        }
+        Ok(None) => {
+            trace!("tcp client {} served by transport {:?}", peer_addr, transport);
+            Ok(None)
+        }
+        Err((err, _)) if err.kind() == ErrorKind::UnexpectedEof => {
+            debug!(
+                "tcp transport handshake failed, received EOF before completed, peer: {}",
+                peer_addr
+            );
+            Ok(None)
+        }
+        Err((err, stream)) => {
+            warn!("tcp transport handshake failed. peer: {}, {}", peer_addr, err);
+
+            // Probers served by the fallback aren't banned, the switch to closing would tell them apart
+            match stream {
+                Some(stream) if context.fallback_addr().is_some() && stream.is_recording() => {
+                    serve_fallback(context, peer_addr, stream, timeout).await?;
+                }
+                _ => context.record_handshake_failure(&peer_addr, &err),
+            }
+            Ok(None)
+        }
+    }
+}
+
 #[inline]
 async fn timeout_fut<F, R>(duration: Option<Duration>, f: F) -> io::Result<R>
 where
@@ -125,11 +312,73 @@ where
     }
 }
 
+/// Connect to `addr` directly, binding a source address of the family of each resolved address
+///
+/// If `check_internal`, resolved addresses are checked by the internal destinations filter.
+async fn connect_remote_checked(
+    context: &ServiceContext,
+    addr: &Address,
+    user: Option<&ServerUser>,
+    peer_addr: &SocketAddr,
+    check_internal: bool,
+) -> io::Result<OutboundTcpStream> {
+    match *addr {
+        Address::SocketAddress(ref addr) => {
+            let opts = context.connect_opts_for(AddrFamily::from(addr), user, peer_addr);
+            OutboundTcpStream::connect_with_opts(addr, &opts).await
+        }
+        Address::DomainNameAddress(ref domain, port) => {
+            lookup_then_connect!(context.context_ref(), domain, port, |addr| {
+                if check_internal && context.check_resolved_outbound_blocked(&addr) {
+                    Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("{domain} resolved to internal address {addr}"),
+                    ))
+                } else {
+                    let opts = context.connect_opts_for(AddrFamily::from(&addr), user, peer_addr);
+                    OutboundTcpStream::connect_with_opts(&addr, &opts).await
+                }
+            })
+            .map(|(_, stream)| stream)
+        }
+    }
+}
+
+/// Read the first payload from the client until a domain name is sniffed
+///
+/// Payload read is kept in `buf`. Returns `UnexpectedEof` if the client closed without sending anything.
+async fn sniff_first_payload<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    timeout: Duration,
+) -> io::Result<Option<SniffedDomain>> {
+    let deadline = time::Instant::now() + timeout;
+    let mut chunk = [0u8; 4096];
+
+    while buf.len() < MAX_SNIFF_BUFFER_SIZE {
+        let n = match time::timeout_at(deadline, stream.read(&mut chunk)).await {
+            Ok(Ok(0)) if buf.is_empty() => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(Ok(0)) | Err(..) => break,
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => return Err(err),
+        };
+        buf.extend_from_slice(&chunk[..n]);
+
+        match sniff_stream(buf) {
+            SniffResult::Found(sniffed) => return Ok(Some(sniffed)),
+            SniffResult::NotFound => break,
+            SniffResult::Incomplete => {}
+        }
+    }
+
+    Ok(None)
+}
+
 struct TcpServerClient {
     context: Arc<ServiceContext>,
     method: CipherKind,
     peer_addr: SocketAddr,
-    stream: MonProxyStream<TokioTcpStream>,
+    stream: MonProxyStream<ClientStream>,
     timeout: Option<Duration>,
     relay_cfg: Option<ServerConfig>,
 }
@@ -158,6 +407,11 @@ impl TcpServerClient {
                     "tcp handshake failed, timeout before a complete target Address, peer: {}",
                     self.peer_addr
                 );
+                // Probers sending short requests are waiting for responses
+                if self.can_fallback() {
+                    return self.serve_fallback().await;
+                }
+                self.context.record_handshake_failure(&self.peer_addr, &err);
                 return Ok(());
             }
             Err(err) => {
@@ -166,12 +420,18 @@ impl TcpServerClient {
                 // Keep connection open. Except AEAD-2022
                 warn!("tcp handshake failed. peer: {}, {}", self.peer_addr, err);
 
+                // Probers served by the fallback aren't banned, the switch to closing would tell them apart
+                if self.can_fallback() {
+                    return self.serve_fallback().await;
+                }
+                self.context.record_handshake_failure(&self.peer_addr, &err);
+
                 #[cfg(feature = "aead-cipher-2022")]
                 if self.method.is_aead_2022() {
                     // Set SO_LINGER(0) for misbehave clients, which will eventually receive RST. (ECONNRESET)
                     // This will also prevent the socket entering TIME_WAIT state.
 
-                    let stream = self.stream.into_inner().into_inner();
+                    let (stream, _) = self.into_record_stream().into_parts();
                     let _ = stream.set_linger(Some(Duration::ZERO));
 
                     return Ok(());
@@ -196,12 +456,171 @@ impl TcpServerClient {
             }
         };
 
+        self.record_stream_mut().stop_recording();
+
+        // Users of nodes sharing the port are counted separately
+        let node = self.stream.get_ref().node();
+        if node > 0 {
+            self.stream.set_flow_stat(self.context.node_flow_stat(node));
+        }
+
+        let user = self.stream.get_ref().user();
+        let Self {
+            context,
+            method,
+            peer_addr,
+            mut stream,
+            timeout,
+            relay_cfg,
+        } = self;
+        let relay = TcpRelay {
+            context,
+            method,
+            peer_addr,
+            timeout,
+            relay_cfg,
+        };
+
+        // Tunnels are limited and logged as UDP associations
+        if relay.context.udp_over_tcp()
+            && let Some(version) = UdpOverTcpVersion::of(&target_addr)
+        {
+            let framing = timeout_fut(timeout, udp_over_tcp::read_framing(&mut stream, version)).await?;
+            return relay.relay_udp(stream, framing, user, node).await;
+        }
+
+        #[cfg(feature = "server-mux")]
+        if relay.context.mux() && is_mux_destination(&target_addr) {
+            return relay.relay_mux(stream, user, node).await;
+        }
+
+        relay.relay_tcp(&mut stream, user, target_addr).await
+    }
+
+    /// Accepted stream, recording bytes read for the fallback backend
+    fn record_stream(&self) -> &RecordStream<TokioTcpStream> {
+        let stream = self.stream.get_ref().get_ref();
+        #[cfg(feature = "server-transport")]
+        let stream = stream.get_ref();
+        stream
+    }
+
+    fn record_stream_mut(&mut self) -> &mut RecordStream<TokioTcpStream> {
+        let stream = self.stream.get_mut().get_mut();
+        #[cfg(feature = "server-transport")]
+        let stream = stream.get_mut();
+        stream
+    }
+
+    fn into_record_stream(self) -> RecordStream<TokioTcpStream> {
+        let stream = self.stream.into_inner().into_inner();
+        #[cfg(feature = "server-transport")]
+        let stream = stream.into_inner();
+        stream
+    }
+
+    /// Check if the connection could be forwarded to the fallback backend
+    fn can_fallback(&self) -> bool {
+        self.context.fallback_addr().is_some() && self.record_stream().is_recording()
+    }
+
+    /// Forward the connection to the fallback backend
+    async fn serve_fallback(self) -> io::Result<()> {
+        let context = self.context.clone();
+        let peer_addr = self.peer_addr;
+        let timeout = self.timeout;
+        serve_fallback(&context, peer_addr, self.into_record_stream(), timeout).await
+    }
+}
+
+/// Relays of a client's streams to destinations, shared by streams multiplexed in its connection
+struct TcpRelay {
+    context: Arc<ServiceContext>,
+    method: CipherKind,
+    peer_addr: SocketAddr,
+    timeout: Option<Duration>,
+    relay_cfg: Option<ServerConfig>,
+}
+
+impl TcpRelay {
+    /// Relay the stream to `target_addr`, applying limits and policies of the user
+    async fn relay_tcp<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        stream: &mut S,
+        user: Option<Arc<ServerUser>>,
+        target_addr: Address,
+    ) -> io::Result<()> {
+        let start = SystemTime::now();
+        let started = Instant::now();
+
+        // Held until the connection is closed
+        let _limit_guard = match (self.context.user_limiter(), user.as_ref()) {
+            (Some(limiter), Some(user)) => match limiter.acquire_tcp(user) {
+                Ok(guard) => guard,
+                Err(err) => {
+                    error!(
+                        "tcp client {} user {} rejected, {}",
+                        self.peer_addr,
+                        user.name(),
+                        err
+                    );
+                    return Ok(());
+                }
+            },
+            _ => None,
+        };
+
         trace!(
             "accepted tcp client connection {}, establishing tunnel to {}",
             self.peer_addr, target_addr
         );
 
-        if self.context.check_outbound_blocked(&target_addr).await {
+        // Payload read while sniffing, will be sent after connected
+        let mut initial_data = Vec::new();
+        let sniff_config = *self.context.sniff_config();
+        let p2p_policy = self.context.p2p_policy();
+        let sniffed = if sniff_config.tcp || p2p_policy.is_some() {
+            let timeout = if sniff_config.tcp {
+                sniff_config.timeout
+            } else {
+                sniff_config.timeout.min(P2P_SNIFF_TIMEOUT)
+            };
+            match sniff_first_payload(stream, &mut initial_data, timeout).await {
+                Ok(sniffed) => sniffed,
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        } else {
+            None
+        };
+
+        let is_p2p = p2p_policy.is_some()
+            && (is_bittorrent_stream(&initial_data)
+                || sniffed.as_ref().is_some_and(|s| is_tracker_domain(&s.domain)));
+        // Domain names are only sniffed for P2P detection if sniffing is disabled
+        let sniffed = sniffed.filter(|_| sniff_config.tcp);
+
+        let target_addr = match sniffed {
+            Some(ref sniffed) => {
+                debug!(
+                    "tcp client {} outbound {} sniffed {} domain {}",
+                    self.peer_addr, target_addr, sniffed.protocol, sniffed.domain
+                );
+
+                if sniff_config.override_destination {
+                    Address::DomainNameAddress(sniffed.domain.clone(), target_addr.port())
+                } else {
+                    target_addr
+                }
+            }
+            None => target_addr,
+        };
+
+        if self
+            .context
+            .check_sniffed_outbound_blocked(&target_addr, sniffed.as_ref())
+            .await
+        {
             error!(
                 "tcp client {} outbound {} blocked by ACL rules",
                 self.peer_addr, target_addr
@@ -209,24 +628,99 @@ impl TcpServerClient {
             return Ok(());
         }
 
-        let mut remote_stream = match timeout_fut(
-            self.timeout,
-            match self.relay_cfg.as_ref() {
-                Some(relay_cfg) => ProxyClientStream::connect_with_opts_map(
-                    self.context.context(),
-                    relay_cfg,
-                    &target_addr,
-                    self.context.connect_opts_ref(),
-                ).map(|res| res.map(|s| Box::new(s) as Box<dyn AsyncStream>)).boxed(),
-                None => OutboundTcpStream::connect_remote_with_opts(
-                    self.context.context_ref(),
-                    &target_addr,
-                    self.context.connect_opts_ref(),
-                ).map(|res| res.map(|s| Box::new(s) as Box<dyn AsyncStream>)).boxed(),
-            },
-        )
-        .await
+        if self.context.check_outbound_port_blocked(
+            PortProtocol::Tcp,
+            target_addr.port(),
+            user.as_deref(),
+            &self.peer_addr,
+        ) {
+            error!(
+                "tcp client {} outbound {} blocked by port policy",
+                self.peer_addr, target_addr
+            );
+            return Ok(());
+        }
+
+        if let Some(rule_id) = self
+            .context
+            .check_sniffed_outbound_audit(&target_addr, sniffed.as_ref(), user.as_deref())
+        {
+            error!(
+                "tcp client {} outbound {} blocked by audit rule {}",
+                self.peer_addr, target_addr, rule_id
+            );
+            return Ok(());
+        }
+
+        let mut p2p_limiter = None;
+        if let Some(policy) = p2p_policy
+            && is_p2p
         {
+            p2p_limiter = policy.record(user.as_deref(), &self.peer_addr);
+            match policy.action() {
+                P2pAction::Block => {
+                    error!(
+                        "tcp client {} outbound {} blocked by P2P policy",
+                        self.peer_addr, target_addr
+                    );
+                    return Ok(());
+                }
+                P2pAction::Throttle => warn!(
+                    "tcp client {} outbound {} P2P traffic throttled",
+                    self.peer_addr, target_addr
+                ),
+                P2pAction::Log => warn!(
+                    "tcp client {} outbound {} P2P traffic detected",
+                    self.peer_addr, target_addr
+                ),
+            }
+        }
+
+        let connect_fut = async {
+            match self.relay_cfg.as_ref() {
+                Some(relay_cfg) => {
+                    let stream = timeout_fut(
+                        relay_cfg.timeout(),
+                        connect_remote_checked(
+                            &self.context,
+                            &Address::from(relay_cfg.tcp_external_addr()),
+                            user.as_deref(),
+                            &self.peer_addr,
+                            false,
+                        ),
+                    )
+                    .await?;
+                    let local_addr = stream.local_addr().ok();
+                    let stream = ProxyClientStream::from_stream(self.context.context(), stream, relay_cfg, &target_addr);
+                    Ok::<_, io::Error>((
+                        Box::new(stream) as Box<dyn AsyncStream>,
+                        Some(relay_cfg.addr().to_string()),
+                        local_addr,
+                    ))
+                }
+                None => {
+                    let stream = connect_remote_checked(
+                        &self.context,
+                        &target_addr,
+                        user.as_deref(),
+                        &self.peer_addr,
+                        self.context.internal_addr_filter().is_some(),
+                    )
+                    .await?;
+                    let local_addr = stream.local_addr().ok();
+                    let outbound = if self.context.bind_addr_pool().is_some()
+                        || self.context.connect_opts_ref().bind_local_addr.is_some()
+                    {
+                        local_addr.map(|addr| addr.ip().to_string())
+                    } else {
+                        None
+                    };
+                    Ok((Box::new(stream) as Box<dyn AsyncStream>, outbound, local_addr))
+                }
+            }
+        };
+
+        let (mut remote_stream, outbound, local_addr) = match timeout_fut(self.timeout, connect_fut).await {
             Ok(s) => s,
             Err(err) => {
                 error!(
@@ -237,57 +731,214 @@ impl TcpServerClient {
             }
         };
 
-        // https://github.com/shadowsocks/shadowsocks-rust/issues/232
-        //
-        // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
-        //
-        // Wait at most 500ms, and then sends handshake packet to remote servers.
-        if self.context.connect_opts_ref().tcp.fastopen {
-            let mut buffer = [0u8; 8192];
-            match time::timeout(Duration::from_millis(500), self.stream.read(&mut buffer)).await {
-                Ok(Ok(0)) => {
-                    // EOF. Just terminate right here.
-                    return Ok(());
+        if let Some(limiter) = p2p_limiter {
+            remote_stream = Box::new(ThrottledStream::new(remote_stream, vec![limiter]));
+        }
+
+        let mut remote_stream = CountedStream::new(remote_stream);
+
+        let result = 'relay: {
+            // https://github.com/shadowsocks/shadowsocks-rust/issues/232
+            //
+            // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
+            //
+            // Wait at most 500ms, and then sends handshake packet to remote servers.
+            if !initial_data.is_empty() {
+                // Send the payload read while sniffing
+                if let Err(err) = timeout_fut(self.timeout, remote_stream.write_all(&initial_data)).await {
+                    break 'relay Err(err);
                 }
-                Ok(Ok(n)) => {
-                    // Send the first packet.
-                    timeout_fut(self.timeout, remote_stream.write_all(&buffer[..n])).await?;
+            } else if self.context.connect_opts_ref().tcp.fastopen {
+                let mut buffer = [0u8; 8192];
+                match time::timeout(Duration::from_millis(500), stream.read(&mut buffer)).await {
+                    Ok(Ok(0)) => {
+                        // EOF. Just terminate right here.
+                        break 'relay Ok(());
+                    }
+                    Ok(Ok(n)) => {
+                        // Send the first packet.
+                        if let Err(err) = timeout_fut(self.timeout, remote_stream.write_all(&buffer[..n])).await {
+                            break 'relay Err(err);
+                        }
+                    }
+                    Ok(Err(err)) => break 'relay Err(err),
+                    Err(..) => {
+                        // Timeout. Send handshake to server.
+                        if let Err(err) = timeout_fut(self.timeout, remote_stream.write(&[])).await {
+                            break 'relay Err(err);
+                        }
+
+                        trace!(
+                            "tcp tunnel {} -> {} sent TFO connect without data",
+                            self.peer_addr, target_addr
+                        );
+                    }
                 }
-                Ok(Err(err)) => return Err(err),
-                Err(..) => {
-                    // Timeout. Send handshake to server.
-                    timeout_fut(self.timeout, remote_stream.write(&[])).await?;
+            }
+
+            // Source address bound for this connection, chosen from the outbound pool if any
+            match sniffed {
+                Some(ref sniffed) => debug!(
+                    "established tcp tunnel {} <-> {} ({} {}) from {:?}",
+                    self.peer_addr, target_addr, sniffed.protocol, sniffed.domain, local_addr
+                ),
+                None => debug!(
+                    "established tcp tunnel {} <-> {} from {:?}",
+                    self.peer_addr, target_addr, local_addr
+                ),
+            }
 
+            match copy_encrypted_bidirectional(self.method, stream, &mut remote_stream).await {
+                Ok((rn, wn)) => {
                     trace!(
-                        "tcp tunnel {} -> {} sent TFO connect without data",
-                        self.peer_addr, target_addr
+                        "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                        self.peer_addr, target_addr, rn, wn
+                    );
+                }
+                Err(err) => {
+                    trace!(
+                        "tcp tunnel {} <-> {} closed with error: {}",
+                        self.peer_addr, target_addr, err
                     );
                 }
             }
+
+            Ok(())
+        };
+
+        if let Some(access_log) = self.context.access_log() {
+            access_log.log(AccessLogEntry {
+                start,
+                user: user.as_ref().map(|u| u.name().to_owned()),
+                client: self.peer_addr,
+                protocol: AccessProtocol::Tcp,
+                destination: target_addr.to_string(),
+                sniffed: sniffed.map(|s| s.domain),
+                outbound,
+                bytes_up: remote_stream.written_bytes(),
+                bytes_down: remote_stream.read_bytes(),
+                duration: started.elapsed(),
+            });
         }
 
+        result
+    }
+
+    /// Relay UDP packets tunneled in the stream, limited and logged as a UDP association
+    async fn relay_udp<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: S,
+        framing: PacketFraming,
+        user: Option<Arc<ServerUser>>,
+        node: usize,
+    ) -> io::Result<()> {
+        udp_over_tcp::serve(
+            self.context.clone(),
+            self.peer_addr,
+            stream,
+            framing,
+            user,
+            node,
+            self.relay_cfg.clone(),
+        )
+        .await
+    }
+
+    /// Relay streams of a sing-mux session independently, each checked and logged like a connection
+    #[cfg(feature = "server-mux")]
+    async fn relay_mux(
+        self,
+        stream: MonProxyStream<ClientStream>,
+        user: Option<Arc<ServerUser>>,
+        node: usize,
+    ) -> io::Result<()> {
+        let mut session = timeout_fut(self.timeout, MuxSession::accept(stream)).await?;
         debug!(
-            "established tcp tunnel {} <-> {} with {:?}",
+            "tcp client {} multiplexing streams with {:?}",
             self.peer_addr,
-            target_addr,
-            self.context.connect_opts_ref()
+            session.protocol()
         );
 
-        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut remote_stream).await {
-            Ok((rn, wn)) => {
-                trace!(
-                    "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
-                    self.peer_addr, target_addr, rn, wn
-                );
-            }
-            Err(err) => {
-                trace!(
-                    "tcp tunnel {} <-> {} closed with error: {}",
-                    self.peer_addr, target_addr, err
+        let relay = Arc::new(self);
+        let streams = Arc::new(Semaphore::new(MAX_MUX_STREAMS));
+        while let Some(stream) = session.next_stream().await {
+            let mut stream = stream?;
+            // Waiting for a permit would stop driving streams of the session
+            let Ok(permit) = streams.clone().try_acquire_owned() else {
+                debug!(
+                    "tcp client {} mux stream rejected, {} streams relaying",
+                    relay.peer_addr, MAX_MUX_STREAMS
                 );
-            }
+                stream.reset();
+                continue;
+            };
+            let relay = relay.clone();
+            let user = user.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                let request = match timeout_fut(relay.timeout, stream.accept_request()).await {
+                    Ok(request) => request,
+                    Err(err) => {
+                        debug!("tcp client {} mux stream request failed, {}", relay.peer_addr, err);
+                        return;
+                    }
+                };
+
+                let result = match request.network {
+                    MuxNetwork::Tcp => relay.relay_tcp(&mut stream, user, request.destination).await,
+                    MuxNetwork::Udp { packet_addr } => {
+                        let framing = if packet_addr {
+                            PacketFraming::SocksAddress
+                        } else {
+                            PacketFraming::Connected(request.destination)
+                        };
+                        relay.relay_udp(stream, framing, user, node).await
+                    }
+                };
+                if let Err(err) = result {
+                    debug!("tcp client {} mux stream aborted with error: {}", relay.peer_addr, err);
+                }
+            });
         }
 
+        trace!("tcp client {} mux session closed", relay.peer_addr);
         Ok(())
     }
 }
+
+/// Forward the connection to the fallback backend, replaying all bytes read from the client
+///
+/// Probers will see the backend's responses, instead of being reset by the server.
+/// Connecting and replaying are limited by `timeout`, the relay is closed when idle.
+async fn serve_fallback(
+    context: &ServiceContext,
+    peer_addr: SocketAddr,
+    stream: RecordStream<TokioTcpStream>,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let fallback_addr = context.fallback_addr().expect("fallback address").clone();
+    let (mut local_stream, recorded) = stream.into_parts();
+    let recorded = recorded.unwrap_or_default();
+
+    debug!(
+        "tcp client {} failed handshake, forwarding {} bytes to fallback {}",
+        peer_addr,
+        recorded.len(),
+        fallback_addr
+    );
+
+    // Backends are usually local, outbound options are not applied
+    let mut remote_stream = timeout_fut(
+        timeout,
+        OutboundTcpStream::connect_remote_with_opts(context.context_ref(), &fallback_addr, &ConnectOpts::default()),
+    )
+    .await?;
+    timeout_fut(timeout, remote_stream.write_all(&recorded)).await?;
+
+    let res = copy_bidirectional_idle(&mut local_stream, &mut remote_stream, FALLBACK_IDLE_TIMEOUT).await;
+    trace!(
+        "tcp client {} fallback to {} closed with result {:?}",
+        peer_addr, fallback_addr, res
+    );
+    Ok(())
+}
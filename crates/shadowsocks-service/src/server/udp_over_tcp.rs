@@ -0,0 +1,269 @@
+//! UDP-over-TCP of sing-box, tunneling UDP packets in shadowsocks TCP streams
+//!
+//! Clients open a stream to a magic domain name, then send packets prefixed by their destinations and lengths.
+//! Streams of version 2 start with a request, which may connect all packets to a single destination.
+//!
+//! UDP streams multiplexed by sing-mux are relayed the same way, with SOCKS5 addresses.
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use bytes::{BufMut, BytesMut};
+use log::{debug, trace};
+use shadowsocks::{
+    ServerConfig,
+    config::ServerUser,
+    relay::{
+        socks5::Address,
+        udprelay::options::UdpSocketControlData,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::net::UDP_ASSOCIATION_SEND_CHANNEL_SIZE;
+
+use super::{context::ServiceContext, udprelay::UdpAssociation};
+
+/// Destination of version 1 tunnels
+const MAGIC_ADDRESS_V1: &str = "sp.udp-over-tcp.arpa";
+/// Destination of version 2 tunnels
+const MAGIC_ADDRESS_V2: &str = "sp.v2.udp-over-tcp.arpa";
+
+/// Address types of packets, different from SOCKS5
+const ADDR_TYPE_IPV4: u8 = 0x00;
+const ADDR_TYPE_IPV6: u8 = 0x01;
+const ADDR_TYPE_DOMAIN_NAME: u8 = 0x02;
+
+/// Version of UDP-over-TCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum UdpOverTcpVersion {
+    V1,
+    V2,
+}
+
+impl UdpOverTcpVersion {
+    /// Version requested by the destination of a TCP stream, `None` if it isn't a magic address
+    pub(super) fn of(addr: &Address) -> Option<Self> {
+        match addr {
+            Address::DomainNameAddress(name, _) if name == MAGIC_ADDRESS_V1 => Some(Self::V1),
+            Address::DomainNameAddress(name, _) if name == MAGIC_ADDRESS_V2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Framing of packets in a stream
+#[derive(Debug, Clone)]
+pub(super) enum PacketFraming {
+    /// Packets are prefixed by addresses of UDP-over-TCP
+    UotAddress,
+    /// Packets are prefixed by SOCKS5 addresses
+    #[cfg_attr(not(feature = "server-mux"), allow(dead_code))]
+    SocksAddress,
+    /// Packets are connected to a single destination, prefixed by lengths only
+    Connected(Address),
+}
+
+/// Read the request of a tunnel if any, returning the framing of its packets
+pub(super) async fn read_framing<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    version: UdpOverTcpVersion,
+) -> io::Result<PacketFraming> {
+    if version == UdpOverTcpVersion::V1 {
+        return Ok(PacketFraming::UotAddress);
+    }
+
+    let is_connect = reader.read_u8().await? != 0;
+    let destination = Address::read_from(reader).await?;
+    if is_connect {
+        Ok(PacketFraming::Connected(destination))
+    } else {
+        Ok(PacketFraming::UotAddress)
+    }
+}
+
+/// Read the destination of a packet
+async fn read_address<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Address> {
+    let addr = match reader.read_u8().await? {
+        ADDR_TYPE_IPV4 => {
+            let mut ip = [0u8; 4];
+            reader.read_exact(&mut ip).await?;
+            Address::SocketAddress(SocketAddr::from((ip, reader.read_u16().await?)))
+        }
+        ADDR_TYPE_IPV6 => {
+            let mut ip = [0u8; 16];
+            reader.read_exact(&mut ip).await?;
+            Address::SocketAddress(SocketAddr::from((ip, reader.read_u16().await?)))
+        }
+        ADDR_TYPE_DOMAIN_NAME => {
+            let mut name = vec![0u8; reader.read_u8().await? as usize];
+            reader.read_exact(&mut name).await?;
+            let name = String::from_utf8(name)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid domain name of packet"))?;
+            Address::DomainNameAddress(name, reader.read_u16().await?)
+        }
+        addr_type => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid address type {addr_type:#x} of packet"),
+            ));
+        }
+    };
+    Ok(addr)
+}
+
+/// Write the source of a packet
+fn write_address(buf: &mut BytesMut, addr: &Address) {
+    match addr {
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            buf.put_u8(ADDR_TYPE_IPV4);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            buf.put_u8(ADDR_TYPE_IPV6);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        Address::DomainNameAddress(name, port) => {
+            buf.put_u8(ADDR_TYPE_DOMAIN_NAME);
+            buf.put_u8(name.len() as u8);
+            buf.put_slice(name.as_bytes());
+            buf.put_u16(*port);
+        }
+    }
+}
+
+/// Relay packets of a stream through a UDP association, applying the same policies as the UDP server
+pub(super) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    context: Arc<ServiceContext>,
+    peer_addr: SocketAddr,
+    stream: S,
+    framing: PacketFraming,
+    user: Option<Arc<ServerUser>>,
+    node: usize,
+    relay_cfg: Option<ServerConfig>,
+) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    debug!("tcp client {} tunneling udp with {:?}", peer_addr, framing);
+
+    let (response_tx, mut response_rx) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+    let assoc = UdpAssociation::new_stream(
+        context,
+        peer_addr,
+        response_tx,
+        relay_cfg,
+        crate::DEFAULT_UDP_EXPIRY_DURATION,
+    );
+
+    // Packets are sent as the user of the tunnel
+    let mut control = UdpSocketControlData::default();
+    control.user = user;
+    control.node = node;
+
+    let uplink = async {
+        loop {
+            let target_addr = match framing {
+                PacketFraming::Connected(ref addr) => Ok(addr.clone()),
+                PacketFraming::UotAddress => read_address(&mut reader).await,
+                PacketFraming::SocksAddress => Address::read_from(&mut reader).await.map_err(io::Error::from),
+            };
+            let target_addr = match target_addr {
+                Ok(addr) => addr,
+                // Streams are closed between packets
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let length = match reader.read_u16().await {
+                Ok(length) => length as usize,
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && matches!(framing, PacketFraming::Connected(..)) => {
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            let mut data = BytesMut::zeroed(length);
+            reader.read_exact(&mut data).await?;
+
+            // Dropped like packets of a congested socket
+            if let Err(err) = assoc.try_send((peer_addr, target_addr, data.freeze(), Some(control.clone()))) {
+                trace!("udp over tcp {} dropped {} bytes, {}", peer_addr, length, err);
+            }
+        }
+    };
+
+    let downlink = async {
+        let mut buf = BytesMut::new();
+        while let Some((addr, data)) = response_rx.recv().await {
+            let Ok(length) = u16::try_from(data.len()) else {
+                continue;
+            };
+
+            buf.clear();
+            match framing {
+                PacketFraming::Connected(..) => {}
+                PacketFraming::UotAddress => write_address(&mut buf, &addr),
+                PacketFraming::SocksAddress => addr.write_to_buf(&mut buf),
+            }
+            buf.put_u16(length);
+            buf.put_slice(&data);
+            writer.write_all(&buf).await?;
+        }
+        Ok(())
+    };
+
+    let result = tokio::select! {
+        r = uplink => r,
+        r = downlink => r,
+    };
+    trace!("udp over tcp {} closed with result {:?}", peer_addr, result);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_packet_address() {
+        let addrs = [
+            Address::SocketAddress("127.0.0.1:53".parse().unwrap()),
+            Address::SocketAddress("[::1]:443".parse().unwrap()),
+            Address::DomainNameAddress("example.com".to_owned(), 8080),
+        ];
+        let mut buf = BytesMut::new();
+        for addr in &addrs {
+            write_address(&mut buf, addr);
+        }
+        assert_eq!(&buf[..7], &[ADDR_TYPE_IPV4, 127, 0, 0, 1, 0, 53]);
+
+        let mut reader = &buf[..];
+        for addr in &addrs {
+            assert_eq!(&read_address(&mut reader).await.unwrap(), addr);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let mut buf = vec![1u8];
+        Address::DomainNameAddress("example.com".to_owned(), 53).write_to_buf(&mut buf);
+        let framing = read_framing(&mut &buf[..], UdpOverTcpVersion::V2).await.unwrap();
+        assert!(
+            matches!(framing, PacketFraming::Connected(Address::DomainNameAddress(ref name, 53)) if name == "example.com")
+        );
+
+        buf[0] = 0;
+        let framing = read_framing(&mut &buf[..], UdpOverTcpVersion::V2).await.unwrap();
+        assert!(matches!(framing, PacketFraming::UotAddress));
+    }
+
+    #[test]
+    fn test_magic_address() {
+        let v2 = Address::DomainNameAddress(MAGIC_ADDRESS_V2.to_owned(), 0);
+        assert_eq!(UdpOverTcpVersion::of(&v2), Some(UdpOverTcpVersion::V2));
+        let other = Address::DomainNameAddress("example.com".to_owned(), 443);
+        assert_eq!(UdpOverTcpVersion::of(&other), None);
+    }
+}
@@ -1,6 +1,13 @@
 //! Shadowsocks UDP server
 
-use std::{cell::RefCell, io, net::SocketAddr, sync::{Arc, atomic::{AtomicI64, Ordering}}, time::Duration};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io,
+    net::SocketAddr,
+    sync::{Arc, atomic::{AtomicI64, Ordering}},
+    time::{Duration, Instant, SystemTime},
+};
 
 use bytes::Bytes;
 use futures::future;
@@ -8,27 +15,46 @@ use log::{debug, error, info, trace, warn};
 use lru_time_cache::LruCache;
 use rand::{Rng, SeedableRng, rngs::SmallRng};
 use shadowsocks::{
-    ServerConfig,
+    ServerAddr, ServerConfig,
     config::ServerUser,
     crypto::CipherCategory,
     lookup_then,
     net::{
-        AcceptOpts, AddrFamily, UdpSocket as OutboundUdpSocket, UdpSocket as InboundUdpSocket,
+        AcceptOpts, AddrFamily, ConnectOpts, UdpSocket as OutboundUdpSocket, UdpSocket,
         get_ip_stack_capabilities,
     },
     relay::{
         socks5::Address,
-        udprelay::{MAXIMUM_UDP_PAYLOAD_SIZE, ProxySocket, options::UdpSocketControlData},
+        udprelay::{MAXIMUM_UDP_PAYLOAD_SIZE, ProxySocket, options::UdpSocketControlData, proxy_socket::UdpSocketType},
+    },
+};
+use tokio::{net::UdpSocket as TokioUdpSocket, runtime::Handle, sync::mpsc, task::JoinHandle, time};
+
+use crate::{
+    acl::{P2pAction, PortProtocol, UserLimitGuard},
+    net::{
+        MonProxySocket, RateLimiter, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
+        packet_window::PacketWindowFilter,
+        proxy_protocol::ProxyProtocolSocket,
+        sniff::{is_bittorrent_packet, is_tracker_domain},
+        utils::to_ipv4_mapped,
     },
 };
-use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle, time};
 
-use crate::net::{
-    MonProxySocket, UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
-    packet_window::PacketWindowFilter, utils::to_ipv4_mapped,
+use super::{
+    ACCEPT_HEARTBEAT_INTERVAL,
+    access_log::{AccessLogEntry, AccessProtocol},
+    context::ServiceContext,
 };
 
-use super::context::ServiceContext;
+/// Maximum destinations of an association tracked for the access log, tracked ones are logged when exceeded
+const MAX_UDP_ACCESS_RECORDS: usize = 1024;
+
+/// Interval of logging destinations of long-lived associations, traffic since the last entry is logged again
+const UDP_ACCESS_LOG_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Inbound socket, datagrams from trusted load balancers are received from their original clients
+type InboundUdpSocket = ProxyProtocolSocket<UdpSocket>;
 
 #[derive(Debug, Clone, Copy)]
 enum NatKey {
@@ -95,6 +121,7 @@ impl UdpServer {
         capacity: Option<usize>,
         accept_opts: AcceptOpts,
         relay_cfg: Option<ServerConfig>,
+        std_socket: Option<std::net::UdpSocket>,
     ) -> io::Result<Self> {
         let time_to_live = time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
 
@@ -120,8 +147,17 @@ impl UdpServer {
 
         let (keepalive_tx, keepalive_rx) = mpsc::channel(UDP_ASSOCIATION_KEEP_ALIVE_CHANNEL_SIZE);
 
-        let socket = ProxySocket::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?;
-        let socket = MonProxySocket::from_socket(socket, context.flow_stat());
+        let socket = match std_socket {
+            Some(std_socket) => {
+                std_socket.set_nonblocking(true)?;
+                let socket = UdpSocket::from(TokioUdpSocket::from_std(std_socket)?);
+                ProxySocket::from_socket(UdpSocketType::Server, context.context(), &svr_cfg, socket)
+            }
+            None => ProxySocket::bind_with_opts(context.context(), &svr_cfg, accept_opts).await?,
+        };
+        let socket = socket.map_io(|socket| ProxyProtocolSocket::new(socket, context.proxy_protocol_trust().clone(), time_to_live));
+        let mut socket = MonProxySocket::from_socket(socket, context.flow_stat());
+        socket.set_shared_node_flow_stats(context.shared_node_flow_stats().to_vec());
         let listener = Arc::new(socket);
 
         Ok(Self {
@@ -155,6 +191,7 @@ impl UdpServer {
         );
 
         let mut cleanup_timer = time::interval(self.time_to_live);
+        let mut heartbeat = time::interval(ACCEPT_HEARTBEAT_INTERVAL);
 
         let mut orx_opt = None;
 
@@ -234,6 +271,10 @@ impl UdpServer {
                     self.assoc_map.cleanup_expired();
                 }
 
+                _ = heartbeat.tick() => {
+                    self.context.mark_accept_alive();
+                }
+
                 peer_addr_opt = self.keepalive_rx.recv() => {
                     let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectedly");
                     self.assoc_map.keep_alive(&peer_addr);
@@ -304,7 +345,13 @@ impl UdpServer {
             return None;
         }
 
-        if context.check_outbound_blocked(&target_addr).await {
+        if context.check_client_banned(&peer_addr) {
+            debug!("udp client {} access denied, banned for failing handshakes", peer_addr);
+            return None;
+        }
+
+        let sniffed = context.sniff_packet(&buffer[..n]);
+        if context.check_sniffed_outbound_blocked(&target_addr, sniffed.as_ref()).await {
             warn!("udp client {} outbound {} blocked by ACL rules", peer_addr, target_addr);
             return None;
         }
@@ -382,7 +429,18 @@ impl UdpServer {
 
 type UdpAssociationSendMessage = (SocketAddr, Address, Bytes, Option<UdpSocketControlData>);
 
-struct UdpAssociation {
+/// Where responses of an association are sent back to the client
+enum UdpInbound {
+    /// Socket of the UDP server, keeping the association alive in its NAT map
+    Socket {
+        socket: Arc<MonProxySocket<InboundUdpSocket>>,
+        keepalive_tx: mpsc::Sender<NatKey>,
+    },
+    /// UDP-over-TCP tunnel, responses are framed into the client's stream
+    Stream(mpsc::Sender<(Address, Bytes)>),
+}
+
+pub(super) struct UdpAssociation {
     assoc_handle: JoinHandle<()>,
     sender: mpsc::Sender<UdpAssociationSendMessage>,
 }
@@ -402,8 +460,26 @@ impl UdpAssociation {
         relay_cfg: Option<ServerConfig>,
         server_session_expire_duration: Duration,
     ) -> Self {
+        let inbound = UdpInbound::Socket {
+            socket: inbound,
+            keepalive_tx,
+        };
+        let (assoc_handle, sender) =
+            UdpAssociationContext::create(context, inbound, peer_addr, None, relay_cfg, server_session_expire_duration);
+        Self { assoc_handle, sender }
+    }
+
+    /// Association of a UDP-over-TCP client, sending responses to `response_tx`
+    pub(super) fn new_stream(
+        context: Arc<ServiceContext>,
+        peer_addr: SocketAddr,
+        response_tx: mpsc::Sender<(Address, Bytes)>,
+        relay_cfg: Option<ServerConfig>,
+        server_session_expire_duration: Duration,
+    ) -> Self {
+        let inbound = UdpInbound::Stream(response_tx);
         let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, None, relay_cfg, server_session_expire_duration);
+            UdpAssociationContext::create(context, inbound, peer_addr, None, relay_cfg, server_session_expire_duration);
         Self { assoc_handle, sender }
     }
 
@@ -417,12 +493,16 @@ impl UdpAssociation {
         relay_cfg: Option<ServerConfig>,
         server_session_expire_duration: Duration,
     ) -> Self {
+        let inbound = UdpInbound::Socket {
+            socket: inbound,
+            keepalive_tx,
+        };
         let (assoc_handle, sender) =
-            UdpAssociationContext::create(context, inbound, peer_addr, keepalive_tx, Some(client_session_id), relay_cfg, server_session_expire_duration);
+            UdpAssociationContext::create(context, inbound, peer_addr, Some(client_session_id), relay_cfg, server_session_expire_duration);
         Self { assoc_handle, sender }
     }
 
-    fn try_send(&self, data: UdpAssociationSendMessage) -> io::Result<()> {
+    pub(super) fn try_send(&self, data: UdpAssociationSendMessage) -> io::Result<()> {
         if self.sender.try_send(data).is_err() {
             let err = io::Error::other("udp relay channel full");
             return Err(err);
@@ -434,7 +514,6 @@ impl UdpAssociation {
 struct ClientSessionContext {
     client_session_id: u64,
     packet_window_filter: PacketWindowFilter,
-    client_user: Option<Arc<ServerUser>>,
 }
 
 impl ClientSessionContext {
@@ -442,7 +521,6 @@ impl ClientSessionContext {
         Self {
             client_session_id,
             packet_window_filter: PacketWindowFilter::new(),
-            client_user: None,
         }
     }
 }
@@ -470,11 +548,16 @@ struct UdpAssociationContext {
     peer_addr: SocketAddr,
     outbound_ipv4_socket: Option<OutboundUdpSocket>,
     outbound_ipv6_socket: Option<OutboundUdpSocket>,
-    keepalive_tx: mpsc::Sender<NatKey>,
     keepalive_flag: bool,
-    inbound: Arc<MonProxySocket<InboundUdpSocket>>,
+    inbound: UdpInbound,
     // AEAD 2022
     client_session: Option<ClientSessionContext>,
+    // Authenticated by EIH (AEAD 2022) or trial decryption (AEAD, AEAD 2022)
+    client_user: Option<Arc<ServerUser>>,
+    // Node sharing the server port `client_user` belongs to
+    client_node: usize,
+    // Counts the association of `client_user` until dropped
+    user_limit_guard: Option<UserLimitGuard>,
     server_session_id: u64,
     server_packet_id: u64,
     timestamp_diff: Arc<AtomicI64>,
@@ -485,10 +568,29 @@ struct UdpAssociationContext {
     client_packet_id: u64,
     server_session: Option<ServerSessionContext>,
     server_session_expire_duration: Duration,
+    // Throttling P2P traffic
+    p2p_limiter: Option<Arc<RateLimiter>>,
+    // Destinations for the access log
+    access_records: HashMap<Address, UdpAccessRecord>,
+    // Ports blocked by the port policy, hits are counted once per association instead of every packet
+    blocked_ports: HashSet<u16>,
+}
+
+/// Access of an association to a destination, logged when the association is closed or periodically
+struct UdpAccessRecord {
+    start: SystemTime,
+    started: Instant,
+    last_active: Instant,
+    user: Option<String>,
+    sniffed: Option<String>,
+    outbound: Option<String>,
+    bytes_up: u64,
+    bytes_down: u64,
 }
 
 impl Drop for UdpAssociationContext {
     fn drop(&mut self) {
+        self.flush_access_records();
         debug!("udp association for {} is closed", self.peer_addr);
     }
 }
@@ -520,9 +622,8 @@ fn generate_client_session_id() -> u64 {
 impl UdpAssociationContext {
     fn create(
         context: Arc<ServiceContext>,
-        inbound: Arc<MonProxySocket<InboundUdpSocket>>,
+        inbound: UdpInbound,
         peer_addr: SocketAddr,
-        keepalive_tx: mpsc::Sender<NatKey>,
         client_session_id: Option<u64>,
         relay_cfg: Option<ServerConfig>,
         server_session_expire_duration: Duration,
@@ -537,10 +638,12 @@ impl UdpAssociationContext {
             peer_addr,
             outbound_ipv4_socket: None,
             outbound_ipv6_socket: None,
-            keepalive_tx,
             keepalive_flag: false,
             inbound,
             client_session: client_session_id.map(ClientSessionContext::new),
+            client_user: None,
+            client_node: 0,
+            user_limit_guard: None,
             // server_session_id must be generated randomly
             server_session_id: generate_server_session_id(),
             server_packet_id: 0,
@@ -553,6 +656,9 @@ impl UdpAssociationContext {
             client_packet_id: 0,
             server_session: None,
             server_session_expire_duration,
+            p2p_limiter: None,
+            access_records: HashMap::new(),
+            blocked_ports: HashSet::new(),
         };
         let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
 
@@ -564,6 +670,10 @@ impl UdpAssociationContext {
         let mut outbound_ipv6_buffer = Vec::new();
         let mut proxied_buffer = Vec::new();
         let mut keepalive_interval = time::interval(Duration::from_secs(1));
+        let mut access_log_interval = time::interval_at(
+            time::Instant::now() + UDP_ACCESS_LOG_INTERVAL,
+            UDP_ACCESS_LOG_INTERVAL,
+        );
         let timestamp_diff = if self.context.context_ref().comply_with_incoming() {
             Some(self.timestamp_diff.clone())
         } else { None };
@@ -660,8 +770,14 @@ impl UdpAssociationContext {
                     self.send_received_respond_packet(addr, &proxied_buffer[..n]).await;
                 }
 
+                _ = access_log_interval.tick(), if !self.access_records.is_empty() => {
+                    self.flush_access_records();
+                }
+
                 _ = keepalive_interval.tick() => {
-                    if self.keepalive_flag {
+                    if let UdpInbound::Socket { ref keepalive_tx, .. } = self.inbound
+                        && self.keepalive_flag
+                    {
                         let nat_key = match self.client_session {
                             None => NatKey::PeerAddr(self.peer_addr),
                             #[cfg(feature = "aead-cipher-2022")]
@@ -670,7 +786,7 @@ impl UdpAssociationContext {
                             Some(..) => unreachable!("client_session_id is not None but aead-cipher-2022 is not enabled"),
                         };
 
-                        if self.keepalive_tx.try_send(nat_key).is_err() {
+                        if keepalive_tx.try_send(nat_key).is_err() {
                             debug!("udp relay {:?} keep-alive failed, channel full or closed", nat_key);
                         } else {
                             self.keepalive_flag = false;
@@ -739,7 +855,15 @@ impl UdpAssociationContext {
             control,
         );
 
-        if self.context.check_outbound_blocked(target_addr).await {
+        let sniffed = self.context.sniff_packet(data);
+        if let Some(ref sniffed) = sniffed {
+            debug!(
+                "udp client {} outbound {} sniffed {} domain {}",
+                self.peer_addr, target_addr, sniffed.protocol, sniffed.domain
+            );
+        }
+
+        if self.context.check_sniffed_outbound_blocked(target_addr, sniffed.as_ref()).await {
             error!(
                 "udp client {} outbound {} blocked by ACL rules",
                 self.peer_addr, target_addr
@@ -749,50 +873,166 @@ impl UdpAssociationContext {
 
         if let Some(control) = control {
             // Check if Packet ID is in the window
+            //
+            // AEAD ciphers have no sessions, their control only carries the user found by trial decryption
+            if let Some(ref mut session_context) = self.client_session {
+                let packet_id = control.packet_id;
+                if !session_context
+                    .packet_window_filter
+                    .validate_packet_id(packet_id, u64::MAX)
+                {
+                    error!("udp client {} packet_id {} out of window", self.peer_addr, packet_id);
+                    return;
+                }
+            }
 
-            let session_context = self
-                .client_session
-                .get_or_insert_with(|| ClientSessionContext::new(control.client_session_id));
-
-            let packet_id = control.packet_id;
-            if !session_context
-                .packet_window_filter
-                .validate_packet_id(packet_id, u64::MAX)
+            if self.client_user.is_none()
+                && let (Some(limiter), Some(user)) = (self.context.user_limiter(), control.user.as_ref())
             {
-                error!("udp client {} packet_id {} out of window", self.peer_addr, packet_id);
-                return;
+                match limiter.acquire_udp(user) {
+                    Ok(guard) => self.user_limit_guard = guard,
+                    Err(err) => {
+                        error!("udp client {} user {} rejected, {}", self.peer_addr, user.name(), err);
+                        return;
+                    }
+                }
             }
 
-            session_context.client_user.clone_from(&control.user);
+            self.client_user.clone_from(&control.user);
+            self.client_node = control.node;
+        }
+
+        let user = self.client_user.as_deref();
+        let port = target_addr.port();
+        if self.blocked_ports.contains(&port) {
+            trace!(
+                "udp client {} outbound {} blocked by port policy",
+                self.peer_addr, target_addr
+            );
+            return;
+        }
+        if self
+            .context
+            .check_outbound_port_blocked(PortProtocol::Udp, port, user, &self.peer_addr)
+        {
+            error!(
+                "udp client {} outbound {} blocked by port policy",
+                self.peer_addr, target_addr
+            );
+            // Clients under temporary block are rejected on all ports, only until the block expires
+            if self
+                .context
+                .port_policy()
+                .is_some_and(|policy| policy.is_port_blocked(PortProtocol::Udp, port))
+            {
+                self.blocked_ports.insert(port);
+            }
+            return;
         }
 
-        if let Err(err) = self.dispatch_received_outbound_packet(target_addr, data).await {
+        if let Some(rule_id) = self.context.check_sniffed_outbound_audit(target_addr, sniffed.as_ref(), user) {
             error!(
-                "udp relay {} -> {} with {} bytes, error: {}",
+                "udp client {} outbound {} blocked by audit rule {}",
+                self.peer_addr, target_addr, rule_id
+            );
+            return;
+        }
+
+        if let Some(policy) = self.context.p2p_policy()
+            && (is_bittorrent_packet(data) || sniffed.as_ref().is_some_and(|s| is_tracker_domain(&s.domain)))
+        {
+            let limiter = policy.record(user, &self.peer_addr);
+            match policy.action() {
+                P2pAction::Block => {
+                    error!(
+                        "udp client {} outbound {} blocked by P2P policy",
+                        self.peer_addr, target_addr
+                    );
+                    return;
+                }
+                P2pAction::Throttle => {
+                    if self.p2p_limiter.is_none() {
+                        warn!(
+                            "udp client {} outbound {} P2P traffic throttled",
+                            self.peer_addr, target_addr
+                        );
+                    }
+                    self.p2p_limiter = limiter;
+                }
+                P2pAction::Log => debug!(
+                    "udp client {} outbound {} P2P traffic detected",
+                    self.peer_addr, target_addr
+                ),
+            }
+        }
+
+        if let Some(ref limiter) = self.p2p_limiter
+            && !limiter.try_acquire(data.len())
+        {
+            trace!(
+                "udp client {} outbound {} dropped {} bytes by P2P throttling",
                 self.peer_addr,
                 target_addr,
-                data.len(),
-                err
+                data.len()
             );
+            return;
+        }
+
+        let outbound_family = match self.dispatch_received_outbound_packet(target_addr, data).await {
+            Ok(family) => family,
+            Err(err) => {
+                error!(
+                    "udp relay {} -> {} with {} bytes, error: {}",
+                    self.peer_addr,
+                    target_addr,
+                    data.len(),
+                    err
+                );
+                None
+            }
+        };
+
+        if self.context.access_log().is_some() {
+            self.record_access(target_addr, sniffed.map(|s| s.domain), outbound_family, data.len());
         }
     }
 
-    async fn dispatch_received_outbound_packet(&mut self, target_addr: &Address, data: &[u8]) -> io::Result<()> {
+    /// Send `data` to `target_addr`, returns family of the outbound socket if sent directly
+    async fn dispatch_received_outbound_packet(
+        &mut self,
+        target_addr: &Address,
+        data: &[u8],
+    ) -> io::Result<Option<AddrFamily>> {
         match self.relay_cfg.as_ref() {
-            Some(_) => self.send_received_outbound_proxied_packet(target_addr, data).await,
+            Some(_) => self
+                .send_received_outbound_proxied_packet(target_addr, data)
+                .await
+                .map(|_| None),
             None => match *target_addr {
-                Address::SocketAddress(sa) => self.send_received_outbound_packet(sa, data).await,
+                Address::SocketAddress(sa) => self.send_received_outbound_packet(sa, data).await.map(Some),
                 Address::DomainNameAddress(ref dname, port) => {
                     lookup_then!(self.context.context_ref(), dname, port, |sa| {
                         self.send_received_outbound_packet(sa, data).await
                     })
-                    .map(|_| ())
+                    .map(|(_, family)| Some(family))
                 }
             }
         }
     }
 
-    async fn send_received_outbound_packet(&mut self, original_target_addr: SocketAddr, data: &[u8]) -> io::Result<()> {
+    /// Send `data` to `original_target_addr` directly, returns family of the outbound socket
+    async fn send_received_outbound_packet(
+        &mut self,
+        original_target_addr: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<AddrFamily> {
+        if self.context.check_resolved_outbound_blocked(&original_target_addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("outbound {original_target_addr} is an internal address"),
+            ));
+        }
+
         let ip_stack_caps = get_ip_stack_capabilities();
 
         let target_addr = match original_target_addr {
@@ -800,7 +1040,9 @@ impl UdpAssociationContext {
                 // If IPv4-mapped-IPv6 is supported.
                 // Converts IPv4 address to IPv4-mapped-IPv6
                 // All sockets will be created in IPv6 (nearly all modern OS supports IPv6 sockets)
-                if ip_stack_caps.support_ipv4_mapped_ipv6 {
+                //
+                // Sockets binding to addresses in the outbound address pool are family specific.
+                if ip_stack_caps.support_ipv4_mapped_ipv6 && self.context.bind_addr_pool().is_none() {
                     SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
                 } else {
                     original_target_addr
@@ -823,18 +1065,16 @@ impl UdpAssociationContext {
             SocketAddr::V4(..) => match self.outbound_ipv4_socket {
                 Some(ref mut socket) => socket,
                 None => {
-                    let socket =
-                        OutboundUdpSocket::connect_any_with_opts(AddrFamily::Ipv4, self.context.connect_opts_ref())
-                            .await?;
+                    let connect_opts = self.outbound_connect_opts(AddrFamily::Ipv4);
+                    let socket = OutboundUdpSocket::connect_any_with_opts(AddrFamily::Ipv4, &connect_opts).await?;
                     self.outbound_ipv4_socket.insert(socket)
                 }
             },
             SocketAddr::V6(..) => match self.outbound_ipv6_socket {
                 Some(ref mut socket) => socket,
                 None => {
-                    let socket =
-                        OutboundUdpSocket::connect_any_with_opts(AddrFamily::Ipv6, self.context.connect_opts_ref())
-                            .await?;
+                    let connect_opts = self.outbound_connect_opts(AddrFamily::Ipv6);
+                    let socket = OutboundUdpSocket::connect_any_with_opts(AddrFamily::Ipv6, &connect_opts).await?;
                     self.outbound_ipv6_socket.insert(socket)
                 }
             },
@@ -851,7 +1091,7 @@ impl UdpAssociationContext {
                         data.len()
                     );
                 }
-                Ok(())
+                Ok(AddrFamily::from(&target_addr))
             }
             Err(err) => Err(err),
         }
@@ -888,8 +1128,22 @@ impl UdpAssociationContext {
             Some(ref mut socket) => socket,
             None => {
                 debug_assert!(matches!(self.relay_cfg, Some(_)));
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), self.relay_cfg.as_ref().unwrap(), self.context.connect_opts_ref()).await?;
+                let relay_cfg = self.relay_cfg.as_ref().unwrap();
+                // Source address is chosen by family of the resolved relay server address
+                let socket = match *relay_cfg.udp_external_addr() {
+                    ServerAddr::SocketAddr(ref addr) => {
+                        OutboundUdpSocket::connect_with_opts(addr, &self.outbound_connect_opts(AddrFamily::from(addr)))
+                            .await?
+                    }
+                    ServerAddr::DomainName(ref dname, port) => {
+                        lookup_then!(self.context.context_ref(), dname, port, |addr| {
+                            OutboundUdpSocket::connect_with_opts(&addr, &self.outbound_connect_opts(AddrFamily::from(&addr)))
+                                .await
+                        })?
+                        .1
+                    }
+                };
+                let socket = ProxySocket::from_socket(UdpSocketType::Client, self.context.context(), relay_cfg, socket);
 
                 self.proxied_socket.insert(socket)
             }
@@ -918,12 +1172,114 @@ impl UdpAssociationContext {
         Ok(())
     }
 
+    fn record_access(
+        &mut self,
+        target_addr: &Address,
+        sniffed: Option<String>,
+        outbound_family: Option<AddrFamily>,
+        n: usize,
+    ) {
+        let outbound = match self.access_records.get(target_addr) {
+            Some(record) if record.outbound.is_some() => None,
+            _ => self.outbound_for_access(outbound_family),
+        };
+
+        let now = Instant::now();
+        if let Some(record) = self.access_records.get_mut(target_addr) {
+            record.last_active = now;
+            record.bytes_up += n as u64;
+            if record.sniffed.is_none() {
+                record.sniffed = sniffed;
+            }
+            if record.outbound.is_none() {
+                record.outbound = outbound;
+            }
+            return;
+        }
+
+        if self.access_records.len() >= MAX_UDP_ACCESS_RECORDS {
+            self.flush_access_records();
+        }
+
+        let user = self.client_user.as_ref().map(|u| u.name().to_owned());
+
+        self.access_records.insert(
+            target_addr.clone(),
+            UdpAccessRecord {
+                start: SystemTime::now(),
+                started: now,
+                last_active: now,
+                user,
+                sniffed,
+                outbound,
+                bytes_up: n as u64,
+                bytes_down: 0,
+            },
+        );
+    }
+
+    fn flush_access_records(&mut self) {
+        let access_log = match self.context.access_log() {
+            Some(access_log) => access_log,
+            None => return,
+        };
+
+        for (target_addr, record) in self.access_records.drain() {
+            access_log.log(AccessLogEntry {
+                start: record.start,
+                user: record.user,
+                client: self.peer_addr,
+                protocol: AccessProtocol::Udp,
+                destination: target_addr.to_string(),
+                sniffed: record.sniffed,
+                outbound: record.outbound,
+                bytes_up: record.bytes_up,
+                bytes_down: record.bytes_down,
+                duration: record.last_active.duration_since(record.started),
+            });
+        }
+    }
+
+    /// Outbound of the access log, the relay server, or the source address bound by the socket of `family`
+    fn outbound_for_access(&self, family: Option<AddrFamily>) -> Option<String> {
+        if let Some(ref relay_cfg) = self.relay_cfg {
+            return Some(relay_cfg.addr().to_string());
+        }
+        if self.context.bind_addr_pool().is_none() && self.context.connect_opts_ref().bind_local_addr.is_none() {
+            return None;
+        }
+        let socket = match family? {
+            AddrFamily::Ipv4 => self.outbound_ipv4_socket.as_ref(),
+            AddrFamily::Ipv6 => self.outbound_ipv6_socket.as_ref(),
+        };
+        socket?.local_addr().ok().map(|addr| addr.ip().to_string())
+    }
+
+    fn outbound_connect_opts(&self, family: AddrFamily) -> ConnectOpts {
+        let user = self.client_user.as_deref();
+        self.context
+            .connect_opts_for(family, user, &self.peer_addr)
+            .into_owned()
+    }
+
     async fn send_received_respond_packet(&mut self, mut addr: Address, data: &[u8]) {
         trace!("udp relay {} <- {} received {} bytes", self.peer_addr, addr, data.len());
 
         // Keep association alive in map
         self.keepalive_flag = true;
 
+        if let Some(ref limiter) = self.p2p_limiter
+            && !limiter.try_acquire(data.len())
+        {
+            trace!(
+                "udp relay {} <- {} dropped {} bytes by P2P throttling",
+                self.peer_addr,
+                addr,
+                data.len()
+            );
+            return;
+        }
+
         // Convert IPv4-mapped-IPv6 to IPv4
         //
         // It is an undefined behavior in shadowsocks' protocol about how to handle IPv4-mapped-IPv6.
@@ -935,10 +1291,40 @@ impl UdpAssociationContext {
             addr = Address::SocketAddress(SocketAddr::new(v4.into(), v6.port()));
         }
 
+        if let Some(record) = self.access_records.get_mut(&addr) {
+            record.last_active = Instant::now();
+            record.bytes_down += data.len() as u64;
+        }
+
+        let inbound = match self.inbound {
+            UdpInbound::Socket { ref socket, .. } => socket,
+            UdpInbound::Stream(ref response_tx) => {
+                // Dropped like packets of a congested socket
+                if response_tx.try_send((addr.clone(), Bytes::copy_from_slice(data))).is_err() {
+                    trace!(
+                        "udp relay {} <- {} dropped {} bytes, tunnel congested or closed",
+                        self.peer_addr,
+                        addr,
+                        data.len()
+                    );
+                }
+                return;
+            }
+        };
+
         match self.client_session {
             None => {
                 // Naive route, send data directly back to client without session
-                match self.inbound.send_to(self.peer_addr, &addr, data).await {
+                //
+                // Encrypted with the key of the user found by trial decryption (AEAD)
+                let mut control = UdpSocketControlData::default();
+                control.user.clone_from(&self.client_user);
+                control.node = self.client_node;
+
+                match inbound
+                    .send_to_with_ctrl(self.peer_addr, &addr, &control, data)
+                    .await
+                {
                     Err(err) => {
                         warn!(
                             "udp failed to send back {} bytes to client {}, from target {}, error: {}",
@@ -978,11 +1364,11 @@ impl UdpAssociationContext {
                 control.client_session_id = client_session.client_session_id;
                 control.server_session_id = self.server_session_id;
                 control.packet_id = self.server_packet_id;
-                control.user.clone_from(&client_session.client_user);
+                control.user.clone_from(&self.client_user);
+                control.node = self.client_node;
                 control.timestamp_diff = self.timestamp_diff.load(Ordering::Acquire);
 
-                match self
-                    .inbound
+                match inbound
                     .send_to_with_ctrl(self.peer_addr, &addr, &control, data)
                     .await
                 {
@@ -3,19 +3,19 @@
 #[cfg(unix)]
 use std::path::PathBuf;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::{self, Debug, Display},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     str::{self, FromStr},
-    sync::{Arc, RwLock},
-    time::Duration,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
 use base64::Engine as _;
 use byte_string::ByteStr;
 use bytes::Bytes;
 use cfg_if::cfg_if;
-use log::{error, warn};
+use log::{error, trace, warn};
 use thiserror::Error;
 use url::{self, Url};
 
@@ -289,6 +289,16 @@ impl ServerUser {
         Ok(Self::new(name, key))
     }
 
+    /// Create a user with the key derived from `password` as a server of `method` does
+    pub fn with_password<N>(name: N, method: CipherKind, password: &str) -> Result<Self, ServerConfigError>
+    where
+        N: Into<String>,
+    {
+        let mut key = vec![0u8; method.key_len()];
+        make_derived_key(method, password, &mut key)?;
+        Ok(Self::new(name, key))
+    }
+
     /// Name of the user
     pub fn name(&self) -> &str {
         self.name.as_str()
@@ -327,10 +337,107 @@ pub enum ServerUserError {
     InvalidKeyEncoding(#[from] base64::DecodeError),
 }
 
+/// Users matched by trial decryption remembered for each source, tried first for its next connections and packets
+const TRIAL_USERS_PER_SOURCE: usize = 4;
+
+/// Sources remembered by trial decryption, the least recently seen ones are forgotten first
+const MAX_TRIAL_SOURCES: usize = 16384;
+
+/// Period of counting full trials of a source that matched no user
+const TRIAL_FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Full trials of a source matching no user in `TRIAL_FAILURE_WINDOW`, then only its remembered users are tried
+///
+/// A full trial costs a key derivation and a decryption for every user, unauthenticated probes must not repeat it.
+const MAX_TRIAL_FAILURES_PER_SOURCE: u32 = 8;
+
+/// Trial decryption state of a source address
+struct TrialSource {
+    // (node, user hash), the most recently matched first
+    users: VecDeque<(usize, Bytes)>,
+    failures: u32,
+    window_start: Instant,
+    last_seen: Instant,
+}
+
+/// Sources of trial decryption, bounded by `MAX_TRIAL_SOURCES`
+#[derive(Default)]
+struct TrialSources {
+    sources: HashMap<IpAddr, TrialSource>,
+}
+
+impl TrialSources {
+    /// Users remembered for `source`, and whether other users may be tried
+    fn candidates(&mut self, source: IpAddr) -> (Vec<(usize, Bytes)>, bool) {
+        let now = Instant::now();
+        match self.sources.get_mut(&source) {
+            None => (Vec::new(), true),
+            Some(state) => {
+                state.last_seen = now;
+                if now.duration_since(state.window_start) >= TRIAL_FAILURE_WINDOW {
+                    state.failures = 0;
+                    state.window_start = now;
+                }
+                (
+                    state.users.iter().cloned().collect(),
+                    state.failures < MAX_TRIAL_FAILURES_PER_SOURCE,
+                )
+            }
+        }
+    }
+
+    fn record_match(&mut self, source: IpAddr, node: usize, user_hash: &[u8]) {
+        let state = self.entry(source);
+        state.users.retain(|(n, h)| !(*n == node && h == user_hash));
+        state.users.push_front((node, Bytes::copy_from_slice(user_hash)));
+        state.users.truncate(TRIAL_USERS_PER_SOURCE);
+    }
+
+    fn record_failure(&mut self, source: IpAddr) {
+        let state = self.entry(source);
+        state.failures += 1;
+        if state.failures == MAX_TRIAL_FAILURES_PER_SOURCE {
+            warn!(
+                "trial decryption from {} matched no user {} times, only its known users are tried for {:?}",
+                source, MAX_TRIAL_FAILURES_PER_SOURCE, TRIAL_FAILURE_WINDOW
+            );
+        }
+    }
+
+    fn entry(&mut self, source: IpAddr) -> &mut TrialSource {
+        let now = Instant::now();
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_TRIAL_SOURCES {
+            // Forget the least recently seen half at once, instead of scanning for every new source
+            let mut last_seen = self.sources.values().map(|s| s.last_seen).collect::<Vec<_>>();
+            let (_, median, _) = last_seen.select_nth_unstable(MAX_TRIAL_SOURCES / 2);
+            let median = *median;
+            self.sources.retain(|_, s| s.last_seen > median);
+        }
+        self.sources.entry(source).or_insert_with(|| TrialSource {
+            users: VecDeque::new(),
+            failures: 0,
+            window_start: now,
+            last_seen: now,
+        })
+    }
+}
+
 /// Server multi-users manager
 #[derive(Clone, Debug)]
 pub struct ServerUserManager {
     users: Arc<RwLock<HashMap<Bytes, Arc<ServerUser>>>>,
+    previous_keys: Arc<RwLock<Vec<(Bytes, Instant)>>>,
+    previous_key_users: Arc<Mutex<HashMap<Bytes, Bytes>>>,
+    shared_nodes: Arc<RwLock<Vec<(Bytes, Arc<ServerUserManager>)>>>,
+    trial_sources: Arc<Mutex<TrialSources>>,
+    // Client of the connection or packet, set by `with_trial_source`
+    trial_source: Option<IpAddr>,
+}
+
+impl Debug for TrialSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrialSources").field("sources", &self.sources.len()).finish()
+    }
 }
 
 impl ServerUserManager {
@@ -338,7 +445,185 @@ impl ServerUserManager {
     pub fn new() -> Self {
         Self {
             users: Arc::new(RwLock::new(HashMap::new())),
+            previous_keys: Arc::new(RwLock::new(Vec::new())),
+            previous_key_users: Arc::new(Mutex::new(HashMap::new())),
+            shared_nodes: Arc::new(RwLock::new(Vec::new())),
+            trial_sources: Arc::new(Mutex::new(TrialSources::default())),
+            trial_source: None,
+        }
+    }
+
+    /// The manager for a connection or packet from `source`, sharing users and states with this one
+    ///
+    /// Trial decryption tries users matched for `source` before first, and limits full trials of it.
+    pub fn with_trial_source(&self, source: IpAddr) -> Self {
+        Self {
+            trial_source: Some(source.to_canonical()),
+            ..self.clone()
+        }
+    }
+
+    /// Set nodes sharing the server port, each with its own server key (iPSK) and users
+    ///
+    /// Nodes are numbered by their position in `nodes` starting from 1, 0 is the node of this manager.
+    /// Set before starting the server, numbers of clients already accepted are not updated.
+    pub fn set_shared_nodes(&self, nodes: Vec<(Bytes, Arc<ServerUserManager>)>) {
+        let mut shared_nodes = self.shared_nodes.write().expect("user manager poisoned");
+        *shared_nodes = nodes;
+    }
+
+    /// Number of nodes sharing the server port, besides the node of this manager
+    pub fn shared_node_count(&self) -> usize {
+        let shared_nodes = self.shared_nodes.read().expect("user manager poisoned");
+        shared_nodes.len()
+    }
+
+    /// Find the user of an Extensible Identity Header, with the number of the node it belongs to
+    ///
+    /// `decrypt_identity` decrypts the identity header with a server key (iPSK) into a user hash.
+    /// `key` is tried first, then previous keys of this node, then keys of nodes sharing the port.
+    pub fn find_user_by_identity<F>(&self, key: &[u8], mut decrypt_identity: F) -> Option<(Arc<ServerUser>, usize)>
+    where
+        F: FnMut(&[u8]) -> [u8; 16],
+    {
+        if let Some(user) = self.find_node_user(key, &mut decrypt_identity) {
+            return Some((user, 0));
+        }
+
+        let shared_nodes = self.shared_nodes.read().expect("user manager poisoned").clone();
+        for (idx, (node_key, user_manager)) in shared_nodes.iter().enumerate() {
+            // Nodes sharing a port must use the same method
+            if node_key.len() != key.len() {
+                continue;
+            }
+            if let Some(user) = user_manager.find_node_user(node_key, &mut decrypt_identity) {
+                return Some((user, idx + 1));
+            }
+        }
+
+        None
+    }
+
+    /// Find the user whose key decrypts the first chunk of a client, with the number of the node it belongs to
+    ///
+    /// For methods without Extensible Identity Headers, `try_key` attempts decrypting with a user key.
+    /// Users matched for the source of `with_trial_source` are tried first, then other users of this node,
+    /// then users of nodes sharing the port. Sources matching no user repeatedly are only tried with their known users.
+    pub fn find_user_by_trial<F>(&self, mut try_key: F) -> Option<(Arc<ServerUser>, usize)>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        let (known_users, may_try_all) = match self.trial_source {
+            Some(source) => self.trial_sources.lock().expect("user manager poisoned").candidates(source),
+            None => (Vec::new(), true),
+        };
+        let shared_nodes = self.shared_nodes.read().expect("user manager poisoned").clone();
+        let node_manager = |node: usize| match node {
+            0 => Some(self),
+            _ => shared_nodes.get(node - 1).map(|(_, user_manager)| user_manager.as_ref()),
+        };
+
+        for (node, user_hash) in &known_users {
+            if let Some(user) = node_manager(*node).and_then(|user_manager| user_manager.get_user_by_hash(user_hash))
+                && try_key(user.key())
+            {
+                self.record_trial_match(*node, user_hash);
+                return Some((user, *node));
+            }
+        }
+
+        if !may_try_all {
+            trace!("trial decryption from {:?} skipped, too many failures", self.trial_source);
+            return None;
+        }
+
+        for node in 0..=shared_nodes.len() {
+            let user_manager = node_manager(node).expect("node sharing the port");
+            for user in user_manager.users_iter() {
+                let tried = known_users
+                    .iter()
+                    .any(|(n, user_hash)| *n == node && user_hash == user.identity_hash());
+                if !tried && try_key(user.key()) {
+                    trace!("{:?} of node {} chosen by trial decryption", user, node);
+                    self.record_trial_match(node, user.identity_hash());
+                    return Some((user, node));
+                }
+            }
+        }
+
+        if let Some(source) = self.trial_source {
+            self.trial_sources.lock().expect("user manager poisoned").record_failure(source);
+        }
+        None
+    }
+
+    fn record_trial_match(&self, node: usize, user_hash: &[u8]) {
+        if let Some(source) = self.trial_source {
+            let mut trial_sources = self.trial_sources.lock().expect("user manager poisoned");
+            trial_sources.record_match(source, node, user_hash);
+        }
+    }
+
+    fn find_node_user<F>(&self, key: &[u8], decrypt_identity: &mut F) -> Option<Arc<ServerUser>>
+    where
+        F: FnMut(&[u8]) -> [u8; 16],
+    {
+        let user_hash = decrypt_identity(key);
+        if let Some(user) = self.get_user_by_hash(&user_hash) {
+            return Some(user);
         }
+
+        // Clients may still use server keys replaced recently
+        for previous_key in self.previous_keys() {
+            if previous_key.len() != key.len() || previous_key == key {
+                continue;
+            }
+            let user_hash = decrypt_identity(&previous_key);
+            if let Some(user) = self.get_user_by_hash(&user_hash) {
+                trace!("{:?} authenticated with a previous server key", user);
+                self.record_previous_key_use(&user_hash, &previous_key);
+                return Some(user);
+            }
+        }
+
+        None
+    }
+
+    /// Keep accepting identity headers of a replaced server key (iPSK) for `grace_period`
+    ///
+    /// Clients update their keys slowly, the most recently added key is tried first.
+    pub fn add_previous_key<K>(&self, key: K, grace_period: Duration)
+    where
+        K: Into<Bytes>,
+    {
+        let key = key.into();
+        let now = Instant::now();
+        let mut previous_keys = self.previous_keys.write().expect("user manager poisoned");
+        previous_keys.retain(|(k, expires)| *k != key && *expires > now);
+        previous_keys.insert(0, (key, now + grace_period));
+    }
+
+    /// Previous server keys (iPSK) still accepted, most recent first
+    pub fn previous_keys(&self) -> Vec<Bytes> {
+        let now = Instant::now();
+        let previous_keys = self.previous_keys.read().expect("user manager poisoned");
+        previous_keys
+            .iter()
+            .filter(|(_, expires)| *expires > now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Record that a user authenticated with a previous server key
+    pub fn record_previous_key_use(&self, user_hash: &[u8], key: &[u8]) {
+        let mut users = self.previous_key_users.lock().expect("user manager poisoned");
+        users.insert(Bytes::copy_from_slice(user_hash), Bytes::copy_from_slice(key));
+    }
+
+    /// Take users authenticated with previous server keys since last call, by user hash
+    pub fn take_previous_key_users(&self) -> HashMap<Bytes, Bytes> {
+        let mut users = self.previous_key_users.lock().expect("user manager poisoned");
+        std::mem::take(&mut *users)
     }
 
     /// Add a new user
@@ -808,7 +1093,15 @@ impl ServerConfig {
                     let user_info = format!("{}:{}", self.method(), self.password());
                     URL_PASSWORD_BASE64_ENGINE.encode(user_info)
                 } else {
-                    format!("{}:{}", self.method(), percent_encoding::utf8_percent_encode(self.password(), percent_encoding::NON_ALPHANUMERIC))
+                    // Identity keys (iPSK) are kept apart from the user key (uPSK)
+                    let mut password = self
+                        .identity_keys
+                        .iter()
+                        .map(|key| USER_KEY_BASE64_ENGINE.encode(key))
+                        .collect::<Vec<_>>();
+                    password.push(self.password().to_owned());
+                    let password = password.join(":");
+                    format!("{}:{}", self.method(), percent_encoding::utf8_percent_encode(&password, percent_encoding::NON_ALPHANUMERIC))
                 };
             } else {
                 let mut user_info = format!("{}:{}", self.method(), self.password());
@@ -1438,6 +1731,26 @@ impl FromStr for ReplayAttackPolicy {
     }
 }
 
+impl<'de> serde::Deserialize<'de> for ReplayAttackPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse::<Self>()
+            .map_err(|_| serde::de::Error::invalid_value(serde::de::Unexpected::Str(&s), &"default, ignore, detect or reject"))
+    }
+}
+
+impl serde::Serialize for ReplayAttackPolicy {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1447,4 +1760,100 @@ mod test {
         let server_config = ServerConfig::from_url("ss://foo:bar@127.0.0.1:9999");
         assert!(matches!(server_config, Err(UrlParseError::InvalidMethod)));
     }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    #[test]
+    fn test_server_config_to_url_with_identity_keys() {
+        let password = "YWJjZGVmZ2hpamtsbW5vcA==:cXJzdHV2d3h5ejAxMjM0NQ==";
+        let server_config =
+            ServerConfig::new(("127.0.0.1", 9999), password, CipherKind::AEAD2022_BLAKE3_AES_128_GCM).unwrap();
+
+        let parsed = ServerConfig::from_url(&server_config.to_url()).unwrap();
+        assert_eq!(parsed.key(), server_config.key());
+        assert_eq!(parsed.identity_keys(), server_config.identity_keys());
+    }
+
+    #[test]
+    fn test_server_user_manager_previous_keys() {
+        let manager = ServerUserManager::new();
+        manager.add_previous_key(vec![1u8; 16], Duration::from_secs(60));
+        manager.add_previous_key(vec![2u8; 16], Duration::ZERO);
+        manager.add_previous_key(vec![3u8; 16], Duration::from_secs(60));
+        manager.add_previous_key(vec![1u8; 16], Duration::from_secs(60));
+
+        // Expired keys are dropped, the most recent first
+        assert_eq!(
+            manager.previous_keys(),
+            vec![Bytes::from(vec![1u8; 16]), Bytes::from(vec![3u8; 16])]
+        );
+    }
+
+    #[test]
+    fn test_server_user_manager_find_user_by_trial() {
+        let manager = ServerUserManager::new();
+        for i in 1..=8u8 {
+            manager.add_user(ServerUser::new(i.to_string(), vec![i; 32]));
+        }
+        let node_manager = Arc::new(ServerUserManager::new());
+        node_manager.add_user(ServerUser::new("9", vec![9u8; 32]));
+        manager.set_shared_nodes(vec![(Bytes::from(vec![0u8; 32]), node_manager)]);
+
+        let (user, node) = manager.find_user_by_trial(|key| key[0] == 9).unwrap();
+        assert_eq!((user.name(), node), ("9", 1));
+
+        // Users matched for a source are tried first for it
+        let source = manager.with_trial_source("198.51.100.1".parse().unwrap());
+        let (user, _) = source.find_user_by_trial(|key| key[0] == 5).unwrap();
+        assert_eq!(user.name(), "5");
+        let mut tries = 0;
+        let (user, node) = manager
+            .with_trial_source("::ffff:198.51.100.1".parse().unwrap())
+            .find_user_by_trial(|key| {
+                tries += 1;
+                key[0] == 5
+            })
+            .unwrap();
+        assert_eq!((user.name(), node, tries), ("5", 0, 1));
+
+        assert!(manager.find_user_by_trial(|_| false).is_none());
+    }
+
+    #[test]
+    fn test_server_user_manager_trial_failures_capped() {
+        let manager = ServerUserManager::new();
+        for i in 1..=8u8 {
+            manager.add_user(ServerUser::new(i.to_string(), vec![i; 32]));
+        }
+
+        let source = manager.with_trial_source("198.51.100.1".parse().unwrap());
+        source.find_user_by_trial(|key| key[0] == 3).unwrap();
+
+        let mut tries = 0;
+        for _ in 0..MAX_TRIAL_FAILURES_PER_SOURCE {
+            assert!(source.find_user_by_trial(|_| { tries += 1; false }).is_none());
+        }
+        assert_eq!(tries, 8 * MAX_TRIAL_FAILURES_PER_SOURCE);
+
+        // Only the known user is tried once failures reached the limit
+        let mut tries = 0;
+        assert!(source.find_user_by_trial(|_| { tries += 1; false }).is_none());
+        assert_eq!(tries, 1);
+        assert!(source.find_user_by_trial(|key| key[0] == 3).is_some());
+        assert!(source.find_user_by_trial(|key| key[0] == 4).is_none());
+
+        // Other sources are still tried with all users
+        let other = manager.with_trial_source("198.51.100.2".parse().unwrap());
+        assert!(other.find_user_by_trial(|key| key[0] == 4).is_some());
+    }
+
+    #[test]
+    fn test_trial_sources_bounded() {
+        let mut sources = TrialSources::default();
+        for i in 0..=MAX_TRIAL_SOURCES as u32 {
+            sources.record_failure(IpAddr::from(i.to_be_bytes()));
+        }
+        assert!(sources.sources.len() <= MAX_TRIAL_SOURCES / 2 + 1);
+        // The latest source is kept
+        assert!(sources.sources.contains_key(&IpAddr::from((MAX_TRIAL_SOURCES as u32).to_be_bytes())));
+    }
 }
@@ -6,7 +6,7 @@ use byte_string::ByteStr;
 use log::warn;
 
 use crate::{
-    config::{ReplayAttackPolicy, ServerType}, crypto::CipherKind, dns_resolver::DnsResolver, relay::tcprelay::proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF, security::replay::ReplayProtector
+    config::{ReplayAttackPolicy, ServerType}, crypto::CipherKind, dns_resolver::DnsResolver, relay::tcprelay::proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF, security::replay::{ReplayProtector, ReplayProtectorConfig, ReplayedNonce}
 };
 
 /// Service context
@@ -90,6 +90,8 @@ impl Context {
     }
 
     /// Check nonce replay
+    ///
+    /// Rejected nonces are returned as `ReplayedNonce` in the `io::Error`.
     pub fn check_nonce_replay(&self, method: CipherKind, nonce: &[u8]) -> io::Result<()> {
         if nonce.is_empty() {
             return Ok(());
@@ -114,8 +116,7 @@ impl Context {
             }
             ReplayAttackPolicy::Reject => {
                 if self.replay_protector.check_nonce_and_set(method, nonce) {
-                    let err = io::Error::other("detected repeated nonce (iv/salt)");
-                    Err(err)
+                    Err(ReplayedNonce.into())
                 } else {
                     Ok(())
                 }
@@ -164,6 +165,16 @@ impl Context {
         self.replay_policy
     }
 
+    /// Replace the protector against replay attack with settings, all nonces remembered are lost
+    pub fn set_replay_protector_config(&mut self, config: ReplayProtectorConfig) {
+        self.replay_protector = ReplayProtector::with_config(config);
+    }
+
+    /// Get the protector against replay attack
+    pub fn replay_protector(&self) -> &ReplayProtector {
+        &self.replay_protector
+    }
+
     /// Set AEAD 2022 timestamp limit
     pub fn set_timestamp_limit(&mut self, timestamp_limit: u64) {
         self.timestamp_limit = timestamp_limit;
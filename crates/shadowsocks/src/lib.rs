@@ -20,4 +20,4 @@ pub mod manager;
 pub mod net;
 pub mod plugin;
 pub mod relay;
-mod security;
+pub mod security;
@@ -36,6 +36,7 @@ use std::{
     marker::Unpin,
     pin::Pin,
     slice,
+    sync::Arc,
     task::{self, Poll},
 };
 
@@ -46,6 +47,7 @@ use log::trace;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
+    config::{ServerUser, ServerUserManager},
     context::Context,
     crypto::{CipherKind, v1::Cipher},
 };
@@ -64,6 +66,8 @@ pub enum ProtocolError {
     DecryptDataError,
     #[error("decrypt length failed")]
     DecryptLengthError,
+    #[error("no user could decrypt length")]
+    UserNotFound,
     #[error(
         "buffer size too large ({0:#x}), AEAD encryption protocol requires buffer to be smaller than 0x3FFF, the higher two bits must be set to zero"
     )]
@@ -98,10 +102,18 @@ pub struct DecryptedReader {
     method: CipherKind,
     salt: Option<Bytes>,
     has_handshaked: bool,
+    user_manager: Option<Arc<ServerUserManager>>,
+    user: Option<Arc<ServerUser>>,
+    node: usize,
 }
 
 impl DecryptedReader {
     pub fn new(method: CipherKind, key: &[u8]) -> Self {
+        Self::with_user_manager(method, key, None)
+    }
+
+    /// Create a reader finding the user by trying keys of users in `user_manager` on the first chunk
+    pub fn with_user_manager(method: CipherKind, key: &[u8], user_manager: Option<Arc<ServerUserManager>>) -> Self {
         if method.salt_len() > 0 {
             Self {
                 state: DecryptReadState::WaitSalt {
@@ -112,6 +124,9 @@ impl DecryptedReader {
                 method,
                 salt: None,
                 has_handshaked: false,
+                user_manager,
+                user: None,
+                node: 0,
             }
         } else {
             Self {
@@ -121,6 +136,9 @@ impl DecryptedReader {
                 method,
                 salt: None,
                 has_handshaked: false,
+                user_manager: None,
+                user: None,
+                node: 0,
             }
         }
     }
@@ -129,6 +147,16 @@ impl DecryptedReader {
         self.salt.as_deref()
     }
 
+    /// User chosen by trial decryption
+    pub fn user(&self) -> Option<Arc<ServerUser>> {
+        self.user.clone()
+    }
+
+    /// Number of the node sharing the server port that the user belongs to
+    pub fn node(&self) -> usize {
+        self.node
+    }
+
     /// Attempt to read decrypted data from stream
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -205,9 +233,12 @@ impl DecryptedReader {
 
         trace!("got AEAD salt {:?}", ByteStr::new(salt));
 
-        let cipher = Cipher::new(self.method, key, salt);
+        // Users are found by trying their keys on the length of the first chunk
+        if self.user_manager.is_none() {
+            let cipher = Cipher::new(self.method, key, salt);
 
-        self.cipher = Some(cipher);
+            self.cipher = Some(cipher);
+        }
 
         Ok(()).into()
     }
@@ -223,10 +254,35 @@ impl DecryptedReader {
             return Ok(None).into();
         }
 
-        let cipher = self.cipher.as_mut().expect("cipher is None");
-
         let m = &mut self.buffer[..length_len];
-        let length = Self::decrypt_length(cipher, m)?;
+        let length = match self.cipher {
+            Some(ref mut cipher) => Self::decrypt_length(cipher, m)?,
+            None => {
+                let user_manager = self.user_manager.as_ref().expect("user_manager is None");
+                let salt = self.salt.as_ref().expect("salt is None");
+                let encrypted_length = m.to_vec();
+
+                let mut user_cipher = None;
+                let found = user_manager.find_user_by_trial(|key| {
+                    let mut cipher = Cipher::new(self.method, key, salt);
+                    m.copy_from_slice(&encrypted_length);
+                    let decrypted = cipher.decrypt_packet(m);
+                    if decrypted {
+                        user_cipher = Some(cipher);
+                    }
+                    decrypted
+                });
+                let Some((user, node)) = found else {
+                    return Err(ProtocolError::UserNotFound).into();
+                };
+
+                trace!("{:?} of node {} chosen by trial decryption", user, node);
+                self.user = Some(user);
+                self.node = node;
+                self.cipher = user_cipher;
+                Self::check_length(u16::from_be_bytes([m[0], m[1]]) as usize)?
+            }
+        };
 
         Ok(Some(length)).into()
     }
@@ -307,6 +363,10 @@ impl DecryptedReader {
             u16::from_be_bytes([m[0], m[1]]) as usize
         };
 
+        Self::check_length(plen)
+    }
+
+    fn check_length(plen: usize) -> ProtocolResult<usize> {
         if plen > MAX_PACKET_SIZE {
             // https://shadowsocks.org/doc/aead.html
             //
@@ -357,6 +417,11 @@ impl EncryptedWriter {
         self.salt.as_ref()
     }
 
+    /// Reset cipher with authenticated user key
+    pub fn reset_cipher_with_key(&mut self, key: &[u8]) {
+        self.cipher = Cipher::new(self.cipher.kind(), key, &self.salt);
+    }
+
     /// Attempt to write encrypted data into the writer
     pub fn poll_write_encrypted<S>(
         &mut self,
@@ -94,6 +94,8 @@ pub enum ProtocolError {
     MissingExtendedIdentityHeader,
     #[error("invalid client user identity {:?}", ByteStr::new(.0))]
     InvalidClientUser(Bytes),
+    #[error("no user could decrypt header chunk")]
+    UserNotFound,
     #[error("decrypt header chunk failed")]
     DecryptHeaderChunkError,
     #[error("decrypt data failed")]
@@ -137,6 +139,7 @@ pub struct DecryptedReader {
     data_chunk_count: u64,
     user_manager: Option<Arc<ServerUserManager>>,
     user: Option<Arc<ServerUser>>,
+    node: usize,
     has_handshaked: bool,
     timestamp_diff: Arc<AtomicI64>,
 }
@@ -146,6 +149,10 @@ impl GetUser for DecryptedReader {
     fn user(&self) -> Option<Arc<ServerUser>> {
         self.user.clone()
     }
+
+    fn node(&self) -> usize {
+        self.node
+    }
 }
 
 impl DecryptedReader {
@@ -174,6 +181,7 @@ impl DecryptedReader {
                 data_chunk_count: 0,
                 user_manager,
                 user: None,
+                node: 0,
                 has_handshaked: false,
                 timestamp_diff,
             }
@@ -191,6 +199,7 @@ impl DecryptedReader {
                 data_chunk_count: 0,
                 user_manager,
                 user: None,
+                node: 0,
                 has_handshaked: false,
                 timestamp_diff,
             }
@@ -320,35 +329,47 @@ impl DecryptedReader {
                     let (eih, remain_header_chunk) = header_chunk.split_at_mut(16);
                     header_chunk = remain_header_chunk;
 
-                    let key_material = [key, salt].concat();
-                    let identity_sub_key = blake3::derive_key(AEAD2022_EIH_SUBKEY_CONTEXT, &key_material);
-                    let mut user_hash = Block::from([0u8; 16]);
-                    match self.method {
-                        CipherKind::AEAD2022_BLAKE3_AES_128_GCM => {
-                            let cipher = Aes128::new_from_slice(&identity_sub_key[0..16]).expect("AES-128");
-                            cipher.decrypt_block_b2b(Block::from_slice(eih), &mut user_hash);
+                    let method = self.method;
+                    let decrypt_user_hash = |ipsk: &[u8]| {
+                        let key_material = [ipsk, &*salt].concat();
+                        let identity_sub_key = blake3::derive_key(AEAD2022_EIH_SUBKEY_CONTEXT, &key_material);
+                        let mut user_hash = Block::from([0u8; 16]);
+                        match method {
+                            CipherKind::AEAD2022_BLAKE3_AES_128_GCM => {
+                                let cipher = Aes128::new_from_slice(&identity_sub_key[0..16]).expect("AES-128");
+                                cipher.decrypt_block_b2b(Block::from_slice(eih), &mut user_hash);
+                            }
+                            CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
+                                let cipher = Aes256::new_from_slice(&identity_sub_key[0..32]).expect("AES-256");
+                                cipher.decrypt_block_b2b(Block::from_slice(eih), &mut user_hash);
+                            }
+                            _ => unreachable!("{} doesn't support EIH", method),
                         }
-                        CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
-                            let cipher = Aes256::new_from_slice(&identity_sub_key[0..32]).expect("AES-256");
-                            cipher.decrypt_block_b2b(Block::from_slice(eih), &mut user_hash);
-                        }
-                        _ => unreachable!("{} doesn't support EIH", self.method),
-                    }
+                        user_hash
+                    };
 
-                    let user_hash = user_hash.as_slice();
+                    let mut user_hash = None;
+                    let found = user_manager.find_user_by_identity(key, |ipsk| {
+                        let hash = decrypt_user_hash(ipsk);
+                        user_hash.get_or_insert(hash);
+                        hash.into()
+                    });
+                    let user_hash = user_hash.expect("EIH decrypted");
                     trace!(
                         "server EIH {:?}, hash: {:?}",
                         ByteStr::new(eih),
-                        ByteStr::new(user_hash)
+                        ByteStr::new(user_hash.as_slice())
                     );
 
-                    match user_manager.get_user_by_hash(user_hash) {
+                    match found {
                         None => {
-                            return Err(ProtocolError::InvalidClientUser(Bytes::copy_from_slice(user_hash))).into();
+                            return Err(ProtocolError::InvalidClientUser(Bytes::copy_from_slice(user_hash.as_slice())))
+                                .into();
                         }
-                        Some(user) => {
-                            trace!("{:?} chosen by EIH", user);
+                        Some((user, node)) => {
+                            trace!("{:?} of node {} chosen by EIH", user, node);
                             self.user = Some(user.clone());
+                            self.node = node;
                             TcpCipher::new(self.method, user.key(), salt)
                         }
                     }
@@ -357,6 +378,29 @@ impl DecryptedReader {
                     unreachable!("user_manager must not be None")
                 }
             }
+        } else if self.stream_ty == StreamType::Server
+            && let Some(ref user_manager) = self.user_manager
+        {
+            // Methods without EIH find the user by trying their keys on the header chunk
+            let method = self.method;
+            let encrypted_header_chunk = header_chunk.to_vec();
+            let found = user_manager.find_user_by_trial(|user_key| {
+                let mut cipher = TcpCipher::new(method, user_key, salt);
+                header_chunk.copy_from_slice(&encrypted_header_chunk);
+                cipher.decrypt_packet(header_chunk)
+            });
+            header_chunk.copy_from_slice(&encrypted_header_chunk);
+
+            match found {
+                None => return Err(ProtocolError::UserNotFound).into(),
+                Some((user, node)) => {
+                    trace!("{:?} of node {} chosen by trial decryption", user, node);
+                    let cipher = TcpCipher::new(self.method, user.key(), salt);
+                    self.user = Some(user);
+                    self.node = node;
+                    cipher
+                }
+            }
         } else {
             TcpCipher::new(self.method, key, salt)
         };
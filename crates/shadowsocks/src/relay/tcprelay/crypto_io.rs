@@ -84,18 +84,28 @@ pub enum DecryptedReader {
 }
 
 impl GetUser for DecryptedReader {
-    /// Get authenticated user key (AEAD2022)
+    /// Get authenticated user key (AEAD, AEAD2022)
     fn user(&self) -> Option<Arc<ServerUser>> {
         match *self {
             #[cfg(feature = "stream-cipher")]
             Self::Stream(..) => None,
             #[cfg(feature = "aead-cipher")]
-            Self::Aead(..) => None,
+            Self::Aead(ref reader) => reader.user(),
             Self::None => None,
             #[cfg(feature = "aead-cipher-2022")]
             Self::Aead2022(ref reader) => reader.user(),
         }
     }
+
+    fn node(&self) -> usize {
+        match *self {
+            #[cfg(feature = "aead-cipher")]
+            Self::Aead(ref reader) => reader.node(),
+            #[cfg(feature = "aead-cipher-2022")]
+            Self::Aead2022(ref reader) => reader.node(),
+            _ => 0,
+        }
+    }
 }
 
 impl DecryptedReader {
@@ -112,7 +122,7 @@ impl DecryptedReader {
         user_manager: Option<Arc<ServerUserManager>>,
         timestamp_diff: Arc<AtomicI64>,
     ) -> Self {
-        if cfg!(not(feature = "aead-cipher-2022")) {
+        if cfg!(not(any(feature = "aead-cipher", feature = "aead-cipher-2022"))) {
             let _ = stream_ty;
             let _ = user_manager;
         }
@@ -121,7 +131,11 @@ impl DecryptedReader {
             #[cfg(feature = "stream-cipher")]
             CipherCategory::Stream => Self::Stream(StreamDecryptedReader::new(method, key)),
             #[cfg(feature = "aead-cipher")]
-            CipherCategory::Aead => Self::Aead(AeadDecryptedReader::new(method, key)),
+            CipherCategory::Aead => Self::Aead(AeadDecryptedReader::with_user_manager(
+                method,
+                key,
+                user_manager.filter(|_| stream_ty == StreamType::Server),
+            )),
             CipherCategory::None => {
                 let _ = method;
                 let _ = key;
@@ -323,11 +337,13 @@ impl EncryptedWriter {
     /// Reset cipher with authenticated user key
     pub fn reset_cipher_with_key(&mut self, key: &[u8]) {
         match *self {
+            #[cfg(feature = "aead-cipher")]
+            Self::Aead(ref mut writer) => writer.reset_cipher_with_key(key),
             #[cfg(feature = "aead-cipher-2022")]
             Self::Aead2022(ref mut writer) => writer.reset_cipher_with_key(key),
             _ => {
                 let _ = key;
-                panic!("only AEAD-2022 cipher could authenticate with multiple users");
+                panic!("only AEAD and AEAD-2022 ciphers could authenticate with multiple users");
             }
         }
     }
@@ -355,6 +371,10 @@ impl<S> GetUser for CryptoStream<S> {
     fn user(&self) -> Option<Arc<ServerUser>> {
         self.dec.user()
     }
+
+    fn node(&self) -> usize {
+        self.dec.node()
+    }
 }
 
 impl<S> CryptoStream<S> {
@@ -9,6 +9,9 @@ pub use self::{
     proxy_stream::{ProxyClientStream, ProxyServerStream},
 };
 
+#[cfg(feature = "aead-cipher-2022")]
+pub use self::aead_2022::ProtocolError as Aead2022ProtocolError;
+
 #[cfg(feature = "aead-cipher")]
 mod aead;
 #[cfg(feature = "aead-cipher-2022")]
@@ -31,4 +34,7 @@ pub enum StreamType {
 
 pub trait GetUser {
     fn user(&self) -> Option<Arc<ServerUser>>;
+
+    /// Number of the node sharing the server port that the user belongs to, 0 for the server's own node
+    fn node(&self) -> usize;
 }
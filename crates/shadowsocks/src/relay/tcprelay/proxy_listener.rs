@@ -77,22 +77,40 @@ impl ProxyListener {
     where
         F: FnOnce(ProxyServerStream<TcpStream>) -> S,
         S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.accept_wrap_map(|s| s, map_fn).await
+    }
+
+    /// Accepts a shadowsocks' client connection, wrapping the plain `TcpStream` before decryption,
+    /// and maps the `ProxyServerStream` to another stream type
+    pub async fn accept_wrap_map<W, T, F, S>(&self, wrap_fn: W, map_fn: F) -> io::Result<(S, SocketAddr)>
+    where
+        W: FnOnce(TcpStream) -> T,
+        T: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(ProxyServerStream<T>) -> S,
+        S: AsyncRead + AsyncWrite + Unpin,
     {
         let (stream, peer_addr) = self.listener.accept().await?;
-        
-        // Create a ProxyServerStream and read the target address from it
-        let stream = ProxyServerStream::from_stream_with_user_manager(
-            self.context.clone(),
-            stream,
-            self.method,
-            &self.key,
-            self.user_manager.clone(),
-        );
-        
-        let stream = map_fn(stream);
+        let stream = wrap_fn(stream);
+        let stream = map_fn(self.wrap_stream(stream, peer_addr));
         Ok((stream, peer_addr))
     }
 
+    /// Create a `ProxyServerStream` decrypting `stream` accepted from the internal listener
+    ///
+    /// For streams that need handshakes of their own before the shadowsocks handshake.
+    /// Users found by trial decryption are remembered for `peer_addr`.
+    pub fn wrap_stream<S>(&self, stream: S, peer_addr: SocketAddr) -> ProxyServerStream<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let user_manager = self
+            .user_manager
+            .as_ref()
+            .map(|user_manager| Arc::new(user_manager.with_trial_source(peer_addr.ip())));
+        ProxyServerStream::from_stream_with_user_manager(self.context.clone(), stream, self.method, &self.key, user_manager)
+    }
+
     /// Get local binded address
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.listener.local_addr()
@@ -47,6 +47,10 @@ impl<S> GetUser for ProxyServerStream<S> {
     fn user(&self) -> Option<Arc<ServerUser>> {
         self.stream.user()
     }
+
+    fn node(&self) -> usize {
+        self.stream.node()
+    }
 }
 
 impl<S> ProxyServerStream<S> {
@@ -11,13 +11,14 @@
 //! +--------+-----------+-----------+
 //! ```
 
-use std::io::Cursor;
+use std::{io::Cursor, sync::Arc};
 
 use byte_string::ByteStr;
 use bytes::{BufMut, BytesMut};
 use log::trace;
 
 use crate::{
+    config::{ServerUser, ServerUserManager},
     context::Context,
     crypto::{CipherKind, v1::Cipher},
     relay::socks5::{Address, Error as Socks5Error},
@@ -34,6 +35,8 @@ pub enum ProtocolError {
     InvalidAddress(Socks5Error),
     #[error("decrypt payload failed")]
     DecryptPayloadError,
+    #[error("no user could decrypt payload")]
+    UserNotFound,
 }
 
 /// AEAD protocol result
@@ -105,9 +108,50 @@ pub fn decrypt_payload_aead(
         return Err(ProtocolError::DecryptPayloadError);
     }
 
+    extract_payload(payload, salt_len, tag_len)
+}
+
+/// Decrypt UDP AEAD protocol packet of a client, finding the user by trying keys of users in `user_manager`
+pub fn decrypt_client_payload_aead(
+    _context: &Context,
+    method: CipherKind,
+    payload: &mut [u8],
+    user_manager: &ServerUserManager,
+) -> ProtocolResult<(usize, Address, Arc<ServerUser>, usize)> {
+    let plen = payload.len();
+    let salt_len = method.salt_len();
+    if plen < salt_len {
+        return Err(ProtocolError::PacketTooShortForSalt(salt_len, plen));
+    }
+
+    let (salt, data) = payload.split_at_mut(salt_len);
+
+    trace!("UDP packet got AEAD salt {:?}", ByteStr::new(salt));
+
+    let tag_len = method.tag_len();
+    if data.len() < tag_len {
+        return Err(ProtocolError::PacketTooShortForTag(tag_len, data.len()));
+    }
+
+    let encrypted_data = data.to_vec();
+    let found = user_manager.find_user_by_trial(|key| {
+        let mut cipher = Cipher::new(method, key, salt);
+        data.copy_from_slice(&encrypted_data);
+        cipher.decrypt_packet(data)
+    });
+    let Some((user, node)) = found else {
+        return Err(ProtocolError::UserNotFound);
+    };
+
+    let (n, addr) = extract_payload(payload, salt_len, tag_len)?;
+    Ok((n, addr, user, node))
+}
+
+/// Move the decrypted payload after the address to the beginning of `payload`
+fn extract_payload(payload: &mut [u8], salt_len: usize, tag_len: usize) -> ProtocolResult<(usize, Address)> {
     // Truncate TAG
-    let data_len = data.len() - tag_len;
-    let data = &mut data[..data_len];
+    let data_len = payload.len() - salt_len - tag_len;
+    let data = &payload[salt_len..salt_len + data_len];
 
     let (dn, addr) = parse_packet(data)?;
 
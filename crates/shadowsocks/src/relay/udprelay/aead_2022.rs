@@ -96,6 +96,8 @@ pub enum ProtocolError {
     DecryptPayloadError,
     #[error("invalid client user identity {:?}", ByteStr::new(.0))]
     InvalidClientUser(Bytes),
+    #[error("no user could decrypt payload")]
+    UserNotFound,
     #[error("invalid socket type, expecting {0:#x}, but found {1:#x}")]
     InvalidSocketType(u8, u8),
     #[error("invalid timestamp {0} - now {1} = {ts_diff}", ts_diff = *.0 as i64 - *.1 as i64)]
@@ -245,7 +247,7 @@ fn decrypt_message(
     key: &[u8],
     packet: &mut [u8],
     user_manager: Option<&ServerUserManager>,
-) -> ProtocolResult<Option<Arc<ServerUser>>> {
+) -> ProtocolResult<Option<(Arc<ServerUser>, usize)>> {
     let mut client_user = None;
 
     match method {
@@ -264,10 +266,17 @@ fn decrypt_message(
                 u64::from_be(session_id_slice[0])
             };
 
-            let cipher = get_cipher(method, key, session_id);
+            match user_manager {
+                Some(user_manager) => {
+                    client_user = Some(decrypt_message_by_trial(method, session_id, nonce, message, user_manager)?);
+                }
+                None => {
+                    let cipher = get_cipher(method, key, session_id);
 
-            if !cipher.decrypt_packet(nonce, message) {
-                return Err(ProtocolError::DecryptPayloadError);
+                    if !cipher.decrypt_packet(nonce, message) {
+                        return Err(ProtocolError::DecryptPayloadError);
+                    }
+                }
             }
         }
         #[cfg(feature = "aead-cipher-2022-extra")]
@@ -286,10 +295,17 @@ fn decrypt_message(
                 u64::from_be(session_id_slice[0])
             };
 
-            let cipher = get_cipher(method, key, session_id);
+            match user_manager {
+                Some(user_manager) => {
+                    client_user = Some(decrypt_message_by_trial(method, session_id, nonce, message, user_manager)?);
+                }
+                None => {
+                    let cipher = get_cipher(method, key, session_id);
 
-            if !cipher.decrypt_packet(nonce, message) {
-                return Err(ProtocolError::DecryptPayloadError);
+                    if !cipher.decrypt_packet(nonce, message) {
+                        return Err(ProtocolError::DecryptPayloadError);
+                    }
+                }
             }
         }
         CipherKind::AEAD2022_BLAKE3_AES_128_GCM | CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
@@ -300,28 +316,11 @@ fn decrypt_message(
             // No padding is required because these 2 fields are 128-bits, which is exactly the same as AES's block size
 
             let (packet_header, mut message) = packet.split_at_mut(16);
+            let encrypted_packet_header: [u8; 16] = (&*packet_header).try_into().expect("packet header");
 
-            match method {
-                CipherKind::AEAD2022_BLAKE3_AES_128_GCM => {
-                    let cipher = Aes128::new_from_slice(key).expect("AES-128 init");
-                    let block = Block::from_mut_slice(packet_header);
-                    cipher.decrypt_block(block);
-                }
-                CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
-                    let cipher = Aes256::new_from_slice(key).expect("AES-256 init");
-                    let block = Block::from_mut_slice(packet_header);
-                    cipher.decrypt_block(block);
-                }
-                _ => unreachable!("{} is not an AES-*-GCM cipher", method),
-            }
-
-            // Session ID is the first 64-bits
+            decrypt_packet_header(method, key, packet_header);
 
-            let session_id = {
-                let session_id_buf = &packet_header[0..8];
-                let session_id_slice: &[u64] = unsafe { slice::from_raw_parts(session_id_buf.as_ptr() as *const _, 1) };
-                u64::from_be(session_id_slice[0])
-            };
+            let mut session_id = get_session_id(packet_header);
 
             let cipher = if method_support_eih(method) {
                 if let Some(user_manager) = user_manager {
@@ -330,43 +329,37 @@ fn decrypt_message(
 
                     let (eih, remain_message) = message.split_at_mut(16);
                     message = remain_message;
-
-                    let session_id_packet_id = &packet_header[0..16];
+                    let encrypted_eih: [u8; 16] = (&*eih).try_into().expect("EIH");
 
                     trace!(
                         "server EIH {:?}, session_id_packet_id: {:?}",
                         ByteStr::new(eih),
-                        ByteStr::new(session_id_packet_id)
+                        ByteStr::new(packet_header)
                     );
 
-                    match method {
-                        CipherKind::AEAD2022_BLAKE3_AES_128_GCM => {
-                            let cipher = Aes128::new_from_slice(key).expect("AES-128 init");
-                            cipher.decrypt_block(Block::from_mut_slice(eih));
-                        }
-                        CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
-                            let cipher = Aes256::new_from_slice(key).expect("AES-256 init");
-                            cipher.decrypt_block(Block::from_mut_slice(eih))
-                        }
-                        _ => unreachable!("{} doesn't support EIH", method),
-                    }
-
-                    for i in 0..16 {
-                        eih[i] ^= session_id_packet_id[i];
-                    }
-
-                    match user_manager.clone_user_by_hash(eih) {
-                        None => {
-                            error!("user with identity {:?} not found", ByteStr::new(eih));
-                            return Err(ProtocolError::InvalidClientUser(Bytes::copy_from_slice(eih)));
-                        }
-                        Some(user) => {
-                            trace!("{:?} chosen by EIH", user);
-                            let cipher = get_cipher(method, user.key(), session_id);
-                            client_user = Some(user);
-                            cipher
-                        }
-                    }
+                    let mut user_hash = None;
+                    let found = user_manager.find_user_by_identity(key, |ipsk| {
+                        packet_header.copy_from_slice(&encrypted_packet_header);
+                        decrypt_packet_header(method, ipsk, packet_header);
+                        eih.copy_from_slice(&encrypted_eih);
+                        decrypt_identity_header(method, ipsk, packet_header, eih);
+                        let hash: [u8; 16] = (&*eih).try_into().expect("EIH");
+                        user_hash.get_or_insert(hash);
+                        hash
+                    });
+
+                    let Some((user, node)) = found else {
+                        let user_hash = user_hash.expect("EIH decrypted");
+                        error!("user with identity {:?} not found", ByteStr::new(&user_hash));
+                        return Err(ProtocolError::InvalidClientUser(Bytes::copy_from_slice(&user_hash)));
+                    };
+
+                    // Header may be decrypted with keys of other nodes
+                    session_id = get_session_id(packet_header);
+                    trace!("{:?} of node {} chosen by EIH", user, node);
+                    let cipher = get_cipher(method, user.key(), session_id);
+                    client_user = Some((user, node));
+                    cipher
                 } else {
                     get_cipher(method, key, session_id)
                 }
@@ -385,6 +378,75 @@ fn decrypt_message(
     Ok(client_user)
 }
 
+/// Decrypt ChaCha*-Poly1305 packets without EIH by trying keys of users in `user_manager`
+fn decrypt_message_by_trial(
+    method: CipherKind,
+    session_id: u64,
+    nonce: &[u8],
+    message: &mut [u8],
+    user_manager: &ServerUserManager,
+) -> ProtocolResult<(Arc<ServerUser>, usize)> {
+    let encrypted_message = message.to_vec();
+    let found = user_manager.find_user_by_trial(|user_key| {
+        // Not cached, keys of most users don't match, users known for the source are tried first
+        let cipher = UdpCipher::new(method, user_key, session_id);
+        message.copy_from_slice(&encrypted_message);
+        cipher.decrypt_packet(nonce, message)
+    });
+
+    match found {
+        Some((user, node)) => {
+            trace!("{:?} of node {} chosen by trial decryption", user, node);
+            Ok((user, node))
+        }
+        None => Err(ProtocolError::UserNotFound),
+    }
+}
+
+/// Decrypt [SessionID + PacketID] of AES-*-GCM packets, encrypted with AES-ECB with PSK
+///
+/// No padding is required because these 2 fields are 128-bits, which is exactly the same as AES's block size
+fn decrypt_packet_header(method: CipherKind, key: &[u8], packet_header: &mut [u8]) {
+    match method {
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM => {
+            let cipher = Aes128::new_from_slice(key).expect("AES-128 init");
+            cipher.decrypt_block(Block::from_mut_slice(packet_header));
+        }
+        CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
+            let cipher = Aes256::new_from_slice(key).expect("AES-256 init");
+            cipher.decrypt_block(Block::from_mut_slice(packet_header));
+        }
+        _ => unreachable!("{} is not an AES-*-GCM cipher", method),
+    }
+}
+
+/// Decrypt EIH into the user's identity hash, with the decrypted [SessionID + PacketID]
+fn decrypt_identity_header(method: CipherKind, key: &[u8], session_id_packet_id: &[u8], eih: &mut [u8]) {
+    match method {
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM => {
+            let cipher = Aes128::new_from_slice(key).expect("AES-128 init");
+            cipher.decrypt_block(Block::from_mut_slice(eih));
+        }
+        CipherKind::AEAD2022_BLAKE3_AES_256_GCM => {
+            let cipher = Aes256::new_from_slice(key).expect("AES-256 init");
+            cipher.decrypt_block(Block::from_mut_slice(eih))
+        }
+        _ => unreachable!("{} doesn't support EIH", method),
+    }
+
+    for i in 0..16 {
+        eih[i] ^= session_id_packet_id[i];
+    }
+}
+
+/// Session ID is the first 64-bits of the decrypted packet header
+#[inline]
+fn get_session_id(packet_header: &[u8]) -> u64 {
+    let session_id_buf = &packet_header[0..8];
+    let session_id_slice: &[u64] = unsafe { slice::from_raw_parts(session_id_buf.as_ptr() as *const _, 1) };
+    u64::from_be(session_id_slice[0])
+}
+
 #[inline]
 fn get_nonce_len(method: CipherKind) -> usize {
     match method {
@@ -532,7 +594,10 @@ pub fn decrypt_client_payload_aead_2022(
         return Err(ProtocolError::PacketTooShort(header_len, payload.len()));
     }
 
-    let user = decrypt_message(context, method, key, payload, user_manager)?;
+    let (user, node) = match decrypt_message(context, method, key, payload, user_manager)? {
+        Some((user, node)) => (Some(user), node),
+        None => (None, 0),
+    };
 
     let data = &payload[nonce_len..payload.len() - tag_len];
     let mut cursor = Cursor::new(data);
@@ -568,6 +633,7 @@ pub fn decrypt_client_payload_aead_2022(
         server_session_id: 0,
         packet_id,
         user,
+        node,
         timestamp_diff,
     };
 
@@ -677,7 +743,8 @@ pub fn decrypt_server_payload_aead_2022(
         client_session_id,
         server_session_id,
         packet_id,
-        user,
+        user: user.map(|(user, _)| user),
+        node: 0,
         timestamp_diff,
     };
 
@@ -693,3 +760,130 @@ pub fn decrypt_server_payload_aead_2022(
 
     Ok((payload_len, addr, control))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::ServerType;
+
+    #[test]
+    fn test_decrypt_with_previous_server_key() {
+        let context = Context::new(ServerType::Server);
+        let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+        let previous_key = Bytes::from_static(&[1u8; 16]);
+        let current_key = [2u8; 16];
+
+        let user_manager = ServerUserManager::new();
+        let user = ServerUser::new("1", vec![3u8; 16]);
+        let user_hash = user.clone_identity_hash();
+        user_manager.add_user(user.clone());
+
+        let addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let control = UdpSocketControlData {
+            client_session_id: 1,
+            packet_id: 1,
+            ..Default::default()
+        };
+        let mut packet = BytesMut::new();
+        encrypt_client_payload_aead_2022(
+            &context,
+            method,
+            user.key(),
+            &addr,
+            &control,
+            &[previous_key.clone()],
+            b"hello",
+            &mut packet,
+        );
+
+        // Rejected before the previous key is registered
+        let mut payload = packet.to_vec();
+        assert!(
+            decrypt_client_payload_aead_2022(&context, method, &current_key, &mut payload, Some(&user_manager))
+                .is_err()
+        );
+
+        user_manager.add_previous_key(previous_key.clone(), Duration::from_secs(60));
+        let mut payload = packet.to_vec();
+        let (n, decrypted_addr, decrypted_control) =
+            decrypt_client_payload_aead_2022(&context, method, &current_key, &mut payload, Some(&user_manager))
+                .unwrap();
+        assert_eq!(&payload[..n], b"hello");
+        assert_eq!(decrypted_addr, addr);
+        assert_eq!(decrypted_control.client_session_id, 1);
+        assert_eq!(decrypted_control.user.unwrap().name(), "1");
+
+        let users = user_manager.take_previous_key_users();
+        assert_eq!(users.get(&user_hash), Some(&previous_key));
+        assert!(user_manager.take_previous_key_users().is_empty());
+    }
+
+    #[test]
+    fn test_decrypt_with_shared_node_key() {
+        let context = Context::new(ServerType::Server);
+        let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+        let key = [1u8; 16];
+        let node_key = Bytes::from_static(&[2u8; 16]);
+
+        // Same user in both nodes
+        let user = ServerUser::new("1", vec![3u8; 16]);
+        let user_manager = ServerUserManager::new();
+        user_manager.add_user(user.clone());
+        let node_user_manager = Arc::new(ServerUserManager::new());
+        node_user_manager.add_user(user.clone());
+        user_manager.set_shared_nodes(vec![(node_key.clone(), node_user_manager)]);
+
+        let addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let control = UdpSocketControlData {
+            client_session_id: 1,
+            packet_id: 1,
+            ..Default::default()
+        };
+        for (ipsk, expected_node) in [(Bytes::copy_from_slice(&key), 0), (node_key, 1)] {
+            let mut packet = BytesMut::new();
+            encrypt_client_payload_aead_2022(&context, method, user.key(), &addr, &control, &[ipsk], b"hello", &mut packet);
+
+            let mut payload = packet.to_vec();
+            let (n, _, decrypted_control) =
+                decrypt_client_payload_aead_2022(&context, method, &key, &mut payload, Some(&user_manager)).unwrap();
+            assert_eq!(&payload[..n], b"hello");
+            assert_eq!(decrypted_control.client_session_id, 1);
+            assert_eq!(decrypted_control.node, expected_node);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_chacha20_by_trial() {
+        let context = Context::new(ServerType::Server);
+        let method = CipherKind::AEAD2022_BLAKE3_CHACHA20_POLY1305;
+        let key = [1u8; 32];
+
+        let user_manager = ServerUserManager::new();
+        for i in 2..5u8 {
+            user_manager.add_user(ServerUser::new(i.to_string(), vec![i; 32]));
+        }
+
+        let addr = Address::DomainNameAddress("example.com".to_owned(), 443);
+        let control = UdpSocketControlData {
+            client_session_id: 1,
+            packet_id: 1,
+            ..Default::default()
+        };
+        let mut packet = BytesMut::new();
+        encrypt_client_payload_aead_2022(&context, method, &[3u8; 32], &addr, &control, &[], b"hello", &mut packet);
+
+        let mut payload = packet.to_vec();
+        let (n, decrypted_addr, decrypted_control) =
+            decrypt_client_payload_aead_2022(&context, method, &key, &mut payload, Some(&user_manager)).unwrap();
+        assert_eq!(&payload[..n], b"hello");
+        assert_eq!(decrypted_addr, addr);
+        assert_eq!(decrypted_control.user.unwrap().name(), "3");
+
+        // Keys of unknown users are rejected
+        let mut packet = BytesMut::new();
+        encrypt_client_payload_aead_2022(&context, method, &key, &addr, &control, &[], b"hello", &mut packet);
+        let mut payload = packet.to_vec();
+        assert!(decrypt_client_payload_aead_2022(&context, method, &key, &mut payload, Some(&user_manager)).is_err());
+    }
+}
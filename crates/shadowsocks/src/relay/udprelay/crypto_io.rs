@@ -31,7 +31,7 @@ use crate::{
 };
 
 #[cfg(feature = "aead-cipher")]
-use super::aead::{decrypt_payload_aead, encrypt_payload_aead};
+use super::aead::{decrypt_client_payload_aead, decrypt_payload_aead, encrypt_payload_aead};
 #[cfg(feature = "aead-cipher-2022")]
 use super::aead_2022::{
     decrypt_client_payload_aead_2022, decrypt_server_payload_aead_2022, encrypt_client_payload_aead_2022,
@@ -167,12 +167,19 @@ pub fn decrypt_client_payload(
                 .map_err(Into::into)
         }
         #[cfg(feature = "aead-cipher")]
-        CipherCategory::Aead => {
-            let _ = user_manager;
-            decrypt_payload_aead(context, method, key, payload)
+        CipherCategory::Aead => match user_manager {
+            Some(user_manager) => decrypt_client_payload_aead(context, method, payload, user_manager)
+                .map(|(n, a, user, node)| {
+                    let mut control = UdpSocketControlData::default();
+                    control.user = Some(user);
+                    control.node = node;
+                    (n, a, Some(control))
+                })
+                .map_err(Into::into),
+            None => decrypt_payload_aead(context, method, key, payload)
                 .map(|(n, a)| (n, a, None))
-                .map_err(Into::into)
-        }
+                .map_err(Into::into),
+        },
         #[cfg(feature = "aead-cipher-2022")]
         CipherCategory::Aead2022 => decrypt_client_payload_aead_2022(context, method, key, payload, user_manager)
             .map(|(n, a, c)| (n, a, Some(c)))
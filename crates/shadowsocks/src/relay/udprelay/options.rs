@@ -19,6 +19,8 @@ pub struct UdpSocketControlData {
     pub packet_id: u64,
     /// Server user instance
     pub user: Option<Arc<ServerUser>>,
+    /// Number of the node sharing the server port that `user` belongs to, 0 for the server's own node
+    pub node: usize,
     /// Timestamp diff for ComplyWithIncoming (local now - incoming timestamp)
     pub timestamp_diff: i64,
 }
@@ -30,6 +32,7 @@ impl UdpSocketControlData {
             server_session_id: self.server_session_id,
             packet_id: self.packet_id,
             user: self.user,
+            node: self.node,
             timestamp_diff: 0,
         }
     }
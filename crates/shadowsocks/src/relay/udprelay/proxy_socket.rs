@@ -171,6 +171,24 @@ impl<S> ProxySocket<S> {
     pub fn set_recv_timeout(&mut self, t: Option<Duration>) {
         self.recv_timeout = t;
     }
+
+    /// Wrap the underlying I/O object, keeping keys and users of the socket
+    pub fn map_io<T, F>(self, f: F) -> ProxySocket<T>
+    where
+        F: FnOnce(S) -> T,
+    {
+        ProxySocket {
+            socket_type: self.socket_type,
+            io: f(self.io),
+            method: self.method,
+            key: self.key,
+            send_timeout: self.send_timeout,
+            recv_timeout: self.recv_timeout,
+            context: self.context,
+            identity_keys: self.identity_keys,
+            user_manager: self.user_manager,
+        }
+    }
 }
 
 impl<S> ProxySocket<S>
@@ -424,6 +442,13 @@ where
         }
     }
 
+    /// Users of packets from `peer_addr`, its associations are found by trial decryption once
+    fn source_user_manager(&self, peer_addr: SocketAddr) -> Option<ServerUserManager> {
+        self.user_manager
+            .as_ref()
+            .map(|user_manager| user_manager.with_trial_source(peer_addr.ip()))
+    }
+
     /// Receive packet from Shadowsocks' UDP server
     ///
     /// This function will use `recv_buf` to store intermediate data, so it has to be big enough to store the whole shadowsocks' packet
@@ -496,7 +521,8 @@ where
             },
         };
 
-        let (n, addr, control) = match self.decrypt_recv_buffer(&mut recv_buf[..recv_n], self.user_manager.as_deref()) {
+        let user_manager = self.source_user_manager(target_addr);
+        let (n, addr, control) = match self.decrypt_recv_buffer(&mut recv_buf[..recv_n], user_manager.as_ref()) {
             Ok(x) => x,
             Err(err) => return Err(ProxySocketError::ProtocolErrorWithPeer(target_addr, err)),
         };
@@ -559,7 +585,8 @@ where
         let src = ready!(self.io.poll_recv_from(cx, recv_buf))?;
 
         let n_recv = recv_buf.filled().len();
-        match self.decrypt_recv_buffer(recv_buf.filled_mut(), self.user_manager.as_deref()) {
+        let user_manager = self.source_user_manager(src);
+        match self.decrypt_recv_buffer(recv_buf.filled_mut(), user_manager.as_ref()) {
             Ok(x) => Poll::Ready(Ok((x.0, src, x.1, n_recv, x.2))),
             Err(err) => Poll::Ready(Err(ProxySocketError::ProtocolError(err))),
         }
@@ -1,7 +1,10 @@
-use std::fmt;
-
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    time::Duration,
+};
 #[cfg(feature = "aead-cipher-2022")]
-use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use cfg_if::cfg_if;
 #[cfg(feature = "aead-cipher-2022")]
@@ -17,18 +20,109 @@ use self::ppbloom::PingPongBloom;
 #[cfg(feature = "security-replay-attack-detect")]
 mod ppbloom;
 
+/// Magic of files saved by `ReplayProtector::save_salts`
+#[cfg(feature = "aead-cipher-2022")]
+const SALT_FILE_MAGIC: &[u8; 8] = b"SSSALT01";
+
+/// Settings of `ReplayProtector`
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayProtectorConfig {
+    /// Nonces remembered by the bloom filter of stream and AEAD ciphers, 0 disables the filter
+    ///
+    /// The filter takes about 3.6 bytes per entry at false positive rate 1e-6, 3.6 MB with the server's default.
+    pub bloom_entries: usize,
+    /// False positive rate of the bloom filter
+    pub bloom_error_rate: f64,
+    /// How long AEAD 2022 salts are remembered, should cover the whole timestamp window
+    pub salt_expiry: Duration,
+}
+
+impl ReplayProtectorConfig {
+    /// Default settings of clients or servers
+    pub fn new(config_type: ServerType) -> Self {
+        let (bloom_entries, bloom_error_rate) = if config_type.is_local() {
+            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
+        } else {
+            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
+        };
+
+        Self {
+            bloom_entries,
+            bloom_error_rate,
+            salt_expiry: Duration::from_secs(DEFAULT_SALT_EXPIRY_SECS),
+        }
+    }
+}
+
+// Entries for server's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_NUM_ENTRIES_FOR_SERVER: usize = 1_000_000;
+
+// Entries for client's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_NUM_ENTRIES_FOR_CLIENT: usize = 10_000;
+
+// Error rate for server's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
+
+// Error rate for client's bloom filter
+//
+// Borrowed from shadowsocks-libev's default value
+const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
+
+// Salts are valid in timestamps of both directions
+#[cfg(feature = "aead-cipher-2022")]
+const DEFAULT_SALT_EXPIRY_SECS: u64 = SERVER_STREAM_TIMESTAMP_MAX_DIFF * 2;
+#[cfg(not(feature = "aead-cipher-2022"))]
+const DEFAULT_SALT_EXPIRY_SECS: u64 = 60;
+
+/// Error of a nonce (IV or salt) seen before, rejected by `Context::check_nonce_replay`
+///
+/// Carried by the returned `io::Error`, which could be checked with `ReplayedNonce::is_replayed`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ReplayedNonce;
+
+impl ReplayedNonce {
+    /// Check if `err` is caused by a replayed nonce
+    pub fn is_replayed(err: &io::Error) -> bool {
+        err.get_ref().is_some_and(|e| e.is::<Self>())
+    }
+}
+
+impl fmt::Display for ReplayedNonce {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("detected repeated nonce (iv/salt)")
+    }
+}
+
+impl std::error::Error for ReplayedNonce {}
+
+impl From<ReplayedNonce> for io::Error {
+    fn from(e: ReplayedNonce) -> Self {
+        Self::other(e)
+    }
+}
+
 /// A Bloom Filter based protector against replay attack
 pub struct ReplayProtector {
     // Check for duplicated IV/Nonce, for prevent replay attack
     // https://github.com/shadowsocks/shadowsocks-org/issues/44
     #[cfg(feature = "security-replay-attack-detect")]
-    nonce_ppbloom: spin::Mutex<PingPongBloom>,
+    nonce_ppbloom: Option<spin::Mutex<PingPongBloom>>,
 
     // AEAD 2022 specific filter.
     // AEAD 2022 TCP protocol has a timestamp, which can already reject most of the replay requests,
     // so we only need to remember nonce that are in the valid time range
+    //
+    // Values are when the salts were first seen, in Unix timestamp seconds
     #[cfg(feature = "aead-cipher-2022")]
-    nonce_set: spin::Mutex<LruCache<Vec<u8>, ()>>,
+    nonce_set: spin::Mutex<LruCache<Vec<u8>, u64>>,
+    #[cfg(feature = "aead-cipher-2022")]
+    salt_expiry: Duration,
 }
 
 impl fmt::Debug for ReplayProtector {
@@ -39,15 +133,21 @@ impl fmt::Debug for ReplayProtector {
 
 impl ReplayProtector {
     /// Create a new ReplayProtector
-    #[allow(unused_variables)]
     pub fn new(config_type: ServerType) -> Self {
+        Self::with_config(ReplayProtectorConfig::new(config_type))
+    }
+
+    /// Create a new ReplayProtector with settings
+    #[allow(unused_variables)]
+    pub fn with_config(config: ReplayProtectorConfig) -> Self {
         Self {
             #[cfg(feature = "security-replay-attack-detect")]
-            nonce_ppbloom: spin::Mutex::new(PingPongBloom::new(config_type)),
+            nonce_ppbloom: (config.bloom_entries > 0)
+                .then(|| spin::Mutex::new(PingPongBloom::new(config.bloom_entries, config.bloom_error_rate))),
+            #[cfg(feature = "aead-cipher-2022")]
+            nonce_set: spin::Mutex::new(LruCache::with_expiry_duration(config.salt_expiry)),
             #[cfg(feature = "aead-cipher-2022")]
-            nonce_set: spin::Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(
-                SERVER_STREAM_TIMESTAMP_MAX_DIFF * 2,
-            ))),
+            salt_expiry: config.salt_expiry,
         }
     }
 
@@ -66,7 +166,7 @@ impl ReplayProtector {
             if set.get(nonce).is_some() {
                 return true;
             }
-            set.insert(nonce.to_vec(), ());
+            set.insert(nonce.to_vec(), unix_timestamp(SystemTime::now()));
             return false;
         }
 
@@ -74,11 +174,113 @@ impl ReplayProtector {
 
         cfg_if! {
             if #[cfg(feature = "security-replay-attack-detect")] {
-                let mut ppbloom = self.nonce_ppbloom.lock();
-                ppbloom.check_and_set(nonce)
+                match self.nonce_ppbloom {
+                    Some(ref ppbloom) => ppbloom.lock().check_and_set(nonce),
+                    None => false,
+                }
             } else {
                 false
             }
         }
     }
+
+    /// Save AEAD 2022 salts remembered to `writer`, returns the number of salts saved
+    ///
+    /// Salts are lost after restarts, so requests captured within the timestamp window could be replayed.
+    pub fn save_salts<W: Write>(&self, writer: W) -> io::Result<usize> {
+        cfg_if! {
+            if #[cfg(feature = "aead-cipher-2022")] {
+                let salts: Vec<(Vec<u8>, u64)> = {
+                    let set = self.nonce_set.lock();
+                    set.peek_iter().map(|(salt, seen)| (salt.clone(), *seen)).collect()
+                };
+
+                let mut writer = writer;
+                writer.write_all(SALT_FILE_MAGIC)?;
+                for (salt, seen) in salts.iter() {
+                    writer.write_all(&seen.to_be_bytes())?;
+                    writer.write_all(&[salt.len() as u8])?;
+                    writer.write_all(salt)?;
+                }
+                writer.flush()?;
+                Ok(salts.len())
+            } else {
+                let _ = writer;
+                Ok(0)
+            }
+        }
+    }
+
+    /// Load AEAD 2022 salts saved by `save_salts`, returns the number of salts loaded
+    ///
+    /// Salts seen before the expiry are skipped.
+    pub fn load_salts<R: Read>(&self, reader: R) -> io::Result<usize> {
+        cfg_if! {
+            if #[cfg(feature = "aead-cipher-2022")] {
+                let mut buf = Vec::new();
+                let mut reader = reader;
+                reader.read_to_end(&mut buf)?;
+
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid salt file");
+                let mut data = buf.strip_prefix(&SALT_FILE_MAGIC[..]).ok_or_else(invalid)?;
+
+                let now = unix_timestamp(SystemTime::now());
+                let mut salts = Vec::new();
+                while !data.is_empty() {
+                    if data.len() < 9 {
+                        return Err(invalid());
+                    }
+                    let seen = u64::from_be_bytes(data[..8].try_into().expect("8 bytes"));
+                    let len = data[8] as usize;
+                    let salt = data.get(9..9 + len).ok_or_else(invalid)?;
+                    data = &data[9 + len..];
+
+                    if now.saturating_sub(seen) < self.salt_expiry.as_secs() {
+                        salts.push((salt.to_vec(), seen));
+                    }
+                }
+
+                let loaded = salts.len();
+                let mut set = self.nonce_set.lock();
+                for (salt, seen) in salts {
+                    set.insert(salt, seen);
+                }
+                Ok(loaded)
+            } else {
+                let _ = reader;
+                Ok(0)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(all(test, feature = "aead-cipher-2022"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_load_salts() {
+        let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+        let protector = ReplayProtector::new(ServerType::Server);
+        assert!(!protector.check_nonce_and_set(method, b"0123456789abcdef"));
+
+        let mut saved = Vec::new();
+        assert_eq!(protector.save_salts(&mut saved).unwrap(), 1);
+        // Expired salts are not loaded
+        saved.extend_from_slice(&0u64.to_be_bytes());
+        saved.push(16);
+        saved.extend_from_slice(b"fedcba9876543210");
+
+        let restarted = ReplayProtector::new(ServerType::Server);
+        assert_eq!(restarted.load_salts(&saved[..]).unwrap(), 1);
+        assert!(restarted.check_nonce_and_set(method, b"0123456789abcdef"));
+        assert!(!restarted.check_nonce_and_set(method, b"fedcba9876543210"));
+
+        assert!(restarted.load_salts(&b"garbage"[..]).is_err());
+    }
 }
@@ -1,28 +1,6 @@
 use bloomfilter::Bloom;
 use log::debug;
 
-use crate::config::ServerType;
-
-// Entries for server's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_NUM_ENTRIES_FOR_SERVER: usize = 1_000_000;
-
-// Entries for client's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_NUM_ENTRIES_FOR_CLIENT: usize = 10_000;
-
-// Error rate for server's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
-
-// Error rate for client's bloom filter
-//
-// Borrowed from shadowsocks-libev's default value
-const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
-
 // A bloom filter borrowed from shadowsocks-libev's `ppbloom`
 //
 // It contains 2 bloom filters and each one holds 1/2 entries.
@@ -36,14 +14,8 @@ pub struct PingPongBloom {
 }
 
 impl PingPongBloom {
-    pub fn new(ty: ServerType) -> Self {
-        let (mut item_count, fp_p) = if ty.is_local() {
-            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
-        } else {
-            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
-        };
-
-        item_count /= 2;
+    pub fn new(item_count: usize, fp_p: f64) -> Self {
+        let item_count = (item_count / 2).max(1);
 
         Self {
             blooms: [
@@ -1,9 +1,26 @@
 use serde::{Deserialize, Serialize};
-use shadowsocks_service::shadowsocks::{config::Mode, relay::tcprelay::proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF};
+use shadowsocks_service::acl::{GeoDataConfig, IpBanConfig, P2pAction, PortAutoBlock, PortRange, UserLimits};
+use shadowsocks_service::net::{BindAddrStrategy, SniffConfig};
+use shadowsocks_service::server::access_log::AccessLogConfig;
+use shadowsocks_service::shadowsocks::{
+    config::{Mode, ReplayAttackPolicy},
+    relay::tcprelay::proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF,
+    security::replay::ReplayProtectorConfig,
+};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::time::Duration;
 
+use crate::manager::UserKeyDerivation;
 use crate::v2board::ApiConfig;
 
+/// Salts of AEAD 2022 are remembered this long if `timestamp_limit` is 0
+const SALT_EXPIRY_WITHOUT_TIMESTAMP: Duration = Duration::from_secs(3600);
+
+/// Soft limit of open files assumed if it can't be read
+const DEFAULT_NOFILE: u64 = 1024;
+
 /// Application configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -68,6 +85,14 @@ pub struct ShadowsocksConfig {
     #[serde(default = "default_udp_mtu")]
     pub udp_mtu: usize,
 
+    /// Address listened by the server, "::" also accepts IPv4 if dual-stack (default: "::")
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: IpAddr,
+
+    /// Ports listened in addition to `server_port` of the panel, like [8443, "20000-20100"] (default: empty)
+    #[serde(default)]
+    pub listen_ports: Vec<PortRange>,
+
     /// Shadowsocks server mode: "tcp_only", "udp_only", or "tcp_and_udp" (default: "tcp_and_udp")
     #[serde(default = "default_mode")]
     pub mode: Mode,
@@ -79,6 +104,217 @@ pub struct ShadowsocksConfig {
     // AEAD 2022 complying with incoming timestamp (default: false)
     #[serde(default = "default_comply_with_incoming")]
     pub comply_with_incoming: bool,
+
+    /// Outbound source address pool (default: empty, addresses are chosen by the system)
+    #[serde(default)]
+    pub outbound_bind_addrs: Vec<IpAddr>,
+
+    /// Strategy for choosing addresses from the pool: "stable", "random" or "round_robin" (default: "stable")
+    #[serde(default)]
+    pub outbound_bind_strategy: BindAddrStrategy,
+
+    /// Block outbound connections to loopback, private, link-local and other internal addresses (default: true)
+    #[serde(default = "default_block_internal_addrs")]
+    pub block_internal_addrs: bool,
+
+    /// Internal addresses or networks still allowed when `block_internal_addrs` is enabled (default: empty)
+    #[serde(default)]
+    pub allowed_internal_addrs: Vec<String>,
+
+    /// Public addresses of the node not assigned to its interfaces, e.g. behind cloud NAT, blocked with
+    /// internal addresses. Interface addresses are blocked automatically (default: empty)
+    #[serde(default)]
+    pub public_addrs: Vec<IpAddr>,
+
+    /// Blocked outbound TCP ports or port ranges, e.g. [25, "6881-6889"] (default: empty)
+    #[serde(default)]
+    pub tcp_blocked_ports: Vec<PortRange>,
+
+    /// Allowed outbound TCP ports, other ports are blocked if not empty (default: empty)
+    #[serde(default)]
+    pub tcp_allowed_ports: Vec<PortRange>,
+
+    /// Blocked outbound UDP ports or port ranges (default: empty)
+    #[serde(default)]
+    pub udp_blocked_ports: Vec<PortRange>,
+
+    /// Allowed outbound UDP ports, other ports are blocked if not empty (default: empty)
+    #[serde(default)]
+    pub udp_allowed_ports: Vec<PortRange>,
+
+    /// Blocked port hits within `port_block_window` to block a user temporarily (default: None, disabled)
+    pub port_block_threshold: Option<u32>,
+
+    /// Period of counting blocked port hits in seconds (default: 60)
+    #[serde(default = "default_port_block_window")]
+    pub port_block_window: u64,
+
+    /// Duration of the temporary block in seconds (default: 600)
+    #[serde(default = "default_port_block_duration")]
+    pub port_block_duration: u64,
+
+    /// Access Control List file path, or HTTP(S) URL (default: None)
+    pub acl: Option<String>,
+
+    /// Interval of checking the ACL for changes in seconds, 0 disables reloading (default: 30)
+    #[serde(default = "default_acl_reload_interval")]
+    pub acl_reload_interval: u64,
+
+    /// GeoIP database for `geoip:` ACL rules, MaxMind `.mmdb` or v2ray `geoip.dat` (default: None)
+    pub geoip: Option<PathBuf>,
+
+    /// v2ray `geosite.dat` for `geosite:` ACL rules (default: None)
+    pub geosite: Option<PathBuf>,
+
+    /// Sniff domain names from TLS SNI and HTTP Host of TCP connections (default: false)
+    #[serde(default)]
+    pub sniff: bool,
+
+    /// Sniff domain names from SNI of QUIC Initial packets (default: false)
+    #[serde(default)]
+    pub sniff_quic: bool,
+
+    /// Connect TCP connections to sniffed domain names instead of the original targets (default: false)
+    #[serde(default)]
+    pub sniff_override_destination: bool,
+
+    /// Time waiting for the first payload to sniff in milliseconds (default: 300)
+    #[serde(default = "default_sniff_timeout")]
+    pub sniff_timeout: u64,
+
+    /// Action on detected BitTorrent traffic: "block", "throttle" or "log" (default: None, disabled)
+    pub p2p_action: Option<P2pAction>,
+
+    /// Bandwidth of a user's throttled P2P traffic in KiB/s (default: 64)
+    #[serde(default = "default_p2p_throttle_rate")]
+    pub p2p_throttle_rate: u64,
+
+    /// File exporting P2P detections of users since start in JSON, written on every push (default: None)
+    pub p2p_export: Option<PathBuf>,
+
+    /// Access log file in JSON lines (default: None, disabled)
+    pub access_log: Option<PathBuf>,
+
+    /// Rotate the access log when it grows over this size in MiB, 0 disables (default: 100)
+    #[serde(default = "default_access_log_max_size")]
+    pub access_log_max_size: u64,
+
+    /// Rotate the access log after this many seconds, 0 disables (default: 86400)
+    #[serde(default = "default_access_log_rotate_interval")]
+    pub access_log_rotate_interval: u64,
+
+    /// Rotated access log files to keep (default: 7)
+    #[serde(default = "default_access_log_max_files")]
+    pub access_log_max_files: usize,
+
+    /// Concurrent TCP connections of a user (default: None, unlimited)
+    pub user_max_tcp_connections: Option<u32>,
+
+    /// Concurrent UDP associations of a user (default: None, unlimited)
+    pub user_max_udp_associations: Option<u32>,
+
+    /// New TCP connections and UDP associations per second of a user (default: None, unlimited)
+    pub user_max_new_connections: Option<u32>,
+
+    /// Apply per-user limits sent by the panel, which stock V2Board doesn't send (default: false)
+    #[serde(default)]
+    pub user_limits_from_panel: bool,
+
+    /// Handshake failures from a network to ban it (default: None, disabled)
+    pub ban_threshold: Option<u32>,
+
+    /// Period of counting handshake failures in seconds (default: 60)
+    #[serde(default = "default_ban_window")]
+    pub ban_window: u64,
+
+    /// Duration of the first ban in seconds, doubled for each ban again (default: 600)
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+
+    /// Upper limit of ban durations in seconds (default: 86400)
+    #[serde(default = "default_ban_max_duration")]
+    pub ban_max_duration: u64,
+
+    /// Prefix length of IPv4 networks banned together (default: 32)
+    #[serde(default = "default_ban_ipv4_prefix")]
+    pub ban_ipv4_prefix: u8,
+
+    /// Prefix length of IPv6 networks banned together (default: 64)
+    #[serde(default = "default_ban_ipv6_prefix")]
+    pub ban_ipv6_prefix: u8,
+
+    /// File exporting current bans in JSON, written on every push (default: None)
+    pub ban_export: Option<PathBuf>,
+
+    /// nftables set of banned IPv4 networks, like "inet filter ss22v2b_ban4" (default: None)
+    pub ban_nftables_set_v4: Option<String>,
+
+    /// nftables set of banned IPv6 networks, like "inet filter ss22v2b_ban6" (default: None)
+    pub ban_nftables_set_v6: Option<String>,
+
+    /// Backend receiving connections failing handshakes, like "127.0.0.1:80" (default: None, reset)
+    pub fallback: Option<String>,
+
+    /// Load balancers sending PROXY protocol (v1 or v2) headers, addresses or networks (default: empty)
+    #[serde(default)]
+    pub proxy_protocol_trusted: Vec<String>,
+
+    /// File persisting AEAD 2022 salts seen, against replays after restarts (default: None)
+    pub replay_cache: Option<PathBuf>,
+
+    /// Save the salt file every this many seconds, 0 only saves on shutdown (default: 60)
+    #[serde(default = "default_replay_cache_save_interval")]
+    pub replay_cache_save_interval: u64,
+
+    /// Policy against replays of legacy ciphers: "default", "ignore", "detect" or "reject" (default: "default")
+    #[serde(default)]
+    pub replay_attack_policy: ReplayAttackPolicy,
+
+    /// Nonces remembered by the bloom filter of legacy ciphers, 0 disables it (default: 1000000)
+    #[serde(default = "default_replay_bloom_entries")]
+    pub replay_bloom_entries: usize,
+
+    /// False positive rate of the bloom filter of legacy ciphers (default: 1e-6)
+    #[serde(default = "default_replay_bloom_error_rate")]
+    pub replay_bloom_error_rate: f64,
+
+    /// Keep accepting the previous server_key for this many seconds after the panel changes it, 0 disables (default: 86400)
+    #[serde(default = "default_server_key_grace_period")]
+    pub server_key_grace_period: u64,
+
+    /// Derivation of AEAD 2022 user keys: "uuid_prefix" or "password" (default: "uuid_prefix")
+    #[serde(default)]
+    pub user_key_derivation: UserKeyDerivation,
+
+    /// PEM certificate chain serving WebSocket transports over TLS (default: None, plain WebSocket)
+    pub transport_tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `transport_tls_cert` (default: None)
+    pub transport_tls_key: Option<PathBuf>,
+
+    /// Relay UDP-over-TCP tunnels of sing-box clients as UDP, unless `mode` disables UDP (default: true)
+    #[serde(default = "default_udp_over_tcp")]
+    pub udp_over_tcp: bool,
+
+    /// Demultiplex sing-mux sessions (h2mux, smux and yamux, with padding) of sing-box clients (default: true)
+    #[serde(default = "default_mux")]
+    pub mux: bool,
+
+    /// Password of ShadowTLS v3 in front of all nodes (default: None, ShadowTLS disabled)
+    pub shadow_tls_password: Option<String>,
+
+    /// TLS server `host:port` relaying handshakes of ShadowTLS (default: None)
+    pub shadow_tls_handshake_server: Option<String>,
+
+    /// Switch to this user after startup, only keeping the capability of binding low ports (default: None)
+    pub user: Option<String>,
+
+    /// Switch to this group after startup, the primary group of `user` if not set (default: None)
+    pub group: Option<String>,
+
+    /// Restrict file access with Landlock and syscalls with seccomp after startup, Linux only (default: false)
+    #[serde(default)]
+    pub sandbox: bool,
 }
 
 impl Default for ShadowsocksConfig {
@@ -95,9 +331,69 @@ impl Default for ShadowsocksConfig {
             ipv6_first: false,
             udp_max_associations: None,
             udp_mtu: default_udp_mtu(),
+            listen_addr: default_listen_addr(),
+            listen_ports: Vec::new(),
             mode: default_mode(),
             timestamp_limit: default_timestamp_limit(),
             comply_with_incoming: false,
+            outbound_bind_addrs: Vec::new(),
+            outbound_bind_strategy: BindAddrStrategy::default(),
+            block_internal_addrs: default_block_internal_addrs(),
+            allowed_internal_addrs: Vec::new(),
+            public_addrs: Vec::new(),
+            tcp_blocked_ports: Vec::new(),
+            tcp_allowed_ports: Vec::new(),
+            udp_blocked_ports: Vec::new(),
+            udp_allowed_ports: Vec::new(),
+            port_block_threshold: None,
+            port_block_window: default_port_block_window(),
+            port_block_duration: default_port_block_duration(),
+            acl: None,
+            acl_reload_interval: default_acl_reload_interval(),
+            geoip: None,
+            geosite: None,
+            sniff: false,
+            sniff_quic: false,
+            sniff_override_destination: false,
+            sniff_timeout: default_sniff_timeout(),
+            p2p_action: None,
+            p2p_throttle_rate: default_p2p_throttle_rate(),
+            p2p_export: None,
+            access_log: None,
+            access_log_max_size: default_access_log_max_size(),
+            access_log_rotate_interval: default_access_log_rotate_interval(),
+            access_log_max_files: default_access_log_max_files(),
+            user_max_tcp_connections: None,
+            user_max_udp_associations: None,
+            user_max_new_connections: None,
+            user_limits_from_panel: false,
+            ban_threshold: None,
+            ban_window: default_ban_window(),
+            ban_duration: default_ban_duration(),
+            ban_max_duration: default_ban_max_duration(),
+            ban_ipv4_prefix: default_ban_ipv4_prefix(),
+            ban_ipv6_prefix: default_ban_ipv6_prefix(),
+            ban_export: None,
+            ban_nftables_set_v4: None,
+            ban_nftables_set_v6: None,
+            fallback: None,
+            proxy_protocol_trusted: Vec::new(),
+            replay_cache: None,
+            replay_cache_save_interval: default_replay_cache_save_interval(),
+            replay_attack_policy: ReplayAttackPolicy::Default,
+            replay_bloom_entries: default_replay_bloom_entries(),
+            replay_bloom_error_rate: default_replay_bloom_error_rate(),
+            server_key_grace_period: default_server_key_grace_period(),
+            user_key_derivation: UserKeyDerivation::default(),
+            transport_tls_cert: None,
+            transport_tls_key: None,
+            udp_over_tcp: default_udp_over_tcp(),
+            mux: default_mux(),
+            shadow_tls_password: None,
+            shadow_tls_handshake_server: None,
+            user: None,
+            group: None,
+            sandbox: false,
         }
     }
 }
@@ -117,6 +413,160 @@ impl ShadowsocksConfig {
     pub fn keep_alive_duration(&self) -> Option<Duration> {
         self.keep_alive.map(Duration::from_secs)
     }
+
+    /// Get addresses to listen, `server_port` first, then other ports in `listen_ports`
+    ///
+    /// Ports beyond `max_listen_ports` of the open files limit are rejected, large ranges should be redirected by
+    /// the firewall.
+    pub fn listen_addrs(&self, server_port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        self.listen_addrs_within(server_port, nofile_limit())
+    }
+
+    /// Get addresses to listen like `listen_addrs`, with the limit of `nofile` open files
+    pub(crate) fn listen_addrs_within(&self, server_port: u16, nofile: u64) -> anyhow::Result<Vec<SocketAddr>> {
+        let max_ports = max_listen_ports(nofile);
+        let mut seen = HashSet::from([server_port]);
+        let ports: Vec<u16> = self
+            .listen_ports
+            .iter()
+            .flat_map(|range| range.ports())
+            .filter(|port| *port != 0 && seen.insert(*port))
+            .collect();
+        if ports.len() > max_ports {
+            anyhow::bail!(
+                "listen_ports has {} ports, at most {} are supported with {} open files; raise LimitNOFILE, \
+                 or redirect large ranges to one port with nftables instead, \
+                 like `nft add rule inet nat prerouting meta l4proto {{ tcp, udp }} th dport 20000-50000 redirect to :8443`",
+                ports.len(),
+                max_ports,
+                nofile
+            );
+        }
+        Ok(std::iter::once(server_port)
+            .chain(ports)
+            .map(|port| SocketAddr::new(self.listen_addr, port))
+            .collect())
+    }
+
+    /// Get ACL reload interval as Duration, None if reloading is disabled
+    pub fn acl_reload_duration(&self) -> Option<Duration> {
+        (self.acl_reload_interval > 0).then(|| Duration::from_secs(self.acl_reload_interval))
+    }
+
+    /// Get databases for GeoIP and GeoSite ACL rules
+    pub fn geodata_config(&self) -> GeoDataConfig {
+        GeoDataConfig {
+            geoip: self.geoip.clone(),
+            geosite: self.geosite.clone(),
+        }
+    }
+
+    /// Get sniffing settings
+    pub fn sniff_config(&self) -> SniffConfig {
+        SniffConfig {
+            tcp: self.sniff,
+            quic: self.sniff_quic,
+            override_destination: self.sniff_override_destination,
+            timeout: Duration::from_millis(self.sniff_timeout),
+        }
+    }
+
+    /// Get access log settings
+    pub fn access_log_config(&self) -> Option<AccessLogConfig> {
+        self.access_log.as_ref().map(|path| AccessLogConfig {
+            path: path.clone(),
+            max_size: self.access_log_max_size * 1024 * 1024,
+            rotate_interval: (self.access_log_rotate_interval > 0)
+                .then(|| Duration::from_secs(self.access_log_rotate_interval)),
+            max_files: self.access_log_max_files,
+        })
+    }
+
+    /// Check if per-user connection limits are enforced, by node-wide defaults or the panel
+    pub fn user_limits_enabled(&self) -> bool {
+        self.user_limits_from_panel || !self.user_limits().is_unlimited()
+    }
+
+    /// Get default per-user connection limits, overridden by the panel
+    pub fn user_limits(&self) -> UserLimits {
+        UserLimits {
+            max_tcp_connections: self.user_max_tcp_connections,
+            max_udp_associations: self.user_max_udp_associations,
+            max_new_connections: self.user_max_new_connections,
+        }
+    }
+
+    /// Get settings of the protector against replay attack
+    pub fn replay_protector_config(&self) -> ReplayProtectorConfig {
+        ReplayProtectorConfig {
+            bloom_entries: self.replay_bloom_entries,
+            bloom_error_rate: self.replay_bloom_error_rate,
+            salt_expiry: self.salt_expiry(),
+        }
+    }
+
+    /// How long AEAD 2022 salts are remembered
+    ///
+    /// Timestamps of requests are accepted in both directions, so salts must outlive twice the limit,
+    /// and never less than the protocol's default window. Without the timestamp check, salts couldn't
+    /// expire by time, they are remembered for `SALT_EXPIRY_WITHOUT_TIMESTAMP`.
+    pub fn salt_expiry(&self) -> Duration {
+        if self.timestamp_limit == 0 {
+            return SALT_EXPIRY_WITHOUT_TIMESTAMP;
+        }
+        Duration::from_secs(self.timestamp_limit.max(SERVER_STREAM_TIMESTAMP_MAX_DIFF) * 2)
+    }
+
+    /// Get the salt file saving interval as Duration, None if only saving on shutdown
+    pub fn replay_cache_save_duration(&self) -> Option<Duration> {
+        (self.replay_cache_save_interval > 0).then(|| Duration::from_secs(self.replay_cache_save_interval))
+    }
+
+    /// Get the grace period of previous server keys as Duration, None if disabled
+    pub fn server_key_grace_duration(&self) -> Option<Duration> {
+        (self.server_key_grace_period > 0).then(|| Duration::from_secs(self.server_key_grace_period))
+    }
+
+    /// Get settings of banning clients failing handshakes
+    pub fn ip_ban_config(&self) -> Option<IpBanConfig> {
+        self.ban_threshold.map(|threshold| IpBanConfig {
+            threshold,
+            window: Duration::from_secs(self.ban_window),
+            duration: Duration::from_secs(self.ban_duration),
+            max_duration: Duration::from_secs(self.ban_max_duration.max(self.ban_duration)),
+            ipv4_prefix: self.ban_ipv4_prefix,
+            ipv6_prefix: self.ban_ipv6_prefix,
+        })
+    }
+
+    /// Get temporary block settings of the port policy
+    pub fn port_auto_block(&self) -> Option<PortAutoBlock> {
+        self.port_block_threshold.map(|threshold| PortAutoBlock {
+            threshold,
+            window: Duration::from_secs(self.port_block_window),
+            duration: Duration::from_secs(self.port_block_duration),
+        })
+    }
+}
+
+/// Ports of `listen_ports` listened at most with `nofile` open files
+///
+/// Each port runs a TCP and a UDP server, half of the open files are kept for connections.
+fn max_listen_ports(nofile: u64) -> usize {
+    usize::try_from(nofile / 4).unwrap_or(usize::MAX)
+}
+
+/// Soft limit of open files, raised to the hard limit at startup
+#[cfg(target_os = "linux")]
+fn nofile_limit() -> u64 {
+    use nix::sys::resource::{Resource, getrlimit};
+
+    getrlimit(Resource::RLIMIT_NOFILE).map_or(DEFAULT_NOFILE, |(soft, _)| soft)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn nofile_limit() -> u64 {
+    DEFAULT_NOFILE
 }
 
 fn default_timeout() -> u64 {
@@ -131,6 +581,10 @@ fn default_udp_mtu() -> usize {
     1500
 }
 
+fn default_listen_addr() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
 fn default_mode() -> Mode {
     Mode::TcpAndUdp
 }
@@ -142,3 +596,83 @@ fn default_timestamp_limit() -> u64 {
 fn default_comply_with_incoming() -> bool {
     false
 }
+
+fn default_block_internal_addrs() -> bool {
+    true
+}
+
+fn default_udp_over_tcp() -> bool {
+    true
+}
+
+fn default_mux() -> bool {
+    true
+}
+
+fn default_port_block_window() -> u64 {
+    60
+}
+
+fn default_port_block_duration() -> u64 {
+    600
+}
+
+fn default_acl_reload_interval() -> u64 {
+    30
+}
+
+fn default_sniff_timeout() -> u64 {
+    300
+}
+
+fn default_p2p_throttle_rate() -> u64 {
+    64
+}
+
+fn default_access_log_max_size() -> u64 {
+    100
+}
+
+fn default_access_log_rotate_interval() -> u64 {
+    86400
+}
+
+fn default_access_log_max_files() -> usize {
+    7
+}
+
+fn default_ban_window() -> u64 {
+    60
+}
+
+fn default_ban_duration() -> u64 {
+    600
+}
+
+fn default_ban_max_duration() -> u64 {
+    86400
+}
+
+fn default_ban_ipv4_prefix() -> u8 {
+    32
+}
+
+fn default_ban_ipv6_prefix() -> u8 {
+    64
+}
+
+fn default_replay_cache_save_interval() -> u64 {
+    60
+}
+
+fn default_replay_bloom_entries() -> usize {
+    1_000_000
+}
+
+fn default_replay_bloom_error_rate() -> f64 {
+    1e-6
+}
+
+fn default_server_key_grace_period() -> u64 {
+    86400
+}
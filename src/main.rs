@@ -4,12 +4,19 @@ mod v2board;
 
 use async_trait::async_trait;
 use clap::Parser;
-use log::{debug, info};
-use std::{error::Error, sync::Arc};
+use futures::future;
+use log::{debug, error, info};
+use std::{
+    error::Error,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use crate::config::Config;
-use crate::manager::ShadowsocksServerManager;
-use crate::v2board::{ApiClient, EventCallback, ServerConfig, UserInfo, UserTraffic};
+use crate::manager::{ShadowsocksServerManager, notify, spawn_watchdog, user_link, watchdog_timeout};
+use crate::v2board::{ApiClient, ApiConfig, EventCallback, ServerConfig, UserInfo, UserTraffic, UserViolation};
 
 /// Command line arguments
 #[derive(Parser, Debug)]
@@ -18,16 +25,25 @@ struct Args {
     /// Path to the configuration file
     #[arg(short, long, default_value = "config.toml")]
     config: String,
+
+    /// Print ss:// links of the node's users connecting to HOST, then exit
+    #[arg(long, value_name = "HOST")]
+    print_links: Option<String>,
 }
 
 /// Example callback implementation
 struct ServerCallback {
     server_manager: Arc<ShadowsocksServerManager>,
+    // Readiness is sent to systemd once the first config is served
+    ready: Arc<AtomicBool>,
 }
 
 impl ServerCallback {
     fn new(server_manager: Arc<ShadowsocksServerManager>) -> Self {
-        Self { server_manager }
+        Self {
+            server_manager,
+            ready: Arc::new(AtomicBool::new(false)),
+        }
     }
 }
 
@@ -40,10 +56,18 @@ impl EventCallback for ServerCallback {
         );
 
         let server_manager = self.server_manager.clone();
+        let ready = self.ready.clone();
         tokio::spawn(async move {
             if let Err(e) = server_manager.start_server(config).await {
                 panic!("Failed to start server: {}", e);
             }
+            if !ready.swap(true, Ordering::SeqCst) {
+                match notify("READY=1") {
+                    Ok(true) => debug!("Notified systemd of readiness"),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to notify systemd of readiness: {}", e),
+                }
+            }
         });
     }
 
@@ -61,10 +85,64 @@ impl EventCallback for ServerCallback {
     async fn get_traffic_data(&self) -> Option<Vec<UserTraffic>> {
         self.server_manager.collect_user_traffic().await
     }
+
+    async fn get_violations(&self) -> Option<Vec<UserViolation>> {
+        self.server_manager.collect_violations().await
+    }
+}
+
+/// Callback of a node sharing the port of the main node
+struct SharedNodeCallback {
+    server_manager: Arc<ShadowsocksServerManager>,
+    node_id: i32,
+}
+
+#[async_trait]
+impl EventCallback for SharedNodeCallback {
+    fn on_server_config_updated(&self, config: ServerConfig) {
+        info!(
+            "[Callback] Shared node {} config updated: port={}, cipher={:?}",
+            self.node_id, config.server_port, config.cipher
+        );
+
+        let server_manager = self.server_manager.clone();
+        let node_id = self.node_id;
+        tokio::spawn(async move {
+            if let Err(e) = server_manager.update_shared_node_config(node_id, config).await {
+                error!("Failed to apply config of shared node {}: {}", node_id, e);
+            }
+        });
+    }
+
+    fn on_users_updated(&self, users: Vec<UserInfo>) {
+        info!("[Callback] Shared node {} users updated: {} users", self.node_id, users.len());
+
+        let server_manager = self.server_manager.clone();
+        let node_id = self.node_id;
+        tokio::spawn(async move {
+            if let Err(e) = server_manager.update_shared_node_users(node_id, users).await {
+                error!("Failed to update users of shared node {}: {}", node_id, e);
+            }
+        });
+    }
+
+    async fn get_traffic_data(&self) -> Option<Vec<UserTraffic>> {
+        match self.server_manager.collect_shared_node_traffic(self.node_id) {
+            Ok(traffic) => Some(traffic),
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+
+    async fn get_violations(&self) -> Option<Vec<UserViolation>> {
+        // Violations of all nodes are reported by the main node
+        None
+    }
 }
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Box<dyn Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     // Parse command line arguments
@@ -73,23 +151,141 @@ async fn main() -> Result<(), Box<dyn Error>> {
     info!("Starting Shadowsocks V2Board server...");
     info!("Loading configuration from: {}", args.config);
 
+    // Ports of `listen_ports` are bounded by the limit of open files
+    raise_nofile_limit();
+
     // Load configuration
     let config = Config::load_from_file(&args.config)?;
     
     debug!("Shadowsocks settings: {:?}", config.shadowsocks);
-    
-    // Create API client
-    let mut api_client = ApiClient::new(config.api.clone())?;
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+
+    if let Some(host) = args.print_links {
+        return runtime.build()?.block_on(print_links(&config, &host));
+    }
 
     // Create server manager with shadowsocks config
-    let server_manager = Arc::new(ShadowsocksServerManager::new(config.shadowsocks.clone()));
+    let mut server_manager = ShadowsocksServerManager::new(config.shadowsocks.clone())?;
+    for node_id in &config.api.shared_node_ids {
+        server_manager.add_shared_node(*node_id);
+    }
+    #[cfg(unix)]
+    {
+        use shadowsocks_service::net::systemd_activate_socket::take_systemd_activated_sockets;
+
+        let sockets = take_systemd_activated_sockets()?;
+        if !sockets.is_empty() {
+            info!("Serving on sockets passed by systemd");
+            server_manager.set_activated_sockets(sockets);
+        }
+    }
+
+    // Threads of the runtime inherit the user and the sandbox
+    server_manager.enter_sandbox()?;
+
+    runtime.build()?.block_on(run(config, Arc::new(server_manager)))
+}
+
+/// Print ss:// links of the node's users connecting to `host`
+async fn print_links(config: &Config, host: &str) -> Result<(), Box<dyn Error>> {
+    let api_client = ApiClient::new(config.api.clone())?;
+    let node = api_client.get_node_info().await?;
+    for user in api_client.get_user_list().await? {
+        match user_link(&config.shadowsocks, &node, &user, host) {
+            Ok(link) => println!("{}", link),
+            Err(e) => error!("Skipping link of user {}: {}", user.id, e),
+        }
+    }
+    Ok(())
+}
+
+async fn run(config: Config, server_manager: Arc<ShadowsocksServerManager>) -> Result<(), Box<dyn Error>> {
+    server_manager.start_acl_reload().await?;
+    server_manager.start_replay_cache_save();
+
+    // Create API client
+    let mut api_client = ApiClient::new(config.api.clone())?;
 
     // Register callback
     let callback = Arc::new(ServerCallback::new(server_manager.clone()));
     api_client.set_callback(callback);
 
+    // Nodes sharing the port have their own users and traffic in the panel
+    let mut shared_api_clients = Vec::new();
+    for node_id in &config.api.shared_node_ids {
+        let mut shared_api_client = ApiClient::new(ApiConfig {
+            node_id: *node_id,
+            ..config.api.clone()
+        })?;
+        shared_api_client.set_callback(Arc::new(SharedNodeCallback {
+            server_manager: server_manager.clone(),
+            node_id: *node_id,
+        }));
+        shared_api_clients.push(shared_api_client);
+    }
+
+    if let Some(timeout) = watchdog_timeout() {
+        info!("Pinging systemd watchdog, timeout {}s", timeout.as_secs());
+        let pull_watches = std::iter::once(&api_client)
+            .chain(&shared_api_clients)
+            .map(|client| client.pull_watch())
+            .collect();
+        spawn_watchdog(server_manager.clone(), pull_watches, timeout);
+    }
+
     info!("Starting API client...");
-    api_client.run().await?;
+    let api_clients = future::try_join_all(
+        std::iter::once(&api_client)
+            .chain(&shared_api_clients)
+            .map(|client| client.run()),
+    );
+    tokio::select! {
+        res = api_clients => { res?; }
+        _ = shutdown_signal() => info!("Shutting down..."),
+    }
+    let _ = notify("STOPPING=1");
+
+    if let Err(e) = server_manager.save_replay_cache().await {
+        error!("{}", e);
+    }
 
     Ok(())
 }
+
+/// Raise the soft limit of open files to the hard limit, systemd keeps the soft one at 1024
+fn raise_nofile_limit() {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::sys::resource::{Resource, getrlimit, setrlimit};
+
+        let result = getrlimit(Resource::RLIMIT_NOFILE).and_then(|(soft, hard)| {
+            if soft < hard {
+                setrlimit(Resource::RLIMIT_NOFILE, hard, hard)?;
+            }
+            Ok(hard)
+        });
+        match result {
+            Ok(limit) => debug!("Open files limited to {}", limit),
+            Err(e) => log::warn!("Failed to raise the limit of open files: {}", e),
+        }
+    }
+}
+
+/// Wait for SIGINT, or SIGTERM sent by service managers
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
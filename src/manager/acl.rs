@@ -0,0 +1,151 @@
+use anyhow::{Result, anyhow};
+use log::{debug, error, info};
+use reqwest::Client;
+use shadowsocks_service::acl::{AccessControl, GeoDataConfig};
+use shadowsocks_service::server::context::ServiceContext;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+
+/// Where the Access Control List is loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AclSource {
+    File(PathBuf),
+    Url(String),
+}
+
+impl AclSource {
+    /// `http://` and `https://` URLs are fetched remotely, others are local file paths
+    pub fn parse(s: &str) -> Self {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            AclSource::Url(s.to_string())
+        } else {
+            AclSource::File(PathBuf::from(s))
+        }
+    }
+}
+
+impl fmt::Display for AclSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclSource::File(path) => write!(f, "{}", path.display()),
+            AclSource::Url(url) => f.write_str(url),
+        }
+    }
+}
+
+/// Reloads the Access Control List when its source changes
+///
+/// New rules are parsed completely before replacing the current ones,
+/// the previous rules are kept if loading fails.
+pub struct AclReloader {
+    source: AclSource,
+    geodata: GeoDataConfig,
+    context: ServiceContext,
+    client: Client,
+    etag: Option<String>,
+    file_hash: Option<blake3::Hash>,
+}
+
+impl AclReloader {
+    pub fn new(source: AclSource, geodata: GeoDataConfig, context: ServiceContext) -> Result<Self> {
+        let client = Client::builder().timeout(Duration::from_secs(30)).build()?;
+
+        Ok(Self {
+            source,
+            geodata,
+            context,
+            client,
+            etag: None,
+            file_hash: None,
+        })
+    }
+
+    /// Assume the local file was loaded as it is now, it will be reloaded only after changing
+    pub async fn mark_loaded(&mut self) {
+        if let AclSource::File(ref path) = self.source {
+            let path = path.clone();
+            self.file_hash = tokio::task::spawn_blocking(move || std::fs::read(path).ok().map(|c| blake3::hash(&c)))
+                .await
+                .ok()
+                .flatten();
+        }
+    }
+
+    /// Load the ACL if its source changed, returns whether the ACL was replaced
+    pub async fn reload(&mut self) -> Result<bool> {
+        let acl = match self.source {
+            AclSource::File(ref path) => {
+                // Contents are compared, modification time and length miss edits within the same second.
+                // They are read once, the rules parsed are of the contents hashed.
+                let path = path.clone();
+                let geodata = self.geodata.clone();
+                let loaded_hash = self.file_hash;
+                let (hash, acl) = tokio::task::spawn_blocking(move || {
+                    let contents = std::fs::read(&path)?;
+                    let hash = blake3::hash(&contents);
+                    if loaded_hash == Some(hash) {
+                        return Ok::<_, std::io::Error>((hash, None));
+                    }
+                    let acl = AccessControl::load_from_file_contents(&contents, &path, &geodata);
+                    Ok((hash, Some(acl)))
+                })
+                .await??;
+
+                // Recorded even if parsing failed, a broken file won't be parsed again until it changes
+                self.file_hash = Some(hash);
+                match acl {
+                    Some(acl) => acl?,
+                    None => return Ok(false),
+                }
+            }
+            AclSource::Url(ref url) => {
+                let mut request = self.client.get(url);
+                if let Some(ref etag) = self.etag {
+                    request = request.header("If-None-Match", etag);
+                }
+
+                let res = request.send().await?;
+                if res.status().as_u16() == 304 {
+                    return Ok(false);
+                }
+                if res.status().as_u16() > 399 {
+                    return Err(anyhow!("request {} failed: status {}", url, res.status()));
+                }
+
+                let etag = res
+                    .headers()
+                    .get("etag")
+                    .and_then(|v| v.to_str().ok())
+                    .map(ToOwned::to_owned);
+                let body = res.bytes().await?;
+                // Record before parsing, broken rules won't be parsed again until they change
+                self.etag = etag;
+
+                let geodata = self.geodata.clone();
+                tokio::task::spawn_blocking(move || AccessControl::load_from_reader(body.as_ref(), &geodata)).await??
+            }
+        };
+
+        self.context.replace_acl(Arc::new(acl));
+        Ok(true)
+    }
+
+    /// Check the source periodically
+    pub async fn run(mut self, period: Duration) {
+        let mut ticker = interval(period);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            match self.reload().await {
+                Ok(true) => info!("ACL reloaded from {}", self.source),
+                Ok(false) => debug!("ACL {} not modified", self.source),
+                Err(e) => error!("Failed to reload ACL from {}, keeping previous rules: {}", self.source, e),
+            }
+        }
+    }
+}
@@ -1,6 +1,15 @@
+mod acl;
+mod nftables;
+mod sandbox;
 mod server;
+mod shared_node;
+mod systemd;
+mod transport;
+mod user_key;
 
 #[cfg(test)]
 mod tests;
 
 pub use server::ShadowsocksServerManager;
+pub use systemd::{notify, spawn_watchdog, watchdog_timeout};
+pub use user_key::{UserKeyDerivation, user_link};
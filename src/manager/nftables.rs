@@ -0,0 +1,75 @@
+use anyhow::{Result, anyhow};
+use log::{debug, error};
+use shadowsocks_service::acl::IpBan;
+use std::fmt;
+use std::process::Command;
+use std::thread;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// An nftables set, written as `family table set` like `inet filter ss22v2b_ban4`
+///
+/// The set must be created with `flags interval, timeout` to accept networks with expiration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NftSet {
+    family: String,
+    table: String,
+    name: String,
+}
+
+impl NftSet {
+    pub fn parse(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        match parts[..] {
+            [family, table, name] => Ok(Self {
+                family: family.to_string(),
+                table: table.to_string(),
+                name: name.to_string(),
+            }),
+            _ => Err(anyhow!("Invalid nftables set {:?}, expecting \"family table set\"", s)),
+        }
+    }
+
+    /// Add the banned network to this set, expiring with the ban
+    fn add(&self, ban: &IpBan) -> Result<()> {
+        let element = format!("{{ {} timeout {}s }}", ban.network, ban.duration.as_secs().max(1));
+        let output = Command::new("nft")
+            .args(["add", "element", &self.family, &self.table, &self.name, &element])
+            .output()?;
+        if !output.status.success() {
+            return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NftSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.family, self.table, self.name)
+    }
+}
+
+/// Applies new bans to nftables sets in a background thread, as `nft` blocks
+pub fn spawn_nftables_sync(
+    mut bans: UnboundedReceiver<IpBan>,
+    ipv4_set: Option<NftSet>,
+    ipv6_set: Option<NftSet>,
+) -> Result<()> {
+    thread::Builder::new().name("nftables-sync".to_string()).spawn(move || {
+        while let Some(ban) = bans.blocking_recv() {
+            let set = if ban.network.addr().is_ipv4() {
+                ipv4_set.as_ref()
+            } else {
+                ipv6_set.as_ref()
+            };
+            let Some(set) = set else {
+                continue;
+            };
+
+            match set.add(&ban) {
+                Ok(()) => debug!("Added {} to nftables set {}", ban.network, set),
+                Err(e) => error!("Failed to add {} to nftables set {}: {}", ban.network, set, e),
+            }
+        }
+    })?;
+    Ok(())
+}
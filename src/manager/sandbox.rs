@@ -0,0 +1,381 @@
+use anyhow::{Result, anyhow};
+
+use super::server::ShadowsocksServerManager;
+
+impl ShadowsocksServerManager {
+    /// Switch to `user` and `group`, then apply Landlock and seccomp if `sandbox` is enabled
+    ///
+    /// Must be called before the runtime spawns its threads, capabilities and Landlock are per-thread
+    /// and only inherited by threads spawned after. The access log writer starts with the first entry,
+    /// so it runs in the sandbox too.
+    #[cfg(target_os = "linux")]
+    pub fn enter_sandbox(&self) -> Result<()> {
+        use log::{info, warn};
+
+        let ss_config = &self.ss_config;
+        if ss_config.user.is_none() && ss_config.group.is_none() && !ss_config.sandbox {
+            return Ok(());
+        }
+        if ss_config.ban_nftables_set_v4.is_some() || ss_config.ban_nftables_set_v6.is_some() {
+            return Err(anyhow!(
+                "ban_nftables_set_v4 and ban_nftables_set_v6 run nft as root, can't be used with user, group or sandbox"
+            ));
+        }
+
+        if ss_config.user.is_some() || ss_config.group.is_some() {
+            linux::drop_privileges(ss_config.user.as_deref(), ss_config.group.as_deref(), self.needs_bind())?;
+        }
+
+        if ss_config.sandbox {
+            let (read_paths, write_paths) = self.sandbox_paths();
+            if linux::restrict_filesystem(&read_paths, &write_paths)? {
+                info!("Restricted file access with Landlock");
+            } else {
+                warn!("Landlock isn't supported by the kernel, file access isn't restricted");
+            }
+            linux::restrict_syscalls()?;
+            info!("Restricted syscalls with seccomp");
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn enter_sandbox(&self) -> Result<()> {
+        let ss_config = &self.ss_config;
+        if ss_config.user.is_some() || ss_config.group.is_some() || ss_config.sandbox {
+            return Err(anyhow!("user, group and sandbox are only supported on Linux"));
+        }
+        Ok(())
+    }
+
+    /// Check if the server may bind ports, instead of serving on sockets passed by systemd
+    #[cfg(target_os = "linux")]
+    fn needs_bind(&self) -> bool {
+        let mode = self.ss_config.mode;
+        !self.ss_config.listen_ports.is_empty()
+            || (mode.enable_tcp() && self.activated_sockets.tcp_listener.is_none())
+            || (mode.enable_udp() && self.activated_sockets.udp_socket.is_none())
+    }
+
+    /// Paths read and written after startup, like reloaded ACLs and saved state files
+    #[cfg(target_os = "linux")]
+    fn sandbox_paths(&self) -> (Vec<std::path::PathBuf>, Vec<std::path::PathBuf>) {
+        use super::acl::AclSource;
+        use std::path::{Path, PathBuf};
+
+        let ss_config = &self.ss_config;
+
+        // Name resolution reads /etc and may load NSS modules
+        let mut read_paths: Vec<PathBuf> = ["/etc", "/usr", "/lib", "/lib64", "/run/systemd/resolve"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        if let Some(AclSource::File(path)) = ss_config.acl.as_deref().map(AclSource::parse) {
+            read_paths.push(path);
+        }
+        read_paths.extend(
+            [
+                &ss_config.geoip,
+                &ss_config.geosite,
+                &ss_config.transport_tls_cert,
+                &ss_config.transport_tls_key,
+            ]
+            .into_iter()
+            .flatten()
+            .cloned(),
+        );
+
+        // Files are replaced by renaming, or rotated, in their directories
+        let write_paths = [
+            &ss_config.access_log,
+            &ss_config.replay_cache,
+            &ss_config.ban_export,
+            &ss_config.p2p_export,
+        ]
+            .into_iter()
+            .flatten()
+            .map(|path| match path.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            })
+            .collect();
+
+        (read_paths, write_paths)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(super) mod linux {
+    use anyhow::{Context, Result, anyhow};
+    use caps::{CapSet, Capability, CapsHashSet};
+    use landlock::{ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus, path_beneath_rules};
+    use log::info;
+    use nix::sys::prctl;
+    use nix::unistd::{Gid, Group, Uid, User, setgid, setgroups, setuid};
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter, SeccompRule,
+        TargetArch,
+    };
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    /// Switch to the user and group, keeping only `CAP_NET_BIND_SERVICE` if the server may bind low ports again
+    pub fn drop_privileges(user: Option<&str>, group: Option<&str>, keep_bind: bool) -> Result<()> {
+        let user = match user {
+            Some(name) => Some(User::from_name(name)?.ok_or_else(|| anyhow!("Unknown user {}", name))?),
+            None => None,
+        };
+        let gid: Gid = match (group, &user) {
+            (Some(name), _) => Group::from_name(name)?.ok_or_else(|| anyhow!("Unknown group {}", name))?.gid,
+            (None, Some(user)) => user.gid,
+            (None, None) => return Ok(()),
+        };
+
+        // setgid and setuid of libc apply to all threads
+        let Some(user) = user else {
+            setgroups(&[gid]).context("Failed to set supplementary groups")?;
+            setgid(gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+            info!("Switched to group {}", gid);
+            return Ok(());
+        };
+
+        let retained: CapsHashSet = if keep_bind && Uid::effective().is_root() {
+            [Capability::CAP_NET_BIND_SERVICE].into_iter().collect()
+        } else {
+            CapsHashSet::new()
+        };
+        if Uid::effective().is_root() {
+            // Capabilities couldn't be gained back, even by executing programs
+            for cap in caps::all().difference(&retained) {
+                caps::drop(None, CapSet::Bounding, *cap)?;
+            }
+        }
+        if !retained.is_empty() {
+            prctl::set_keepcaps(true)?;
+        }
+
+        setgroups(&[gid]).context("Failed to set supplementary groups")?;
+        setgid(gid).with_context(|| format!("Failed to switch to group {}", gid))?;
+        setuid(user.uid).with_context(|| format!("Failed to switch to user {}", user.name))?;
+
+        caps::set(None, CapSet::Effective, &retained)?;
+        caps::set(None, CapSet::Permitted, &retained)?;
+        caps::clear(None, CapSet::Inheritable)?;
+        if !retained.is_empty() {
+            prctl::set_keepcaps(false)?;
+        }
+
+        info!("Switched to user {} and group {}, retaining {:?}", user.name, gid, retained);
+        Ok(())
+    }
+
+    /// Restrict file access to reading `read_paths` and writing `write_paths`, false if Landlock isn't supported
+    pub fn restrict_filesystem(read_paths: &[PathBuf], write_paths: &[PathBuf]) -> Result<bool> {
+        let abi = ABI::V5;
+        let status = Ruleset::default()
+            .handle_access(AccessFs::from_all(abi))?
+            .create()?
+            .add_rules(path_beneath_rules(read_paths, AccessFs::from_read(abi)))?
+            .add_rules(path_beneath_rules(write_paths, AccessFs::from_all(abi)))?
+            .restrict_self()?;
+        Ok(status.ruleset != RulesetStatus::NotEnforced)
+    }
+
+    /// Allow only syscalls of relaying, others fail with `EPERM`
+    ///
+    /// Syscalls able to leave the sandbox are only allowed with some arguments, see `filtered_syscalls`.
+    /// `clone3` fails with `ENOSYS` instead, its flags can't be checked, so glibc falls back to `clone`.
+    /// Applied to all threads, including any spawned before.
+    pub fn restrict_syscalls() -> Result<()> {
+        let arch: TargetArch = std::env::consts::ARCH.try_into()?;
+        let clone3 = SeccompFilter::new(
+            BTreeMap::from([(libc::SYS_clone3, Vec::new())]),
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::ENOSYS as u32),
+            arch,
+        )?;
+
+        // Errors of any filter take precedence, so `clone3` is left to the filter above
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> =
+            allowed_syscalls().into_iter().map(|nr| (nr, Vec::new())).collect();
+        rules.extend(filtered_syscalls()?);
+        rules.insert(libc::SYS_clone3, Vec::new());
+        let allowed = SeccompFilter::new(rules, SeccompAction::Errno(libc::EPERM as u32), SeccompAction::Allow, arch)?;
+
+        // Applying a filter needs syscalls denied by `allowed`
+        for filter in [clone3, allowed] {
+            let program: BpfProgram = filter.try_into()?;
+            seccompiler::apply_filter_all_threads(&program)?;
+        }
+        Ok(())
+    }
+
+    /// Syscalls allowed only with arguments needed by relaying
+    ///
+    /// `clone` can't create namespaces, `socket` only creates IP and Unix sockets, `prctl` only names threads
+    /// and `ioctl` only changes blocking and close-on-exec or queries pending bytes and terminals.
+    // Requests of `ioctl` are `u64` on glibc, but `i32` on musl
+    #[allow(clippy::unnecessary_cast)]
+    fn filtered_syscalls() -> Result<Vec<(i64, Vec<SeccompRule>)>> {
+        let namespaces = libc::CLONE_NEWUSER
+            | libc::CLONE_NEWNET
+            | libc::CLONE_NEWNS
+            | libc::CLONE_NEWPID
+            | libc::CLONE_NEWIPC
+            | libc::CLONE_NEWUTS
+            | libc::CLONE_NEWCGROUP;
+        let clone = vec![SeccompRule::new(vec![SeccompCondition::new(
+            0,
+            SeccompCmpArgLen::Qword,
+            SeccompCmpOp::MaskedEq(namespaces as u64),
+            0,
+        )?])?];
+
+        Ok(vec![
+            (libc::SYS_clone, clone),
+            (
+                libc::SYS_socket,
+                arg_in(0, &[libc::AF_INET as u64, libc::AF_INET6 as u64, libc::AF_UNIX as u64])?,
+            ),
+            (libc::SYS_socketpair, arg_in(0, &[libc::AF_UNIX as u64])?),
+            (libc::SYS_prctl, arg_in(0, &[libc::PR_SET_NAME as u64, libc::PR_GET_NAME as u64])?),
+            (
+                libc::SYS_ioctl,
+                arg_in(
+                    1,
+                    &[
+                        libc::FIONBIO as u64,
+                        libc::FIOCLEX as u64,
+                        libc::FIONREAD as u64,
+                        libc::TCGETS as u64,
+                    ],
+                )?,
+            ),
+        ])
+    }
+
+    /// Rules matching if the argument at `index` is one of `values`
+    fn arg_in(index: u8, values: &[u64]) -> Result<Vec<SeccompRule>> {
+        values
+            .iter()
+            .map(|&value| {
+                let condition = SeccompCondition::new(index, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, value)?;
+                Ok(SeccompRule::new(vec![condition])?)
+            })
+            .collect()
+    }
+
+    pub(super) fn allowed_syscalls() -> Vec<i64> {
+        let mut syscalls = vec![
+            // Files
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_readv,
+            libc::SYS_writev,
+            libc::SYS_pread64,
+            libc::SYS_pwrite64,
+            libc::SYS_openat,
+            libc::SYS_close,
+            libc::SYS_lseek,
+            libc::SYS_fstat,
+            libc::SYS_newfstatat,
+            libc::SYS_statx,
+            libc::SYS_statfs,
+            libc::SYS_fstatfs,
+            libc::SYS_fcntl,
+            libc::SYS_dup,
+            libc::SYS_dup3,
+            libc::SYS_pipe2,
+            libc::SYS_getdents64,
+            libc::SYS_fsync,
+            libc::SYS_fdatasync,
+            libc::SYS_ftruncate,
+            libc::SYS_renameat,
+            libc::SYS_renameat2,
+            libc::SYS_unlinkat,
+            libc::SYS_readlinkat,
+            libc::SYS_faccessat,
+            libc::SYS_faccessat2,
+            libc::SYS_getcwd,
+            // Memory
+            libc::SYS_mmap,
+            libc::SYS_munmap,
+            libc::SYS_mprotect,
+            libc::SYS_mremap,
+            libc::SYS_madvise,
+            libc::SYS_brk,
+            libc::SYS_membarrier,
+            // Threads and signals
+            libc::SYS_futex,
+            libc::SYS_exit,
+            libc::SYS_exit_group,
+            libc::SYS_set_robust_list,
+            libc::SYS_rseq,
+            libc::SYS_sched_yield,
+            libc::SYS_sched_getaffinity,
+            libc::SYS_gettid,
+            libc::SYS_getpid,
+            libc::SYS_tgkill,
+            libc::SYS_prlimit64,
+            libc::SYS_rt_sigaction,
+            libc::SYS_rt_sigprocmask,
+            libc::SYS_rt_sigreturn,
+            libc::SYS_sigaltstack,
+            libc::SYS_restart_syscall,
+            // Polling
+            libc::SYS_epoll_create1,
+            libc::SYS_epoll_ctl,
+            libc::SYS_epoll_pwait,
+            libc::SYS_eventfd2,
+            libc::SYS_ppoll,
+            libc::SYS_pselect6,
+            // Sockets
+            libc::SYS_bind,
+            libc::SYS_listen,
+            libc::SYS_accept4,
+            libc::SYS_connect,
+            libc::SYS_getsockname,
+            libc::SYS_getpeername,
+            libc::SYS_setsockopt,
+            libc::SYS_getsockopt,
+            libc::SYS_sendto,
+            libc::SYS_recvfrom,
+            libc::SYS_sendmsg,
+            libc::SYS_recvmsg,
+            libc::SYS_sendmmsg,
+            libc::SYS_recvmmsg,
+            libc::SYS_shutdown,
+            // Time, randomness and identity
+            libc::SYS_clock_gettime,
+            libc::SYS_clock_getres,
+            libc::SYS_clock_nanosleep,
+            libc::SYS_nanosleep,
+            libc::SYS_gettimeofday,
+            libc::SYS_getrandom,
+            libc::SYS_uname,
+            libc::SYS_sysinfo,
+            libc::SYS_getuid,
+            libc::SYS_geteuid,
+            libc::SYS_getgid,
+            libc::SYS_getegid,
+            libc::SYS_capget,
+        ];
+        #[cfg(target_arch = "x86_64")]
+        syscalls.extend([
+            libc::SYS_open,
+            libc::SYS_stat,
+            libc::SYS_lstat,
+            libc::SYS_access,
+            libc::SYS_readlink,
+            libc::SYS_rename,
+            libc::SYS_unlink,
+            libc::SYS_pipe,
+            libc::SYS_dup2,
+            libc::SYS_poll,
+            libc::SYS_select,
+            libc::SYS_epoll_wait,
+            libc::SYS_arch_prctl,
+        ]);
+        syscalls
+    }
+}
@@ -1,17 +1,36 @@
 use anyhow::{Result, anyhow};
+use futures::future;
 use log::{debug, error, info, warn};
-use shadowsocks_service::server::{ServerBuilder, context::ServiceContext};
-use shadowsocks_service::shadowsocks::config::{ServerUser, ServerUserManager};
+use shadowsocks_service::acl::{
+    AccessControl, AuditRules, InternalAddrFilter, IpBanList, P2pPolicy, PortPolicy, PortRange, PortRules, UserLimiter,
+    UserLimits,
+};
+use shadowsocks_service::net::{BindAddrPool, FlowStat, ProxyProtocolTrust};
+#[cfg(unix)]
+use shadowsocks_service::net::systemd_activate_socket::SystemdActivatedSockets;
+use shadowsocks_service::config::{SecurityConfig, SecurityReplayAttackConfig};
+use shadowsocks_service::server::{Server, ServerBuilder, access_log::AccessLog, context::ServiceContext};
+use shadowsocks_service::shadowsocks::config::ServerUserManager;
 use shadowsocks_service::shadowsocks::net::AcceptOpts;
-use shadowsocks_service::shadowsocks::{ServerConfig as ShadowsocksConfig, crypto::CipherKind};
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use shadowsocks_service::shadowsocks::relay::socks5::Address;
+use shadowsocks_service::shadowsocks::{
+    ServerConfig as ShadowsocksConfig,
+    crypto::{CipherCategory, CipherKind},
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 use tokio::task::JoinHandle;
 
+use super::acl::{AclReloader, AclSource};
+use super::nftables::{NftSet, spawn_nftables_sync};
+use super::shared_node::SharedNode;
+use super::transport::node_transport;
+use super::user_key::UserKeyDerivation;
 use crate::config::ShadowsocksConfig as AppShadowsocksConfig;
-use crate::v2board::{ServerConfig, UserInfo};
+use crate::v2board::{RouteRule, ServerConfig, UserInfo, UserViolation};
 
 /// Manages the Shadowsocks server lifecycle
 pub struct ShadowsocksServerManager {
@@ -21,45 +40,263 @@ pub struct ShadowsocksServerManager {
     pub(super) user_manager: Arc<ServerUserManager>,
     pub(super) context: ServiceContext,
     pub(super) ss_config: Arc<AppShadowsocksConfig>,
+    pub(super) shared_nodes: Vec<Arc<SharedNode>>,
+    // Held while (re)starting, nodes sharing the port restart the server too
+    pub(super) start_lock: Mutex<()>,
+    // P2P detections of users since start, by user ID
+    pub(super) p2p_hits: std::sync::Mutex<HashMap<i32, u64>>,
+    // Sockets passed by systemd, duplicated for every (re)start
+    #[cfg(unix)]
+    pub(super) activated_sockets: SystemdActivatedSockets,
 }
 
 impl ShadowsocksServerManager {
-    pub fn new(ss_config: AppShadowsocksConfig) -> Self {
+    pub fn new(ss_config: AppShadowsocksConfig) -> Result<Self> {
+        // Rejected at startup, not when the panel sends the node
+        ss_config.listen_addrs(0)?;
+
         let mut context = ServiceContext::new();
         // Apply IPv6 first setting
         context.set_ipv6_first(ss_config.ipv6_first);
         context.set_timestamp_limit(ss_config.timestamp_limit);
         context.set_comply_with_incoming(ss_config.comply_with_incoming);
+        context.set_replay_protector_config(ss_config.replay_protector_config());
+        context.set_security_config(&SecurityConfig {
+            replay_attack: SecurityReplayAttackConfig {
+                policy: ss_config.replay_attack_policy,
+            },
+        });
+
+        if let Some(path) = ss_config.replay_cache.as_ref() {
+            match context.load_replay_salts(path) {
+                Ok(loaded) => info!("Loaded {} salts from {}", loaded, path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to load salts from {}: {}", path.display(), e),
+            }
+        }
+
+        if !ss_config.outbound_bind_addrs.is_empty() {
+            let pool = BindAddrPool::new(
+                ss_config.outbound_bind_addrs.iter().copied(),
+                ss_config.outbound_bind_strategy,
+            );
+            context.set_bind_addr_pool(Arc::new(pool));
+        }
+
+        if ss_config.block_internal_addrs {
+            let mut filter = InternalAddrFilter::new();
+            for ip in interface_addrs().into_iter().chain(ss_config.public_addrs.iter().copied()) {
+                filter.add_local(ip);
+            }
+            for rule in &ss_config.allowed_internal_addrs {
+                if let Err(err) = filter.add_allowed(rule) {
+                    warn!("Ignoring invalid allowed internal address {}: {}", rule, err);
+                }
+            }
+            context.set_internal_addr_filter(Arc::new(filter));
+        }
+
+        let tcp_rules = PortRules::new(ss_config.tcp_blocked_ports.clone(), ss_config.tcp_allowed_ports.clone());
+        let udp_rules = PortRules::new(ss_config.udp_blocked_ports.clone(), ss_config.udp_allowed_ports.clone());
+        if !tcp_rules.is_empty() || !udp_rules.is_empty() {
+            let mut policy = PortPolicy::new(tcp_rules, udp_rules);
+            if let Some(auto_block) = ss_config.port_auto_block() {
+                policy.set_auto_block(auto_block);
+            }
+            context.set_port_policy(Arc::new(policy));
+        }
+
+        context.set_sniff_config(ss_config.sniff_config());
+        context.set_udp_over_tcp(ss_config.udp_over_tcp && ss_config.mode.enable_udp());
+        context.set_mux(ss_config.mux);
+
+        if let Some(action) = ss_config.p2p_action {
+            context.set_p2p_policy(Arc::new(P2pPolicy::new(action, ss_config.p2p_throttle_rate * 1024)));
+        }
+
+        if ss_config.user_limits_enabled() {
+            context.set_user_limiter(Arc::new(UserLimiter::new(ss_config.user_limits())));
+        }
+
+        if let Some(ban_config) = ss_config.ip_ban_config() {
+            let mut ban_list = IpBanList::new(ban_config);
+            let ipv4_set = ss_config.ban_nftables_set_v4.as_deref().map(NftSet::parse).transpose()?;
+            let ipv6_set = ss_config.ban_nftables_set_v6.as_deref().map(NftSet::parse).transpose()?;
+            if ipv4_set.is_some() || ipv6_set.is_some() {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                ban_list.set_notifier(tx);
+                spawn_nftables_sync(rx, ipv4_set, ipv6_set)?;
+            }
+            context.set_ip_ban_list(Arc::new(ban_list));
+        }
+
+        if let Some(fallback) = ss_config.fallback.as_deref() {
+            let addr = fallback
+                .parse::<Address>()
+                .map_err(|_| anyhow!("Invalid fallback address {}", fallback))?;
+            context.set_fallback_addr(addr);
+        }
+
+        if !ss_config.proxy_protocol_trusted.is_empty() {
+            let mut trust = ProxyProtocolTrust::new();
+            for rule in &ss_config.proxy_protocol_trusted {
+                if let Err(err) = trust.add_trusted(rule) {
+                    warn!("Ignoring invalid PROXY protocol source {}: {}", rule, err);
+                }
+            }
+            context.set_proxy_protocol_trust(trust);
+        }
+
+        if let Some(access_log_config) = ss_config.access_log_config() {
+            let path = access_log_config.path.clone();
+            let access_log = AccessLog::open(access_log_config)
+                .map_err(|e| anyhow!("Failed to open access log {}: {}", path.display(), e))?;
+            context.set_access_log(Arc::new(access_log));
+        }
+
+        // Remote ACLs are fetched in start_acl_reload
+        if let Some(AclSource::File(acl_path)) = ss_config.acl.as_deref().map(AclSource::parse) {
+            let acl = AccessControl::load_from_file_with_geodata(&acl_path, &ss_config.geodata_config())
+                .map_err(|e| anyhow!("Failed to load ACL {}: {}", acl_path.display(), e))?;
+            context.set_acl(Arc::new(acl));
+        }
 
-        Self {
+        Ok(Self {
             server_handle: Arc::new(RwLock::new(None)),
             users: Arc::new(RwLock::new(Vec::new())),
             current_config: Arc::new(RwLock::new(None)),
             user_manager: Arc::new(ServerUserManager::new()),
-            context: ServiceContext::new(),
+            context,
             ss_config: Arc::new(ss_config),
+            shared_nodes: Vec::new(),
+            start_lock: Mutex::new(()),
+            p2p_hits: std::sync::Mutex::new(HashMap::new()),
+            #[cfg(unix)]
+            activated_sockets: SystemdActivatedSockets::default(),
+        })
+    }
+
+    /// Fetch the remote ACL, and start checking the ACL for changes in background
+    pub async fn start_acl_reload(&self) -> Result<()> {
+        let source = match self.ss_config.acl.as_deref() {
+            Some(acl) => AclSource::parse(acl),
+            None => return Ok(()),
+        };
+
+        let mut reloader = AclReloader::new(source.clone(), self.ss_config.geodata_config(), self.context.clone())?;
+        match source {
+            AclSource::File(..) => reloader.mark_loaded().await,
+            AclSource::Url(..) => {
+                reloader
+                    .reload()
+                    .await
+                    .map_err(|e| anyhow!("Failed to load ACL {}: {}", source, e))?;
+                info!("ACL loaded from {}", source);
+            }
         }
+
+        if let Some(period) = self.ss_config.acl_reload_duration() {
+            tokio::spawn(reloader.run(period));
+        }
+
+        Ok(())
     }
 
-    /// Add users to the given user manager with the specified cipher
+    /// Save salts seen to `replay_cache` periodically in background
+    pub fn start_replay_cache_save(&self) {
+        let (Some(path), Some(period)) = (
+            self.ss_config.replay_cache.clone(),
+            self.ss_config.replay_cache_save_duration(),
+        ) else {
+            return;
+        };
+
+        let context = self.context.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let (context, save_path) = (context.clone(), path.clone());
+                match tokio::task::spawn_blocking(move || context.save_replay_salts(&save_path)).await {
+                    Ok(Ok(saved)) => debug!("Saved {} salts to {}", saved, path.display()),
+                    Ok(Err(e)) => error!("Failed to save salts to {}: {}", path.display(), e),
+                    Err(e) => error!("Failed to save salts to {}: {}", path.display(), e),
+                }
+            }
+        });
+    }
+
+    /// Save salts seen to `replay_cache`, called on shutdown
+    pub async fn save_replay_cache(&self) -> Result<()> {
+        if let Some(path) = self.ss_config.replay_cache.clone() {
+            let context = self.context.clone();
+            let save_path = path.clone();
+            let saved = tokio::task::spawn_blocking(move || context.save_replay_salts(&save_path))
+                .await?
+                .map_err(|e| anyhow!("Failed to save salts to {}: {}", path.display(), e))?;
+            info!("Saved {} salts to {}", saved, path.display());
+        }
+        Ok(())
+    }
+
+    /// Add users to the given user manager with the specified cipher, users with invalid keys are skipped
     pub(crate) fn add_users_to_manager(
         manager: &ServerUserManager,
         users: &[UserInfo],
         cipher: Option<&str>,
+        derivation: UserKeyDerivation,
     ) {
-        let psw_length = if cipher == Some("2022-blake3-aes-128-gcm") {
-            16
-        } else {
-            32
+        let Some(Ok(method)) = cipher.map(CipherKind::from_str) else {
+            warn!("Skipping users of invalid cipher {:?}", cipher);
+            return;
         };
-        debug!("Using password length: {}", psw_length);
+
+        debug!("Deriving keys of {} users by {:?}", method, derivation);
         for user in users.iter() {
-            // UUID is used as both the user name and key for Shadowsocks 2022
-            manager.add_user(ServerUser::new(
-                user.id.to_string(),
-                user.uuid.as_bytes()[..psw_length].to_vec(),
-            ));
-            debug!("Added user {} with UUID {}", user.id, user.uuid);
+            match derivation.server_user(user, method) {
+                Ok(server_user) => {
+                    manager.add_user(server_user);
+                    debug!("Added user {} with UUID {}", user.id, user.uuid);
+                }
+                Err(e) => warn!("Skipping user {}: {}", user.id, e),
+            }
+        }
+    }
+
+    /// Replace audit rules with routes from the panel
+    ///
+    /// Only blocking actions are supported, invalid rules are skipped.
+    pub(crate) fn update_audit_rules(&self, routes: &[RouteRule]) {
+        let mut rules = AuditRules::new();
+        for route in routes {
+            match route.action.as_str() {
+                "block" | "block_ip" => {
+                    if let Err(e) = rules.add_rule(route.id, &route.match_rules) {
+                        warn!("Ignoring invalid audit rule {}: {}", route.id, e);
+                    }
+                }
+                "block_port" => {
+                    let ports = route
+                        .match_rules
+                        .iter()
+                        .map(|p| p.parse::<PortRange>())
+                        .collect::<Result<Vec<_>, _>>();
+                    match ports {
+                        Ok(ports) => rules.add_port_rule(route.id, ports),
+                        Err(e) => warn!("Ignoring invalid audit rule {}: {}", route.id, e),
+                    }
+                }
+                action => debug!("Skipping audit rule {} with unsupported action {}", route.id, action),
+            }
+        }
+
+        if rules.is_empty() {
+            self.context.replace_audit_rules(None);
+        } else {
+            info!("Loaded {} audit rules", rules.len());
+            self.context.replace_audit_rules(Some(Arc::new(rules)));
         }
     }
 
@@ -80,12 +317,19 @@ impl ShadowsocksServerManager {
 
     /// Start a new server with the given configuration
     pub async fn start_server(&self, config: ServerConfig) -> Result<()> {
+        let _start_guard = self.start_lock.lock().await;
+
         // Stop existing server first
         self.stop_server().await;
 
+        let previous_config = self.current_config.read().await.clone();
+
+        let listen_addrs = self.ss_config.listen_addrs(config.server_port as u16)?;
         info!(
-            "Starting Shadowsocks server on port {} with cipher {:?}",
-            config.server_port, config.cipher
+            "Starting Shadowsocks server on port {} and {} other ports with cipher {:?}",
+            config.server_port,
+            listen_addrs.len() - 1,
+            config.cipher
         );
 
         // Parse cipher
@@ -97,35 +341,80 @@ impl ShadowsocksServerManager {
         };
 
         // Get server key
-        let server_key = config
-            .server_key
-            .as_ref()
+        let server_key = node_server_key(&config, cipher)
             .ok_or_else(|| anyhow!("Server key not specified in config"))?;
         debug!("Server key: {}", server_key);
 
-        // Create server address - bind to all interfaces
-        let listen_addr =
-            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.server_port as u16);
+        let listen_addr = listen_addrs[0];
 
         // Create shadowsocks config
-        let mut ss_config = ShadowsocksConfig::new(listen_addr, server_key.as_str(), cipher)?;
-            ss_config.set_mode(self.ss_config.mode.clone());
+        let mut ss_config = ShadowsocksConfig::new(listen_addr, server_key, cipher)?;
+        ss_config.set_mode(self.ss_config.mode);
 
         // Build user manager from stored users
         let users_guard = self.users.read().await;
 
         let manager = self.user_manager.clone();
         manager.clear_users();
-        Self::add_users_to_manager(&manager, &users_guard, config.cipher.as_deref());
+        Self::add_users_to_manager(
+            &manager,
+            &users_guard,
+            config.cipher.as_deref(),
+            self.ss_config.user_key_derivation,
+        );
         drop(users_guard);
 
+        if let Some(previous) = previous_config {
+            self.keep_previous_server_key(&manager, &previous, &config, listen_addr, cipher);
+        }
+
         ss_config.set_user_manager(manager.clone());
 
         // Apply timeout settings
         ss_config.set_timeout(self.ss_config.timeout_duration());
 
-        // Build and start server
+        self.update_audit_rules(&config.routes);
+
+        // Servers of all ports share the context and users, with their flow statistics and replay protection
+        let mut servers = Vec::with_capacity(listen_addrs.len());
+        for addr in listen_addrs {
+            let server = self
+                .build_server(&config, ss_config.clone(), addr, cipher)
+                .await
+                .map_err(|e| anyhow!("Failed to listen on {}: {}", addr, e))?;
+            servers.push(server);
+        }
+
+        // Spawn server in background
+        let handle = tokio::spawn(async move {
+            if let Err(e) = future::try_join_all(servers.into_iter().map(Server::run)).await {
+                error!("Shadowsocks server error: {}", e);
+            }
+        });
+
+        // Store the handle
+        let mut server_handle = self.server_handle.write().await;
+        *server_handle = Some(handle);
+
+        // Store current config
+        let mut current_config = self.current_config.write().await;
+        *current_config = Some(config);
+
+        info!("Shadowsocks server started successfully");
+        Ok(())
+    }
+
+    /// Build the server listening on `listen_addr`, the one of `server_port` serves on sockets passed by systemd
+    async fn build_server(
+        &self,
+        config: &ServerConfig,
+        mut ss_config: ShadowsocksConfig,
+        listen_addr: SocketAddr,
+        cipher: CipherKind,
+    ) -> Result<Server> {
+        ss_config.set_addr(listen_addr);
         let mut builder = ServerBuilder::with_context(self.context.clone(), ss_config);
+        self.add_shared_nodes_to_builder(&mut builder, config, listen_addr, cipher).await;
 
         // Apply UDP timeout and capacity settings
         builder.set_udp_expiry_duration(self.ss_config.udp_timeout_duration());
@@ -142,31 +431,51 @@ impl ShadowsocksServerManager {
             accept_opts.tcp.keepalive = Some(keepalive);
         }
         builder.set_accept_opts(accept_opts);
+        if listen_addr.port() == config.server_port as u16 {
+            self.apply_activated_sockets(&mut builder, config.server_port as u16)?;
+        }
 
         if let Some(relay) = self.ss_config.relay.as_ref() {
-            builder.set_relay_config_from_url(&relay);
+            builder.set_relay_config_from_url(relay);
             debug!("Relay server: {:?}", builder.relay_config().unwrap())
         }
 
-        let server = builder.build().await?;
-
-        // Spawn server in background
-        let handle = tokio::spawn(async move {
-            if let Err(e) = server.run().await {
-                error!("Shadowsocks server error: {}", e);
-            }
-        });
+        if let Some(transport) = node_transport(config, &self.ss_config)? {
+            builder.set_transport(transport);
+        }
 
-        // Store the handle
-        let mut server_handle = self.server_handle.write().await;
-        *server_handle = Some(handle);
+        Ok(builder.build().await?)
+    }
 
-        // Store current config
-        let mut current_config = self.current_config.write().await;
-        *current_config = Some(config);
+    /// Keep accepting the replaced server key for a grace period, clients update their subscriptions slowly
+    pub(super) fn keep_previous_server_key(
+        &self,
+        user_manager: &ServerUserManager,
+        previous: &ServerConfig,
+        config: &ServerConfig,
+        listen_addr: SocketAddr,
+        cipher: CipherKind,
+    ) {
+        let Some(grace_period) = self.ss_config.server_key_grace_duration() else {
+            return;
+        };
+        let Some(previous_key) = previous.server_key.as_ref() else {
+            return;
+        };
+        if previous.server_key == config.server_key || previous.cipher != config.cipher {
+            return;
+        }
 
-        info!("Shadowsocks server started successfully");
-        Ok(())
+        match ShadowsocksConfig::new(listen_addr, previous_key.as_str(), cipher) {
+            Ok(previous_ss_config) => {
+                info!(
+                    "Server key changed, accepting the previous key for {} seconds",
+                    grace_period.as_secs()
+                );
+                user_manager.add_previous_key(previous_ss_config.key().to_vec(), grace_period);
+            }
+            Err(e) => warn!("Ignoring invalid previous server key: {}", e),
+        }
     }
 
     /// Update users in the server
@@ -178,24 +487,115 @@ impl ShadowsocksServerManager {
         // Update stored users
         let mut users_list = self.users.write().await;
         *users_list = users;
+        drop(users_list);
+        self.update_user_limits().await;
+        let users_list = self.users.read().await;
 
         // Rebuild stored manager only if we have an active config
         let current_config = self.current_config.read().await.clone();
         if let Some(cfg) = current_config {
             let manager = self.user_manager.clone();
             manager.clear_users();
-            Self::add_users_to_manager(&manager, &users_list, cfg.cipher.as_deref());
+            Self::add_users_to_manager(
+                &manager,
+                &users_list,
+                cfg.cipher.as_deref(),
+                self.ss_config.user_key_derivation,
+            );
         } else {
             debug!("No active config; user manager rebuild skipped");
         }
     }
 
+    /// Apply per-user connection limits from the panel, of users of this node and nodes sharing the port
+    pub(crate) async fn update_user_limits(&self) {
+        let Some(limiter) = self.context.user_limiter() else {
+            return;
+        };
+        if !self.ss_config.user_limits_from_panel {
+            return;
+        }
+
+        let mut users = self.users.read().await.clone();
+        for node in &self.shared_nodes {
+            users.extend(node.users.read().await.iter().cloned());
+        }
+
+        let overrides: HashMap<String, UserLimits> = users
+            .iter()
+            .map(|user| {
+                let limits = UserLimits {
+                    max_tcp_connections: user.max_tcp_connections,
+                    max_udp_associations: user.max_udp_associations,
+                    max_new_connections: user.max_new_connections,
+                };
+                (user.id.to_string(), limits)
+            })
+            .filter(|(_, limits)| !limits.is_unlimited())
+            .collect();
+        limiter.set_overrides(overrides);
+    }
+
     /// Retrieve per-user traffic since last call
     pub async fn collect_user_traffic(&self) -> Option<Vec<crate::v2board::UserTraffic>> {
+        let result = Self::take_user_traffic(&self.user_manager, &self.context.flow_stat());
+
+        if let Some(policy) = self.context.port_policy() {
+            for (hash, hits) in policy.take_hits() {
+                if let Some(user) = self.find_user_by_hash(&hash) {
+                    warn!("User {} hit blocked outbound ports {} times", user.name(), hits);
+                }
+            }
+        }
+
+        if let Some(access_log) = self.context.access_log() {
+            let dropped = access_log.take_dropped();
+            if dropped > 0 {
+                warn!("Access log dropped {} entries, writing is too slow", dropped);
+            }
+        }
+
+        if let Some(policy) = self.context.p2p_policy() {
+            let mut p2p_hits = self.p2p_hits.lock().unwrap();
+            for (hash, hits) in policy.take_hits() {
+                if let Some(user) = self.find_user_by_hash(&hash) {
+                    warn!("User {} sent P2P traffic {} times", user.name(), hits);
+                    if let Ok(user_id) = user.name().parse::<i32>() {
+                        *p2p_hits.entry(user_id).or_default() += hits;
+                    }
+                }
+            }
+        }
+
+        for (hash, key) in self.user_manager.take_previous_key_users() {
+            if let Some(user) = self.user_manager.get_user_by_hash(&hash) {
+                warn!(
+                    "User {} still uses previous server key starting with {}",
+                    user.name(),
+                    key_fingerprint(&key)
+                );
+            }
+        }
+
+        if let Err(e) = self.export_bans() {
+            error!("Failed to export bans: {}", e);
+        }
+        if let Err(e) = self.export_p2p_hits() {
+            error!("Failed to export P2P detections: {}", e);
+        }
+
+        Some(result)
+    }
+
+    /// Take per-user traffic counted in `flow_stat`, of users in `user_manager`
+    pub(super) fn take_user_traffic(
+        user_manager: &ServerUserManager,
+        flow_stat: &FlowStat,
+    ) -> Vec<crate::v2board::UserTraffic> {
         let mut result = Vec::new();
-        let flow_map = self.context.flow_stat().get_multiple();
+        let flow_map = flow_stat.get_multiple();
         for (hash, stat) in flow_map {
-            if let Some(user) = self.user_manager.get_user_by_hash(&hash) {
+            if let Some(user) = user_manager.get_user_by_hash(&hash) {
                 if let Ok(id) = user.name().parse::<i32>() {
                     let upload = stat.rx() as i64;
                     let download = stat.tx() as i64;
@@ -213,7 +613,100 @@ impl ShadowsocksServerManager {
                 }
             }
         }
+        result
+    }
 
+    /// Write current bans of clients failing handshakes to `ban_export`
+    pub(crate) fn export_bans(&self) -> Result<()> {
+        let (Some(path), Some(ban_list)) = (self.ss_config.ban_export.as_ref(), self.context.ip_ban_list()) else {
+            return Ok(());
+        };
+
+        // Replace the file at once, readers never see a partial list
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&ban_list.bans())?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Write P2P detections of users since start to `p2p_export`
+    pub(crate) fn export_p2p_hits(&self) -> Result<()> {
+        let Some(path) = self.ss_config.p2p_export.as_ref() else {
+            return Ok(());
+        };
+
+        let mut hits: Vec<(i32, u64)> = self.p2p_hits.lock().unwrap().iter().map(|(&id, &n)| (id, n)).collect();
+        hits.sort_unstable();
+        let hits: Vec<_> = hits
+            .into_iter()
+            .map(|(user_id, hits)| serde_json::json!({ "user_id": user_id, "hits": hits }))
+            .collect();
+
+        // Replace the file at once, readers never see a partial list
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&hits)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Retrieve audit rule violations of users since last call
+    pub async fn collect_violations(&self) -> Option<Vec<UserViolation>> {
+        let mut result = Vec::new();
+        for violation in self.context.audit_log().take() {
+            let Some(hash) = violation.user else {
+                continue;
+            };
+            if let Some(user) = self.find_user_by_hash(&hash) {
+                match user.name().parse::<i32>() {
+                    Ok(user_id) => result.push(UserViolation {
+                        user_id,
+                        rule_id: violation.rule_id,
+                        target: violation.target,
+                    }),
+                    Err(_) => warn!("Cannot parse id: {}", user.name()),
+                }
+            }
+        }
         Some(result)
     }
 }
+
+/// Identify a key in logs without revealing it, by the hex of its first 4 bytes
+pub(super) fn key_fingerprint(key: &[u8]) -> String {
+    key.iter().take(4).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Server key of a node, AEAD nodes need none as their users are found by trial decryption
+pub(super) fn node_server_key(config: &ServerConfig, cipher: CipherKind) -> Option<&str> {
+    match config.server_key.as_deref() {
+        Some(server_key) => Some(server_key),
+        None if cipher.category() == CipherCategory::Aead => Some(""),
+        None => None,
+    }
+}
+
+/// Addresses assigned to interfaces of the host
+#[cfg(target_os = "linux")]
+fn interface_addrs() -> Vec<IpAddr> {
+    match nix::ifaddrs::getifaddrs() {
+        Ok(ifaddrs) => ifaddrs
+            .filter_map(|ifaddr| {
+                let addr = ifaddr.address?;
+                match (addr.as_sockaddr_in(), addr.as_sockaddr_in6()) {
+                    (Some(v4), _) => Some(IpAddr::V4(v4.ip())),
+                    (_, Some(v6)) => Some(IpAddr::V6(v6.ip())),
+                    _ => None,
+                }
+            })
+            .collect(),
+        Err(err) => {
+            warn!("Failed to list interface addresses: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_addrs() -> Vec<IpAddr> {
+    Vec::new()
+}
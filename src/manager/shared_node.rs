@@ -0,0 +1,183 @@
+use anyhow::{Result, anyhow};
+use log::{error, info, warn};
+use shadowsocks_service::net::FlowStat;
+use shadowsocks_service::server::ServerBuilder;
+use shadowsocks_service::shadowsocks::config::{ServerUser, ServerUserManager};
+use shadowsocks_service::shadowsocks::{ServerConfig as ShadowsocksConfig, crypto::CipherKind};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use super::server::{ShadowsocksServerManager, key_fingerprint, node_server_key};
+use crate::v2board::{ServerConfig, UserInfo, UserTraffic};
+
+/// Another node of the panel listening on the port of the server, distinguished by its server key (iPSK)
+pub struct SharedNode {
+    pub(super) node_id: i32,
+    pub(super) config: RwLock<Option<ServerConfig>>,
+    pub(super) users: RwLock<Vec<UserInfo>>,
+    pub(super) user_manager: Arc<ServerUserManager>,
+    pub(super) flow_stat: Arc<FlowStat>,
+}
+
+impl SharedNode {
+    pub fn new(node_id: i32) -> Self {
+        Self {
+            node_id,
+            config: RwLock::new(None),
+            users: RwLock::new(Vec::new()),
+            user_manager: Arc::new(ServerUserManager::new()),
+            flow_stat: Arc::new(FlowStat::new()),
+        }
+    }
+}
+
+impl ShadowsocksServerManager {
+    /// Share the server port with another node of the panel, with its own users and traffic
+    pub fn add_shared_node(&mut self, node_id: i32) {
+        self.shared_nodes.push(Arc::new(SharedNode::new(node_id)));
+    }
+
+    fn shared_node(&self, node_id: i32) -> Result<&Arc<SharedNode>> {
+        self.shared_nodes
+            .iter()
+            .find(|node| node.node_id == node_id)
+            .ok_or_else(|| anyhow!("Node {} is not sharing the server port", node_id))
+    }
+
+    /// Find the user by identity hash in this node, then in nodes sharing the port
+    pub(super) fn find_user_by_hash(&self, hash: &[u8]) -> Option<Arc<ServerUser>> {
+        self.user_manager.get_user_by_hash(hash).or_else(|| {
+            self.shared_nodes
+                .iter()
+                .find_map(|node| node.user_manager.get_user_by_hash(hash))
+        })
+    }
+
+    /// Apply the config of a node sharing the port, the server is restarted if its key changed
+    pub async fn update_shared_node_config(&self, node_id: i32, config: ServerConfig) -> Result<()> {
+        let node = self.shared_node(node_id)?;
+
+        let previous = node.config.write().await.replace(config.clone());
+        let unchanged = previous.as_ref().is_some_and(|previous| {
+            previous.server_port == config.server_port
+                && previous.cipher == config.cipher
+                && previous.server_key == config.server_key
+        });
+
+        {
+            let users = node.users.read().await;
+            node.user_manager.clear_users();
+            Self::add_users_to_manager(
+                &node.user_manager,
+                &users,
+                config.cipher.as_deref(),
+                self.ss_config.user_key_derivation,
+            );
+        }
+
+        if unchanged {
+            return Ok(());
+        }
+
+        let current_config = self.current_config.read().await.clone();
+        let Some(current_config) = current_config else {
+            // Added when the server starts
+            return Ok(());
+        };
+
+        if let Some(previous) = previous
+            && let Some(Ok(cipher)) = config.cipher.as_deref().map(CipherKind::from_str)
+        {
+            let listen_addr = SocketAddr::new(self.ss_config.listen_addr, config.server_port as u16);
+            self.keep_previous_server_key(&node.user_manager, &previous, &config, listen_addr, cipher);
+        }
+
+        info!("Restarting server for the new config of shared node {}", node_id);
+        self.start_server(current_config).await
+    }
+
+    /// Replace users of a node sharing the port
+    pub async fn update_shared_node_users(&self, node_id: i32, users: Vec<UserInfo>) -> Result<()> {
+        let node = self.shared_node(node_id)?;
+        info!("Updating {} users of shared node {}", users.len(), node_id);
+
+        let mut users_list = node.users.write().await;
+        *users_list = users;
+        drop(users_list);
+        self.update_user_limits().await;
+        let users_list = node.users.read().await;
+
+        let config = node.config.read().await.clone();
+        if let Some(config) = config {
+            node.user_manager.clear_users();
+            Self::add_users_to_manager(
+                &node.user_manager,
+                &users_list,
+                config.cipher.as_deref(),
+                self.ss_config.user_key_derivation,
+            );
+        }
+        Ok(())
+    }
+
+    /// Retrieve per-user traffic of a node sharing the port since last call
+    pub fn collect_shared_node_traffic(&self, node_id: i32) -> Result<Vec<UserTraffic>> {
+        let node = self.shared_node(node_id)?;
+
+        for (hash, key) in node.user_manager.take_previous_key_users() {
+            if let Some(user) = node.user_manager.get_user_by_hash(&hash) {
+                warn!(
+                    "User {} of shared node {} still uses previous server key starting with {}",
+                    user.name(),
+                    node_id,
+                    key_fingerprint(&key)
+                );
+            }
+        }
+
+        Ok(Self::take_user_traffic(&node.user_manager, &node.flow_stat))
+    }
+
+    /// Add nodes sharing the port to the server, they must use the port and cipher of the server
+    pub(super) async fn add_shared_nodes_to_builder(
+        &self,
+        builder: &mut ServerBuilder,
+        config: &ServerConfig,
+        listen_addr: SocketAddr,
+        cipher: CipherKind,
+    ) {
+        for node in &self.shared_nodes {
+            let node_config = node.config.read().await;
+            let Some(node_config) = node_config.as_ref() else {
+                continue;
+            };
+
+            if node_config.server_port != config.server_port || node_config.cipher != config.cipher {
+                error!(
+                    "Shared node {} uses port {} with cipher {:?}, expecting port {} with cipher {:?}, skipped",
+                    node.node_id, node_config.server_port, node_config.cipher, config.server_port, config.cipher
+                );
+                continue;
+            }
+
+            let Some(server_key) = node_server_key(node_config, cipher) else {
+                error!("Server key not specified in config of shared node {}, skipped", node.node_id);
+                continue;
+            };
+
+            match ShadowsocksConfig::new(listen_addr, server_key, cipher) {
+                Ok(mut node_ss_config) => {
+                    node_ss_config.set_user_manager(node.user_manager.clone());
+                    builder.add_shared_node(node_ss_config, node.flow_stat.clone());
+                    // Logged once, for the server of `server_port`
+                    if listen_addr.port() == config.server_port as u16 {
+                        info!("Sharing port {} with node {}", config.server_port, node.node_id);
+                    }
+                }
+                Err(e) => error!("Invalid server key of shared node {}: {}, skipped", node.node_id, e),
+            }
+        }
+    }
+}
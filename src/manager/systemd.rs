@@ -0,0 +1,132 @@
+use anyhow::Result;
+use log::{debug, warn};
+use shadowsocks_service::server::ServerBuilder;
+use std::env;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::server::ShadowsocksServerManager;
+use crate::v2board::PullWatch;
+
+#[cfg(unix)]
+use shadowsocks_service::net::systemd_activate_socket::SystemdActivatedSockets;
+
+/// Send a state like `READY=1` to systemd, returns false if not started by systemd with `Type=notify`
+///
+/// <https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html>
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+
+    // Names starting with `@` are in the abstract namespace
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(..) => return Err(io::Error::new(io::ErrorKind::Unsupported, "abstract NOTIFY_SOCKET")),
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Timeout of the systemd watchdog, `None` if `WatchdogSec` isn't set
+pub fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse::<u32>().ok() != Some(std::process::id())
+    {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the systemd watchdog while the server accepts and the panel is pulled
+///
+/// Pings are skipped if either is stuck, systemd restarts the service after `timeout` without them.
+pub fn spawn_watchdog(server_manager: Arc<ShadowsocksServerManager>, pull_watches: Vec<PullWatch>, timeout: Duration) {
+    let period = timeout / 2;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        loop {
+            ticker.tick().await;
+
+            if !server_manager.is_serving(period).await {
+                warn!("Watchdog not pinged, the server isn't accepting");
+                continue;
+            }
+            if let Some(watch) = pull_watches.iter().find(|watch| !watch.is_alive(period)) {
+                warn!("Watchdog not pinged, pulling node {} is stuck", watch.node_id());
+                continue;
+            }
+
+            if let Err(e) = notify("WATCHDOG=1") {
+                warn!("Failed to ping watchdog: {}", e);
+            }
+        }
+    });
+}
+
+impl ShadowsocksServerManager {
+    /// Serve on sockets passed by systemd instead of binding the port of the node
+    #[cfg(unix)]
+    pub fn set_activated_sockets(&mut self, sockets: SystemdActivatedSockets) {
+        self.activated_sockets = sockets;
+    }
+
+    /// Pass duplicates of sockets passed by systemd, originals are kept for restarts
+    #[cfg(unix)]
+    pub(super) fn apply_activated_sockets(&self, builder: &mut ServerBuilder, port: u16) -> Result<()> {
+        if let Some(listener) = self.activated_sockets.tcp_listener.as_ref() {
+            let addr = listener.local_addr()?;
+            if addr.port() != port {
+                warn!("Serving TCP on systemd socket {}, instead of port {} of the node", addr, port);
+            }
+            debug!("Serving TCP on systemd socket {}", addr);
+            builder.set_tcp_listener(listener.try_clone()?);
+        }
+        if let Some(socket) = self.activated_sockets.udp_socket.as_ref() {
+            let addr = socket.local_addr()?;
+            if addr.port() != port {
+                warn!("Serving UDP on systemd socket {}, instead of port {} of the node", addr, port);
+            }
+            debug!("Serving UDP on systemd socket {}", addr);
+            builder.set_udp_socket(socket.try_clone()?);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(super) fn apply_activated_sockets(&self, _builder: &mut ServerBuilder, _port: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// Check if the server is running and its accept loops were polled within `max_idle`
+    pub async fn is_serving(&self, max_idle: Duration) -> bool {
+        let running = self
+            .server_handle
+            .read()
+            .await
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished());
+        running && self.context.accept_idle_duration() <= max_idle
+    }
+}
@@ -1,7 +1,13 @@
 use super::server::ShadowsocksServerManager;
-use crate::v2board::{ServerConfig, UserInfo};
+use super::user_key::UserKeyDerivation;
+use crate::v2board::{RouteRule, ServerConfig, UserInfo, UserViolation};
+use super::acl::{AclReloader, AclSource};
+use super::nftables::NftSet;
 use crate::config::ShadowsocksConfig;
-use shadowsocks_service::shadowsocks::config::ServerUserManager;
+use shadowsocks_service::shadowsocks::config::{ServerUser, ServerUserManager};
+use shadowsocks_service::acl::{AccessControl, P2pAction, PortRange, UserLimits};
+use shadowsocks_service::net::sniff::{SniffedDomain, SniffedProtocol};
+use shadowsocks_service::shadowsocks::relay::socks5::Address;
 
 fn make_users(n: usize) -> Vec<UserInfo> {
     (0..n)
@@ -9,6 +15,10 @@ fn make_users(n: usize) -> Vec<UserInfo> {
             id: i as i32,
             // Ensure UUID string is long enough (> 32 bytes)
             uuid: format!("{}-aaaaaaaa-aaaaaaaa-aaaaaaaa-aaaaaaaa", i),
+            password: None,
+            max_tcp_connections: None,
+            max_udp_associations: None,
+            max_new_connections: None,
         })
         .collect()
 }
@@ -23,7 +33,7 @@ async fn test_add_users_password_length_16_for_2022_128() {
     let users = make_users(3);
 
     // Call private helper within the same module
-    ShadowsocksServerManager::add_users_to_manager(&manager, &users, Some("2022-blake3-aes-128-gcm"));
+    ShadowsocksServerManager::add_users_to_manager(&manager, &users, Some("2022-blake3-aes-128-gcm"), UserKeyDerivation::default());
 
     assert_eq!(manager.user_count(), users.len());
     for u in manager.users_iter() {
@@ -37,7 +47,7 @@ async fn test_add_users_password_length_32_for_other_ciphers() {
     let users = make_users(2);
 
     // Use a different cipher which should fall back to 32
-    ShadowsocksServerManager::add_users_to_manager(&manager, &users, Some("2022-blake3-aes-256-gcm"));
+    ShadowsocksServerManager::add_users_to_manager(&manager, &users, Some("2022-blake3-aes-256-gcm"), UserKeyDerivation::default());
 
     assert_eq!(manager.user_count(), users.len());
     for u in manager.users_iter() {
@@ -47,7 +57,7 @@ async fn test_add_users_password_length_32_for_other_ciphers() {
 
 #[tokio::test]
 async fn test_update_users_without_active_config_does_not_touch_manager() {
-    let mgr = ShadowsocksServerManager::new(default_ss_config());
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
     // Ensure manager starts empty
     assert_eq!(mgr.user_manager.user_count(), 0);
 
@@ -60,7 +70,7 @@ async fn test_update_users_without_active_config_does_not_touch_manager() {
 
 #[tokio::test]
 async fn test_update_users_with_active_config_rebuilds_manager() {
-    let mgr = ShadowsocksServerManager::new(default_ss_config());
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
 
     // Seed current_config to simulate an active server configuration
     {
@@ -70,12 +80,15 @@ async fn test_update_users_with_active_config_rebuilds_manager() {
             cipher: Some("2022-blake3-aes-128-gcm".to_string()),
             server_key: Some("dummy-key".to_string()),
             base_config: None,
+            routes: Vec::new(),
+            obfs: None,
+            obfs_settings: None,
         });
     }
 
     // Pre-populate manager with different users to ensure it gets cleared
     let pre_users = make_users(2);
-    ShadowsocksServerManager::add_users_to_manager(&mgr.user_manager, &pre_users, Some("2022-blake3-aes-256-gcm"));
+    ShadowsocksServerManager::add_users_to_manager(&mgr.user_manager, &pre_users, Some("2022-blake3-aes-256-gcm"), UserKeyDerivation::default());
     assert_eq!(mgr.user_manager.user_count(), 2);
 
     // Now update with a new set; should clear and add using active config's cipher (128 -> 16 bytes keys)
@@ -90,7 +103,7 @@ async fn test_update_users_with_active_config_rebuilds_manager() {
 
 #[tokio::test]
 async fn test_start_server_initializes_handle_and_user_manager() {
-    let mgr = ShadowsocksServerManager::new(default_ss_config());
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
 
     // Preload users before starting the server
     {
@@ -104,6 +117,9 @@ async fn test_start_server_initializes_handle_and_user_manager() {
         // 16-byte base64 key
         server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
         base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
     };
 
     mgr.start_server(cfg.clone()).await.expect("server should start");
@@ -131,13 +147,16 @@ async fn test_start_server_initializes_handle_and_user_manager() {
 
 #[tokio::test]
 async fn test_start_server_invalid_cipher_returns_error_and_no_handle() {
-    let mgr = ShadowsocksServerManager::new(default_ss_config());
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
 
     let cfg = ServerConfig {
         server_port: 0,
         cipher: Some("invalid-cipher".to_string()),
         server_key: Some("dummy-key".to_string()),
         base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
     };
 
     let err = mgr.start_server(cfg).await.expect_err("invalid cipher should error");
@@ -150,7 +169,7 @@ async fn test_start_server_invalid_cipher_returns_error_and_no_handle() {
 
 #[tokio::test]
 async fn test_update_users_while_server_running() {
-    let mgr = ShadowsocksServerManager::new(default_ss_config());
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
 
     // Seed initial users
     {
@@ -164,6 +183,9 @@ async fn test_update_users_while_server_running() {
         cipher: Some("2022-blake3-aes-128-gcm".to_string()),
         server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
         base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
     };
 
     mgr.start_server(cfg).await.expect("server should start");
@@ -186,7 +208,7 @@ async fn test_update_users_while_server_running() {
 
 #[tokio::test]
 async fn test_restart_server_with_new_config() {
-    let mgr = ShadowsocksServerManager::new(default_ss_config());
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
 
     // First start with 128-bit cipher and two users
     {
@@ -198,6 +220,9 @@ async fn test_restart_server_with_new_config() {
         cipher: Some("2022-blake3-aes-128-gcm".to_string()),
         server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()), // 16-byte key
         base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
     };
     mgr.start_server(cfg1.clone()).await.expect("first start should succeed");
     assert_eq!(mgr.user_manager.user_count(), 2);
@@ -220,6 +245,9 @@ async fn test_restart_server_with_new_config() {
         // 32-byte key base64
         server_key: Some("MTIzNDU2Nzg5MDEyMzQ1Njc4OTAxMjM0NTY3ODkwMTI=".to_string()),
         base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
     };
 
     mgr.start_server(cfg2.clone()).await.expect("second start should succeed");
@@ -239,3 +267,1636 @@ async fn test_restart_server_with_new_config() {
 
     mgr.stop_server().await;
 }
+
+#[tokio::test]
+async fn test_restart_server_keeps_previous_server_key() {
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    {
+        let mut guard = mgr.users.write().await;
+        *guard = make_users(1);
+    }
+    let cfg1 = ServerConfig {
+        server_port: 0,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()), // "abcdefghijklmnop"
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    mgr.start_server(cfg1.clone()).await.expect("first start should succeed");
+    assert!(mgr.user_manager.previous_keys().is_empty());
+
+    // Same key, nothing to keep
+    mgr.start_server(cfg1.clone()).await.expect("restart should succeed");
+    assert!(mgr.user_manager.previous_keys().is_empty());
+
+    let cfg2 = ServerConfig {
+        server_key: Some("cXJzdHV2d3h5ejAxMjM0NQ==".to_string()), // "qrstuvwxyz012345"
+        ..cfg1
+    };
+    mgr.start_server(cfg2).await.expect("rotated start should succeed");
+    let previous_keys = mgr.user_manager.previous_keys();
+    assert_eq!(previous_keys.len(), 1);
+    assert_eq!(&previous_keys[0][..], b"abcdefghijklmnop");
+
+    mgr.stop_server().await;
+
+    // Disabled grace period
+    let ss_config: ShadowsocksConfig = toml::from_str("server_key_grace_period = 0").unwrap();
+    assert!(ss_config.server_key_grace_duration().is_none());
+}
+
+#[tokio::test]
+async fn test_new_applies_outbound_bind_addrs() {
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    assert!(mgr.context.bind_addr_pool().is_none());
+
+    let mut ss_config = default_ss_config();
+    ss_config.outbound_bind_addrs = vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let pool = mgr.context.bind_addr_pool().expect("bind address pool should be set");
+    assert!(!pool.is_empty());
+}
+
+#[tokio::test]
+async fn test_new_applies_internal_addr_filter() {
+    let mut ss_config = default_ss_config();
+    ss_config.allowed_internal_addrs = vec!["10.0.0.53".to_string(), "invalid".to_string()];
+    ss_config.public_addrs = vec!["203.0.113.10".parse().unwrap()];
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let filter = mgr.context.internal_addr_filter().expect("internal address filter should be set");
+    assert!(filter.is_blocked(&"169.254.169.254".parse().unwrap()));
+    assert!(!filter.is_blocked(&"10.0.0.53".parse().unwrap()));
+    assert!(filter.is_blocked(&"203.0.113.10".parse().unwrap()));
+    assert!(filter.is_blocked(&"64:ff9b::203.0.113.10".parse().unwrap()));
+    assert!(!filter.is_blocked(&"203.0.113.11".parse().unwrap()));
+
+    let mut ss_config = default_ss_config();
+    ss_config.block_internal_addrs = false;
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.context.internal_addr_filter().is_none());
+}
+
+#[tokio::test]
+async fn test_new_applies_port_policy() {
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    assert!(mgr.context.port_policy().is_none());
+
+    let ss_config: ShadowsocksConfig = toml::from_str(
+        r#"
+        tcp_blocked_ports = [25, "6881-6889"]
+        port_block_threshold = 3
+        "#,
+    )
+    .unwrap();
+    let auto_block = ss_config.port_auto_block().expect("auto block should be enabled");
+    assert_eq!(auto_block.threshold, 3);
+
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.context.port_policy().is_some());
+}
+
+#[tokio::test]
+async fn test_udp_port_hits_counted_once_per_association() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::relay::udprelay::ProxySocket;
+    use shadowsocks_service::shadowsocks::ServerConfig as ClientConfig;
+
+    let udp_echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp_echo_addr = udp_echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        let (n, peer) = udp_echo.recv_from(&mut buf).await.unwrap();
+        udp_echo.send_to(&buf[..n], peer).await.unwrap();
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        udp_blocked_ports: vec![PortRange::from(9)],
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("aes-256-gcm".to_string()),
+        server_key: None,
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+    mgr.start_server(cfg).await.expect("server should start");
+
+    let client_cfg = ClientConfig::new(("127.0.0.1", port), users[0].uuid.clone(), CipherKind::AES_256_GCM).unwrap();
+    let context = Context::new_shared(ServerType::Local);
+    let socket = ProxySocket::connect(context, &client_cfg).await.unwrap();
+    let blocked_addr = Address::from(std::net::SocketAddr::from(([127, 0, 0, 1], 9)));
+    for _ in 0..5 {
+        socket.send(&blocked_addr, b"ping").await.unwrap();
+    }
+
+    // Packets of an association are handled in order, blocked ones are done when the echo returns
+    socket.send(&Address::from(udp_echo_addr), b"ping").await.unwrap();
+    let mut recv_buf = vec![0u8; 65536];
+    tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv(&mut recv_buf))
+        .await
+        .expect("target should echo")
+        .unwrap();
+
+    let hits = mgr.context.port_policy().expect("port policy should be set").take_hits();
+    assert_eq!(hits.values().copied().collect::<Vec<_>>(), vec![1]);
+
+    mgr.stop_server().await;
+}
+
+#[tokio::test]
+async fn test_new_applies_p2p_policy() {
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    assert!(mgr.context.p2p_policy().is_none());
+
+    let ss_config: ShadowsocksConfig = toml::from_str(
+        r#"
+        p2p_action = "throttle"
+        "#,
+    )
+    .unwrap();
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    let policy = mgr.context.p2p_policy().expect("p2p policy should be set");
+    assert_eq!(policy.action(), P2pAction::Throttle);
+
+    let peer = "198.51.100.1:40000".parse().unwrap();
+    let limiter = policy.record(None, &peer).expect("p2p traffic should be throttled");
+    assert_eq!(limiter.rate(), 64 * 1024);
+}
+
+#[tokio::test]
+async fn test_p2p_hits_exported() {
+    let export_path = std::env::temp_dir().join(format!("ss22v2b-test-p2p-{}.json", std::process::id()));
+    let ss_config = ShadowsocksConfig {
+        p2p_action: Some(P2pAction::Log),
+        p2p_export: Some(export_path.clone()),
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let user = ServerUser::new("7", vec![0u8; 16]);
+    mgr.user_manager.add_user(user.clone());
+    let policy = mgr.context.p2p_policy().expect("p2p policy should be set");
+    let peer = "198.51.100.1:40000".parse().unwrap();
+    for _ in 0..2 {
+        policy.record(Some(&user), &peer);
+    }
+    mgr.collect_user_traffic().await.unwrap();
+    policy.record(Some(&user), &peer);
+    mgr.collect_user_traffic().await.unwrap();
+
+    // Counted since start, across pushes
+    let hits: serde_json::Value = serde_json::from_slice(&std::fs::read(&export_path).unwrap()).unwrap();
+    assert_eq!(hits, serde_json::json!([{ "user_id": 7, "hits": 3 }]));
+
+    std::fs::remove_file(&export_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_new_opens_access_log() {
+    let dir = std::env::temp_dir().join(format!("ss22v2b-test-access-log-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut ss_config = default_ss_config();
+    ss_config.access_log = Some(dir.join("access.log"));
+    let access_log_config = ss_config.access_log_config().unwrap();
+    assert_eq!(access_log_config.max_size, 100 * 1024 * 1024);
+    assert_eq!(access_log_config.rotate_interval, Some(std::time::Duration::from_secs(86400)));
+
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.context.access_log().is_some());
+    assert!(dir.join("access.log").exists());
+
+    let mut ss_config = default_ss_config();
+    ss_config.access_log = Some(dir.join("missing").join("access.log"));
+    assert!(ShadowsocksServerManager::new(ss_config).is_err());
+
+    drop(mgr);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_new_loads_acl() {
+    let acl_path = std::env::temp_dir().join(format!("ss22v2b-test-{}.acl", std::process::id()));
+    std::fs::write(&acl_path, "[outbound_block_list]\n||example.com\n").unwrap();
+
+    let mut ss_config = default_ss_config();
+    ss_config.acl = Some(acl_path.to_string_lossy().into_owned());
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.context.acl().is_some());
+
+    // geosite: rules without a GeoSite database
+    std::fs::write(&acl_path, "[outbound_block_list]\ngeosite:category-ads\n").unwrap();
+    let mut ss_config = default_ss_config();
+    ss_config.acl = Some(acl_path.to_string_lossy().into_owned());
+    assert!(ShadowsocksServerManager::new(ss_config).is_err());
+
+    std::fs::remove_file(&acl_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_acl_reload_keeps_previous_rules_on_error() {
+    let acl_path = std::env::temp_dir().join(format!("ss22v2b-test-reload-{}.acl", std::process::id()));
+    std::fs::write(&acl_path, "[outbound_block_list]\n||example.com\n").unwrap();
+
+    let mut ss_config = default_ss_config();
+    ss_config.acl = Some(acl_path.to_string_lossy().into_owned());
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    let original = mgr.context.acl().unwrap();
+
+    let mut reloader = AclReloader::new(
+        AclSource::parse(&acl_path.to_string_lossy()),
+        Default::default(),
+        mgr.context.clone(),
+    )
+    .unwrap();
+    reloader.mark_loaded().await;
+    assert!(!reloader.reload().await.unwrap());
+
+    // Broken rules are rejected, previous rules are kept
+    std::fs::write(&acl_path, "[outbound_block_list]\ngeosite:category-ads\n").unwrap();
+    assert!(reloader.reload().await.is_err());
+    assert!(std::sync::Arc::ptr_eq(&original, &mgr.context.acl().unwrap()));
+    // Not parsed again until changed
+    assert!(!reloader.reload().await.unwrap());
+
+    std::fs::write(&acl_path, "[outbound_block_list]\n||example.com\n||example.org\n").unwrap();
+    assert!(reloader.reload().await.unwrap());
+    assert!(!std::sync::Arc::ptr_eq(&original, &mgr.context.acl().unwrap()));
+    assert_eq!(mgr.context.acl().unwrap().file_path(), acl_path.as_path());
+
+    // Edits keeping the length are reloaded, even within the same second
+    let reloaded = mgr.context.acl().unwrap();
+    std::fs::write(&acl_path, "[outbound_block_list]\n||example.com\n||example.net\n").unwrap();
+    assert!(reloader.reload().await.unwrap());
+    assert!(!std::sync::Arc::ptr_eq(&reloaded, &mgr.context.acl().unwrap()));
+
+    std::fs::remove_file(&acl_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_update_audit_rules_from_routes() {
+    let routes: Vec<RouteRule> = serde_json::from_str(
+        r#"[
+            {"id": 1, "match": ["domain:example.com", "10.8.0.0/16"], "action": "block"},
+            {"id": 2, "match": "25,465", "action": "block_port"},
+            {"id": 3, "match": ["example.org"], "action": "dns", "action_value": "1.1.1.1"}
+        ]"#,
+    )
+    .unwrap();
+    assert_eq!(routes[1].match_rules, vec!["25", "465"]);
+
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    mgr.update_audit_rules(&routes);
+    let rules = mgr.context.audit_rules().expect("audit rules should be loaded");
+    // The dns rule is skipped
+    assert_eq!(rules.len(), 2);
+    assert_eq!(rules.check_matched(&Address::DomainNameAddress("a.example.com".to_owned(), 443)), Some(1));
+    assert_eq!(rules.check_matched(&Address::DomainNameAddress("mail.example.net".to_owned(), 465)), Some(2));
+    assert_eq!(rules.check_matched(&Address::DomainNameAddress("example.org".to_owned(), 443)), None);
+
+    // Violations of known users are collected
+    let users = vec![UserInfo {
+        id: 7,
+        uuid: "7-aaaaaaaa-aaaaaaaa-aaaaaaaa-aaaaaaaa".to_string(),
+        password: None,
+        max_tcp_connections: None,
+        max_udp_associations: None,
+        max_new_connections: None,
+    }];
+    ShadowsocksServerManager::add_users_to_manager(&mgr.user_manager, &users, Some("2022-blake3-aes-128-gcm"), UserKeyDerivation::default());
+    let user = mgr.user_manager.users_iter().next().unwrap().clone();
+    mgr.context.check_outbound_audit(&Address::DomainNameAddress("a.example.com".to_owned(), 443), Some(&user));
+    mgr.context.check_outbound_audit(&Address::DomainNameAddress("a.example.com".to_owned(), 443), None);
+    let violations = mgr.collect_violations().await.unwrap();
+    assert_eq!(violations, vec![UserViolation { user_id: 7, rule_id: 1, target: "a.example.com:443".to_owned() }]);
+
+    mgr.update_audit_rules(&[]);
+    assert!(mgr.context.audit_rules().is_none());
+}
+
+#[tokio::test]
+async fn test_sniffed_domain_matches_acl() {
+    let acl_path = std::env::temp_dir().join(format!("ss22v2b-test-sniff-{}.acl", std::process::id()));
+    std::fs::write(&acl_path, "[outbound_block_list]\n||example.com\n").unwrap();
+
+    let ss_config: ShadowsocksConfig = toml::from_str(
+        r#"
+        sniff = true
+        sniff_timeout = 100
+        "#,
+    )
+    .unwrap();
+    let mut ss_config = ShadowsocksConfig {
+        acl: Some(acl_path.to_string_lossy().into_owned()),
+        ..ss_config
+    };
+    ss_config.block_internal_addrs = false;
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.context.sniff_config().tcp);
+    assert_eq!(mgr.context.sniff_config().timeout, std::time::Duration::from_millis(100));
+
+    let target = Address::SocketAddress("93.184.216.34:443".parse().unwrap());
+    let sniffed = SniffedDomain {
+        protocol: SniffedProtocol::Tls,
+        domain: "www.example.com".to_string(),
+    };
+    assert!(!mgr.context.check_sniffed_outbound_blocked(&target, None).await);
+    assert!(mgr.context.check_sniffed_outbound_blocked(&target, Some(&sniffed)).await);
+
+    // Allow-listed domains forged in the ClientHello don't unblock the target
+    std::fs::write(
+        &acl_path,
+        "[outbound_block_list]\n203.0.113.0/24\n[outbound_allow_list]\n||allowed.example.org\n",
+    )
+    .unwrap();
+    let acl = AccessControl::load_from_file(&acl_path).unwrap();
+    mgr.context.replace_acl(std::sync::Arc::new(acl));
+    let blocked_target = Address::SocketAddress("203.0.113.5:443".parse().unwrap());
+    let forged = SniffedDomain {
+        protocol: SniffedProtocol::Tls,
+        domain: "www.allowed.example.org".to_string(),
+    };
+    assert!(mgr.context.check_sniffed_outbound_blocked(&blocked_target, None).await);
+    assert!(mgr.context.check_sniffed_outbound_blocked(&blocked_target, Some(&forged)).await);
+    assert!(!mgr.context.check_sniffed_outbound_blocked(&target, Some(&forged)).await);
+
+    std::fs::remove_file(&acl_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_user_limits_overridden_by_panel() {
+    let ss_config: ShadowsocksConfig = toml::from_str(
+        r#"
+        user_max_tcp_connections = 2
+        user_max_new_connections = 10
+        user_limits_from_panel = true
+        "#,
+    )
+    .unwrap();
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let mut users = make_users(2);
+    users[1].max_tcp_connections = Some(1);
+    users[1].max_udp_associations = Some(3);
+    mgr.update_users(users).await;
+
+    let limiter = mgr.context.user_limiter().expect("user limiter should be enabled");
+    assert_eq!(limiter.limits_of("0"), UserLimits {
+        max_tcp_connections: Some(2),
+        max_udp_associations: None,
+        max_new_connections: Some(10),
+    });
+    assert_eq!(limiter.limits_of("1"), UserLimits {
+        max_tcp_connections: Some(1),
+        max_udp_associations: Some(3),
+        max_new_connections: Some(10),
+    });
+
+    let user = ServerUser::new("1", vec![0u8; 16]);
+    let _guard = limiter.acquire_tcp(&user).unwrap();
+    assert!(limiter.acquire_tcp(&user).is_err());
+}
+
+#[tokio::test]
+async fn test_user_limits_off_by_default() {
+    // No limits at all without node-wide defaults or user_limits_from_panel
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    let mut users = make_users(1);
+    users[0].max_tcp_connections = Some(1);
+    mgr.update_users(users.clone()).await;
+    assert!(mgr.context.user_limiter().is_none());
+
+    // Node-wide defaults aren't overridden by the panel unless enabled
+    let ss_config: ShadowsocksConfig = toml::from_str("user_max_tcp_connections = 4").unwrap();
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    mgr.update_users(users).await;
+    let limiter = mgr.context.user_limiter().expect("user limiter should be enabled");
+    assert_eq!(limiter.limits_of("0").max_tcp_connections, Some(4));
+}
+
+#[tokio::test]
+async fn test_shared_node_user_limits() {
+    let ss_config: ShadowsocksConfig = toml::from_str("user_limits_from_panel = true").unwrap();
+    let mut mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    mgr.add_shared_node(2);
+
+    let mut users = make_users(1);
+    users[0].max_tcp_connections = Some(3);
+    mgr.update_users(users).await;
+
+    let mut node_users = make_users(3);
+    node_users.remove(0);
+    node_users[0].max_udp_associations = Some(2);
+    mgr.update_shared_node_users(2, node_users).await.unwrap();
+
+    // Limits of both nodes are applied
+    let limiter = mgr.context.user_limiter().expect("user limiter should be enabled");
+    assert_eq!(limiter.limits_of("0").max_tcp_connections, Some(3));
+    assert_eq!(limiter.limits_of("1").max_udp_associations, Some(2));
+    assert!(limiter.limits_of("2").is_unlimited());
+
+    // Updating users of this node keeps limits of the shared node
+    mgr.update_users(make_users(1)).await;
+    assert!(limiter.limits_of("0").is_unlimited());
+    assert_eq!(limiter.limits_of("1").max_udp_associations, Some(2));
+}
+
+#[tokio::test]
+async fn test_ban_clients_failing_handshakes() {
+    let export_path = std::env::temp_dir().join(format!("ss22v2b-test-bans-{}.json", std::process::id()));
+
+    let ss_config: ShadowsocksConfig = toml::from_str(
+        r#"
+        ban_threshold = 2
+        ban_duration = 300
+        ban_ipv4_prefix = 24
+        "#,
+    )
+    .unwrap();
+    let ss_config = ShadowsocksConfig {
+        ban_export: Some(export_path.clone()),
+        ..ss_config
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let peer: std::net::SocketAddr = "198.51.100.1:40000".parse().unwrap();
+    let neighbor: std::net::SocketAddr = "198.51.100.2:40000".parse().unwrap();
+    let err = std::io::Error::other("decrypt header chunk failed");
+    mgr.context.record_handshake_failure(&peer, &err);
+    assert!(!mgr.context.check_client_banned(&neighbor));
+    mgr.context.record_handshake_failure(&peer, &err);
+    assert!(mgr.context.check_client_banned(&neighbor));
+
+    mgr.export_bans().unwrap();
+    let bans: serde_json::Value = serde_json::from_slice(&std::fs::read(&export_path).unwrap()).unwrap();
+    assert_eq!(bans[0]["network"], "198.51.100.0/24");
+    assert_eq!(bans[0]["failure"], "bad_auth");
+    assert_eq!(bans[0]["duration_secs"], 300);
+
+    std::fs::remove_file(&export_path).unwrap();
+}
+
+#[test]
+fn test_parse_nftables_set() {
+    let set = NftSet::parse("inet filter  ss22v2b_ban4").unwrap();
+    assert_eq!(set.to_string(), "inet filter ss22v2b_ban4");
+    assert!(NftSet::parse("filter ss22v2b_ban4").is_err());
+}
+
+#[tokio::test]
+async fn test_fallback_failed_handshakes_to_backend() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let backend_task = tokio::spawn(async move {
+        let (mut stream, _) = backend.accept().await.unwrap();
+        let mut request = vec![0u8; 64];
+        stream.read_exact(&mut request).await.unwrap();
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        request
+    });
+
+    let ss_config = ShadowsocksConfig {
+        fallback: Some(backend_addr.to_string()),
+        ban_threshold: Some(1),
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    mgr.start_server(cfg).await.expect("server should start");
+
+    // Request of a prober, long enough to fail decryption
+    let request = format!("GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: {}\r\n\r\n", "a".repeat(13));
+    assert_eq!(request.len(), 64);
+
+    let mut stream = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("server should be listening");
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = vec![0u8; 19];
+    tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut response))
+        .await
+        .expect("backend should respond")
+        .unwrap();
+    assert_eq!(response, b"HTTP/1.1 200 OK\r\n\r\n");
+    assert_eq!(backend_task.await.unwrap(), request.as_bytes());
+
+    // Probers served by the fallback are never banned
+    assert!(!mgr.context.check_client_banned(&stream.local_addr().unwrap()));
+
+    mgr.stop_server().await;
+}
+
+#[tokio::test]
+async fn test_replay_cache_survives_restart() {
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+
+    let cache_path = std::env::temp_dir().join(format!("ss22v2b-test-salts-{}.bin", std::process::id()));
+    let ss_config = ShadowsocksConfig {
+        replay_cache: Some(cache_path.clone()),
+        ..default_ss_config()
+    };
+
+    let method = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+    let salt = b"0123456789abcdef";
+
+    let mgr = ShadowsocksServerManager::new(ss_config.clone()).unwrap();
+    assert!(mgr.context.context_ref().check_nonce_replay(method, salt).is_ok());
+    mgr.save_replay_cache().await.unwrap();
+
+    let restarted = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(restarted.context.context_ref().check_nonce_replay(method, salt).is_err());
+
+    std::fs::remove_file(&cache_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_shared_node_on_same_port() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        ..default_ss_config()
+    };
+    let mut mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    mgr.add_shared_node(2);
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    let node_cfg = ServerConfig {
+        server_key: Some("cXJzdHV2d3h5ejAxMjM0NQ==".to_string()),
+        ..cfg.clone()
+    };
+
+    // The same user in both nodes
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+    mgr.update_shared_node_users(2, users.clone()).await.unwrap();
+    mgr.update_shared_node_config(2, node_cfg).await.unwrap();
+    mgr.start_server(cfg).await.expect("server should start");
+
+    let user = ServerUser::new("0", users[0].uuid.as_bytes()[..16].to_vec());
+    let password = format!("cXJzdHV2d3h5ejAxMjM0NQ==:{}", user.encoded_key());
+    let client_cfg = ClientConfig::new(
+        ("127.0.0.1", port),
+        password,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+    )
+    .unwrap();
+
+    let context = Context::new_shared(ServerType::Local);
+    let mut stream = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match ProxyClientStream::connect(context.clone(), &client_cfg, echo_addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("server should be listening");
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("target should echo")
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+    drop(stream);
+
+    // Traffic is counted in the shared node only
+    let traffic = mgr.collect_shared_node_traffic(2).unwrap();
+    assert_eq!(traffic.len(), 1);
+    assert_eq!(traffic[0].id, 0);
+    assert!(traffic[0].upload > 0 && traffic[0].download > 0);
+    assert!(mgr.collect_user_traffic().await.unwrap().is_empty());
+    assert!(mgr.collect_shared_node_traffic(3).is_err());
+
+    mgr.stop_server().await;
+}
+
+#[tokio::test]
+async fn test_legacy_aead_users_found_by_trial_decryption() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::relay::udprelay::ProxySocket;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+    let udp_echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp_echo_addr = udp_echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        let (n, peer) = udp_echo.recv_from(&mut buf).await.unwrap();
+        udp_echo.send_to(&buf[..n], peer).await.unwrap();
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+
+    // AEAD nodes of the panel come without a server key
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("aes-256-gcm".to_string()),
+        server_key: None,
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    let users = make_users(3);
+    mgr.update_users(users.clone()).await;
+    mgr.start_server(cfg).await.expect("server should start");
+
+    let client_cfg = ClientConfig::new(("127.0.0.1", port), users[2].uuid.clone(), CipherKind::AES_256_GCM).unwrap();
+
+    let context = Context::new_shared(ServerType::Local);
+    let mut stream = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match ProxyClientStream::connect(context.clone(), &client_cfg, echo_addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("server should be listening");
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("target should echo")
+        .unwrap();
+    assert_eq!(&buf, b"hello");
+    drop(stream);
+
+    // Responses are encrypted with the key of the user
+    let socket = ProxySocket::connect(context.clone(), &client_cfg).await.unwrap();
+    socket.send(&Address::from(udp_echo_addr), b"ping").await.unwrap();
+    let mut recv_buf = vec![0u8; 65536];
+    let (n, _, _) = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv(&mut recv_buf))
+        .await
+        .expect("target should echo")
+        .unwrap();
+    assert_eq!(&recv_buf[..n], b"ping");
+
+    let traffic = mgr.collect_user_traffic().await.unwrap();
+    assert_eq!(traffic.len(), 1);
+    assert_eq!(traffic[0].id, 2);
+    assert!(traffic[0].upload > 0 && traffic[0].download > 0);
+
+    mgr.stop_server().await;
+}
+
+#[test]
+fn test_user_key_derivation() {
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+
+    let mut user = make_users(1).remove(0);
+    let aes_128 = CipherKind::AEAD2022_BLAKE3_AES_128_GCM;
+    let aes_256 = CipherKind::AEAD2022_BLAKE3_AES_256_GCM;
+
+    let prefix = UserKeyDerivation::UuidPrefix.server_user(&user, aes_256).unwrap();
+    assert_eq!(prefix.key(), &user.uuid.as_bytes()[..32]);
+
+    // The panel's password is base64 of a key fitting the cipher
+    assert!(UserKeyDerivation::Password.server_user(&user, aes_128).is_err());
+    user.password = Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string());
+    let password = UserKeyDerivation::Password.server_user(&user, aes_128).unwrap();
+    assert_eq!(password.key(), b"abcdefghijklmnop");
+    assert!(UserKeyDerivation::Password.server_user(&user, aes_256).is_err());
+    assert_eq!(
+        UserKeyDerivation::Password.password(&user, CipherKind::AES_256_GCM).unwrap(),
+        "YWJjZGVmZ2hpamtsbW5vcA=="
+    );
+
+    user.uuid = "short".to_string();
+    assert!(UserKeyDerivation::UuidPrefix.server_user(&user, aes_128).is_err());
+    assert_eq!(UserKeyDerivation::UuidPrefix.password(&user, CipherKind::AES_256_GCM).unwrap(), "short");
+
+    let ss_config: ShadowsocksConfig = toml::from_str(r#"user_key_derivation = "password""#).unwrap();
+    assert_eq!(ss_config.user_key_derivation, UserKeyDerivation::Password);
+    assert!(toml::from_str::<ShadowsocksConfig>(r#"user_key_derivation = "blake3""#).is_err());
+}
+
+#[test]
+fn test_user_link_matches_derivation() {
+    use super::user_key::user_link;
+    use shadowsocks_service::shadowsocks::ServerConfig as ClientConfig;
+
+    let mut user = make_users(1).remove(0);
+    user.password = Some("MDEyMzQ1Njc4OWFiY2RlZg==".to_string());
+    let cfg = ServerConfig {
+        server_port: 8388,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+
+    for derivation in [UserKeyDerivation::UuidPrefix, UserKeyDerivation::Password] {
+        let ss_config = ShadowsocksConfig {
+            user_key_derivation: derivation,
+            ..default_ss_config()
+        };
+        let link = user_link(&ss_config, &cfg, &user, "example.com").unwrap();
+        let client_cfg = ClientConfig::from_url(&link).unwrap();
+        let server_user = derivation.server_user(&user, client_cfg.method()).unwrap();
+        assert_eq!(client_cfg.key(), server_user.key());
+        assert_eq!(client_cfg.identity_keys()[0].as_ref(), b"abcdefghijklmnop");
+        assert_eq!(client_cfg.addr().to_string(), "example.com:8388");
+        assert_eq!(client_cfg.remarks(), Some("0"));
+    }
+
+    // AEAD links carry the UUID as password
+    let cfg = ServerConfig {
+        cipher: Some("aes-256-gcm".to_string()),
+        server_key: None,
+        ..cfg
+    };
+    let link = user_link(&default_ss_config(), &cfg, &user, "example.com").unwrap();
+    assert_eq!(ClientConfig::from_url(&link).unwrap().password(), user.uuid);
+}
+
+#[test]
+fn test_user_link_with_transport_plugin() {
+    use super::user_key::user_link;
+    use crate::v2board::ObfsSettings;
+    use shadowsocks_service::shadowsocks::ServerConfig as ClientConfig;
+
+    let user = make_users(1).remove(0);
+    let cfg = ServerConfig {
+        server_port: 8388,
+        cipher: Some("aes-256-gcm".to_string()),
+        server_key: None,
+        base_config: None,
+        routes: Vec::new(),
+        obfs: Some("http".to_string()),
+        obfs_settings: Some(ObfsSettings {
+            host: Some("example.com".to_string()),
+            path: Some("ws".to_string()),
+        }),
+    };
+
+    let link = user_link(&default_ss_config(), &cfg, &user, "example.com").unwrap();
+    let plugin = ClientConfig::from_url(&link).unwrap().plugin().cloned().unwrap();
+    assert_eq!(plugin.plugin, "obfs-local");
+    assert_eq!(plugin.plugin_opts.as_deref(), Some("obfs=http;obfs-host=example.com"));
+
+    // TLS follows the local certificate settings
+    let ss_config = ShadowsocksConfig {
+        transport_tls_cert: Some("cert.pem".into()),
+        transport_tls_key: Some("key.pem".into()),
+        ..default_ss_config()
+    };
+    let cfg = ServerConfig {
+        obfs: Some("websocket".to_string()),
+        ..cfg
+    };
+    let link = user_link(&ss_config, &cfg, &user, "example.com").unwrap();
+    let plugin = ClientConfig::from_url(&link).unwrap().plugin().cloned().unwrap();
+    assert_eq!(plugin.plugin, "v2ray-plugin");
+    assert_eq!(plugin.plugin_opts.as_deref(), Some("tls;path=/ws;mux=0;host=example.com"));
+
+    let cfg = ServerConfig {
+        obfs: Some("quic".to_string()),
+        ..cfg
+    };
+    assert!(user_link(&ss_config, &cfg, &user, "example.com").is_err());
+}
+
+#[tokio::test]
+async fn test_obfs_http_and_websocket_transports() {
+    use crate::v2board::ObfsSettings;
+    use bytes::Bytes;
+    use shadowsocks_service::net::transport::WebSocketStream;
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            });
+        }
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    let users = make_users(2);
+    mgr.update_users(users.clone()).await;
+
+    for obfs in ["http", "websocket"] {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let cfg = ServerConfig {
+            server_port: port as u32,
+            cipher: Some("aes-256-gcm".to_string()),
+            server_key: None,
+            base_config: None,
+            routes: Vec::new(),
+            obfs: Some(obfs.to_string()),
+            obfs_settings: Some(ObfsSettings {
+                host: Some("example.com".to_string()),
+                path: Some("/ws".to_string()),
+            }),
+        };
+        mgr.start_server(cfg).await.expect("server should start");
+
+        // Plays the client plugin, wrapping connections from the shadowsocks client
+        let plugin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plugin_addr = plugin.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut local, _) = plugin.accept().await.unwrap();
+            let mut remote = loop {
+                match TcpStream::connect(("127.0.0.1", port)).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+            let path = if obfs == "http" { "/" } else { "/ws" };
+            let request = format!(
+                "GET {path} HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+            );
+            remote.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                response.push(remote.read_u8().await.unwrap());
+            }
+            assert!(response.starts_with(b"HTTP/1.1 101 "));
+
+            if obfs == "http" {
+                let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+            } else {
+                // Frames of the server are accepted unmasked
+                let mut remote = WebSocketStream::new(remote, Bytes::new());
+                let _ = tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+            }
+        });
+
+        let client_cfg =
+            ClientConfig::new(plugin_addr, users[1].uuid.clone(), CipherKind::AES_256_GCM).unwrap();
+        let context = Context::new_shared(ServerType::Local);
+        let mut stream = ProxyClientStream::connect(context, &client_cfg, echo_addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .expect("target should echo")
+            .unwrap();
+        assert_eq!(&buf, b"hello", "echo through {obfs}");
+        drop(stream);
+
+        let traffic = mgr.collect_user_traffic().await.unwrap();
+        assert_eq!(traffic.len(), 1);
+        assert_eq!(traffic[0].id, 1);
+
+        mgr.stop_server().await;
+    }
+}
+
+#[test]
+fn test_shadow_tls_node_transport() {
+    use super::transport::{client_plugin, node_transport};
+    use shadowsocks_service::net::transport::ServerTransport;
+
+    let mut cfg = ServerConfig {
+        server_port: 8388,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("AAAAAAAAAAAAAAAAAAAAAA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    let ss_config = ShadowsocksConfig {
+        shadow_tls_password: Some("secret".to_string()),
+        shadow_tls_handshake_server: Some("www.example.com:443".to_string()),
+        ..default_ss_config()
+    };
+
+    match node_transport(&cfg, &ss_config).unwrap() {
+        Some(ServerTransport::ShadowTls(config)) => {
+            assert_eq!(config.password, "secret");
+            assert_eq!(config.handshake_server, "www.example.com:443");
+        }
+        transport => panic!("unexpected transport {:?}", transport),
+    }
+    assert!(client_plugin(&cfg, &ss_config).unwrap().is_none());
+
+    // Only one transport wraps connections of a node
+    cfg.obfs = Some("http".to_string());
+    assert!(node_transport(&cfg, &ss_config).is_err());
+
+    let ss_config = ShadowsocksConfig {
+        shadow_tls_handshake_server: None,
+        ..ss_config
+    };
+    cfg.obfs = None;
+    assert!(node_transport(&cfg, &ss_config).is_err());
+}
+
+#[tokio::test]
+async fn test_udp_over_tcp() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1500];
+        loop {
+            let (n, peer_addr) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], peer_addr).await.unwrap();
+        }
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    mgr.start_server(cfg).await.expect("server should start");
+
+    let user = ServerUser::new("0", users[0].uuid.as_bytes()[..16].to_vec());
+    let password = format!("YWJjZGVmZ2hpamtsbW5vcA==:{}", user.encoded_key());
+    let client_cfg = ClientConfig::new(
+        ("127.0.0.1", port),
+        password,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+    )
+    .unwrap();
+    let context = Context::new_shared(ServerType::Local);
+
+    // Version 1 frames every packet with its address, IPv4 is 0x00
+    let mut packet = vec![0x00, 127, 0, 0, 1];
+    packet.extend(echo_addr.port().to_be_bytes());
+    packet.extend(5u16.to_be_bytes());
+    packet.extend(b"hello");
+    // Version 2 connected to the echo server frames packets with lengths only
+    let mut request = vec![0x01, 0x01, 127, 0, 0, 1];
+    request.extend(echo_addr.port().to_be_bytes());
+    request.extend(5u16.to_be_bytes());
+    request.extend(b"hello");
+
+    for (magic, request, response_len) in [
+        ("sp.udp-over-tcp.arpa", packet, 7 + 2 + 5),
+        ("sp.v2.udp-over-tcp.arpa", request, 2 + 5),
+    ] {
+        let mut stream = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                match ProxyClientStream::connect(context.clone(), &client_cfg, (magic.to_owned(), 0)).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("server should be listening");
+        stream.write_all(&request).await.unwrap();
+
+        let mut response = vec![0u8; response_len];
+        tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut response))
+            .await
+            .expect("target should echo")
+            .unwrap();
+        assert!(response.ends_with(&[0, 5, b'h', b'e', b'l', b'l', b'o']));
+        if magic == "sp.udp-over-tcp.arpa" {
+            assert_eq!(&response[..5], &[0x00, 127, 0, 0, 1]);
+            assert_eq!(&response[5..7], &echo_addr.port().to_be_bytes());
+        }
+    }
+
+    let traffic = mgr.collect_user_traffic().await.unwrap();
+    assert_eq!(traffic.len(), 1);
+    assert!(traffic[0].upload > 0 && traffic[0].download > 0);
+
+    mgr.stop_server().await;
+}
+
+#[tokio::test]
+async fn test_mux() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    mgr.start_server(cfg).await.expect("server should start");
+
+    let user = ServerUser::new("0", users[0].uuid.as_bytes()[..16].to_vec());
+    let password = format!("YWJjZGVmZ2hpamtsbW5vcA==:{}", user.encoded_key());
+    let client_cfg = ClientConfig::new(
+        ("127.0.0.1", port),
+        password,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+    )
+    .unwrap();
+    let context = Context::new_shared(ServerType::Local);
+
+    let mut stream = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match ProxyClientStream::connect(context.clone(), &client_cfg, ("sp.mux.sing-box.arpa".to_owned(), 444)).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("server should be listening");
+
+    // smux frames: version 1, command, length and stream ID in little endian
+    fn frame(cmd: u8, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![1, cmd];
+        frame.extend((data.len() as u16).to_le_bytes());
+        frame.extend(1u32.to_le_bytes());
+        frame.extend(data);
+        frame
+    }
+
+    // Session of version 0 with smux, then a stream to the echo server over TCP
+    let mut request = vec![0, 1];
+    request.extend(frame(0, b""));
+    let mut stream_request = vec![0, 0, 0x01, 127, 0, 0, 1];
+    stream_request.extend(echo_addr.port().to_be_bytes());
+    stream_request.extend(b"hello");
+    request.extend(frame(2, &stream_request));
+    stream.write_all(&request).await.unwrap();
+
+    // Status of the stream, then the echo
+    let mut received = Vec::new();
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while received.len() < 1 + 5 {
+            let mut header = [0u8; 8];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_eq!(&header[..2], &[1, 2]);
+            assert_eq!(&header[4..], &1u32.to_le_bytes());
+            let mut data = vec![0u8; u16::from_le_bytes([header[2], header[3]]) as usize];
+            stream.read_exact(&mut data).await.unwrap();
+            received.extend(data);
+        }
+    })
+    .await
+    .expect("target should echo");
+    assert_eq!(received, b"\0hello");
+
+    let traffic = mgr.collect_user_traffic().await.unwrap();
+    assert_eq!(traffic.len(), 1);
+    assert!(traffic[0].upload > 0 && traffic[0].download > 0);
+
+    mgr.stop_server().await;
+}
+
+#[tokio::test]
+async fn test_proxy_protocol() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::net::TcpStream as OutboundTcpStream;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        proxy_protocol_trusted: vec!["127.0.0.1".to_string(), "invalid".to_string()],
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.context.proxy_protocol_trust().is_trusted(&"127.0.0.1:1".parse().unwrap()));
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    mgr.start_server(cfg).await.expect("server should start");
+
+    let user = ServerUser::new("0", users[0].uuid.as_bytes()[..16].to_vec());
+    let password = format!("YWJjZGVmZ2hpamtsbW5vcA==:{}", user.encoded_key());
+    let client_cfg = ClientConfig::new(
+        ("127.0.0.1", port),
+        password,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+    )
+    .unwrap();
+    let context = Context::new_shared(ServerType::Local);
+    let server_addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+
+    let header = format!("PROXY TCP4 192.0.2.1 127.0.0.1 56324 {port}\r\n");
+    let mut stream = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match OutboundTcpStream::connect_with_opts(&server_addr, &Default::default()).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("server should be listening");
+    stream.write_all(header.as_bytes()).await.unwrap();
+
+    let mut stream = ProxyClientStream::from_stream(context.clone(), stream, &client_cfg, echo_addr);
+    stream.write_all(b"hello").await.unwrap();
+    let mut response = [0u8; 5];
+    tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut response))
+        .await
+        .expect("target should echo")
+        .unwrap();
+    assert_eq!(&response, b"hello");
+
+    // Trusted sources must send headers
+    let mut stream = ProxyClientStream::connect(context, &client_cfg, echo_addr).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let result = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut response))
+        .await
+        .expect("server should close the connection");
+    assert!(matches!(result, Ok(0) | Err(..)));
+
+    mgr.stop_server().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_systemd_activated_sockets() {
+    use shadowsocks_service::net::systemd_activate_socket::SystemdActivatedSockets;
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    // Sockets passed by systemd are served instead of the port of the node
+    let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = tcp_listener.local_addr().unwrap().port();
+    let udp_socket = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        ..default_ss_config()
+    };
+    let mut mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    mgr.set_activated_sockets(SystemdActivatedSockets {
+        tcp_listener: Some(tcp_listener),
+        udp_socket: Some(udp_socket),
+    });
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+    assert!(!mgr.is_serving(std::time::Duration::from_secs(5)).await);
+
+    let node_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let cfg = ServerConfig {
+        server_port: node_port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+
+    let user = ServerUser::new("0", users[0].uuid.as_bytes()[..16].to_vec());
+    let password = format!("YWJjZGVmZ2hpamtsbW5vcA==:{}", user.encoded_key());
+    let client_cfg = ClientConfig::new(
+        ("127.0.0.1", port),
+        password,
+        CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+    )
+    .unwrap();
+    let context = Context::new_shared(ServerType::Local);
+
+    // Sockets must survive restarts of the server
+    for _ in 0..2 {
+        mgr.start_server(cfg.clone()).await.expect("server should start");
+
+        let mut stream = ProxyClientStream::connect(context.clone(), &client_cfg, echo_addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .expect("target should echo")
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(mgr.is_serving(std::time::Duration::from_secs(5)).await);
+    }
+
+    mgr.stop_server().await;
+}
+
+#[test]
+fn test_sandbox_rejects_nftables() {
+    // Nothing is changed before the config is rejected
+    let ss_config = ShadowsocksConfig {
+        user: Some("nobody".to_string()),
+        sandbox: true,
+        ban_threshold: Some(3),
+        ban_nftables_set_v4: Some("inet filter ss22v2b_ban4".to_string()),
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    assert!(mgr.enter_sandbox().is_err());
+
+    // Nothing to do without user, group or sandbox
+    let mgr = ShadowsocksServerManager::new(default_ss_config()).unwrap();
+    mgr.enter_sandbox().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_seccomp_allows_relaying() {
+    // Directory of the access log, set when the test binary runs this test alone
+    const CHILD_ENV: &str = "SS22V2B_TEST_SECCOMP_DIR";
+
+    if let Some(dir) = std::env::var_os(CHILD_ENV) {
+        relay_under_seccomp(std::path::Path::new(&dir));
+        return;
+    }
+
+    let dir = std::env::temp_dir().join(format!("ss22v2b-test-seccomp-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Filters can't be removed, the test binary is run again to relay under the filter like the server after startup
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["manager::tests::test_seccomp_allows_relaying", "--exact", "--test-threads=1"])
+        .env(CHILD_ENV, &dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+
+    // Written by the access log writer, started after the filter
+    let access_log = std::fs::read_to_string(dir.join("access.log")).unwrap();
+    assert_eq!(access_log.lines().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+fn relay_under_seccomp(dir: &std::path::Path) {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::relay::udprelay::ProxySocket;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        access_log: Some(dir.join("access.log")),
+        ..default_ss_config()
+    };
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    super::sandbox::linux::restrict_syscalls().unwrap();
+
+    // Syscalls able to leave the sandbox are denied, clone3 fails so that glibc falls back to clone
+    let errno = |ret: i64| (ret == -1).then(|| std::io::Error::last_os_error().raw_os_error().unwrap());
+    assert_eq!(errno(unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, 0) } as i64), Some(libc::EPERM));
+    assert_eq!(errno(unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 1) } as i64), Some(libc::EPERM));
+    assert_eq!(errno(unsafe { libc::syscall(libc::SYS_clone3, std::ptr::null::<u8>(), 0) }), Some(libc::ENOSYS));
+    let clone = unsafe { libc::syscall(libc::SYS_clone, libc::CLONE_NEWUSER | libc::SIGCHLD, 0, 0, 0, 0) };
+    if clone == 0 {
+        unsafe { libc::_exit(0) };
+    }
+    assert_eq!(errno(clone), Some(libc::EPERM));
+
+    // The runtime is built under the filter, like in main
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let udp_echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (n, peer) = udp_echo.recv_from(&mut buf).await.unwrap();
+            udp_echo.send_to(&buf[..n], peer).await.unwrap();
+        });
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let cfg = ServerConfig {
+            server_port: port as u32,
+            cipher: Some("aes-256-gcm".to_string()),
+            server_key: None,
+            base_config: None,
+            routes: Vec::new(),
+            obfs: None,
+            obfs_settings: None,
+        };
+        let users = make_users(1);
+        mgr.update_users(users.clone()).await;
+        mgr.start_server(cfg).await.expect("server should start");
+
+        let client_cfg =
+            ClientConfig::new(("127.0.0.1", port), users[0].uuid.clone(), CipherKind::AES_256_GCM).unwrap();
+        let context = Context::new_shared(ServerType::Local);
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match ProxyClientStream::connect(context.clone(), &client_cfg, echo_addr).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("server should be listening");
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .expect("target should echo")
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(stream);
+
+        let socket = ProxySocket::connect(context, &client_cfg).await.unwrap();
+        socket.send(&Address::from(udp_echo_addr), b"ping").await.unwrap();
+        let mut recv_buf = vec![0u8; 65536];
+        let (n, ..) = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut recv_buf))
+            .await
+            .expect("target should echo")
+            .unwrap();
+        assert_eq!(&recv_buf[..n], b"ping");
+
+        // Access records of UDP are written when the association ends
+        mgr.stop_server().await;
+    });
+    drop(runtime);
+    drop(mgr);
+}
+
+#[test]
+fn test_listen_ports_bounded() {
+    let ss_config = ShadowsocksConfig {
+        listen_ports: vec![PortRange::new(20000, 21023)],
+        ..default_ss_config()
+    };
+    assert_eq!(ss_config.listen_addrs_within(8443, 4096).unwrap().len(), 1025);
+    // server_port isn't counted
+    assert_eq!(ss_config.listen_addrs_within(20000, 4092).unwrap().len(), 1024);
+
+    // Half of the open files are kept for connections
+    let err = ss_config.listen_addrs_within(8443, 4092).unwrap_err();
+    assert!(err.to_string().contains("nftables"));
+    assert!(ss_config.listen_addrs_within(8443, 1024).is_err());
+
+    let ss_config = ShadowsocksConfig {
+        listen_ports: vec![PortRange::new(1, 65535)],
+        ..default_ss_config()
+    };
+    assert!(ss_config.listen_addrs_within(8443, 65536).is_err());
+    assert_eq!(ss_config.listen_addrs_within(8443, 1 << 20).unwrap().len(), 65535);
+}
+
+#[tokio::test]
+async fn test_listen_ports() {
+    use shadowsocks_service::shadowsocks::config::ServerType;
+    use shadowsocks_service::shadowsocks::context::Context;
+    use shadowsocks_service::shadowsocks::crypto::CipherKind;
+    use shadowsocks_service::shadowsocks::{ProxyClientStream, ServerConfig as ClientConfig};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    let free_port = || std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let (port, extra_port) = (free_port(), free_port());
+    let ss_config = ShadowsocksConfig {
+        block_internal_addrs: false,
+        listen_addr: "127.0.0.1".parse().unwrap(),
+        listen_ports: vec![PortRange::from(extra_port), PortRange::from(port)],
+        ..default_ss_config()
+    };
+    assert_eq!(ss_config.listen_addrs(port).unwrap().len(), 2);
+    let mgr = ShadowsocksServerManager::new(ss_config).unwrap();
+    let users = make_users(1);
+    mgr.update_users(users.clone()).await;
+
+    let cfg = ServerConfig {
+        server_port: port as u32,
+        cipher: Some("2022-blake3-aes-128-gcm".to_string()),
+        server_key: Some("YWJjZGVmZ2hpamtsbW5vcA==".to_string()),
+        base_config: None,
+        routes: Vec::new(),
+        obfs: None,
+        obfs_settings: None,
+    };
+    mgr.start_server(cfg).await.expect("server should start");
+
+    // Every port serves the same users, counting traffic together
+    let user = ServerUser::new("0", users[0].uuid.as_bytes()[..16].to_vec());
+    let password = format!("YWJjZGVmZ2hpamtsbW5vcA==:{}", user.encoded_key());
+    let context = Context::new_shared(ServerType::Local);
+    for server_port in [port, extra_port] {
+        let client_cfg = ClientConfig::new(
+            ("127.0.0.1", server_port),
+            password.clone(),
+            CipherKind::AEAD2022_BLAKE3_AES_128_GCM,
+        )
+        .unwrap();
+        let mut stream = ProxyClientStream::connect(context.clone(), &client_cfg, echo_addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tokio::time::timeout(std::time::Duration::from_secs(5), stream.read_exact(&mut buf))
+            .await
+            .expect("target should echo")
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let traffic = mgr.collect_user_traffic().await.unwrap();
+        assert_eq!(traffic.len(), 1);
+        assert_eq!(traffic[0].id, users[0].id);
+        assert!(traffic[0].upload > 0 && traffic[0].download > 0);
+    }
+
+    mgr.stop_server().await;
+}
+
+#[test]
+fn test_salt_expiry_covers_timestamp_window() {
+    let expiry = |timestamp_limit: u64| {
+        let ss_config: ShadowsocksConfig = toml::from_str(&format!("timestamp_limit = {timestamp_limit}")).unwrap();
+        ss_config.replay_protector_config().salt_expiry.as_secs()
+    };
+    assert_eq!(expiry(30), 60);
+    assert_eq!(expiry(120), 240);
+    // Never shorter than the default window
+    assert_eq!(expiry(5), 60);
+    // Salts are still remembered without the timestamp check
+    assert_eq!(expiry(0), 3600);
+}
@@ -0,0 +1,122 @@
+use anyhow::{Result, anyhow, bail};
+use shadowsocks_service::net::transport::{ServerTransport, ShadowTlsConfig, WebSocketConfig, load_tls_config};
+use shadowsocks_service::shadowsocks::{config::Mode, plugin::PluginConfig};
+
+use crate::config::ShadowsocksConfig as AppShadowsocksConfig;
+use crate::v2board::{ObfsSettings, ServerConfig};
+
+/// Transports selected by `obfs` of the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeTransport {
+    Plain,
+    ObfsHttp,
+    WebSocket,
+}
+
+impl NodeTransport {
+    fn of(config: &ServerConfig) -> Result<Self> {
+        match config.obfs.as_deref().unwrap_or_default() {
+            "" | "none" | "plain" => Ok(Self::Plain),
+            "http" => Ok(Self::ObfsHttp),
+            "websocket" | "ws" => Ok(Self::WebSocket),
+            obfs => bail!("Unsupported obfs: {}", obfs),
+        }
+    }
+}
+
+/// Path of WebSocket requests of the node, "/" if not specified
+fn websocket_path(settings: Option<&ObfsSettings>) -> String {
+    match settings.and_then(|s| s.path.as_deref()) {
+        Some(path) if path.starts_with('/') => path.to_owned(),
+        Some(path) if !path.is_empty() => format!("/{}", path),
+        _ => "/".to_owned(),
+    }
+}
+
+/// Check if WebSocket is served over TLS, with both the certificate and the key configured
+fn websocket_tls(ss_config: &AppShadowsocksConfig) -> bool {
+    ss_config.transport_tls_cert.is_some() && ss_config.transport_tls_key.is_some()
+}
+
+/// ShadowTLS of all nodes, `None` if not configured
+fn shadow_tls(ss_config: &AppShadowsocksConfig) -> Result<Option<ShadowTlsConfig>> {
+    match (&ss_config.shadow_tls_password, &ss_config.shadow_tls_handshake_server) {
+        (Some(password), Some(handshake_server)) => Ok(Some(ShadowTlsConfig {
+            password: password.clone(),
+            handshake_server: handshake_server.clone(),
+        })),
+        (None, None) => Ok(None),
+        _ => bail!("Both shadow_tls_password and shadow_tls_handshake_server are required for ShadowTLS"),
+    }
+}
+
+/// Transport of the node driven by `obfs` and `obfs_settings`, `None` without transport
+pub fn node_transport(config: &ServerConfig, ss_config: &AppShadowsocksConfig) -> Result<Option<ServerTransport>> {
+    let transport = NodeTransport::of(config)?;
+    if let Some(shadow_tls) = shadow_tls(ss_config)? {
+        if transport != NodeTransport::Plain {
+            bail!(
+                "ShadowTLS can't be combined with obfs {} of the node",
+                config.obfs.as_deref().unwrap_or_default()
+            );
+        }
+        return Ok(Some(ServerTransport::ShadowTls(shadow_tls)));
+    }
+
+    match transport {
+        NodeTransport::Plain => Ok(None),
+        NodeTransport::ObfsHttp => Ok(Some(ServerTransport::ObfsHttp)),
+        NodeTransport::WebSocket => {
+            let tls = match (&ss_config.transport_tls_cert, &ss_config.transport_tls_key) {
+                (Some(cert), Some(key)) => Some(
+                    load_tls_config(cert, key).map_err(|e| anyhow!("Failed to load transport TLS certificate: {}", e))?,
+                ),
+                (None, None) => None,
+                _ => bail!("Both transport_tls_cert and transport_tls_key are required for TLS"),
+            };
+            Ok(Some(ServerTransport::WebSocket(WebSocketConfig {
+                path: websocket_path(config.obfs_settings.as_ref()),
+                tls,
+            })))
+        }
+    }
+}
+
+/// SIP003 plugin of clients connecting through the node's transport
+pub fn client_plugin(config: &ServerConfig, ss_config: &AppShadowsocksConfig) -> Result<Option<PluginConfig>> {
+    let settings = config.obfs_settings.as_ref();
+    let host = settings.and_then(|s| s.host.as_deref());
+
+    // ShadowTLS clients are configured apart from shadowsocks, not with a SIP003 plugin
+    if shadow_tls(ss_config)?.is_some() {
+        return Ok(None);
+    }
+
+    let (plugin, mut opts) = match NodeTransport::of(config)? {
+        NodeTransport::Plain => return Ok(None),
+        NodeTransport::ObfsHttp => ("obfs-local", vec!["obfs=http".to_owned()]),
+        NodeTransport::WebSocket => {
+            let mut opts = Vec::new();
+            if websocket_tls(ss_config) {
+                opts.push("tls".to_owned());
+            }
+            opts.push(format!("path={}", websocket_path(settings)));
+            // Multiplexed streams are not supported by the server
+            opts.push("mux=0".to_owned());
+            ("v2ray-plugin", opts)
+        }
+    };
+    if let Some(host) = host {
+        match plugin {
+            "obfs-local" => opts.push(format!("obfs-host={}", host)),
+            _ => opts.push(format!("host={}", host)),
+        }
+    }
+
+    Ok(Some(PluginConfig {
+        plugin: plugin.to_owned(),
+        plugin_opts: Some(opts.join(";")),
+        plugin_args: Vec::new(),
+        plugin_mode: Mode::TcpOnly,
+    }))
+}
@@ -0,0 +1,99 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use shadowsocks_service::shadowsocks::config::ServerUser;
+use shadowsocks_service::shadowsocks::crypto::{CipherCategory, CipherKind};
+use shadowsocks_service::shadowsocks::{ServerConfig as ShadowsocksConfig, config::method_support_eih};
+use std::str::FromStr;
+
+use super::transport::client_plugin;
+use crate::config::ShadowsocksConfig as AppShadowsocksConfig;
+use crate::v2board::{ServerConfig, UserInfo};
+
+/// How user keys of AEAD 2022 ciphers are derived from the user list of the panel
+///
+/// Users of AEAD ciphers always use a password, the UUID unless derived by `password`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserKeyDerivation {
+    /// Leading bytes of the UUID string, as many as the key length of the cipher
+    #[default]
+    UuidPrefix,
+    /// The `password` field of the user, base64 of the key
+    Password,
+}
+
+impl UserKeyDerivation {
+    /// Create the server user of a panel user for `method`
+    pub fn server_user(self, user: &UserInfo, method: CipherKind) -> Result<ServerUser> {
+        let name = user.id.to_string();
+        match method.category() {
+            CipherCategory::Aead => Ok(ServerUser::with_password(name, method, &self.aead_password(user)?)?),
+            CipherCategory::Aead2022 => {
+                let key_len = method.key_len();
+                let server_user = match self {
+                    Self::UuidPrefix => {
+                        let key = user.uuid.as_bytes().get(..key_len).ok_or_else(|| {
+                            anyhow!("UUID is shorter than the {} bytes key of {}", key_len, method)
+                        })?;
+                        ServerUser::new(name, key.to_vec())
+                    }
+                    Self::Password => ServerUser::with_encoded_key(name, self.aead_password(user)?.as_str())?,
+                };
+
+                if server_user.key().len() != key_len {
+                    bail!(
+                        "{} bytes key doesn't fit {}, expecting {} bytes",
+                        server_user.key().len(),
+                        method,
+                        key_len
+                    );
+                }
+                Ok(server_user)
+            }
+            _ => bail!("{} doesn't support multiple users", method),
+        }
+    }
+
+    /// Password of a panel user in client configs
+    pub fn password(self, user: &UserInfo, method: CipherKind) -> Result<String> {
+        match method.category() {
+            CipherCategory::Aead2022 => Ok(self.server_user(user, method)?.encoded_key()),
+            _ => self.aead_password(user),
+        }
+    }
+
+    fn aead_password(self, user: &UserInfo) -> Result<String> {
+        match self {
+            Self::Password => user
+                .password
+                .clone()
+                .ok_or_else(|| anyhow!("Password of user {} not provided by the panel", user.id)),
+            Self::UuidPrefix => Ok(user.uuid.clone()),
+        }
+    }
+}
+
+/// ss:// link of a panel user connecting to `host`, named after the user ID
+///
+/// Nodes with transports carry the matching client plugin.
+pub fn user_link(ss_config: &AppShadowsocksConfig, config: &ServerConfig, user: &UserInfo, host: &str) -> Result<String> {
+    let derivation = ss_config.user_key_derivation;
+    let cipher = config.cipher.as_deref().ok_or_else(|| anyhow!("Cipher not specified in server config"))?;
+    let method = CipherKind::from_str(cipher).map_err(|_| anyhow!("Invalid cipher: {}", cipher))?;
+
+    let mut password = derivation.password(user, method)?;
+    if method_support_eih(method) {
+        let server_key = config
+            .server_key
+            .as_deref()
+            .ok_or_else(|| anyhow!("Server key not specified in config"))?;
+        password = format!("{}:{}", server_key, password);
+    }
+
+    let mut client_config = ShadowsocksConfig::new((host.to_owned(), config.server_port as u16), password, method)?;
+    client_config.set_remarks(user.id.to_string());
+    if let Some(plugin) = client_plugin(config, ss_config)? {
+        client_config.set_plugin(plugin);
+    }
+    Ok(client_config.to_url())
+}
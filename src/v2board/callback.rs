@@ -1,4 +1,4 @@
-use crate::v2board::models::{UserInfo, UserTraffic, ServerConfig};
+use crate::v2board::models::{UserInfo, UserTraffic, UserViolation, ServerConfig};
 use async_trait::async_trait;
 
 /// Callback trait for handling events
@@ -12,4 +12,7 @@ pub trait EventCallback: Send + Sync {
     
     /// Called to get traffic data for pushing. Return None to skip push.
     async fn get_traffic_data(&self) -> Option<Vec<UserTraffic>>;
+
+    /// Called to get audit rule violations for reporting. Return None to skip report.
+    async fn get_violations(&self) -> Option<Vec<UserViolation>>;
 }
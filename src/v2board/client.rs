@@ -1,24 +1,64 @@
 use anyhow::{Result, anyhow};
-use log::{error, info};
+use log::{error, info, warn};
 use reqwest::{Client, Response};
 use serde_json::Value;
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tokio::time::interval;
 
-use crate::v2board::models::{ApiConfig, ServerConfig, UserInfo, UserTraffic};
+use crate::v2board::models::{ApiConfig, ServerConfig, UserInfo, UserTraffic, UserViolation};
 use crate::v2board::callback::EventCallback;
 
+/// Violations kept for retrying after failed reports, the oldest are dropped first
+const MAX_PENDING_VIOLATIONS: usize = 4096;
+
+/// Progress of pulling a node, watched for a stuck panel sync
+#[derive(Debug, Clone)]
+pub struct PullWatch {
+    node_id: i32,
+    // Last pull and the longest expected gap until the next one
+    progress: Arc<Mutex<(Instant, Duration)>>,
+}
+
+impl PullWatch {
+    fn new(node_id: i32) -> Self {
+        Self {
+            node_id,
+            progress: Arc::new(Mutex::new((Instant::now(), Duration::ZERO))),
+        }
+    }
+
+    /// Record a pull, the next one is expected within `max_gap`
+    fn record(&self, max_gap: Duration) {
+        *self.progress.lock().unwrap() = (Instant::now(), max_gap);
+    }
+
+    pub fn node_id(&self) -> i32 {
+        self.node_id
+    }
+
+    /// Check if the next pull isn't late by more than `grace`
+    pub fn is_alive(&self, grace: Duration) -> bool {
+        let (pulled_at, max_gap) = *self.progress.lock().unwrap();
+        pulled_at.elapsed() <= max_gap + grace
+    }
+}
+
 pub struct ApiClient {
     client: Client,
     api_host: String,
     node_id: i32,
     key: String,
+    audit_report_path: Option<String>,
     server_config: Arc<RwLock<Option<ServerConfig>>>,
     etags: Arc<RwLock<HashMap<String, String>>>,
     callback: Option<Arc<dyn EventCallback>>,
+    timeout: Duration,
+    pull_watch: PullWatch,
+    // Violations failed to report, sent again with the next push
+    pending_violations: Mutex<Vec<UserViolation>>,
 }
 
 impl ApiClient {
@@ -36,9 +76,13 @@ impl ApiClient {
             api_host: config.api_host.clone(),
             node_id: config.node_id,
             key: config.key.clone(),
+            audit_report_path: config.audit_report_path.clone(),
             server_config: Arc::new(RwLock::new(None)),
             etags: Arc::new(RwLock::new(HashMap::new())),
             callback: None,
+            timeout,
+            pull_watch: PullWatch::new(config.node_id),
+            pending_violations: Mutex::new(Vec::new()),
         })
     }
 
@@ -91,11 +135,11 @@ impl ApiClient {
             return Err(anyhow!("Node not modified"));
         }
 
-        if let Some(new_etag) = res.headers().get("etag") {
-            if let Ok(etag_str) = new_etag.to_str() {
-                let mut etags = self.etags.write().await;
-                etags.insert("node".to_string(), etag_str.to_string());
-            }
+        if let Some(new_etag) = res.headers().get("etag")
+            && let Ok(etag_str) = new_etag.to_str()
+        {
+            let mut etags = self.etags.write().await;
+            etags.insert("node".to_string(), etag_str.to_string());
         }
 
         let json_data = self.parse_response(res, path).await?;
@@ -132,11 +176,11 @@ impl ApiClient {
             return Err(anyhow!("User not modified"));
         }
 
-        if let Some(new_etag) = res.headers().get("etag") {
-            if let Ok(etag_str) = new_etag.to_str() {
-                let mut etags = self.etags.write().await;
-                etags.insert("users".to_string(), etag_str.to_string());
-            }
+        if let Some(new_etag) = res.headers().get("etag")
+            && let Ok(etag_str) = new_etag.to_str()
+        {
+            let mut etags = self.etags.write().await;
+            etags.insert("users".to_string(), etag_str.to_string());
         }
 
         let json_data = self.parse_response(res, path).await?;
@@ -175,11 +219,35 @@ impl ApiClient {
         Ok(())
     }
 
+    pub async fn report_violations(&self, violations: &[UserViolation]) -> Result<()> {
+        let path = match self.audit_report_path.as_deref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let url = self.assemble_url(path);
+
+        let res = self
+            .client
+            .post(&url)
+            .query(&self.build_query_params())
+            .json(violations)
+            .send()
+            .await?;
+
+        self.parse_response(res, path).await?;
+        Ok(())
+    }
+
     /// Set the event callback
     pub fn set_callback(&mut self, callback: Arc<dyn EventCallback>) {
         self.callback = Some(callback);
     }
 
+    /// Watch of the pull task, for detecting a stuck sync
+    pub fn pull_watch(&self) -> PullWatch {
+        self.pull_watch.clone()
+    }
+
     /// Start the client and run continuously
     pub async fn run(&self) -> Result<()> {
         info!("Fetching node configuration...");
@@ -216,7 +284,11 @@ impl ApiClient {
     async fn pull_task(&self, interval_secs: u64) -> Result<()> {
         let mut ticker = interval(Duration::from_secs(interval_secs));
         ticker.tick().await;
-        
+
+        // Fetching users and the node may take a timeout each
+        let max_gap = Duration::from_secs(interval_secs) + self.timeout * 2;
+        self.pull_watch.record(max_gap);
+
         loop {
             ticker.tick().await;
             
@@ -253,6 +325,8 @@ impl ApiClient {
                     }
                 }
             }
+
+            self.pull_watch.record(max_gap);
         }
     }
 
@@ -262,6 +336,8 @@ impl ApiClient {
         
         loop {
             ticker.tick().await;
+
+            self.push_violations().await;
             
             if let Some(callback) = &self.callback {
                 if let Some(traffic_vec) = callback.get_traffic_data().await {
@@ -287,4 +363,43 @@ impl ApiClient {
             }
         }
     }
+
+    /// Push audit rule violations collected since last push, and ones failed to report before
+    pub(super) async fn push_violations(&self) {
+        let new_violations = match &self.callback {
+            Some(callback) => callback.get_violations().await.unwrap_or_default(),
+            None => Vec::new(),
+        };
+
+        for v in &new_violations {
+            warn!(
+                "[Push] User {} violated audit rule {} with target {}",
+                v.user_id, v.rule_id, v.target
+            );
+        }
+
+        if self.audit_report_path.is_none() {
+            return;
+        }
+
+        let mut violations = std::mem::take(&mut *self.pending_violations.lock().unwrap());
+        violations.extend(new_violations);
+        if violations.is_empty() {
+            return;
+        }
+
+        info!("[Push] Reporting {} audit rule violations...", violations.len());
+        match self.report_violations(&violations).await {
+            Ok(_) => info!("[Push] Audit rule violations reported successfully"),
+            Err(e) => {
+                error!("[Push] Failed to report audit rule violations, retrying with next push: {}", e);
+                if violations.len() > MAX_PENDING_VIOLATIONS {
+                    let dropped = violations.len() - MAX_PENDING_VIOLATIONS;
+                    warn!("[Push] Dropped {} audit rule violations pending for too long", dropped);
+                    violations.drain(..dropped);
+                }
+                *self.pending_violations.lock().unwrap() = violations;
+            }
+        }
+    }
 }
@@ -2,9 +2,9 @@ mod models;
 mod callback;
 mod client;
 
-pub use models::{UserInfo, UserTraffic, ApiConfig, ServerConfig};
+pub use models::{UserInfo, UserTraffic, UserViolation, ApiConfig, ServerConfig, RouteRule, ObfsSettings};
 pub use callback::EventCallback;
-pub use client::ApiClient;
+pub use client::{ApiClient, PullWatch};
 
 #[cfg(test)]
 mod tests;
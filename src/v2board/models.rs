@@ -1,9 +1,21 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserInfo {
     pub id: i32,
     pub uuid: String,
+    /// Base64 key or password of the user, used by `user_key_derivation = "password"`
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Overrides `user_max_tcp_connections` of the node
+    #[serde(default)]
+    pub max_tcp_connections: Option<u32>,
+    /// Overrides `user_max_udp_associations` of the node
+    #[serde(default)]
+    pub max_udp_associations: Option<u32>,
+    /// Overrides `user_max_new_connections` of the node
+    #[serde(default)]
+    pub max_new_connections: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,12 +25,26 @@ pub struct UserTraffic {
     pub download: i64,
 }
 
+/// A connection blocked by an audit rule, reported to the panel
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UserViolation {
+    pub user_id: i32,
+    pub rule_id: i32,
+    pub target: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiConfig {
     pub api_host: String,
     pub node_id: i32,
     pub key: String,
     pub timeout: u64,
+    /// Panel API path for reporting audit rule violations, violations are only logged if not set
+    #[serde(default)]
+    pub audit_report_path: Option<String>,
+    /// IDs of other shadowsocks nodes listening on the port of `node_id`, distinguished by their server_key
+    #[serde(default)]
+    pub shared_node_ids: Vec<i32>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -27,7 +53,32 @@ pub struct ServerConfig {
     pub cipher: Option<String>,
     #[serde(rename = "server_key")]
     pub server_key: Option<String>,
-    pub base_config: Option<BaseConfig>
+    pub base_config: Option<BaseConfig>,
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    pub routes: Vec<RouteRule>,
+    /// Transport of the node: "http" for simple-obfs, "websocket" for v2ray-plugin
+    #[serde(default)]
+    pub obfs: Option<String>,
+    #[serde(default)]
+    pub obfs_settings: Option<ObfsSettings>,
+}
+
+/// Settings of the node's transport
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ObfsSettings {
+    /// Host name clients put in requests
+    pub host: Option<String>,
+    /// Path of WebSocket requests
+    pub path: Option<String>,
+}
+
+/// Audit rule of the node
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteRule {
+    pub id: i32,
+    #[serde(rename = "match", default, deserialize_with = "deserialize_match")]
+    pub match_rules: Vec<String>,
+    pub action: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -35,3 +86,35 @@ pub struct BaseConfig {
     pub push_interval: Option<u32>,
     pub pull_interval: Option<u32>,
 }
+
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// `match` of routes is either a list, or a string separated by commas
+fn deserialize_match<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Match {
+        List(Vec<String>),
+        Joined(String),
+    }
+
+    Ok(match Option::<Match>::deserialize(deserializer)? {
+        Some(Match::List(list)) => list,
+        Some(Match::Joined(s)) => s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        None => Vec::new(),
+    })
+}
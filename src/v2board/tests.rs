@@ -1,7 +1,7 @@
 use super::*;
 use log::info;
 use std::fs;
-use std::sync::Once;
+use std::sync::{Arc, Mutex, Once};
 
 static INIT_LOGGER: Once = Once::new();
 
@@ -63,3 +63,93 @@ async fn test_combined() {
     let user_list = client.get_user_list().await.expect("cannot get user list");
     info!("{:?}", user_list);
 }
+
+/// Callback returning each batch of violations once
+struct ViolationsCallback {
+    batches: Mutex<Vec<Vec<UserViolation>>>,
+}
+
+#[async_trait::async_trait]
+impl EventCallback for ViolationsCallback {
+    fn on_server_config_updated(&self, _config: ServerConfig) {}
+
+    fn on_users_updated(&self, _users: Vec<UserInfo>) {}
+
+    async fn get_traffic_data(&self) -> Option<Vec<UserTraffic>> {
+        None
+    }
+
+    async fn get_violations(&self) -> Option<Vec<UserViolation>> {
+        let mut batches = self.batches.lock().unwrap();
+        if batches.is_empty() {
+            None
+        } else {
+            Some(batches.remove(0))
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_failed_violations_are_reported_again() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init_logger();
+
+    // The panel fails the first report and accepts the second
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api_host = format!("http://{}", listener.local_addr().unwrap());
+    let server = tokio::spawn(async move {
+        let mut bodies = Vec::new();
+        for status in ["500 Internal Server Error", "200 OK"] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let body = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).into_owned();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|line| {
+                            let line = line.to_ascii_lowercase();
+                            line.strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= len {
+                        break body.to_owned();
+                    }
+                }
+            };
+            bodies.push(serde_json::from_str::<Vec<UserViolation>>(&body).unwrap());
+            let response = format!("HTTP/1.1 {status}\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}");
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        bodies
+    });
+
+    let violation = |user_id| UserViolation {
+        user_id,
+        rule_id: 1,
+        target: "example.com:443".to_owned(),
+    };
+    let mut client = ApiClient::new(ApiConfig {
+        api_host,
+        node_id: 1,
+        key: "key".to_owned(),
+        timeout: 5,
+        audit_report_path: Some("/audit".to_owned()),
+        shared_node_ids: Vec::new(),
+    })
+    .unwrap();
+    client.set_callback(Arc::new(ViolationsCallback {
+        batches: Mutex::new(vec![vec![violation(1)], vec![violation(2)]]),
+    }));
+
+    client.push_violations().await;
+    client.push_violations().await;
+
+    let bodies = server.await.unwrap();
+    assert_eq!(bodies[0], vec![violation(1)]);
+    assert_eq!(bodies[1], vec![violation(1), violation(2)]);
+}